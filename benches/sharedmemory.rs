@@ -0,0 +1,68 @@
+//! Throughput of the in memory backend with several threads updating sessions at once
+//!
+//! Run with `cargo bench --bench sharedmemory`. With a single shard every
+//! update waits on the same lock, so the sharded runs should pull ahead as
+//! the updates spread over more sessions.
+
+#![feature(test)]
+
+extern crate estimate;
+extern crate test;
+
+use estimate::dal::{SharedMemoryDB, StoryData, StoryDataProvider};
+use estimate::estimates::session::Session;
+use std::thread;
+use test::Bencher;
+
+const THREADS: usize = 8;
+const UPDATES_PER_THREAD: usize = 1000;
+
+/// Update `sessions` sessions from `THREADS` threads at once
+fn concurrent_updates(b: &mut Bencher, db: SharedMemoryDB, sessions: usize) {
+    let mut session_ids = Vec::new();
+    for _ in 0..sessions {
+        let my_session = Session::new();
+        session_ids.push(my_session.session_id.clone());
+        db.get_story_data().unwrap().add_session(my_session).unwrap();
+    }
+    b.iter(|| {
+        let threads: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let db = db.clone();
+                let session_id = session_ids[t % sessions].clone();
+                thread::spawn(move || for n in 0..UPDATES_PER_THREAD {
+                    db.get_story_data()
+                        .unwrap()
+                        .update_session(&session_id, |s, _| {
+                            s.average = Some(n as f32);
+                            Ok(())
+                        })
+                        .unwrap();
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    });
+}
+
+#[bench]
+fn one_lock_one_session(b: &mut Bencher) {
+    concurrent_updates(b, SharedMemoryDB::with_shards(1), 1);
+}
+
+#[bench]
+fn one_lock_many_sessions(b: &mut Bencher) {
+    concurrent_updates(b, SharedMemoryDB::with_shards(1), THREADS);
+}
+
+#[bench]
+fn sharded_one_session(b: &mut Bencher) {
+    concurrent_updates(b, SharedMemoryDB::new(), 1);
+}
+
+#[bench]
+fn sharded_many_sessions(b: &mut Bencher) {
+    concurrent_updates(b, SharedMemoryDB::new(), THREADS);
+}
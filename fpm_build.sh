@@ -27,6 +27,7 @@ fpm --verbose --force -s dir -t deb -n "${NAME}" -v "${DEB_VERSION}" --deb-user
     --architecture "${ARCHITECTURE}" \
     --package ${BUILD_DIR} \
     -s dir target/release/estimate=/opt/storyestimates/bin/estimate \
+    target/release/estimate-admin=/opt/storyestimates/bin/estimate-admin \
     templates=/opt/storyestimates/ \
     vendor/swagger-ui=/opt/storyestimates/ \
     config/nginx/storyestimates.conf=/opt/storyestimates/config/nginx/ \
@@ -0,0 +1,197 @@
+//! Administration of a StoryEstimates deployment, through the same storage
+//! backend and configuration the server uses
+
+extern crate estimate;
+extern crate rocket;
+extern crate serde_json;
+#[macro_use]
+extern crate error_chain;
+
+use estimate::config::{BackendConfig, ExpiryConfig, TenantConfig};
+use estimate::dal::dump;
+use estimate::dal::{RedisDB, StoryBackend, StoryBackendInstance, StoryData, StoryDataProvider};
+use estimate::errors::*;
+use estimate::estimates::session::SessionID;
+use estimate::service::{self, admin};
+use estimate::user::UserID;
+use rocket::config::ConfigError;
+use std::env;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+
+const USAGE: &'static str = "Usage: estimate-admin [--tenant <name>] <command> [arguments]
+
+Commands:
+    sessions [search]             List sessions, or those whose ID contains search
+    show <session>                Print a session with its participants and admins
+    delete-session <session>      Delete a session and everything in it
+    tombstones                    List who deleted which sessions, and when
+    users [search]                List users, or those whose ID contains search
+    remove-user <user>            Delete a user and take them out of every session
+    grant-admin <session> <user>  Make a user an admin of a session
+    stats                         Count users, sessions, participants and admins
+    export <file>                 Write a dump of every user and session
+    import <file>                 Load a dump, replacing sessions with the same ID
+    fsck [--repair]               Check the Redis data, optionally fixing problems
+    migrate                       Bring the Redis data up to the current schema
+
+The backend is configured by Rocket.toml and STORYESTIMATES_* environment
+variables, just like the server. With --tenant, commands work on that tenant's
+data instead of the default tenant's.";
+
+/// The argument at `index`, which the command can't do without
+fn required<'a>(args: &'a [String], index: usize, name: &str) -> Result<&'a str> {
+    args.get(index)
+        .map(|arg| arg.as_str())
+        .ok_or(format!("Missing the {} argument\n\n{}", name, USAGE).into())
+}
+
+fn run(args: &[String]) -> Result<()> {
+    let (tenant, args) = match args.first().map(|arg| arg.as_str()) {
+        Some("--tenant") => (Some(required(args, 1, "tenant")?), &args[2..]),
+        _ => (None, args),
+    };
+    let command = match args.first() {
+        Some(command) => command.as_str(),
+        None => {
+            println!("{}", USAGE);
+            return Ok(());
+        },
+    };
+
+    let _ = rocket::ignite();
+    let config = rocket::config::active().ok_or(ConfigError::NotFound)?;
+    let expiry = ExpiryConfig::from_config(config)?;
+    let backend = match BackendConfig::from_config(config)? {
+        BackendConfig::Memory => {
+            bail!("The memory backend only keeps data inside the server, set memory_data_dir \
+                   to administer it")
+        },
+        // Only a single process can use the journal, so the server must be stopped
+        backend_config => StoryBackend::from_config(backend_config, &expiry)?,
+    };
+    let backend = match tenant {
+        Some(name) => {
+            if !TenantConfig::from_config(config)?.iter().any(|t| t.name == name) {
+                bail!("'{}' is not one of the configured tenants", name);
+            }
+            backend.tenant(name)?
+        },
+        None => backend,
+    };
+    let mut dal = backend.get_story_data()?;
+
+    match command {
+        "sessions" => {
+            let search = args.get(1).map(|s| s.as_str()).unwrap_or("");
+            for session_id in admin::find_sessions(&dal, search)? {
+                println!("{}", session_id);
+            }
+        },
+        "show" => {
+            let session_id = SessionID(required(args, 1, "session")?.to_string());
+            let session = service::lookup_session(&dal, &session_id)?
+                .ok_or(ErrorKind::ObjectNotFound(format!("Session not found: {}", session_id)))?;
+            println!("{}", serde_json::to_string_pretty(&session)?);
+        },
+        "delete-session" => {
+            dal.del_session(&SessionID(required(args, 1, "session")?.to_string()))?;
+        },
+        "tombstones" => {
+            for entry in dal.get_tombstones()? {
+                println!("{}", serde_json::to_string(&entry)?);
+            }
+        },
+        "users" => {
+            let search = args.get(1).map(|s| s.as_str()).unwrap_or("");
+            for user_id in admin::find_users(&dal, search)? {
+                println!("{}", user_id);
+            }
+        },
+        "remove-user" => {
+            admin::remove_user(&mut dal, &UserID(required(args, 1, "user")?.to_string()))?;
+        },
+        "grant-admin" => {
+            let session_id = SessionID(required(args, 1, "session")?.to_string());
+            let user_id = UserID(required(args, 2, "user")?.to_string());
+            admin::grant_admin(&mut dal, &session_id, &user_id)?;
+        },
+        "stats" => {
+            let stats = admin::statistics(&dal)?;
+            println!("users: {}", stats.users);
+            println!("sessions: {} ({} empty)", stats.sessions, stats.empty_sessions);
+            println!("participants: {}", stats.participants);
+            println!("admins: {}", stats.admins);
+        },
+        "export" => {
+            let file = BufWriter::new(File::create(required(args, 1, "file")?)?);
+            let summary = dump::write_dump(&dal, file)?;
+            println!("Exported {} users and {} sessions", summary.users, summary.sessions);
+        },
+        "import" => {
+            let file = BufReader::new(File::open(required(args, 1, "file")?)?);
+            let summary = dump::read_dump(&mut dal, file)?;
+            println!("Imported {} users and {} sessions", summary.users, summary.sessions);
+        },
+        "fsck" => {
+            let repair = args[1..].iter().any(|arg| arg == "--repair");
+            match dal {
+                StoryBackendInstance::Redis(ref db) => fsck(db, repair)?,
+                _ => bail!("fsck only checks the redis backend"),
+            }
+        },
+        "migrate" => {
+            match dal {
+                StoryBackendInstance::Redis(ref db) => {
+                    let ran = db.migrate()?;
+                    println!("Ran {} migrations, the data is at schema version {}",
+                             ran,
+                             db.schema_version()?);
+                },
+                _ => bail!("Only the redis backend has a schema to migrate"),
+            }
+        },
+        _ => bail!("Unknown command '{}'\n\n{}", command, USAGE),
+    }
+    Ok(())
+}
+
+/// Report problems with the data in Redis, fixing those it can when asked to
+fn fsck(db: &RedisDB, mut repair: bool) -> Result<()> {
+    // Records from an older schema look broken, and repairing would delete them
+    if repair {
+        if let Err(e) = db.require_current_schema() {
+            println!("Not repairing anything: {}", e);
+            repair = false;
+        }
+    }
+    let problems = db.check_integrity()?;
+    let mut unresolved = 0;
+    for problem in &problems {
+        if repair && db.repair(problem)? {
+            println!("repaired: {}", problem);
+        } else {
+            println!("{}", problem);
+            unresolved += 1;
+        }
+    }
+    println!("{} problems found, {} left", problems.len(), unresolved);
+    if unresolved > 0 {
+        bail!(ErrorKind::DataIntegrityError(format!("{} problems were not repaired", unresolved)));
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(ref e) = run(&args) {
+        let stderr = &mut ::std::io::stderr();
+        let errmsg = "Error writing to stderr";
+
+        writeln!(stderr, "error: {}", e).expect(errmsg);
+        for e in e.iter().skip(1) {
+            writeln!(stderr, "caused by: {}", e).expect(errmsg);
+        }
+        ::std::process::exit(1);
+    }
+}
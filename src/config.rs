@@ -0,0 +1,778 @@
+use dal::{DEFAULT_REDIS_NAMESPACE, FsyncPolicy};
+use errors::*;
+use num_cpus;
+use redis::{ConnectionAddr, ConnectionInfo, IntoConnectionInfo};
+use rocket::config::{Config, ConfigError};
+use std::env;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Environment variables with this prefix override values from `Rocket.toml`
+/// e.g. `STORYESTIMATES_REDIS_URL` overrides `redis_url`
+const ENV_PREFIX: &'static str = "STORYESTIMATES_";
+
+const DEFAULT_SNAPSHOT_INTERVAL_SECS: i64 = 5 * 60;
+
+const DEFAULT_SESSION_TTL_SECS: i64 = 60 * 60 * 24;
+const DEFAULT_USER_TTL_SECS: i64 = 60 * 60 * 24 * 30;
+const DEFAULT_EXPIRY_SWEEP_INTERVAL_SECS: i64 = 60;
+
+const DEFAULT_BEARER_TTL_SECS: i64 = 15 * 60;
+const DEFAULT_COOKIE_TTL_SECS: i64 = 8 * 60 * 60;
+
+const DEFAULT_OIDC_SCOPE: &'static str = "openid profile";
+/// The shortest secret bearer tokens may be signed with, as HMAC-SHA256 keys
+/// shorter than the hash add nothing
+const MIN_BEARER_SECRET_LENGTH: usize = 32;
+
+const DEFAULT_SQLITE_PATH: &'static str = "storyestimates.sqlite";
+
+/// Paths the server already uses, which tenants can't be named after
+const RESERVED_PATHS: &'static [&'static str] = &["api", "docs"];
+
+const DEFAULT_REDIS_HOST: &'static str = "127.0.0.1";
+const DEFAULT_REDIS_PORT: u16 = 6379;
+
+/// Which `StoryData` implementation the server stores its data in
+pub enum BackendConfig {
+    Memory,
+    DurableMemory(DurableConfig),
+    Redis(RedisConfig),
+    Sqlite(PathBuf),
+}
+
+impl BackendConfig {
+    /// Read the `backend` key (`"memory"`, `"redis"` or `"sqlite"`) and any settings it needs
+    pub fn from_config(config: &Config) -> Result<BackendConfig> {
+        let backend = lookup_str(config, "backend")?.unwrap_or_else(|| "memory".to_string());
+        match backend.as_str() {
+            "memory" => {
+                match DurableConfig::from_config(config)? {
+                    Some(durable) => Ok(BackendConfig::DurableMemory(durable)),
+                    None => Ok(BackendConfig::Memory),
+                }
+            },
+            "redis" => RedisConfig::from_config(config).map(|r| BackendConfig::Redis(r)),
+            "sqlite" => {
+                let path = lookup_str(config, "sqlite_path")?
+                    .unwrap_or_else(|| DEFAULT_SQLITE_PATH.to_string());
+                Ok(BackendConfig::Sqlite(PathBuf::from(path)))
+            },
+            other => {
+                bail!(invalid_config("backend",
+                                     &format!("unknown backend '{}', expected one of: memory, \
+                                               redis, sqlite",
+                                              other)))
+            },
+        }
+    }
+}
+
+/// Where and how the in memory backend persists itself
+pub struct DurableConfig {
+    pub data_dir: PathBuf,
+    pub fsync: FsyncPolicy,
+    pub snapshot_interval: Duration,
+}
+
+impl DurableConfig {
+    /// Persistence is only turned on when `memory_data_dir` is set
+    ///
+    /// `memory_fsync` is one of `"always"`, `"everysec"` (the default) or
+    /// `"never"`, and `memory_snapshot_interval` is in seconds.
+    pub fn from_config(config: &Config) -> Result<Option<DurableConfig>> {
+        let data_dir = match lookup_str(config, "memory_data_dir")? {
+            Some(dir) => PathBuf::from(dir),
+            None => return Ok(None),
+        };
+
+        let fsync = match lookup_str(config, "memory_fsync")? {
+            None => FsyncPolicy::EverySecond,
+            Some(policy) => {
+                match policy.as_str() {
+                    "always" => FsyncPolicy::Always,
+                    "everysec" => FsyncPolicy::EverySecond,
+                    "never" => FsyncPolicy::Never,
+                    other => {
+                        bail!(invalid_config("memory_fsync",
+                                             &format!("unknown policy '{}', expected one of: \
+                                                       always, everysec, never",
+                                                      other)))
+                    },
+                }
+            },
+        };
+
+        let snapshot_interval = match lookup_int(config, "memory_snapshot_interval")? {
+            Some(n) if n > 0 => n,
+            Some(n) => {
+                bail!(invalid_config("memory_snapshot_interval",
+                                     &format!("{} is not a positive number of seconds", n)))
+            },
+            None => DEFAULT_SNAPSHOT_INTERVAL_SECS,
+        };
+
+        Ok(Some(DurableConfig {
+            data_dir: data_dir,
+            fsync: fsync,
+            snapshot_interval: Duration::from_secs(snapshot_interval as u64),
+        }))
+    }
+}
+
+/// Everything needed to build a pool of Redis connections
+pub struct RedisConfig {
+    pub connection: ConnectionInfo,
+    pub pool_size: u32,
+    /// The prefix of every key, so deployments can share a Redis server
+    pub namespace: String,
+}
+
+impl RedisConfig {
+    /// Read the Redis settings for the active environment
+    ///
+    /// A `redis_url` (`redis://` or `unix://`) is used as the base, and the
+    /// individual `redis_host`, `redis_port`, `redis_socket`, `redis_db` and
+    /// `redis_password` keys override the matching part of it.
+    pub fn from_config(config: &Config) -> Result<RedisConfig> {
+        let mut connection = match lookup_str(config, "redis_url")? {
+            Some(url) => {
+                url.as_str()
+                    .into_connection_info()
+                    .chain_err(|| {
+                        invalid_config("redis_url", "expected a redis:// or unix:// URL")
+                    })?
+            },
+            None => {
+                ConnectionInfo {
+                    addr: Box::new(ConnectionAddr::Tcp(DEFAULT_REDIS_HOST.to_owned(),
+                                                       DEFAULT_REDIS_PORT)),
+                    db: 0,
+                    passwd: None,
+                }
+            },
+        };
+
+        let host = lookup_str(config, "redis_host")?;
+        let port = lookup_int(config, "redis_port")?;
+        let socket = lookup_str(config, "redis_socket")?;
+
+        if socket.is_some() && (host.is_some() || port.is_some()) {
+            bail!(invalid_config("redis_socket",
+                                 "can not be combined with 'redis_host' or 'redis_port'"));
+        }
+
+        if let Some(path) = socket {
+            connection.addr = Box::new(ConnectionAddr::Unix(PathBuf::from(path)));
+        } else if host.is_some() || port.is_some() {
+            let (default_host, default_port) = match *connection.addr {
+                ConnectionAddr::Tcp(ref h, p) => (h.clone(), p),
+                _ => (DEFAULT_REDIS_HOST.to_owned(), DEFAULT_REDIS_PORT),
+            };
+            let port = match port {
+                Some(p) if p > 0 && p <= u16::max_value() as i64 => p as u16,
+                Some(p) => {
+                    bail!(invalid_config("redis_port", &format!("{} is not a valid port", p)))
+                },
+                None => default_port,
+            };
+            connection.addr = Box::new(ConnectionAddr::Tcp(host.unwrap_or(default_host), port));
+        }
+
+        if let Some(db) = lookup_int(config, "redis_db")? {
+            if db < 0 {
+                bail!(invalid_config("redis_db", "must not be negative"));
+            }
+            connection.db = db;
+        }
+
+        if let Some(password) = lookup_str(config, "redis_password")? {
+            connection.passwd = Some(password);
+        }
+
+        let pool_size = match lookup_int(config, "redis_pool_size")? {
+            Some(n) if n > 0 && n <= u32::max_value() as i64 => n as u32,
+            Some(n) => {
+                bail!(invalid_config("redis_pool_size",
+                                     &format!("{} is not a valid pool size", n)))
+            },
+            None => num_cpus::get() as u32,
+        };
+
+        let namespace = lookup_str(config, "redis_namespace")?
+            .unwrap_or_else(|| DEFAULT_REDIS_NAMESPACE.to_string());
+        if !is_name(&namespace) {
+            bail!(invalid_config("redis_namespace",
+                                 "expected letters, digits and dashes, with no underscores"));
+        }
+
+        Ok(RedisConfig {
+            connection: connection,
+            pool_size: pool_size,
+            namespace: namespace,
+        })
+    }
+}
+
+/// A separate set of users and sessions, served by the same process
+#[derive(Debug, Clone, PartialEq)]
+pub struct TenantConfig {
+    /// Also the path prefix the tenant's API is served under
+    pub name: String,
+    /// Requests to these hosts are for this tenant
+    pub hosts: Vec<String>,
+}
+
+impl TenantConfig {
+    /// Read the `tenants` key, which lists every tenant and any hosts it is served on
+    ///
+    /// Tenants are separated by commas, and each is a name optionally followed
+    /// by `=` and its hosts separated by `|`, e.g.
+    /// `"acme=estimates.acme.com|acme.example.com, globex"`.
+    pub fn from_config(config: &Config) -> Result<Vec<TenantConfig>> {
+        let tenants = match lookup_str(config, "tenants")? {
+            Some(tenants) => tenants,
+            None => return Ok(Vec::new()),
+        };
+        let mut parsed: Vec<TenantConfig> = Vec::new();
+        for entry in tenants.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()) {
+            let mut parts = entry.splitn(2, '=');
+            let name = parts.next().unwrap_or("").trim().to_string();
+            if !is_name(&name) || RESERVED_PATHS.contains(&name.as_str()) {
+                bail!(invalid_config("tenants", &format!("'{}' can not be a tenant name", name)));
+            }
+            if parsed.iter().any(|t| t.name == name) {
+                bail!(invalid_config("tenants", &format!("'{}' is listed twice", name)));
+            }
+            let hosts: Vec<String> = parts.next()
+                .map(|hosts| {
+                    hosts.split('|')
+                        .map(|h| h.trim().to_lowercase())
+                        .filter(|h| !h.is_empty())
+                        .collect()
+                })
+                .unwrap_or_else(Vec::new);
+            parsed.push(TenantConfig {
+                name: name,
+                hosts: hosts,
+            });
+        }
+        Ok(parsed)
+    }
+}
+
+/// How long sessions and users are kept after they were last used
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpiryConfig {
+    /// `None` keeps sessions forever
+    pub session_ttl: Option<Duration>,
+    /// `None` keeps users forever
+    pub user_ttl: Option<Duration>,
+    /// How often backends that can't expire records on their own look for idle ones
+    pub sweep_interval: Duration,
+}
+
+impl Default for ExpiryConfig {
+    fn default() -> ExpiryConfig {
+        ExpiryConfig {
+            session_ttl: Some(Duration::from_secs(DEFAULT_SESSION_TTL_SECS as u64)),
+            user_ttl: Some(Duration::from_secs(DEFAULT_USER_TTL_SECS as u64)),
+            sweep_interval: Duration::from_secs(DEFAULT_EXPIRY_SWEEP_INTERVAL_SECS as u64),
+        }
+    }
+}
+
+impl ExpiryConfig {
+    /// Read the expiry settings for the active environment
+    ///
+    /// `session_ttl` and `user_ttl` are in seconds, where `0` turns expiry
+    /// off, and `expiry_sweep_interval` is in seconds.
+    pub fn from_config(config: &Config) -> Result<ExpiryConfig> {
+        let defaults = ExpiryConfig::default();
+        let sweep_interval = match lookup_int(config, "expiry_sweep_interval")? {
+            Some(n) if n > 0 => Duration::from_secs(n as u64),
+            Some(n) => {
+                bail!(invalid_config("expiry_sweep_interval",
+                                     &format!("{} is not a positive number of seconds", n)))
+            },
+            None => defaults.sweep_interval,
+        };
+        Ok(ExpiryConfig {
+            session_ttl: lookup_ttl(config, "session_ttl", defaults.session_ttl)?,
+            user_ttl: lookup_ttl(config, "user_ttl", defaults.user_ttl)?,
+            sweep_interval: sweep_interval,
+        })
+    }
+}
+
+/// How bearer tokens are signed, and how long they last
+#[derive(Clone, PartialEq)]
+pub struct BearerConfig {
+    /// `None` signs with a random secret, so tokens only work until a restart
+    /// and only with the process that issued them
+    pub secret: Option<String>,
+    pub ttl: Duration,
+    /// How long a browser stays signed in with a cookie
+    pub cookie_ttl: Duration,
+}
+
+impl Default for BearerConfig {
+    fn default() -> BearerConfig {
+        BearerConfig {
+            secret: None,
+            ttl: Duration::from_secs(DEFAULT_BEARER_TTL_SECS as u64),
+            cookie_ttl: Duration::from_secs(DEFAULT_COOKIE_TTL_SECS as u64),
+        }
+    }
+}
+
+impl BearerConfig {
+    /// Read `bearer_secret`, at least 32 characters, and `bearer_ttl` and
+    /// `cookie_ttl` in seconds
+    pub fn from_config(config: &Config) -> Result<BearerConfig> {
+        let secret = lookup_str(config, "bearer_secret")?;
+        if let Some(ref secret) = secret {
+            if secret.len() < MIN_BEARER_SECRET_LENGTH {
+                bail!(invalid_config("bearer_secret",
+                                     &format!("must be at least {} characters",
+                                              MIN_BEARER_SECRET_LENGTH)));
+            }
+        }
+        let defaults = BearerConfig::default();
+        Ok(BearerConfig {
+            secret: secret,
+            ttl: lookup_lifetime(config, "bearer_ttl", defaults.ttl)?,
+            cookie_ttl: lookup_lifetime(config, "cookie_ttl", defaults.cookie_ttl)?,
+        })
+    }
+}
+
+// The secret stays out of logs
+impl fmt::Debug for BearerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "BearerConfig{{ secret: {}, ttl: {:?}, cookie_ttl: {:?} }}",
+               if self.secret.is_some() { "REDACTED" } else { "None" },
+               self.ttl,
+               self.cookie_ttl)
+    }
+}
+
+/// An OpenID Connect provider users can sign in with
+#[derive(Clone, PartialEq)]
+pub struct OidcConfig {
+    /// What the provider puts in the `iss` of its ID tokens
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Where browsers are sent to sign in
+    pub authorization_endpoint: String,
+    /// Where the server exchanges codes for ID tokens, over HTTPS
+    pub token_endpoint: String,
+    /// The address browsers reach this server at, which callback paths are added to
+    pub redirect_base: String,
+    /// The scopes to ask the provider for, separated by spaces
+    pub scope: String,
+}
+
+impl OidcConfig {
+    /// OpenID Connect is only turned on when `oidc_issuer` is set
+    ///
+    /// It then needs `oidc_client_id`, `oidc_client_secret`,
+    /// `oidc_authorization_endpoint`, `oidc_token_endpoint` and
+    /// `oidc_redirect_base`, and `oidc_scope` defaults to `"openid profile"`.
+    /// The token endpoint has to be an `https://` URL, as the client secret
+    /// is sent to it.
+    pub fn from_config(config: &Config) -> Result<Option<OidcConfig>> {
+        let issuer = match lookup_str(config, "oidc_issuer")? {
+            Some(issuer) => issuer,
+            None => return Ok(None),
+        };
+        let required = |key: &str| -> Result<String> {
+            lookup_str(config, key)?
+                .ok_or(invalid_config(key, "is needed when 'oidc_issuer' is set").into())
+        };
+        let token_endpoint = required("oidc_token_endpoint")?;
+        if !token_endpoint.starts_with("https://") {
+            bail!(invalid_config("oidc_token_endpoint", "expected an https:// URL"));
+        }
+        let scope = lookup_str(config, "oidc_scope")?
+            .unwrap_or_else(|| DEFAULT_OIDC_SCOPE.to_string());
+        if !scope.split(' ').any(|s| s == "openid") {
+            bail!(invalid_config("oidc_scope", "must include 'openid'"));
+        }
+        Ok(Some(OidcConfig {
+            issuer: issuer,
+            client_id: required("oidc_client_id")?,
+            client_secret: required("oidc_client_secret")?,
+            authorization_endpoint: required("oidc_authorization_endpoint")?,
+            token_endpoint: token_endpoint,
+            redirect_base: required("oidc_redirect_base")?.trim_right_matches('/').to_string(),
+            scope: scope,
+        }))
+    }
+}
+
+impl fmt::Debug for OidcConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "OidcConfig{{ issuer: {:?}, client_id: {:?}, client_secret: REDACTED, \
+                authorization_endpoint: {:?}, token_endpoint: {:?}, redirect_base: {:?}, \
+                scope: {:?} }}",
+               self.issuer,
+               self.client_id,
+               self.authorization_endpoint,
+               self.token_endpoint,
+               self.redirect_base,
+               self.scope)
+    }
+}
+
+/// Whether a namespace or tenant name is safe to use in Redis keys and URLs
+fn is_name(name: &str) -> bool {
+    !name.is_empty() &&
+    name.chars().all(|c| match c {
+        'a'...'z' | 'A'...'Z' | '0'...'9' | '-' => true,
+        _ => false,
+    })
+}
+
+fn invalid_config(key: &str, reason: &str) -> ErrorKind {
+    ErrorKind::InvalidConfig(key.to_string(), reason.to_string())
+}
+
+fn env_key(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.to_uppercase())
+}
+
+fn lookup_str(config: &Config, key: &str) -> Result<Option<String>> {
+    if let Ok(value) = env::var(env_key(key)) {
+        return Ok(Some(value));
+    }
+    match config.get_str(key) {
+        Ok(value) => Ok(Some(value.to_string())),
+        Err(ConfigError::NotFound) => Ok(None),
+        Err(_) => bail!(invalid_config(key, "expected a string")),
+    }
+}
+
+fn lookup_int(config: &Config, key: &str) -> Result<Option<i64>> {
+    if let Ok(value) = env::var(env_key(key)) {
+        return value.parse()
+            .map(|n| Some(n))
+            .map_err(|_| invalid_config(key, &format!("'{}' is not an integer", value)).into());
+    }
+    match config.get_int(key) {
+        Ok(value) => Ok(Some(value)),
+        Err(ConfigError::NotFound) => Ok(None),
+        Err(_) => bail!(invalid_config(key, "expected an integer")),
+    }
+}
+
+fn lookup_ttl(config: &Config, key: &str, default: Option<Duration>) -> Result<Option<Duration>> {
+    match lookup_int(config, key)? {
+        Some(0) => Ok(None),
+        Some(n) if n > 0 => Ok(Some(Duration::from_secs(n as u64))),
+        Some(n) => bail!(invalid_config(key, &format!("{} is not a number of seconds", n))),
+        None => Ok(default),
+    }
+}
+
+/// Like `lookup_ttl`, for things that can't last forever
+fn lookup_lifetime(config: &Config, key: &str, default: Duration) -> Result<Duration> {
+    match lookup_int(config, key)? {
+        Some(n) if n > 0 => Ok(Duration::from_secs(n as u64)),
+        Some(n) => {
+            bail!(invalid_config(key, &format!("{} is not a positive number of seconds", n)))
+        },
+        None => Ok(default),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rocket::config::Environment;
+
+    fn config_with(extras: Vec<(&str, &str)>) -> Config {
+        let mut builder = Config::build(Environment::Development);
+        for (key, value) in extras {
+            builder = builder.extra(key, value);
+        }
+        builder.finalize().unwrap()
+    }
+
+    fn is_invalid_config<T>(outcome: Result<T>) -> bool {
+        match outcome {
+            Err(Error(ErrorKind::InvalidConfig(_, _), _)) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn default_backend_config() {
+        match BackendConfig::from_config(&config_with(vec![])).unwrap() {
+            BackendConfig::Memory => (),
+            _ => panic!("Expected the memory backend to be the default"),
+        }
+    }
+
+    #[test]
+    fn durable_memory_backend_config() {
+        let config = config_with(vec![("backend", "memory"),
+                                      ("memory_data_dir", "/var/lib/storyestimates"),
+                                      ("memory_fsync", "always")]);
+        match BackendConfig::from_config(&config).unwrap() {
+            BackendConfig::DurableMemory(durable) => {
+                assert_eq!(durable.data_dir, PathBuf::from("/var/lib/storyestimates"));
+                assert_eq!(durable.fsync, FsyncPolicy::Always);
+                assert_eq!(durable.snapshot_interval,
+                           Duration::from_secs(DEFAULT_SNAPSHOT_INTERVAL_SECS as u64));
+            },
+            _ => panic!("Expected the durable memory backend"),
+        }
+    }
+
+    #[test]
+    fn durable_memory_bad_fsync() {
+        let config = config_with(vec![("memory_data_dir", "/var/lib/storyestimates"),
+                                      ("memory_fsync", "sometimes")]);
+        assert!(is_invalid_config(BackendConfig::from_config(&config)));
+    }
+
+    #[test]
+    fn redis_backend_config() {
+        let config = config_with(vec![("backend", "redis"), ("redis_db", "2")]);
+        assert!(is_invalid_config(BackendConfig::from_config(&config)));
+
+        let config = config_with(vec![("backend", "redis"),
+                                      ("redis_url", "redis://localhost/2")]);
+        match BackendConfig::from_config(&config).unwrap() {
+            BackendConfig::Redis(redis) => assert_eq!(redis.connection.db, 2),
+            _ => panic!("Expected the redis backend"),
+        }
+    }
+
+    #[test]
+    fn sqlite_backend_config() {
+        let config = config_with(vec![("backend", "sqlite"),
+                                      ("sqlite_path", "/var/lib/storyestimates/data.sqlite")]);
+        match BackendConfig::from_config(&config).unwrap() {
+            BackendConfig::Sqlite(path) => {
+                assert_eq!(path, PathBuf::from("/var/lib/storyestimates/data.sqlite"))
+            },
+            _ => panic!("Expected the sqlite backend"),
+        }
+    }
+
+    #[test]
+    fn unknown_backend_config() {
+        let config = config_with(vec![("backend", "floppydisk")]);
+        assert!(is_invalid_config(BackendConfig::from_config(&config)));
+    }
+
+    #[test]
+    fn default_redis_config() {
+        let redis = RedisConfig::from_config(&config_with(vec![])).unwrap();
+        assert_eq!(*redis.connection.addr,
+                   ConnectionAddr::Tcp(DEFAULT_REDIS_HOST.to_owned(), DEFAULT_REDIS_PORT));
+        assert_eq!(redis.connection.db, 0);
+        assert_eq!(redis.connection.passwd, None);
+        assert!(redis.pool_size > 0);
+    }
+
+    #[test]
+    fn redis_url_config() {
+        let config = config_with(vec![("redis_url", "redis://:hunter2@redis.local:6380/3")]);
+        let redis = RedisConfig::from_config(&config).unwrap();
+        assert_eq!(*redis.connection.addr,
+                   ConnectionAddr::Tcp("redis.local".to_owned(), 6380));
+        assert_eq!(redis.connection.db, 3);
+        assert_eq!(redis.connection.passwd, Some("hunter2".to_owned()));
+    }
+
+    #[test]
+    fn redis_keys_override_url() {
+        let config = config_with(vec![("redis_url", "redis://redis.local:6380/3"),
+                                      ("redis_host", "10.0.0.4")]);
+        let redis = RedisConfig::from_config(&config).unwrap();
+        assert_eq!(*redis.connection.addr,
+                   ConnectionAddr::Tcp("10.0.0.4".to_owned(), 6380));
+        assert_eq!(redis.connection.db, 3);
+    }
+
+    #[test]
+    fn redis_socket_config() {
+        let config = config_with(vec![("redis_socket", "/var/run/redis.sock")]);
+        let redis = RedisConfig::from_config(&config).unwrap();
+        assert_eq!(*redis.connection.addr,
+                   ConnectionAddr::Unix(PathBuf::from("/var/run/redis.sock")));
+    }
+
+    #[test]
+    fn redis_socket_and_host_conflict() {
+        let config = config_with(vec![("redis_socket", "/var/run/redis.sock"),
+                                      ("redis_host", "10.0.0.4")]);
+        assert!(is_invalid_config(RedisConfig::from_config(&config)));
+    }
+
+    #[test]
+    fn redis_bad_url() {
+        let config = config_with(vec![("redis_url", "http://redis.local")]);
+        assert!(is_invalid_config(RedisConfig::from_config(&config)));
+    }
+
+    #[test]
+    fn redis_bad_pool_size() {
+        let config = Config::build(Environment::Development)
+            .extra("redis_pool_size", 0)
+            .finalize()
+            .unwrap();
+        assert!(is_invalid_config(RedisConfig::from_config(&config)));
+    }
+
+    #[test]
+    fn redis_bad_port() {
+        let config = Config::build(Environment::Development)
+            .extra("redis_port", 70000)
+            .finalize()
+            .unwrap();
+        assert!(is_invalid_config(RedisConfig::from_config(&config)));
+    }
+
+    #[test]
+    fn redis_namespace_config() {
+        let redis = RedisConfig::from_config(&config_with(vec![])).unwrap();
+        assert_eq!(redis.namespace, DEFAULT_REDIS_NAMESPACE);
+
+        let config = config_with(vec![("redis_namespace", "STAGING")]);
+        assert_eq!(RedisConfig::from_config(&config).unwrap().namespace, "STAGING");
+
+        let config = config_with(vec![("redis_namespace", "STAGING_1")]);
+        assert!(is_invalid_config(RedisConfig::from_config(&config)));
+    }
+
+    #[test]
+    fn tenants_config() {
+        assert_eq!(TenantConfig::from_config(&config_with(vec![])).unwrap(), vec![]);
+
+        let config = config_with(vec![("tenants",
+                                       "acme=estimates.acme.com|Acme.example.com, globex")]);
+        assert_eq!(TenantConfig::from_config(&config).unwrap(),
+                   vec![TenantConfig {
+                            name: "acme".to_string(),
+                            hosts: vec!["estimates.acme.com".to_string(),
+                                        "acme.example.com".to_string()],
+                        },
+                        TenantConfig {
+                            name: "globex".to_string(),
+                            hosts: vec![],
+                        }]);
+    }
+
+    #[test]
+    fn tenants_bad_names() {
+        for tenants in &["acme, acme", "api", "acme corp", "=acme.example.com"] {
+            let config = config_with(vec![("tenants", *tenants)]);
+            assert!(is_invalid_config(TenantConfig::from_config(&config)), "{}", tenants);
+        }
+    }
+
+    #[test]
+    fn default_expiry_config() {
+        let expiry = ExpiryConfig::from_config(&config_with(vec![])).unwrap();
+        assert_eq!(expiry, ExpiryConfig::default());
+    }
+
+    #[test]
+    fn expiry_config() {
+        let config = Config::build(Environment::Development)
+            .extra("session_ttl", 3600)
+            .extra("user_ttl", 0)
+            .extra("expiry_sweep_interval", 5)
+            .finalize()
+            .unwrap();
+        let expiry = ExpiryConfig::from_config(&config).unwrap();
+        assert_eq!(expiry.session_ttl, Some(Duration::from_secs(3600)));
+        assert_eq!(expiry.user_ttl, None);
+        assert_eq!(expiry.sweep_interval, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn expiry_bad_ttl() {
+        let config = Config::build(Environment::Development)
+            .extra("session_ttl", -1)
+            .finalize()
+            .unwrap();
+        assert!(is_invalid_config(ExpiryConfig::from_config(&config)));
+    }
+
+    #[test]
+    fn bearer_config() {
+        assert_eq!(BearerConfig::from_config(&config_with(vec![])).unwrap(),
+                   BearerConfig::default());
+
+        let secret = "correct horse battery staple, but longer";
+        let config = Config::build(Environment::Development)
+            .extra("bearer_secret", secret)
+            .extra("bearer_ttl", 60)
+            .extra("cookie_ttl", 3600)
+            .finalize()
+            .unwrap();
+        let bearer = BearerConfig::from_config(&config).unwrap();
+        assert_eq!(bearer.secret, Some(secret.to_string()));
+        assert_eq!(bearer.ttl, Duration::from_secs(60));
+        assert_eq!(bearer.cookie_ttl, Duration::from_secs(3600));
+        assert!(!format!("{:?}", bearer).contains(secret));
+    }
+
+    #[test]
+    fn bearer_bad_config() {
+        let config = config_with(vec![("bearer_secret", "hunter2")]);
+        assert!(is_invalid_config(BearerConfig::from_config(&config)));
+
+        for key in &["bearer_ttl", "cookie_ttl"] {
+            let config = Config::build(Environment::Development)
+                .extra(key, 0)
+                .finalize()
+                .unwrap();
+            assert!(is_invalid_config(BearerConfig::from_config(&config)), "{}", key);
+        }
+    }
+
+    fn oidc_settings() -> Vec<(&'static str, &'static str)> {
+        vec![("oidc_issuer", "https://sso.example.com"),
+             ("oidc_client_id", "estimates"),
+             ("oidc_client_secret", "hunter2"),
+             ("oidc_authorization_endpoint", "https://sso.example.com/authorize"),
+             ("oidc_token_endpoint", "https://sso.example.com/token"),
+             ("oidc_redirect_base", "https://estimates.example.com/")]
+    }
+
+    #[test]
+    fn oidc_config() {
+        assert_eq!(OidcConfig::from_config(&config_with(vec![])).unwrap(), None);
+
+        let oidc = OidcConfig::from_config(&config_with(oidc_settings())).unwrap().unwrap();
+        assert_eq!(oidc.issuer, "https://sso.example.com");
+        assert_eq!(oidc.redirect_base, "https://estimates.example.com");
+        assert_eq!(oidc.scope, DEFAULT_OIDC_SCOPE);
+        assert!(!format!("{:?}", oidc).contains("hunter2"));
+    }
+
+    #[test]
+    fn oidc_bad_config() {
+        let mut settings = oidc_settings();
+        settings.retain(|&(key, _)| key != "oidc_client_id");
+        assert!(is_invalid_config(OidcConfig::from_config(&config_with(settings))));
+
+        let mut settings = oidc_settings();
+        settings.push(("oidc_token_endpoint", "http://sso.example.com/token"));
+        assert!(is_invalid_config(OidcConfig::from_config(&config_with(settings))));
+
+        let mut settings = oidc_settings();
+        settings.push(("oidc_scope", "profile email"));
+        assert!(is_invalid_config(OidcConfig::from_config(&config_with(settings))));
+    }
+}
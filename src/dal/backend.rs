@@ -0,0 +1,329 @@
+use super::{DurableMemoryDB, JournaledMemoryDB, RedisDBManager, SharedMemoryDB, SharedSqliteDB,
+            SqliteDB, StoryData, StoryDataProvider};
+use super::sharedmemory::{RedisDBInstance, SharedMemoryHandle};
+use config::{BackendConfig, ExpiryConfig};
+use errors::*;
+use estimates::audit::AuditEntry;
+use estimates::participant::Participant;
+use estimates::session::{Session, SessionID};
+use std::sync::MutexGuard;
+use user::{BasicUser, UserID};
+
+/// The storage backend chosen at runtime
+///
+/// `StoryData` has generic methods, so it can't be used as a trait object.
+/// Instead every backend gets a variant here, and the matching
+/// `StoryBackendInstance` forwards each call to the backend it wraps.
+pub enum StoryBackend {
+    Memory(SharedMemoryDB),
+    DurableMemory(DurableMemoryDB),
+    Redis(RedisDBManager),
+    Sqlite(SharedSqliteDB),
+}
+
+/// A handle to the data of whichever backend is in use
+pub enum StoryBackendInstance<'a> {
+    Memory(SharedMemoryHandle),
+    DurableMemory(MutexGuard<'a, JournaledMemoryDB>),
+    Redis(RedisDBInstance),
+    Sqlite(MutexGuard<'a, SqliteDB>),
+}
+
+impl StoryBackend {
+    /// Build the backend described by the configuration, connecting to it if needed
+    pub fn from_config(config: BackendConfig, expiry: &ExpiryConfig) -> Result<StoryBackend> {
+        match config {
+            BackendConfig::Memory => {
+                info!("Using the in memory backend");
+                Ok(StoryBackend::Memory(SharedMemoryDB::new()))
+            },
+            BackendConfig::DurableMemory(durable) => {
+                info!("Using the in memory backend, persisted to {:?}", durable.data_dir);
+                let db = DurableMemoryDB::open(&durable.data_dir,
+                                               durable.fsync,
+                                               durable.snapshot_interval)
+                    .chain_err(|| format!("Could not load data from {:?}", durable.data_dir))?;
+                Ok(StoryBackend::DurableMemory(db))
+            },
+            BackendConfig::Redis(redis) => {
+                Ok(StoryBackend::Redis(RedisDBManager::connect(redis, expiry.clone())?))
+            },
+            BackendConfig::Sqlite(path) => {
+                info!("Opening SQLite database {:?}", path);
+                let db = SharedSqliteDB::open(&path)
+                    .chain_err(|| format!("Could not open SQLite database {:?}", path))?;
+                Ok(StoryBackend::Sqlite(db))
+            },
+        }
+    }
+
+    /// The backend of a tenant, whose data is kept apart from everyone else's
+    ///
+    /// Only Redis can keep tenants apart, by giving each a namespace of its own.
+    pub fn tenant(&self, name: &str) -> Result<StoryBackend> {
+        match *self {
+            StoryBackend::Redis(ref db) => Ok(StoryBackend::Redis(db.tenant(name))),
+            _ => {
+                bail!(ErrorKind::InvalidConfig("tenants".to_string(),
+                                               "only the redis backend can keep tenants apart"
+                                                   .to_string()))
+            },
+        }
+    }
+
+    /// Bring the stored data up to the layout this version of the code expects
+    pub fn migrate(&self) -> Result<()> {
+        if let StoryBackend::Redis(ref db) = *self {
+            db.migrate().chain_err(|| "Could not migrate the data in Redis")?;
+        }
+        Ok(())
+    }
+
+    /// Start any background work the backend needs while the server is running
+    pub fn start_maintenance(&self, expiry: &ExpiryConfig) -> Result<()> {
+        match *self {
+            StoryBackend::Memory(ref db) => db.start_sweeper(expiry),
+            StoryBackend::DurableMemory(ref db) => {
+                db.start_maintenance()?;
+                db.start_sweeper(expiry);
+            },
+            StoryBackend::Redis(ref db) => db.start_sweeper(expiry),
+            StoryBackend::Sqlite(ref db) => db.start_sweeper(expiry),
+        }
+        Ok(())
+    }
+}
+
+impl<'a> StoryDataProvider<'a> for StoryBackend {
+    type StoryData = StoryBackendInstance<'a>;
+
+    fn get_story_data(&'a self) -> Result<Self::StoryData> {
+        match *self {
+            StoryBackend::Memory(ref db) => {
+                db.get_story_data().map(|d| StoryBackendInstance::Memory(d))
+            },
+            StoryBackend::DurableMemory(ref db) => {
+                db.get_story_data().map(|d| StoryBackendInstance::DurableMemory(d))
+            },
+            StoryBackend::Redis(ref db) => {
+                db.get_story_data().map(|d| StoryBackendInstance::Redis(d))
+            },
+            StoryBackend::Sqlite(ref db) => {
+                db.get_story_data().map(|d| StoryBackendInstance::Sqlite(d))
+            },
+        }
+    }
+}
+
+impl From<SharedMemoryDB> for StoryBackend {
+    fn from(db: SharedMemoryDB) -> StoryBackend {
+        StoryBackend::Memory(db)
+    }
+}
+
+impl From<DurableMemoryDB> for StoryBackend {
+    fn from(db: DurableMemoryDB) -> StoryBackend {
+        StoryBackend::DurableMemory(db)
+    }
+}
+
+impl From<RedisDBManager> for StoryBackend {
+    fn from(db: RedisDBManager) -> StoryBackend {
+        StoryBackend::Redis(db)
+    }
+}
+
+impl From<SharedSqliteDB> for StoryBackend {
+    fn from(db: SharedSqliteDB) -> StoryBackend {
+        StoryBackend::Sqlite(db)
+    }
+}
+
+/// Forward a call to the `StoryData` inside any `StoryBackendInstance`
+macro_rules! dispatch {
+    ($instance:expr, $db:ident => $call:expr) => {
+        match $instance {
+            StoryBackendInstance::Memory(ref $db) => $call,
+            StoryBackendInstance::DurableMemory(ref $db) => $call,
+            StoryBackendInstance::Redis(ref $db) => $call,
+            StoryBackendInstance::Sqlite(ref $db) => $call,
+        }
+    }
+}
+
+macro_rules! dispatch_mut {
+    ($instance:expr, $db:ident => $call:expr) => {
+        match $instance {
+            StoryBackendInstance::Memory(ref mut $db) => $call,
+            StoryBackendInstance::DurableMemory(ref mut $db) => $call,
+            StoryBackendInstance::Redis(ref mut $db) => $call,
+            StoryBackendInstance::Sqlite(ref mut $db) => $call,
+        }
+    }
+}
+
+impl<'a> StoryData for StoryBackendInstance<'a> {
+    fn get_user(&self, user_id: &UserID) -> Result<Option<BasicUser>> {
+        dispatch!(*self, db => db.get_user(user_id))
+    }
+    fn add_user(&mut self, user: BasicUser) -> Result<()> {
+        dispatch_mut!(*self, db => db.add_user(user))
+    }
+    fn del_user(&mut self, user_id: &UserID) -> Result<()> {
+        dispatch_mut!(*self, db => db.del_user(user_id))
+    }
+    fn list_users(&self) -> Result<Vec<UserID>> {
+        dispatch!(*self, db => db.list_users())
+    }
+    fn update_user<F>(&mut self, user_id: &UserID, plan: F) -> Result<()>
+        where F: FnMut(&mut BasicUser) -> Result<()>
+    {
+        dispatch_mut!(*self, db => db.update_user(user_id, plan))
+    }
+
+    fn get_session(&self, session_id: &SessionID) -> Result<Option<Session>> {
+        dispatch!(*self, db => db.get_session(session_id))
+    }
+    fn list_sessions(&self) -> Result<Vec<SessionID>> {
+        dispatch!(*self, db => db.list_sessions())
+    }
+    fn add_session(&mut self, session: Session) -> Result<()> {
+        dispatch_mut!(*self, db => db.add_session(session))
+    }
+    fn del_session(&mut self, session_id: &SessionID) -> Result<()> {
+        dispatch_mut!(*self, db => db.del_session(session_id))
+    }
+    fn update_session<F>(&mut self, session_id: &SessionID, plan: F) -> Result<()>
+        where F: FnMut(&mut Session, &mut Vec<Participant>) -> Result<()>
+    {
+        dispatch_mut!(*self, db => db.update_session(session_id, plan))
+    }
+
+    fn get_participants(&self, session_id: &SessionID) -> Result<Vec<Participant>> {
+        dispatch!(*self, db => db.get_participants(session_id))
+    }
+    fn add_participant(&mut self, participant: Participant) -> Result<()> {
+        dispatch_mut!(*self, db => db.add_participant(participant))
+    }
+    fn del_participant(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<()> {
+        dispatch_mut!(*self, db => db.del_participant(user_id, session_id))
+    }
+    fn update_participant<F>(
+        &mut self,
+        session_id: &SessionID,
+        user_id: &UserID,
+        plan: F
+    ) -> Result<()>
+        where F: FnMut(&mut Participant) -> Result<()>
+    {
+        dispatch_mut!(*self, db => db.update_participant(session_id, user_id, plan))
+    }
+
+    fn get_admins(&self, session_id: &SessionID) -> Result<Vec<UserID>> {
+        dispatch!(*self, db => db.get_admins(session_id))
+    }
+    fn add_admin(&mut self, user_id: UserID, session_id: SessionID) -> Result<()> {
+        dispatch_mut!(*self, db => db.add_admin(user_id, session_id))
+    }
+    fn del_admin(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<()> {
+        dispatch_mut!(*self, db => db.del_admin(user_id, session_id))
+    }
+
+    fn get_audit_log(&self, session_id: &SessionID) -> Result<Vec<AuditEntry>> {
+        dispatch!(*self, db => db.get_audit_log(session_id))
+    }
+    fn add_audit_entry(&mut self, entry: AuditEntry) -> Result<()> {
+        dispatch_mut!(*self, db => db.add_audit_entry(entry))
+    }
+    fn get_tombstones(&self) -> Result<Vec<AuditEntry>> {
+        dispatch!(*self, db => db.get_tombstones())
+    }
+    fn add_tombstone(&mut self, entry: AuditEntry) -> Result<()> {
+        dispatch_mut!(*self, db => db.add_tombstone(entry))
+    }
+
+    fn touch_user(&mut self, user_id: &UserID) -> Result<()> {
+        dispatch_mut!(*self, db => db.touch_user(user_id))
+    }
+    fn purge_expired(&mut self, expiry: &ExpiryConfig, now: u64) -> Result<usize> {
+        dispatch_mut!(*self, db => db.purge_expired(expiry, now))
+    }
+
+    fn lock_session<T, F>(&mut self, session_id: &SessionID, operation: F) -> Result<T>
+        where F: FnOnce(&mut Self) -> Result<T>
+    {
+        // The durable memory and SQLite handles already keep every other request
+        // out, and Redis has no lock to hold across calls
+        let _held = match *self {
+            StoryBackendInstance::Memory(ref db) => db.hold_session(session_id),
+            _ => None,
+        };
+        operation(self)
+    }
+
+    fn is_admin(&self, session_id: &SessionID, user_id: &UserID) -> Result<bool> {
+        dispatch!(*self, db => db.is_admin(session_id, user_id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use config::RedisConfig;
+    use dal::{DEFAULT_REDIS_NAMESPACE, fakeredis};
+    use redis::IntoConnectionInfo;
+
+    #[test]
+    fn memory_backend_from_config() {
+        let backend = StoryBackend::from_config(BackendConfig::Memory, &ExpiryConfig::default())
+            .unwrap();
+        let mut dal = backend.get_story_data().unwrap();
+        let my_user = BasicUser::new();
+        dal.add_user(my_user.clone()).unwrap();
+        assert_eq!(dal.get_user(&my_user.user_id).unwrap(), Some(my_user));
+    }
+
+    #[test]
+    fn tenants_need_redis() {
+        assert!(StoryBackend::from(SharedMemoryDB::new()).tenant("acme").is_err());
+    }
+
+    #[test]
+    fn backend_instance_forwards_updates() {
+        let backend = StoryBackend::from(SharedMemoryDB::new());
+        let my_session = Session::new();
+        backend.get_story_data().unwrap().add_session(my_session.clone()).unwrap();
+        backend.get_story_data()
+            .unwrap()
+            .update_session(&my_session.session_id, |s, _| {
+                s.average = Some(3.0);
+                Ok(())
+            })
+            .unwrap();
+        let same_session = backend.get_story_data()
+            .unwrap()
+            .get_session(&my_session.session_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(same_session.average, Some(3.0));
+    }
+
+    #[test]
+    fn redis_backend_from_config() {
+        let config = RedisConfig {
+            connection: fakeredis::shared().fresh_url().as_str().into_connection_info().unwrap(),
+            pool_size: 2,
+            namespace: DEFAULT_REDIS_NAMESPACE.to_string(),
+        };
+        let backend = StoryBackend::from_config(BackendConfig::Redis(config),
+                                                &ExpiryConfig::default())
+            .unwrap();
+        let my_session = Session::new();
+        backend.get_story_data().unwrap().add_session(my_session.clone()).unwrap();
+        let same_session = backend.get_story_data()
+            .unwrap()
+            .get_session(&my_session.session_id)
+            .unwrap();
+        assert_eq!(same_session, Some(my_session));
+    }
+}
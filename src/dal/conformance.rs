@@ -0,0 +1,812 @@
+//! Behavior every `StoryData` implementation must share
+//!
+//! Each check takes a function that builds a new, empty, backend. A backend
+//! runs the whole suite by invoking `storydata_conformance_tests!` in its
+//! test module, which creates one `#[test]` per check.
+
+use super::StoryData;
+use errors::*;
+use estimates::audit::{AuditAction, AuditEntry};
+use estimates::participant::Participant;
+use estimates::session::{Session, SessionID};
+use estimates::vote::VoteState;
+use user::{BasicUser, Nickname, UserID};
+use util::clock;
+
+macro_rules! storydata_conformance_tests {
+    ($new_dal:expr) => {
+        mod conformance {
+            use dal::conformance;
+
+            #[test]
+            fn save_and_get_user() {
+                conformance::save_and_get_user($new_dal)
+            }
+            #[test]
+            fn get_non_existant_user() {
+                conformance::get_non_existant_user($new_dal)
+            }
+            #[test]
+            fn list_and_delete_users() {
+                conformance::list_and_delete_users($new_dal)
+            }
+            #[test]
+            fn delete_non_existent_user() {
+                conformance::delete_non_existent_user($new_dal)
+            }
+            #[test]
+            fn update_user() {
+                conformance::update_user($new_dal)
+            }
+            #[test]
+            fn update_non_existent_user() {
+                conformance::update_non_existent_user($new_dal)
+            }
+            #[test]
+            fn list_sessions() {
+                conformance::list_sessions($new_dal)
+            }
+            #[test]
+            fn save_and_get_session() {
+                conformance::save_and_get_session($new_dal)
+            }
+            #[test]
+            fn get_non_existant_session() {
+                conformance::get_non_existant_session($new_dal)
+            }
+            #[test]
+            fn save_and_get_admins() {
+                conformance::save_and_get_admins($new_dal)
+            }
+            #[test]
+            fn save_and_get_participants() {
+                conformance::save_and_get_participants($new_dal)
+            }
+            #[test]
+            fn participants_belong_to_one_session() {
+                conformance::participants_belong_to_one_session($new_dal)
+            }
+            #[test]
+            fn add_duplicate_participant() {
+                conformance::add_duplicate_participant($new_dal)
+            }
+            #[test]
+            fn add_duplicate_admin() {
+                conformance::add_duplicate_admin($new_dal)
+            }
+            #[test]
+            fn remove_participant() {
+                conformance::remove_participant($new_dal)
+            }
+            #[test]
+            fn remove_nonexistant_participant() {
+                conformance::remove_nonexistant_participant($new_dal)
+            }
+            #[test]
+            fn remove_admin() {
+                conformance::remove_admin($new_dal)
+            }
+            #[test]
+            fn remove_nonexistant_admin() {
+                conformance::remove_nonexistant_admin($new_dal)
+            }
+            #[test]
+            fn test_is_admin() {
+                conformance::test_is_admin($new_dal)
+            }
+            #[test]
+            fn create_and_delete_session() {
+                conformance::create_and_delete_session($new_dal)
+            }
+            #[test]
+            fn delete_session_cascades() {
+                conformance::delete_session_cascades($new_dal)
+            }
+            #[test]
+            fn delete_non_existent_session() {
+                conformance::delete_non_existent_session($new_dal)
+            }
+            #[test]
+            fn create_and_update_session() {
+                conformance::create_and_update_session($new_dal)
+            }
+            #[test]
+            fn update_session_changes_membership() {
+                conformance::update_session_changes_membership($new_dal)
+            }
+            #[test]
+            fn update_non_existent_session() {
+                conformance::update_non_existent_session($new_dal)
+            }
+            #[test]
+            fn failed_plans_change_nothing() {
+                conformance::failed_plans_change_nothing($new_dal)
+            }
+            #[test]
+            fn create_and_update_participant() {
+                conformance::create_and_update_participant($new_dal)
+            }
+            #[test]
+            fn update_non_existent_participant() {
+                conformance::update_non_existent_participant($new_dal)
+            }
+            #[test]
+            fn touch_user() {
+                conformance::touch_user($new_dal)
+            }
+            #[test]
+            fn touch_non_existent_user() {
+                conformance::touch_non_existent_user($new_dal)
+            }
+            #[test]
+            fn session_changes_are_activity() {
+                conformance::session_changes_are_activity($new_dal)
+            }
+            #[test]
+            fn audit_log_keeps_order() {
+                conformance::audit_log_keeps_order($new_dal)
+            }
+            #[test]
+            fn audit_non_existent_session() {
+                conformance::audit_non_existent_session($new_dal)
+            }
+            #[test]
+            fn tombstones_outlive_their_session() {
+                conformance::tombstones_outlive_their_session($new_dal)
+            }
+        }
+    }
+}
+
+fn is_not_found<T>(outcome: Result<T>) -> bool {
+    match outcome {
+        Err(Error(ErrorKind::ObjectNotFound(_), _)) => true,
+        _ => false,
+    }
+}
+
+fn is_user_error<T>(outcome: Result<T>) -> bool {
+    match outcome {
+        Err(Error(ErrorKind::UserError(_), _)) => true,
+        _ => false,
+    }
+}
+
+pub fn save_and_get_user<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let my_user = BasicUser::new();
+    dal.add_user(my_user.clone()).unwrap();
+    let same_user = dal.get_user(&my_user.user_id).unwrap().unwrap();
+    assert!(my_user == same_user);
+}
+
+pub fn get_non_existant_user<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let dal = new_dal();
+    let user_id = UserID::new();
+    let user_opt = dal.get_user(&user_id).unwrap();
+    assert!(user_opt == None);
+}
+
+/// Users are listed ordered by `UserID`
+pub fn list_and_delete_users<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let mut user_ids = Vec::new();
+    for _ in 0..8 {
+        let new_user = BasicUser::new();
+        user_ids.push(new_user.user_id.clone());
+        dal.add_user(new_user).unwrap();
+    }
+    user_ids.sort();
+    assert_eq!(dal.list_users().unwrap(), user_ids);
+
+    let removed = user_ids.remove(3);
+    dal.del_user(&removed).unwrap();
+    assert_eq!(dal.get_user(&removed).unwrap(), None);
+    assert_eq!(dal.list_users().unwrap(), user_ids);
+}
+
+pub fn delete_non_existent_user<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let outcome = dal.del_user(&UserID::new());
+    assert!(is_not_found(outcome));
+}
+
+/// Sessions are listed ordered by `SessionID`
+pub fn update_user<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let my_user = BasicUser::new();
+    dal.add_user(my_user.clone()).unwrap();
+    dal.update_user(&my_user.user_id, |u| {
+            u.last_activity = 42;
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(dal.get_user(&my_user.user_id).unwrap().unwrap().last_activity, 42);
+
+    let outcome = dal.update_user(&my_user.user_id, |u| {
+        u.last_activity = 7;
+        bail!(ErrorKind::UserError("nope".to_string()))
+    });
+    assert!(is_user_error(outcome));
+    assert_eq!(dal.get_user(&my_user.user_id).unwrap().unwrap().last_activity, 42);
+}
+
+pub fn update_non_existent_user<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let new_user = BasicUser::new();
+    assert!(is_not_found(dal.update_user(&new_user.user_id, |_| Ok(()))));
+}
+
+pub fn list_sessions<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    assert_eq!(dal.list_sessions().unwrap(), vec![]);
+    let mut session_ids = Vec::new();
+    for _ in 0..8 {
+        let my_session = Session::new();
+        session_ids.push(my_session.session_id.clone());
+        dal.add_session(my_session).unwrap();
+    }
+    session_ids.sort();
+    assert_eq!(dal.list_sessions().unwrap(), session_ids);
+
+    dal.del_session(&session_ids.remove(0)).unwrap();
+    assert_eq!(dal.list_sessions().unwrap(), session_ids);
+}
+
+pub fn save_and_get_session<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let my_session = Session::new();
+    dal.add_session(my_session.clone()).unwrap();
+    let same_session = dal.get_session(&my_session.session_id).unwrap().unwrap();
+    assert!(my_session == same_session);
+}
+
+pub fn get_non_existant_session<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let dal = new_dal();
+    let session_id = SessionID::new();
+    let session_opt = dal.get_session(&session_id).unwrap();
+    assert!(session_opt == None);
+}
+
+/// Admins come back ordered by `UserID`, whatever order they were added in
+pub fn save_and_get_admins<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    for n in 0..16 {
+        let mut dal = new_dal();
+        let s = Session::new();
+        let mut all_admins = Vec::new();
+        for _ in 0..n {
+            let u = BasicUser::new();
+            dal.add_admin(u.user_id.clone(), s.session_id.clone())
+                .unwrap();
+            all_admins.push(u.user_id);
+        }
+        all_admins.sort();
+
+        let same_admins = dal.get_admins(&s.session_id).unwrap();
+        assert_eq!(same_admins, all_admins);
+    }
+}
+
+/// Participants come back ordered by `UserID`, whatever order they joined in
+pub fn save_and_get_participants<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    for n in 0..16 {
+        let mut dal = new_dal();
+        let s = Session::new();
+        let mut all_participants = Vec::new();
+        for i in 0..n {
+            let new_user = BasicUser::new();
+            let u = Participant::new(&new_user,
+                                     s.session_id.clone(),
+                                     Nickname::new(format!("bob_{}", i)));
+            dal.add_participant(u.clone()).unwrap();
+            all_participants.push(u);
+        }
+        all_participants.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+
+        let same_participants = dal.get_participants(&s.session_id).unwrap();
+        assert_eq!(same_participants, all_participants);
+    }
+}
+
+pub fn participants_belong_to_one_session<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let s = Session::new();
+    let other_s = Session::new();
+    let new_user = BasicUser::new();
+    let u = Participant::new(&new_user, s.session_id.clone(), Nickname::new("bob"));
+    dal.add_participant(u.clone()).unwrap();
+    dal.add_admin(new_user.user_id.clone(), s.session_id.clone()).unwrap();
+
+    assert_eq!(dal.get_participants(&s.session_id).unwrap(), vec![u]);
+    assert_eq!(dal.get_participants(&other_s.session_id).unwrap(), vec![]);
+    assert!(dal.is_admin(&s.session_id, &new_user.user_id).unwrap());
+    assert!(!dal.is_admin(&other_s.session_id, &new_user.user_id).unwrap());
+}
+
+pub fn add_duplicate_participant<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let s = Session::new();
+    let new_user = BasicUser::new();
+    let u = Participant::new(&new_user, s.session_id.clone(), Nickname::new("bob"));
+    dal.add_participant(u.clone()).unwrap();
+    assert!(is_user_error(dal.add_participant(u)));
+    assert_eq!(dal.get_participants(&s.session_id).unwrap().len(), 1);
+}
+
+pub fn add_duplicate_admin<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let s = Session::new();
+    let new_user = BasicUser::new();
+    dal.add_admin(new_user.user_id.clone(), s.session_id.clone()).unwrap();
+    assert!(is_user_error(dal.add_admin(new_user.user_id.clone(), s.session_id.clone())));
+    assert_eq!(dal.get_admins(&s.session_id).unwrap().len(), 1);
+}
+
+pub fn remove_participant<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let s = Session::new();
+    let new_user = BasicUser::new();
+    let u = Participant::new(&new_user, s.session_id.clone(), Nickname::new("bob"));
+    dal.add_participant(u.clone()).unwrap();
+
+    let new_user2 = BasicUser::new();
+    let u2 = Participant::new(&new_user2, s.session_id.clone(), Nickname::new("bill"));
+    dal.add_participant(u2.clone()).unwrap();
+
+    dal.del_participant(&u.user_id, &s.session_id).unwrap();
+
+    let all_participants = dal.get_participants(&s.session_id).unwrap();
+
+    assert_eq!(all_participants.len(), 1);
+    assert_eq!(all_participants[0].user_id, u2.user_id);
+}
+
+pub fn remove_nonexistant_participant<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let s = Session::new();
+    let new_user = BasicUser::new();
+    let outcome = dal.del_participant(&new_user.user_id, &s.session_id);
+    assert!(is_not_found(outcome));
+}
+
+pub fn remove_admin<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let s = Session::new();
+    let new_user = BasicUser::new();
+    dal.add_admin(new_user.user_id.clone(), s.session_id.clone())
+        .unwrap();
+
+    let new_user2 = BasicUser::new();
+    dal.add_admin(new_user2.user_id.clone(), s.session_id.clone())
+        .unwrap();
+
+    dal.del_admin(&new_user.user_id, &s.session_id).unwrap();
+
+    let all_admins = dal.get_admins(&s.session_id).unwrap();
+
+    assert_eq!(all_admins.len(), 1);
+    assert_eq!(all_admins[0], new_user2.user_id);
+}
+
+pub fn remove_nonexistant_admin<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let s = Session::new();
+    let new_user = BasicUser::new();
+    let outcome = dal.del_admin(&new_user.user_id, &s.session_id);
+    assert!(is_user_error(outcome));
+}
+
+pub fn test_is_admin<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let s = Session::new();
+    let real_admin = BasicUser::new();
+    dal.add_admin(real_admin.user_id.clone(), s.session_id.clone())
+        .unwrap();
+
+    let fake_admin = BasicUser::new();
+
+    assert!(dal.is_admin(&s.session_id, &real_admin.user_id).unwrap());
+    assert!(!dal.is_admin(&s.session_id, &fake_admin.user_id).unwrap());
+}
+
+pub fn create_and_delete_session<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let my_session = Session::new();
+    dal.add_session(my_session.clone()).unwrap();
+    dal.del_session(&my_session.session_id).unwrap();
+    let outcome = dal.get_session(&my_session.session_id).unwrap();
+    assert_eq!(outcome, None);
+}
+
+pub fn delete_session_cascades<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let my_session = Session::new();
+    let new_user = BasicUser::new();
+    dal.add_session(my_session.clone()).unwrap();
+    let u = Participant::new(&new_user, my_session.session_id.clone(), Nickname::new("bob"));
+    dal.add_participant(u.clone()).unwrap();
+    dal.add_admin(new_user.user_id.clone(), my_session.session_id.clone()).unwrap();
+    dal.add_audit_entry(AuditEntry::new(my_session.session_id.clone(),
+                                        new_user.user_id.clone(),
+                                        AuditAction::UpdatePolicy))
+        .unwrap();
+    dal.del_session(&my_session.session_id).unwrap();
+    assert_eq!(dal.get_participants(&my_session.session_id).unwrap(), vec![]);
+    assert_eq!(dal.get_admins(&my_session.session_id).unwrap(), vec![]);
+    assert_eq!(dal.get_audit_log(&my_session.session_id).unwrap(), vec![]);
+
+    // A session reusing the ID starts out empty, rather than inheriting the old one's members
+    dal.add_session(my_session.clone()).unwrap();
+    assert_eq!(dal.get_participants(&my_session.session_id).unwrap(), vec![]);
+    assert!(!dal.is_admin(&my_session.session_id, &new_user.user_id).unwrap());
+    dal.add_participant(u).unwrap();
+}
+
+pub fn delete_non_existent_session<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let my_session = Session::new();
+    let outcome = dal.del_session(&my_session.session_id);
+    assert!(is_not_found(outcome));
+}
+
+pub fn create_and_update_session<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let my_session = Session::new();
+    dal.add_session(my_session.clone()).unwrap();
+    let new_user = BasicUser::new();
+    let u = Participant::new(&new_user, my_session.session_id.clone(), Nickname::new("bob"));
+    dal.add_participant(u).unwrap();
+
+    let magic_number = 1234.5f32;
+    let add_magic = |s: &mut Session, participants: &mut Vec<Participant>| -> Result<()> {
+        s.average = Some(magic_number);
+        for p in participants.iter_mut() {
+            p.vote(3);
+        }
+        Ok(())
+    };
+
+    dal.update_session(&my_session.session_id, add_magic)
+        .unwrap();
+    let same_session = dal.get_session(&my_session.session_id).unwrap().unwrap();
+    assert_eq!(same_session.average, Some(magic_number));
+    let all_participants = dal.get_participants(&my_session.session_id).unwrap();
+    assert_eq!(all_participants[0].vote, VoteState::Hidden(3));
+}
+
+/// Participants added to or removed from the list a plan is given join or leave the session
+pub fn update_session_changes_membership<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let my_session = Session::new();
+    dal.add_session(my_session.clone()).unwrap();
+    let leaving = Participant::new(&BasicUser::new(),
+                                   my_session.session_id.clone(),
+                                   Nickname::new("bob"));
+    dal.add_participant(leaving.clone()).unwrap();
+    let staying = Participant::new(&BasicUser::new(),
+                                   my_session.session_id.clone(),
+                                   Nickname::new("bill"));
+    dal.add_participant(staying.clone()).unwrap();
+    let joining = Participant::new(&BasicUser::new(),
+                                   my_session.session_id.clone(),
+                                   Nickname::new("ben"));
+
+    dal.update_session(&my_session.session_id, |_, participants| {
+            participants.retain(|p| p.user_id != leaving.user_id);
+            participants.push(joining.clone());
+            Ok(())
+        })
+        .unwrap();
+    let mut expected = vec![staying, joining];
+    expected.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+    assert_eq!(dal.get_participants(&my_session.session_id).unwrap(), expected);
+
+    // Membership changed this way is as real as any other
+    dal.del_participant(&expected[0].user_id, &my_session.session_id).unwrap();
+    assert!(is_not_found(dal.del_participant(&leaving.user_id, &my_session.session_id)));
+    assert_eq!(dal.get_participants(&my_session.session_id).unwrap(),
+               vec![expected[1].clone()]);
+}
+
+pub fn update_non_existent_session<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let my_session = Session::new();
+    let outcome = dal.update_session(&my_session.session_id, |_, _| Ok(()));
+    assert!(is_not_found(outcome));
+}
+
+/// A plan that fails leaves what it was given as it was, whatever it changed first
+pub fn failed_plans_change_nothing<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let (session_id, user_id) = idle_session(&mut dal);
+    let my_user = BasicUser::new();
+    dal.add_user(my_user.clone()).unwrap();
+    let session = dal.get_session(&session_id).unwrap().unwrap();
+    let participants = dal.get_participants(&session_id).unwrap();
+
+    let outcome = dal.update_session(&session_id, |s, participants| {
+        s.average = Some(1234.5f32);
+        for p in participants.iter_mut() {
+            p.vote(3);
+        }
+        bail!(ErrorKind::UserError("nope".to_string()))
+    });
+    assert!(is_user_error(outcome));
+    let outcome = dal.update_participant(&session_id, &user_id, |p| {
+        p.nickname = Nickname::new("bill");
+        bail!(ErrorKind::UserError("nope".to_string()))
+    });
+    assert!(is_user_error(outcome));
+    let outcome = dal.update_user(&my_user.user_id, |u| {
+        u.last_activity = 0;
+        bail!(ErrorKind::UserError("nope".to_string()))
+    });
+    assert!(is_user_error(outcome));
+
+    assert_eq!(dal.get_session(&session_id).unwrap(), Some(session));
+    assert_eq!(dal.get_participants(&session_id).unwrap(), participants);
+    assert_eq!(dal.get_user(&my_user.user_id).unwrap(), Some(my_user));
+}
+
+pub fn create_and_update_participant<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let my_session = Session::new();
+    dal.add_session(my_session.clone()).unwrap();
+    let new_user = BasicUser::new();
+    let u = Participant::new(&new_user, my_session.session_id.clone(), Nickname::new("bob"));
+    dal.add_participant(u.clone()).unwrap();
+    let new_name = Nickname::new("bill");
+    let update_username = |p: &mut Participant| {
+        p.nickname = new_name.clone();
+        Ok(())
+    };
+    dal.update_participant(&my_session.session_id, &new_user.user_id, update_username)
+        .unwrap();
+    let all_participants = dal.get_participants(&my_session.session_id).unwrap();
+    assert_eq!(all_participants[0].nickname, new_name);
+}
+
+pub fn update_non_existent_participant<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let my_session = Session::new();
+    dal.add_session(my_session.clone()).unwrap();
+    let new_user = BasicUser::new();
+    let outcome = dal.update_participant(&my_session.session_id, &new_user.user_id, |_| Ok(()));
+    assert!(is_not_found(outcome));
+}
+
+pub fn touch_user<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let mut my_user = BasicUser::new();
+    my_user.last_activity = 0;
+    dal.add_user(my_user.clone()).unwrap();
+    let before = clock::now();
+    dal.touch_user(&my_user.user_id).unwrap();
+    let same_user = dal.get_user(&my_user.user_id).unwrap().unwrap();
+    assert!(same_user.last_activity >= before);
+}
+
+pub fn touch_non_existent_user<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let new_user = BasicUser::new();
+    assert!(is_not_found(dal.touch_user(&new_user.user_id)));
+}
+
+/// Add a session last used at the epoch, with one participant who is also an admin
+fn idle_session<D>(dal: &mut D) -> (SessionID, UserID)
+    where D: StoryData
+{
+    let mut idle = Session::new();
+    idle.last_activity = 0;
+    let new_user = BasicUser::new();
+    let u = Participant::new(&new_user, idle.session_id.clone(), Nickname::new("bob"));
+    dal.add_participant(u).unwrap();
+    dal.add_admin(new_user.user_id.clone(), idle.session_id.clone()).unwrap();
+    dal.add_session(idle.clone()).unwrap();
+    assert_eq!(dal.get_session(&idle.session_id).unwrap().unwrap().last_activity, 0);
+    (idle.session_id, new_user.user_id)
+}
+
+fn active_since<D>(dal: &D, session_id: &SessionID, since: u64) -> bool
+    where D: StoryData
+{
+    dal.get_session(session_id).unwrap().unwrap().last_activity >= since
+}
+
+/// Joining, voting and changing admins all keep a session alive
+pub fn session_changes_are_activity<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let before = clock::now();
+
+    let (session_id, _) = idle_session(&mut dal);
+    let new_user = BasicUser::new();
+    let u = Participant::new(&new_user, session_id.clone(), Nickname::new("bill"));
+    dal.add_participant(u).unwrap();
+    assert!(active_since(&dal, &session_id, before));
+
+    let (session_id, _) = idle_session(&mut dal);
+    dal.update_session(&session_id, |_, _| Ok(())).unwrap();
+    assert!(active_since(&dal, &session_id, before));
+
+    let (session_id, user_id) = idle_session(&mut dal);
+    dal.update_participant(&session_id, &user_id, |p| {
+            p.vote(3);
+            Ok(())
+        })
+        .unwrap();
+    assert!(active_since(&dal, &session_id, before));
+
+    let (session_id, user_id) = idle_session(&mut dal);
+    dal.del_participant(&user_id, &session_id).unwrap();
+    assert!(active_since(&dal, &session_id, before));
+
+    let (session_id, _) = idle_session(&mut dal);
+    dal.add_admin(UserID::new(), session_id.clone()).unwrap();
+    assert!(active_since(&dal, &session_id, before));
+
+    let (session_id, user_id) = idle_session(&mut dal);
+    dal.del_admin(&user_id, &session_id).unwrap();
+    assert!(active_since(&dal, &session_id, before));
+}
+
+pub fn audit_log_keeps_order<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let my_session = Session::new();
+    let other_session = Session::new();
+    dal.add_session(my_session.clone()).unwrap();
+    dal.add_session(other_session.clone()).unwrap();
+    let admin = BasicUser::new();
+    let voter = BasicUser::new();
+    let entries = vec![AuditEntry::new(my_session.session_id.clone(),
+                                       admin.user_id.clone(),
+                                       AuditAction::SetRole)
+                           .with_target(voter.user_id.clone())
+                           .with_detail("facilitator"),
+                       AuditEntry::new(my_session.session_id.clone(),
+                                       voter.user_id.clone(),
+                                       AuditAction::UpdateSession)
+                           .with_detail("revealed"),
+                       AuditEntry::new(my_session.session_id.clone(),
+                                       voter.user_id.clone(),
+                                       AuditAction::Kick)
+                           .with_target(admin.user_id.clone())];
+    for entry in entries.iter().cloned() {
+        dal.add_audit_entry(entry).unwrap();
+    }
+    assert_eq!(dal.get_audit_log(&my_session.session_id).unwrap(), entries);
+    assert_eq!(dal.get_audit_log(&other_session.session_id).unwrap(), vec![]);
+}
+
+pub fn audit_non_existent_session<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let my_session = Session::new();
+    let new_user = BasicUser::new();
+    let outcome = dal.add_audit_entry(AuditEntry::new(my_session.session_id.clone(),
+                                                      new_user.user_id,
+                                                      AuditAction::UpdatePolicy));
+    assert!(is_not_found(outcome));
+    assert_eq!(dal.get_audit_log(&my_session.session_id).unwrap(), vec![]);
+}
+
+pub fn tombstones_outlive_their_session<D, F>(new_dal: F)
+    where D: StoryData,
+          F: Fn() -> D
+{
+    let mut dal = new_dal();
+    let my_session = Session::new();
+    dal.add_session(my_session.clone()).unwrap();
+    let admin = BasicUser::new();
+    let mut first = AuditEntry::new(my_session.session_id.clone(),
+                                    admin.user_id.clone(),
+                                    AuditAction::DeleteSession);
+    first.timestamp = 1;
+    // A session that never existed, e.g. one deleted before there were tombstones
+    let mut second = AuditEntry::new(Session::new().session_id,
+                                     admin.user_id.clone(),
+                                     AuditAction::DeleteSession);
+    second.timestamp = 2;
+    dal.del_session(&my_session.session_id).unwrap();
+    dal.add_tombstone(first.clone()).unwrap();
+    dal.add_tombstone(second.clone()).unwrap();
+    assert_eq!(dal.get_tombstones().unwrap(), vec![first, second]);
+    assert_eq!(dal.get_audit_log(&my_session.session_id).unwrap(), vec![]);
+}
@@ -0,0 +1,378 @@
+//! A backend neutral dump of everything in a `StoryData`
+//!
+//! A dump is JSON Lines: a header carrying the format version, then every
+//! user, then every session followed by its participants, admins and audit
+//! log, then the tombstones of deleted sessions.
+
+use super::StoryData;
+use errors::*;
+use estimates::audit::AuditEntry;
+use estimates::participant::Participant;
+use estimates::session::{Session, SessionID};
+use serde_json;
+use std::io::{BufRead, Write};
+use user::{BasicUser, UserID};
+
+/// The version of the dump format written by this code
+///
+/// Version 2 added audit logs and version 3 tombstones, older dumps can still be read.
+pub const DUMP_VERSION: u32 = 3;
+
+/// One line of a dump
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum DumpRecord {
+    Header { version: u32 },
+    User(BasicUser),
+    Session(Session),
+    Participant(Participant),
+    Admin(UserID, SessionID),
+    AuditEntry(AuditEntry),
+    Tombstone(AuditEntry),
+}
+
+/// How many of each record went into or came out of a dump
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DumpSummary {
+    pub users: usize,
+    pub sessions: usize,
+    pub participants: usize,
+    pub admins: usize,
+    pub audit_entries: usize,
+    pub tombstones: usize,
+}
+
+impl DumpSummary {
+    fn count(&mut self, record: &DumpRecord) {
+        match *record {
+            DumpRecord::Header { .. } => (),
+            DumpRecord::User(_) => self.users += 1,
+            DumpRecord::Session(_) => self.sessions += 1,
+            DumpRecord::Participant(_) => self.participants += 1,
+            DumpRecord::Admin(_, _) => self.admins += 1,
+            DumpRecord::AuditEntry(_) => self.audit_entries += 1,
+            DumpRecord::Tombstone(_) => self.tombstones += 1,
+        }
+    }
+}
+
+/// Read everything out of a backend, in the order it appears in a dump
+fn visit<D, F>(dal: &D, mut emit: F) -> Result<()>
+    where D: StoryData,
+          F: FnMut(DumpRecord) -> Result<()>
+{
+    emit(DumpRecord::Header { version: DUMP_VERSION })?;
+    for user_id in dal.list_users()? {
+        // Skip anything that expired while we were reading
+        if let Some(user) = dal.get_user(&user_id)? {
+            emit(DumpRecord::User(user))?;
+        }
+    }
+    for session_id in dal.list_sessions()? {
+        if let Some(session) = dal.get_session(&session_id)? {
+            emit(DumpRecord::Session(session))?;
+            for participant in dal.get_participants(&session_id)? {
+                emit(DumpRecord::Participant(participant))?;
+            }
+            for user_id in dal.get_admins(&session_id)? {
+                emit(DumpRecord::Admin(user_id, session_id.clone()))?;
+            }
+            for entry in dal.get_audit_log(&session_id)? {
+                emit(DumpRecord::AuditEntry(entry))?;
+            }
+        }
+    }
+    for entry in dal.get_tombstones()? {
+        emit(DumpRecord::Tombstone(entry))?;
+    }
+    Ok(())
+}
+
+/// A session read from a dump, waiting for the rest of its records
+struct PendingSession {
+    session: Session,
+    participants: Vec<Participant>,
+    admins: Vec<UserID>,
+    audit: Vec<AuditEntry>,
+}
+
+/// Loads records into a backend, replacing any users and sessions with the same IDs
+struct Importer<'a, D: 'a> {
+    dal: &'a mut D,
+    version: Option<u32>,
+    pending: Option<PendingSession>,
+    summary: DumpSummary,
+}
+
+impl<'a, D> Importer<'a, D>
+    where D: StoryData
+{
+    fn new(dal: &'a mut D) -> Self {
+        Importer {
+            dal: dal,
+            version: None,
+            pending: None,
+            summary: DumpSummary::default(),
+        }
+    }
+
+    fn apply(&mut self, record: DumpRecord) -> Result<()> {
+        self.summary.count(&record);
+        match record {
+            DumpRecord::Header { version } => {
+                if self.version.is_some() {
+                    bail!(ErrorKind::InvalidDump("found a second header".to_string()));
+                }
+                if version < 1 || version > DUMP_VERSION {
+                    let reason = format!("version {} is not supported, expected 1 to {}",
+                                         version,
+                                         DUMP_VERSION);
+                    bail!(ErrorKind::InvalidDump(reason));
+                }
+                self.version = Some(version);
+            },
+            _ if self.version.is_none() => {
+                bail!(ErrorKind::InvalidDump("the header is missing".to_string()))
+            },
+            DumpRecord::User(user) => self.dal.add_user(user)?,
+            DumpRecord::Session(session) => {
+                self.flush()?;
+                self.pending = Some(PendingSession {
+                    session: session,
+                    participants: Vec::new(),
+                    admins: Vec::new(),
+                    audit: Vec::new(),
+                });
+            },
+            DumpRecord::Participant(participant) => {
+                let session_id = participant.session_id.clone();
+                self.pending_for(&session_id)?.participants.push(participant)
+            },
+            DumpRecord::Admin(user_id, session_id) => {
+                self.pending_for(&session_id)?.admins.push(user_id)
+            },
+            DumpRecord::AuditEntry(entry) => {
+                let session_id = entry.session_id.clone();
+                self.pending_for(&session_id)?.audit.push(entry)
+            },
+            DumpRecord::Tombstone(entry) => {
+                self.flush()?;
+                // Unlike users and sessions there is nothing to replace, so only add new ones
+                if !self.dal.get_tombstones()?.contains(&entry) {
+                    self.dal.add_tombstone(entry)?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// The session being read, which a participant, admin or audit entry must belong to
+    fn pending_for(&mut self, session_id: &SessionID) -> Result<&mut PendingSession> {
+        if let Some(ref mut pending) = self.pending {
+            if pending.session.session_id == *session_id {
+                return Ok(pending);
+            }
+        }
+        bail!(ErrorKind::InvalidDump(format!("{} does not follow its session", session_id)))
+    }
+
+    /// Write the session being read, now that all of its records are in
+    fn flush(&mut self) -> Result<()> {
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        let session_id = pending.session.session_id.clone();
+        if self.dal.get_session(&session_id)?.is_some() {
+            self.dal.del_session(&session_id)?;
+        }
+        // Adding members counts as activity in an existing session, so the
+        // session itself goes in last to keep when it was really last used
+        for participant in pending.participants {
+            self.dal.add_participant(participant)?;
+        }
+        for user_id in pending.admins {
+            self.dal.add_admin(user_id, session_id.clone())?;
+        }
+        self.dal.add_session(pending.session)?;
+        // Audit entries need their session to exist, but aren't activity
+        for entry in pending.audit {
+            self.dal.add_audit_entry(entry)?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<DumpSummary> {
+        if self.version.is_none() {
+            bail!(ErrorKind::InvalidDump("the dump is empty".to_string()));
+        }
+        self.flush()?;
+        Ok(self.summary)
+    }
+}
+
+/// Write everything in a backend to `out`, including user tokens
+pub fn write_dump<D, W>(dal: &D, mut out: W) -> Result<DumpSummary>
+    where D: StoryData,
+          W: Write
+{
+    let mut summary = DumpSummary::default();
+    visit(dal, |record| {
+        summary.count(&record);
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        out.write_all(&line)?;
+        Ok(())
+    })?;
+    out.flush()?;
+    Ok(summary)
+}
+
+/// Load a dump into a backend, replacing any users and sessions with the same IDs
+///
+/// Records are written as they are read, so a dump that turns out to be
+/// invalid part way through leaves the records before the problem loaded.
+pub fn read_dump<D, R>(dal: &mut D, input: R) -> Result<DumpSummary>
+    where D: StoryData,
+          R: BufRead
+{
+    let mut importer = Importer::new(dal);
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let record: DumpRecord = serde_json::from_str(&line)
+            .chain_err(|| ErrorKind::InvalidDump(format!("line {} is not a record", index + 1)))?;
+        importer.apply(record)
+            .chain_err(|| format!("Could not load line {} of the dump", index + 1))?;
+    }
+    importer.finish()
+}
+
+/// Copy everything in one backend into another, without writing it out in between
+pub fn migrate<D, E>(from: &D, to: &mut E) -> Result<DumpSummary>
+    where D: StoryData,
+          E: StoryData
+{
+    let mut importer = Importer::new(to);
+    visit(from, |record| importer.apply(record))?;
+    importer.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use config::ExpiryConfig;
+    use dal::{DEFAULT_REDIS_NAMESPACE, MemoryDB, RedisDB, SqliteDB};
+    use dal::fakeredis;
+    use estimates::audit::AuditAction;
+    use user::Nickname;
+
+    fn populate<D>(dal: &mut D) -> Session
+        where D: StoryData
+    {
+        let alice = BasicUser::new();
+        let bob = BasicUser::new();
+        dal.add_user(alice.clone()).unwrap();
+        dal.add_user(bob.clone()).unwrap();
+        let my_session = Session::new();
+        dal.add_session(my_session.clone()).unwrap();
+        for &(user, name) in &[(&alice, "alice"), (&bob, "bob")] {
+            let p = Participant::new(user, my_session.session_id.clone(), Nickname::new(name));
+            dal.add_participant(p).unwrap();
+        }
+        dal.add_admin(alice.user_id.clone(), my_session.session_id.clone()).unwrap();
+        dal.add_audit_entry(AuditEntry::new(my_session.session_id.clone(),
+                                            alice.user_id.clone(),
+                                            AuditAction::Kick)
+                .with_target(bob.user_id.clone()))
+            .unwrap();
+        dal.add_session(Session::new()).unwrap();
+        dal.add_tombstone(AuditEntry::new(SessionID("deleted".to_string()),
+                                          bob.user_id.clone(),
+                                          AuditAction::DeleteSession))
+            .unwrap();
+        my_session
+    }
+
+    fn dump<D>(dal: &D) -> Vec<u8>
+        where D: StoryData
+    {
+        let mut out = Vec::new();
+        write_dump(dal, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn dump_round_trip_between_backends() {
+        let mut memdal = MemoryDB::new();
+        populate(&mut memdal);
+        let memdump = dump(&memdal);
+
+        let mut sqlitedal = SqliteDB::open_in_memory().unwrap();
+        let summary = read_dump(&mut sqlitedal, &memdump[..]).unwrap();
+        assert_eq!(summary,
+                   DumpSummary {
+                       users: 2,
+                       sessions: 2,
+                       participants: 2,
+                       admins: 1,
+                       audit_entries: 1,
+                       tombstones: 1,
+                   });
+        assert_eq!(dump(&sqlitedal), memdump);
+        // Loading it again replaces what is there rather than failing
+        read_dump(&mut sqlitedal, &memdump[..]).unwrap();
+        assert_eq!(dump(&sqlitedal), memdump);
+    }
+
+    #[test]
+    fn migrate_to_redis() {
+        let mut memdal = MemoryDB::new();
+        populate(&mut memdal);
+        let mut redisdal = RedisDB::new(fakeredis::shared().fresh_pool().get().unwrap(),
+                                        ExpiryConfig::default(),
+                                        DEFAULT_REDIS_NAMESPACE.to_string());
+        migrate(&memdal, &mut redisdal).unwrap();
+        assert_eq!(dump(&redisdal), dump(&memdal));
+    }
+
+    #[test]
+    fn dump_starts_with_its_version() {
+        let memdump = dump(&MemoryDB::new());
+        let first_line = memdump.split(|&b| b == b'\n').next().unwrap();
+        assert_eq!(serde_json::from_slice::<DumpRecord>(first_line).unwrap(),
+                   DumpRecord::Header { version: DUMP_VERSION });
+    }
+
+    #[test]
+    fn reject_unknown_versions() {
+        let mut memdal = MemoryDB::new();
+        let future = format!("{}\n",
+                             serde_json::to_string(&DumpRecord::Header { version: 99 }).unwrap());
+        assert!(read_dump(&mut memdal, future.as_bytes()).is_err());
+        assert!(read_dump(&mut memdal, &b""[..]).is_err());
+
+        // Dumps from before audit logs are still fine
+        let old = format!("{}\n",
+                          serde_json::to_string(&DumpRecord::Header { version: 1 }).unwrap());
+        assert!(read_dump(&mut memdal, old.as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn reject_participants_outside_their_session() {
+        let mut memdal = MemoryDB::new();
+        let my_session = populate(&mut memdal);
+        let participant = memdal.get_participants(&my_session.session_id).unwrap().remove(0);
+        let records = vec![DumpRecord::Header { version: DUMP_VERSION },
+                           DumpRecord::Session(Session::new()),
+                           DumpRecord::Participant(participant)];
+        let mut input = Vec::new();
+        for record in &records {
+            input.extend(serde_json::to_vec(record).unwrap());
+            input.push(b'\n');
+        }
+        assert!(read_dump(&mut MemoryDB::new(), &input[..]).is_err());
+    }
+}
@@ -0,0 +1,679 @@
+use super::{MemoryDB, StoryData, StoryDataProvider};
+use super::expiry;
+use super::memory::MemorySnapshot;
+use config::ExpiryConfig;
+use ctrlc;
+use errors::*;
+use estimates::audit::AuditEntry;
+use estimates::participant::Participant;
+use estimates::session::{Session, SessionID};
+use serde_json;
+use service::admin;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+use user::{BasicUser, UserID};
+
+const SNAPSHOT_FILE: &'static str = "snapshot.json";
+const SNAPSHOT_TMP_FILE: &'static str = "snapshot.json.tmp";
+const JOURNAL_FILE: &'static str = "journal.jsonl";
+
+/// When writes to the journal are forced out to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// After every write, nothing acknowledged is ever lost
+    Always,
+    /// Once a second from a background thread, at most a second of writes is lost
+    EverySecond,
+    /// Whenever the operating system gets around to it
+    Never,
+}
+
+/// A single change to the database, appended to the journal as one line of JSON
+///
+/// Updates made through a closure are recorded as the state they produced,
+/// so replaying the journal doesn't depend on the code that made them.
+#[derive(Serialize, Deserialize)]
+#[derive(Debug)]
+enum JournalEntry {
+    AddUser(BasicUser),
+    AddSession(Session),
+    DelSession(SessionID),
+    DelUser(UserID),
+    SetSession(Session, Vec<Participant>),
+    AddParticipant(Participant),
+    DelParticipant(UserID, SessionID),
+    SetParticipant(Participant),
+    AddAdmin(UserID, SessionID),
+    DelAdmin(UserID, SessionID),
+    TouchUser(UserID, u64),
+    TouchSession(SessionID, u64),
+    ExpireSession(SessionID),
+    ExpireUser(UserID),
+    AddAuditEntry(AuditEntry),
+    AddTombstone(AuditEntry),
+    /// Starts each journal, naming the generation of the snapshot it follows
+    Generation(u64),
+}
+
+fn apply(memdal: &mut MemoryDB, entry: JournalEntry) -> Result<()> {
+    match entry {
+        JournalEntry::AddUser(user) => memdal.add_user(user),
+        JournalEntry::AddSession(session) => memdal.add_session(session),
+        JournalEntry::DelSession(session_id) => memdal.del_session(&session_id),
+        JournalEntry::DelUser(user_id) => memdal.del_user(&user_id),
+        JournalEntry::SetSession(session, participants) => {
+            let session_id = session.session_id.clone();
+            memdal.update_session(&session_id, |s, p| {
+                *s = session.clone();
+                *p = participants.clone();
+                Ok(())
+            })
+        },
+        JournalEntry::AddParticipant(participant) => memdal.add_participant(participant),
+        JournalEntry::DelParticipant(user_id, session_id) => {
+            memdal.del_participant(&user_id, &session_id)
+        },
+        JournalEntry::SetParticipant(participant) => {
+            let session_id = participant.session_id.clone();
+            let user_id = participant.user_id.clone();
+            memdal.update_participant(&session_id, &user_id, |p| {
+                *p = participant.clone();
+                Ok(())
+            })
+        },
+        JournalEntry::AddAdmin(user_id, session_id) => memdal.add_admin(user_id, session_id),
+        JournalEntry::DelAdmin(user_id, session_id) => memdal.del_admin(&user_id, &session_id),
+        JournalEntry::TouchUser(user_id, at) => memdal.set_user_activity(&user_id, at),
+        JournalEntry::TouchSession(session_id, at) => {
+            memdal.set_session_activity(&session_id, at);
+            Ok(())
+        },
+        JournalEntry::ExpireSession(session_id) => {
+            memdal.remove_session(&session_id);
+            Ok(())
+        },
+        JournalEntry::ExpireUser(user_id) => {
+            memdal.remove_user(&user_id);
+            Ok(())
+        },
+        JournalEntry::AddAuditEntry(entry) => memdal.add_audit_entry(entry),
+        JournalEntry::AddTombstone(entry) => memdal.add_tombstone(entry),
+        JournalEntry::Generation(_) => Ok(()),
+    }
+}
+
+/// A `MemoryDB` that survives restarts
+///
+/// Every change is appended to a journal before the call returns. A snapshot
+/// of the whole database is written periodically, after which the journal
+/// starts over. At boot the latest snapshot is loaded and the journal is
+/// replayed on top of it.
+///
+/// Each snapshot gets the next generation number, and the journal started
+/// after it begins by naming that generation. A crash after a snapshot is
+/// written but before the journal starts over leaves a journal naming an
+/// older generation, whose entries are all in the snapshot, so it is skipped.
+pub struct JournaledMemoryDB {
+    db: MemoryDB,
+    data_dir: PathBuf,
+    journal: File,
+    fsync: FsyncPolicy,
+    unsynced: bool,
+    generation: u64,
+}
+
+impl JournaledMemoryDB {
+    pub fn open<P>(data_dir: P, fsync: FsyncPolicy) -> Result<Self>
+        where P: AsRef<Path>
+    {
+        let data_dir = data_dir.as_ref().to_path_buf();
+        fs::create_dir_all(&data_dir)?;
+
+        let (mut db, generation) = match File::open(data_dir.join(SNAPSHOT_FILE)) {
+            Ok(file) => {
+                let snapshot: MemorySnapshot = serde_json::from_reader(BufReader::new(file))
+                    .chain_err(|| "Could not read the in memory database snapshot")?;
+                let generation = snapshot.journal_generation;
+                (MemoryDB::from_snapshot(snapshot), generation)
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (MemoryDB::new(), 0),
+            Err(e) => return Err(e.into()),
+        };
+
+        let journal_path = data_dir.join(JOURNAL_FILE);
+        match File::open(&journal_path) {
+            Ok(file) => replay(&mut db, BufReader::new(file), generation)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+
+        let journal = OpenOptions::new().append(true).create(true).open(&journal_path)?;
+        let mut journaled = JournaledMemoryDB {
+            db: db,
+            data_dir: data_dir,
+            journal: journal,
+            fsync: fsync,
+            unsynced: false,
+            generation: generation,
+        };
+        // Fold the replayed journal into a fresh snapshot, so it doesn't grow forever
+        journaled.snapshot()?;
+        Ok(journaled)
+    }
+
+    /// Write the whole database to disk and start a new, empty, journal
+    pub fn snapshot(&mut self) -> Result<()> {
+        let generation = self.generation + 1;
+        let tmp_path = self.data_dir.join(SNAPSHOT_TMP_FILE);
+        {
+            let mut snapshot = self.db.snapshot();
+            snapshot.journal_generation = generation;
+            let mut file = File::create(&tmp_path)?;
+            serde_json::to_writer(&mut file, &snapshot)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, self.data_dir.join(SNAPSHOT_FILE))?;
+        self.journal.set_len(0)?;
+        self.generation = generation;
+        self.record(JournalEntry::Generation(generation))?;
+        self.journal.sync_all()?;
+        self.unsynced = false;
+        Ok(())
+    }
+
+    /// Force any journal writes made since the last sync out to disk
+    pub fn sync(&mut self) -> Result<()> {
+        if self.unsynced {
+            self.journal.sync_data()?;
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, entry: JournalEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        self.journal
+            .write_all(&line)
+            .chain_err(|| format!("Could not write {:?} to the journal", entry))?;
+        if self.fsync == FsyncPolicy::Always {
+            self.journal.sync_data()?;
+        } else {
+            self.unsynced = true;
+        }
+        Ok(())
+    }
+
+    /// Record when the session was last active, so a replay doesn't use the time it ran at
+    fn record_session_activity(&mut self, session_id: &SessionID) -> Result<()> {
+        match self.db.get_session(session_id)? {
+            Some(session) => {
+                self.record(JournalEntry::TouchSession(session_id.clone(), session.last_activity))
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+/// Apply every entry in a journal following the snapshot of `generation`, in order
+///
+/// A journal naming another generation was written before the snapshot and
+/// is already in it, so nothing is applied. Journals from before there were
+/// generations follow generation 0. A crash can leave the last line half
+/// written, which is logged and skipped, as is any entry that can't be applied.
+fn replay<R>(db: &mut MemoryDB, journal: R, generation: u64) -> Result<()>
+    where R: BufRead
+{
+    let mut applied = 0;
+    for (index, line) in journal.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<JournalEntry>(&line) {
+            Ok(JournalEntry::Generation(journal_generation)) => {
+                if journal_generation != generation {
+                    info!("Skipping the journal of generation {}, the snapshot is generation {}",
+                          journal_generation,
+                          generation);
+                    return Ok(());
+                }
+            },
+            Ok(entry) => {
+                if let Err(e) = apply(db, entry) {
+                    warn!("Skipping journal entry on line {}: {}", index + 1, e);
+                } else {
+                    applied += 1;
+                }
+            },
+            Err(e) => warn!("Could not read journal entry on line {}: {}", index + 1, e),
+        }
+    }
+    info!("Replayed {} journal entries", applied);
+    Ok(())
+}
+
+impl StoryData for JournaledMemoryDB {
+    fn get_user(&self, user_id: &UserID) -> Result<Option<BasicUser>> {
+        self.db.get_user(user_id)
+    }
+    fn add_user(&mut self, user: BasicUser) -> Result<()> {
+        self.db.add_user(user.clone())?;
+        self.record(JournalEntry::AddUser(user))
+    }
+    fn del_user(&mut self, user_id: &UserID) -> Result<()> {
+        self.db.del_user(user_id)?;
+        self.record(JournalEntry::DelUser(user_id.clone()))
+    }
+    fn list_users(&self) -> Result<Vec<UserID>> {
+        self.db.list_users()
+    }
+    fn update_user<F>(&mut self, user_id: &UserID, plan: F) -> Result<()>
+        where F: FnMut(&mut BasicUser) -> Result<()>
+    {
+        self.db.update_user(user_id, plan)?;
+        if let Some(user) = self.db.get_user(user_id)? {
+            self.record(JournalEntry::AddUser(user))?;
+        }
+        Ok(())
+    }
+
+    fn get_session(&self, session_id: &SessionID) -> Result<Option<Session>> {
+        self.db.get_session(session_id)
+    }
+    fn list_sessions(&self) -> Result<Vec<SessionID>> {
+        self.db.list_sessions()
+    }
+    fn add_session(&mut self, session: Session) -> Result<()> {
+        self.db.add_session(session.clone())?;
+        self.record(JournalEntry::AddSession(session))
+    }
+    fn del_session(&mut self, session_id: &SessionID) -> Result<()> {
+        self.db.del_session(session_id)?;
+        self.record(JournalEntry::DelSession(session_id.clone()))
+    }
+    fn update_session<F>(&mut self, session_id: &SessionID, plan: F) -> Result<()>
+        where F: FnMut(&mut Session, &mut Vec<Participant>) -> Result<()>
+    {
+        self.db.update_session(session_id, plan)?;
+        if let Some(session) = self.db.get_session(session_id)? {
+            let participants = self.db.get_participants(session_id)?;
+            self.record(JournalEntry::SetSession(session, participants))?;
+        }
+        Ok(())
+    }
+
+    fn get_participants(&self, session_id: &SessionID) -> Result<Vec<Participant>> {
+        self.db.get_participants(session_id)
+    }
+    fn add_participant(&mut self, participant: Participant) -> Result<()> {
+        let session_id = participant.session_id.clone();
+        self.db.add_participant(participant.clone())?;
+        self.record(JournalEntry::AddParticipant(participant))?;
+        self.record_session_activity(&session_id)
+    }
+    fn del_participant(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<()> {
+        self.db.del_participant(user_id, session_id)?;
+        self.record(JournalEntry::DelParticipant(user_id.clone(), session_id.clone()))?;
+        self.record_session_activity(session_id)
+    }
+    fn update_participant<F>(
+        &mut self,
+        session_id: &SessionID,
+        user_id: &UserID,
+        plan: F
+    ) -> Result<()>
+        where F: FnMut(&mut Participant) -> Result<()>
+    {
+        self.db.update_participant(session_id, user_id, plan)?;
+        let updated = self.db
+            .get_participants(session_id)?
+            .into_iter()
+            .find(|p| p.user_id == *user_id);
+        if let Some(participant) = updated {
+            self.record(JournalEntry::SetParticipant(participant))?;
+            self.record_session_activity(session_id)?;
+        }
+        Ok(())
+    }
+
+    fn get_admins(&self, session_id: &SessionID) -> Result<Vec<UserID>> {
+        self.db.get_admins(session_id)
+    }
+    fn add_admin(&mut self, user_id: UserID, session_id: SessionID) -> Result<()> {
+        self.db.add_admin(user_id.clone(), session_id.clone())?;
+        self.record(JournalEntry::AddAdmin(user_id, session_id.clone()))?;
+        self.record_session_activity(&session_id)
+    }
+    fn del_admin(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<()> {
+        self.db.del_admin(user_id, session_id)?;
+        self.record(JournalEntry::DelAdmin(user_id.clone(), session_id.clone()))?;
+        self.record_session_activity(session_id)
+    }
+
+    fn get_audit_log(&self, session_id: &SessionID) -> Result<Vec<AuditEntry>> {
+        self.db.get_audit_log(session_id)
+    }
+    fn add_audit_entry(&mut self, entry: AuditEntry) -> Result<()> {
+        self.db.add_audit_entry(entry.clone())?;
+        self.record(JournalEntry::AddAuditEntry(entry))
+    }
+    fn get_tombstones(&self) -> Result<Vec<AuditEntry>> {
+        self.db.get_tombstones()
+    }
+    fn add_tombstone(&mut self, entry: AuditEntry) -> Result<()> {
+        self.db.add_tombstone(entry.clone())?;
+        self.record(JournalEntry::AddTombstone(entry))
+    }
+
+    fn touch_user(&mut self, user_id: &UserID) -> Result<()> {
+        self.db.touch_user(user_id)?;
+        let at = self.db.get_user(user_id)?.map(|u| u.last_activity).unwrap_or(0);
+        self.record(JournalEntry::TouchUser(user_id.clone(), at))
+    }
+    fn purge_expired(&mut self, expiry: &ExpiryConfig, now: u64) -> Result<usize> {
+        let (sessions, users) = self.db.expired(expiry, now);
+        for session_id in &sessions {
+            self.db.remove_session(session_id);
+            self.record(JournalEntry::ExpireSession(session_id.clone()))?;
+        }
+        for user_id in &users {
+            self.db.remove_user(user_id);
+            self.record(JournalEntry::ExpireUser(user_id.clone()))?;
+        }
+        Ok(sessions.len() + users.len())
+    }
+}
+
+/// A `JournaledMemoryDB` shared between all requests and its maintenance threads
+pub struct DurableMemoryDB {
+    inner: Arc<Mutex<JournaledMemoryDB>>,
+    fsync: FsyncPolicy,
+    snapshot_interval: Duration,
+}
+
+/// Lock the database, even if a thread panicked while holding the lock
+///
+/// `MemoryDB` runs update plans on copies of the records, and each change is
+/// journaled after it is made, so a panic can't leave anything half changed.
+fn lock(inner: &Mutex<JournaledMemoryDB>) -> MutexGuard<JournaledMemoryDB> {
+    inner.lock().unwrap_or_else(|poisoned| {
+        warn!("Recovering the in memory data after a panic");
+        poisoned.into_inner()
+    })
+}
+
+impl DurableMemoryDB {
+    pub fn open<P>(data_dir: P, fsync: FsyncPolicy, snapshot_interval: Duration) -> Result<Self>
+        where P: AsRef<Path>
+    {
+        let db = JournaledMemoryDB::open(data_dir, fsync)?;
+        Ok(DurableMemoryDB {
+            inner: Arc::new(Mutex::new(db)),
+            fsync: fsync,
+            snapshot_interval: snapshot_interval,
+        })
+    }
+
+    pub fn snapshot(&self) -> Result<()> {
+        lock(&self.inner).snapshot()
+    }
+
+    /// Start the threads that sync the journal and take snapshots, and take a
+    /// final snapshot when the process is asked to stop
+    pub fn start_maintenance(&self) -> Result<()> {
+        if self.fsync == FsyncPolicy::EverySecond {
+            let db = self.inner.clone();
+            thread::spawn(move || loop {
+                thread::sleep(Duration::from_secs(1));
+                if let Err(e) = lock(&db).sync() {
+                    warn!("Could not sync the journal: {}", e);
+                }
+            });
+        }
+
+        let db = self.inner.clone();
+        let interval = self.snapshot_interval;
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            if let Err(e) = lock(&db).snapshot() {
+                warn!("Could not snapshot the in memory database: {}", e);
+            }
+        });
+
+        let db = self.inner.clone();
+        ctrlc::set_handler(move || {
+                info!("Shutting down, taking a final snapshot");
+                match lock(&db).snapshot() {
+                    Ok(()) => process::exit(0),
+                    Err(e) => {
+                        warn!("Could not snapshot the in memory database: {}", e);
+                        process::exit(1);
+                    },
+                }
+            })
+            .chain_err(|| "Could not install the shutdown handler")?;
+        Ok(())
+    }
+
+    /// Start removing idle sessions and users in the background
+    pub fn start_sweeper(&self, expiry: &ExpiryConfig) {
+        let db = self.inner.clone();
+        expiry::sweep_with(expiry.clone(),
+                           move |expiry, now| admin::expire(&mut *lock(&db), expiry, now));
+    }
+}
+
+impl<'a> StoryDataProvider<'a> for DurableMemoryDB {
+    type StoryData = MutexGuard<'a, JournaledMemoryDB>;
+
+    fn get_story_data(&'a self) -> Result<Self::StoryData> {
+        Ok(lock(&self.inner))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use estimates::audit::AuditAction;
+    use std::env;
+    use std::io::Cursor;
+    use user::Nickname;
+    use util::generator;
+
+    /// A scratch directory that is removed when the test finishes
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            TempDir(env::temp_dir().join(format!("storyestimates-{}", generator::user_id())))
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A journaled database whose files are removed as soon as they are open
+    fn scratch_db() -> JournaledMemoryDB {
+        let dir = TempDir::new();
+        JournaledMemoryDB::open(&dir.0, FsyncPolicy::Never).unwrap()
+    }
+
+    storydata_conformance_tests!(super::scratch_db);
+
+    fn populate(db: &mut JournaledMemoryDB) -> (BasicUser, Session, Participant) {
+        let new_user = BasicUser::new();
+        db.add_user(new_user.clone()).unwrap();
+        let my_session = Session::new();
+        db.add_session(my_session.clone()).unwrap();
+        db.add_admin(new_user.user_id.clone(), my_session.session_id.clone()).unwrap();
+        let u = Participant::new(&new_user, my_session.session_id.clone(), Nickname::new("bob"));
+        db.add_participant(u.clone()).unwrap();
+        (new_user, my_session, u)
+    }
+
+    #[test]
+    fn restore_from_journal() {
+        let dir = TempDir::new();
+        let (new_user, my_session, u) = {
+            let mut db = JournaledMemoryDB::open(&dir.0, FsyncPolicy::Always).unwrap();
+            populate(&mut db)
+        };
+
+        let db = JournaledMemoryDB::open(&dir.0, FsyncPolicy::Always).unwrap();
+        assert_eq!(db.get_user(&new_user.user_id).unwrap(), Some(new_user.clone()));
+        assert_eq!(db.get_session(&my_session.session_id).unwrap(), Some(my_session.clone()));
+        assert_eq!(db.get_participants(&my_session.session_id).unwrap(), vec![u]);
+        assert!(db.is_admin(&my_session.session_id, &new_user.user_id).unwrap());
+    }
+
+    #[test]
+    fn durable_db_recovers_from_a_panic() {
+        let dir = TempDir::new();
+        let db = DurableMemoryDB::open(&dir.0, FsyncPolicy::Never, Duration::from_secs(60));
+        let shared = Arc::new(db.unwrap());
+        let my_session = Session::new();
+        shared.get_story_data().unwrap().add_session(my_session.clone()).unwrap();
+        let panicker = shared.clone();
+        let session_id = my_session.session_id.clone();
+        let outcome = thread::spawn(move || {
+                panicker.get_story_data()
+                    .unwrap()
+                    .update_session(&session_id, |_, _| panic!("poison the lock"))
+            })
+            .join();
+        assert!(outcome.is_err());
+
+        let mut dal = shared.get_story_data().unwrap();
+        dal.update_session(&my_session.session_id, |s, _| {
+                s.average = Some(2.0);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(dal.get_session(&my_session.session_id).unwrap().unwrap().average,
+                   Some(2.0));
+    }
+
+    #[test]
+    fn restore_updates_from_journal() {
+        let dir = TempDir::new();
+        let (new_user, my_session) = {
+            let mut db = JournaledMemoryDB::open(&dir.0, FsyncPolicy::Never).unwrap();
+            let (new_user, my_session, _) = populate(&mut db);
+            db.update_participant(&my_session.session_id, &new_user.user_id, |p| {
+                    p.vote(8);
+                    Ok(())
+                })
+                .unwrap();
+            db.update_session(&my_session.session_id, |s, p| {
+                    s.take_votes(p);
+                    Ok(())
+                })
+                .unwrap();
+            (new_user, my_session)
+        };
+
+        let db = JournaledMemoryDB::open(&dir.0, FsyncPolicy::Never).unwrap();
+        let session = db.get_session(&my_session.session_id).unwrap().unwrap();
+        assert_eq!(session.average, Some(8.0));
+        let participants = db.get_participants(&my_session.session_id).unwrap();
+        assert_eq!(participants[0].user_id, new_user.user_id);
+        assert_eq!(participants[0].vote, ::estimates::vote::VoteState::Visible(8));
+    }
+
+    #[test]
+    fn snapshot_empties_journal() {
+        let dir = TempDir::new();
+        let mut db = JournaledMemoryDB::open(&dir.0, FsyncPolicy::Always).unwrap();
+        let (new_user, _, _) = populate(&mut db);
+        assert!(fs::metadata(dir.0.join(JOURNAL_FILE)).unwrap().len() > 0);
+
+        db.snapshot().unwrap();
+        assert_eq!(fs::metadata(dir.0.join(JOURNAL_FILE)).unwrap().len(), 0);
+        drop(db);
+
+        let db = JournaledMemoryDB::open(&dir.0, FsyncPolicy::Always).unwrap();
+        assert_eq!(db.get_user(&new_user.user_id).unwrap(), Some(new_user));
+    }
+
+    #[test]
+    fn journal_left_behind_by_a_snapshot_is_skipped() {
+        let dir = TempDir::new();
+        let stale_journal = dir.0.join("stale.jsonl");
+        let my_session = {
+            let mut db = JournaledMemoryDB::open(&dir.0, FsyncPolicy::Always).unwrap();
+            let (new_user, my_session, _) = populate(&mut db);
+            db.add_audit_entry(AuditEntry::new(my_session.session_id.clone(),
+                                                new_user.user_id,
+                                                AuditAction::UpdatePolicy))
+                .unwrap();
+            fs::copy(dir.0.join(JOURNAL_FILE), &stale_journal).unwrap();
+            db.snapshot().unwrap();
+            my_session
+        };
+        // As if the process died after writing the snapshot, before emptying the journal
+        fs::copy(&stale_journal, dir.0.join(JOURNAL_FILE)).unwrap();
+
+        let db = JournaledMemoryDB::open(&dir.0, FsyncPolicy::Always).unwrap();
+        assert_eq!(db.get_audit_log(&my_session.session_id).unwrap().len(), 1);
+        assert_eq!(db.get_participants(&my_session.session_id).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn restore_activity_and_expiry_from_journal() {
+        let dir = TempDir::new();
+        let expiry = ExpiryConfig::default();
+        let (stale_user, my_session, last_activity) = {
+            let mut db = JournaledMemoryDB::open(&dir.0, FsyncPolicy::Never).unwrap();
+            let (new_user, my_session, _) = populate(&mut db);
+            let last_activity = db.get_session(&my_session.session_id)
+                .unwrap()
+                .unwrap()
+                .last_activity;
+            db.touch_user(&new_user.user_id).unwrap();
+
+            let mut stale_user = BasicUser::new();
+            stale_user.last_activity = 0;
+            db.add_user(stale_user.clone()).unwrap();
+            assert_eq!(db.purge_expired(&expiry, last_activity).unwrap(), 1);
+            (stale_user, my_session, last_activity)
+        };
+
+        let db = JournaledMemoryDB::open(&dir.0, FsyncPolicy::Never).unwrap();
+        assert_eq!(db.get_user(&stale_user.user_id).unwrap(), None);
+        let session = db.get_session(&my_session.session_id).unwrap().unwrap();
+        assert_eq!(session.last_activity, last_activity);
+    }
+
+    #[test]
+    fn replay_skips_torn_and_repeated_entries() {
+        let mut memdal = MemoryDB::new();
+        let new_user = BasicUser::new();
+        let my_session = Session::new();
+        let mut journal = Vec::new();
+        for entry in vec![JournalEntry::AddUser(new_user.clone()),
+                          JournalEntry::AddSession(my_session.clone()),
+                          JournalEntry::AddAdmin(new_user.user_id.clone(),
+                                                 my_session.session_id.clone()),
+                          JournalEntry::AddAdmin(new_user.user_id.clone(),
+                                                 my_session.session_id.clone())] {
+            journal.extend(serde_json::to_vec(&entry).unwrap());
+            journal.push(b'\n');
+        }
+        journal.extend_from_slice(b"{\"DelSession\":");
+
+        replay(&mut memdal, Cursor::new(journal), 0).unwrap();
+        assert_eq!(memdal.get_user(&new_user.user_id).unwrap(), Some(new_user.clone()));
+        assert_eq!(memdal.get_session(&my_session.session_id).unwrap(), Some(my_session));
+        assert_eq!(memdal.get_admins(&my_session.session_id).unwrap(),
+                   vec![new_user.user_id]);
+    }
+}
@@ -0,0 +1,59 @@
+use super::StoryData;
+use config::ExpiryConfig;
+use errors::*;
+use service::admin;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use util::clock;
+
+/// Whether something last used at `last_activity` has outlived `ttl` as of `now`
+pub fn has_expired(ttl: Option<Duration>, last_activity: u64, now: u64) -> bool {
+    match ttl {
+        Some(ttl) => last_activity.saturating_add(ttl.as_secs()) <= now,
+        None => false,
+    }
+}
+
+/// Expire sessions and users in `db` every `sweep_interval`, see `service::admin::expire`
+pub fn start_sweeper<D>(db: Arc<Mutex<D>>, expiry: ExpiryConfig)
+    where D: StoryData + Send + 'static
+{
+    sweep_with(expiry, move |expiry, now| match db.lock() {
+        Ok(mut locked) => admin::expire(&mut *locked, expiry, now),
+        Err(_) => {
+            bail!(ErrorKind::BackendUnavailable("The database lock was poisoned".to_string()))
+        },
+    })
+}
+
+/// Call `purge` every `sweep_interval`, for backends that don't sit behind a single lock
+pub fn sweep_with<F>(expiry: ExpiryConfig, mut purge: F)
+    where F: FnMut(&ExpiryConfig, u64) -> Result<usize> + Send + 'static
+{
+    if expiry.session_ttl.is_none() && expiry.user_ttl.is_none() {
+        return;
+    }
+    thread::spawn(move || loop {
+        thread::sleep(expiry.sweep_interval);
+        match purge(&expiry, clock::now()) {
+            Ok(0) => (),
+            Ok(n) => info!("Removed {} expired sessions and users", n),
+            Err(e) => warn!("Could not remove expired sessions and users: {}", e),
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn expiry_boundaries() {
+        let ttl = Some(Duration::from_secs(60));
+        assert!(!has_expired(ttl, 1000, 1059));
+        assert!(has_expired(ttl, 1000, 1060));
+        assert!(!has_expired(None, 0, u64::max_value()));
+        assert!(!has_expired(ttl, u64::max_value(), u64::max_value() - 1));
+    }
+}
@@ -0,0 +1,801 @@
+//! A small Redis stand-in for tests
+//!
+//! It speaks enough of the Redis protocol for `RedisDB` to run against it,
+//! keeping every database in memory, so the Redis backend can be tested
+//! without a Redis server or network access. Besides strings, sets and lists
+//! it understands key expiry, `MULTI`/`EXEC` transactions with `WATCH`, and
+//! publish/subscribe.
+
+use r2d2;
+use r2d2_redis::RedisConnectionManager;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::str;
+use std::sync::{Arc, Mutex, ONCE_INIT, Once};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const WRONGTYPE: &'static str = "WRONGTYPE Operation against a key holding the wrong kind of \
+                                 value";
+
+enum Value {
+    Str(Vec<u8>),
+    Set(BTreeSet<Vec<u8>>),
+    List(Vec<Vec<u8>>),
+}
+
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Int(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+/// Where a connection's replies and published messages are written
+type Writer = Arc<Mutex<TcpStream>>;
+
+struct Database {
+    values: BTreeMap<Vec<u8>, Value>,
+    expiry: BTreeMap<Vec<u8>, Instant>,
+}
+
+/// Everything the server holds, shared by all connections
+struct Store {
+    databases: BTreeMap<i64, Database>,
+    channels: BTreeMap<Vec<u8>, BTreeMap<usize, Writer>>,
+}
+
+/// The state one client connection carries between commands
+struct Client {
+    id: usize,
+    db: i64,
+    transaction: Option<Vec<Vec<Vec<u8>>>>,
+    transaction_failed: bool,
+    /// Keys passed to `WATCH`, with what they held at the time
+    watched: Vec<(i64, Vec<u8>, Option<Vec<u8>>)>,
+    channels: BTreeSet<Vec<u8>>,
+    writer: Writer,
+}
+
+/// A fake Redis server listening on an ephemeral localhost port
+pub struct FakeRedis {
+    addr: SocketAddr,
+    next_db: AtomicUsize,
+}
+
+impl FakeRedis {
+    /// Start a server with no data, serving each connection on its own thread
+    pub fn start() -> io::Result<FakeRedis> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let store = Arc::new(Mutex::new(Store {
+            databases: BTreeMap::new(),
+            channels: BTreeMap::new(),
+        }));
+        thread::spawn(move || {
+            for (id, stream) in listener.incoming().enumerate() {
+                match stream {
+                    Ok(stream) => {
+                        let store = store.clone();
+                        thread::spawn(move || serve(stream, store, id));
+                    },
+                    Err(_) => break,
+                }
+            }
+        });
+        Ok(FakeRedis {
+            addr: addr,
+            next_db: AtomicUsize::new(1),
+        })
+    }
+
+    /// The URL of one of the server's databases
+    pub fn url(&self, db: usize) -> String {
+        format!("redis://{}/{}", self.addr, db)
+    }
+
+    /// The URL of a database nobody else has been given
+    pub fn fresh_url(&self) -> String {
+        self.url(self.next_db.fetch_add(1, Ordering::SeqCst))
+    }
+
+    /// A pool of connections to a database nobody else has been given
+    pub fn fresh_pool(&self) -> r2d2::Pool<RedisConnectionManager> {
+        let manager = RedisConnectionManager::new(self.fresh_url().as_str()).unwrap();
+        let config = r2d2::Config::builder().pool_size(1).build();
+        r2d2::Pool::new(config, manager).unwrap()
+    }
+}
+
+/// A server shared by every test in the process
+///
+/// Tests stay independent by each taking a fresh database from it.
+pub fn shared() -> &'static FakeRedis {
+    static START: Once = ONCE_INIT;
+    static mut SHARED: *const FakeRedis = 0 as *const FakeRedis;
+    unsafe {
+        START.call_once(|| {
+            SHARED = Box::into_raw(Box::new(FakeRedis::start().unwrap()));
+        });
+        &*SHARED
+    }
+}
+
+fn serve(stream: TcpStream, store: Arc<Mutex<Store>>, id: usize) {
+    let mut reader = BufReader::new(match stream.try_clone() {
+        Ok(s) => s,
+        Err(_) => return,
+    });
+    let mut client = Client {
+        id: id,
+        db: 0,
+        transaction: None,
+        transaction_failed: false,
+        watched: Vec::new(),
+        channels: BTreeSet::new(),
+        writer: Arc::new(Mutex::new(stream)),
+    };
+    while let Ok(Some(args)) = read_command(&mut reader) {
+        // Replies are written with the store locked, so they can't be
+        // interleaved with messages published by other connections
+        let mut locked = store.lock().unwrap();
+        let replies = client.handle(&mut locked, args);
+        if send(&client.writer, &replies).is_err() {
+            break;
+        }
+    }
+    let mut locked = store.lock().unwrap();
+    client.unsubscribe_all(&mut locked);
+}
+
+impl Database {
+    fn new() -> Self {
+        Database {
+            values: BTreeMap::new(),
+            expiry: BTreeMap::new(),
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) -> bool {
+        self.expiry.remove(key);
+        self.values.remove(key).is_some()
+    }
+
+    /// What the key holds, as bytes that only compare equal for equal values
+    fn fingerprint(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.purge_expired(Instant::now());
+        self.values.get(key).map(|value| match *value {
+            Value::Str(ref data) => {
+                let mut fingerprint = vec![b's'];
+                fingerprint.extend_from_slice(data);
+                fingerprint
+            },
+            Value::Set(ref members) => {
+                let mut fingerprint = vec![b'S'];
+                for member in members {
+                    fingerprint.extend_from_slice(format!("{}:", member.len()).as_bytes());
+                    fingerprint.extend_from_slice(member);
+                }
+                fingerprint
+            },
+            Value::List(ref items) => {
+                let mut fingerprint = vec![b'L'];
+                for item in items {
+                    fingerprint.extend_from_slice(format!("{}:", item.len()).as_bytes());
+                    fingerprint.extend_from_slice(item);
+                }
+                fingerprint
+            },
+        })
+    }
+
+    fn purge_expired(&mut self, now: Instant) {
+        let expired: Vec<Vec<u8>> = self.expiry
+            .iter()
+            .filter(|&(_, at)| *at <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.remove(&key);
+        }
+    }
+}
+
+impl Client {
+    fn handle(&mut self, store: &mut Store, args: Vec<Vec<u8>>) -> Vec<Reply> {
+        let name = match args.first() {
+            Some(name) => String::from_utf8_lossy(name).to_uppercase(),
+            None => return vec![Reply::Error("ERR empty command".to_string())],
+        };
+        if let Err(reply) = check_arity(&name, args.len()) {
+            if self.transaction.is_some() {
+                self.transaction_failed = true;
+            }
+            return vec![reply];
+        }
+
+        match name.as_str() {
+            "MULTI" => {
+                if self.transaction.is_some() {
+                    return vec![Reply::Error("ERR MULTI calls can not be nested".to_string())];
+                }
+                self.transaction = Some(Vec::new());
+                self.transaction_failed = false;
+                vec![Reply::Status("OK")]
+            },
+            "EXEC" => {
+                let watched_changed = self.watched_changed(store);
+                match self.transaction.take() {
+                    None => vec![Reply::Error("ERR EXEC without MULTI".to_string())],
+                    Some(_) if self.transaction_failed => {
+                        vec![Reply::Error("EXECABORT Transaction discarded because of previous \
+                                           errors."
+                            .to_string())]
+                    },
+                    Some(_) if watched_changed => vec![Reply::Bulk(None)],
+                    Some(queued) => {
+                        let db = &mut self.db;
+                        vec![Reply::Array(queued.into_iter()
+                                 .map(|args| execute(store, db, args))
+                                 .collect())]
+                    },
+                }
+            },
+            "DISCARD" => {
+                self.watched.clear();
+                match self.transaction.take() {
+                    None => vec![Reply::Error("ERR DISCARD without MULTI".to_string())],
+                    Some(_) => vec![Reply::Status("OK")],
+                }
+            },
+            "WATCH" if self.transaction.is_some() => {
+                vec![Reply::Error("ERR WATCH inside MULTI is not allowed".to_string())]
+            },
+            "WATCH" => {
+                for key in &args[1..] {
+                    let fingerprint = database(store, self.db).fingerprint(key);
+                    self.watched.push((self.db, key.clone(), fingerprint));
+                }
+                vec![Reply::Status("OK")]
+            },
+            "UNWATCH" => {
+                self.watched.clear();
+                vec![Reply::Status("OK")]
+            },
+            _ if self.transaction.is_some() => {
+                if let Some(ref mut queued) = self.transaction {
+                    queued.push(args);
+                }
+                vec![Reply::Status("QUEUED")]
+            },
+            "SUBSCRIBE" => {
+                args[1..]
+                    .iter()
+                    .map(|channel| {
+                        store.channels
+                            .entry(channel.clone())
+                            .or_insert(BTreeMap::new())
+                            .insert(self.id, self.writer.clone());
+                        self.channels.insert(channel.clone());
+                        subscription_reply("subscribe", Some(channel), self.channels.len())
+                    })
+                    .collect()
+            },
+            "UNSUBSCRIBE" => {
+                let channels: Vec<Vec<u8>> = if args.len() > 1 {
+                    args[1..].to_vec()
+                } else {
+                    self.channels.iter().cloned().collect()
+                };
+                if channels.is_empty() {
+                    return vec![subscription_reply("unsubscribe", None, 0)];
+                }
+                channels.iter()
+                    .map(|channel| {
+                        self.unsubscribe(store, channel);
+                        subscription_reply("unsubscribe", Some(channel), self.channels.len())
+                    })
+                    .collect()
+            },
+            _ => vec![execute(store, &mut self.db, args)],
+        }
+    }
+
+    /// Whether any watched key changed since `WATCH`, which also stops watching them
+    fn watched_changed(&mut self, store: &mut Store) -> bool {
+        let watched: Vec<_> = self.watched.drain(..).collect();
+        watched.into_iter()
+            .any(|(db, key, fingerprint)| database(store, db).fingerprint(&key) != fingerprint)
+    }
+
+    fn unsubscribe(&mut self, store: &mut Store, channel: &[u8]) {
+        self.channels.remove(channel);
+        let now_empty = match store.channels.get_mut(channel) {
+            Some(subscribers) => {
+                subscribers.remove(&self.id);
+                subscribers.is_empty()
+            },
+            None => false,
+        };
+        if now_empty {
+            store.channels.remove(channel);
+        }
+    }
+
+    fn unsubscribe_all(&mut self, store: &mut Store) {
+        let channels: Vec<Vec<u8>> = self.channels.iter().cloned().collect();
+        for channel in channels {
+            self.unsubscribe(store, &channel);
+        }
+    }
+}
+
+fn database(store: &mut Store, db: i64) -> &mut Database {
+    store.databases.entry(db).or_insert(Database::new())
+}
+
+fn subscription_reply(kind: &str, channel: Option<&Vec<u8>>, count: usize) -> Reply {
+    Reply::Array(vec![Reply::Bulk(Some(kind.as_bytes().to_vec())),
+                      Reply::Bulk(channel.cloned()),
+                      Reply::Int(count as i64)])
+}
+
+fn encode(replies: &[Reply]) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    for reply in replies {
+        write_reply(&mut buffer, reply)?;
+    }
+    Ok(buffer)
+}
+
+fn send(writer: &Writer, replies: &[Reply]) -> io::Result<()> {
+    let buffer = encode(replies)?;
+    writer.lock().unwrap().write_all(&buffer)
+}
+
+fn invalid_input(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Read one line, without its trailing CRLF, or `None` at the end of the stream
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    while line.last() == Some(&b'\n') || line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_int(arg: &[u8]) -> Option<i64> {
+    str::from_utf8(arg).ok().and_then(|n| n.parse().ok())
+}
+
+fn parse_length(header: &[u8], marker: u8) -> io::Result<usize> {
+    if header.first() != Some(&marker) {
+        return Err(invalid_input("unexpected type marker"));
+    }
+    match parse_int(&header[1..]) {
+        Some(n) if n >= 0 => Ok(n as usize),
+        _ => Err(invalid_input("bad length")),
+    }
+}
+
+/// Read a command sent as an array of bulk strings
+fn read_command<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let count = match read_line(reader)? {
+        Some(header) => parse_length(&header, b'*')?,
+        None => return Ok(None),
+    };
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let len = match read_line(reader)? {
+            Some(header) => parse_length(&header, b'$')?,
+            None => return Err(invalid_input("truncated command")),
+        };
+        let mut data = vec![0; len + 2];
+        reader.read_exact(&mut data)?;
+        data.truncate(len);
+        args.push(data);
+    }
+    Ok(Some(args))
+}
+
+fn write_reply<W: Write>(writer: &mut W, reply: &Reply) -> io::Result<()> {
+    match *reply {
+        Reply::Status(status) => write!(writer, "+{}\r\n", status),
+        Reply::Error(ref message) => write!(writer, "-{}\r\n", message),
+        Reply::Int(n) => write!(writer, ":{}\r\n", n),
+        Reply::Bulk(None) => write!(writer, "$-1\r\n"),
+        Reply::Bulk(Some(ref data)) => {
+            write!(writer, "${}\r\n", data.len())?;
+            writer.write_all(data)?;
+            write!(writer, "\r\n")
+        },
+        Reply::Array(ref replies) => {
+            write!(writer, "*{}\r\n", replies.len())?;
+            for reply in replies {
+                write_reply(writer, reply)?;
+            }
+            Ok(())
+        },
+    }
+}
+
+/// Match a key against a `KEYS` pattern, supporting `*` and `?`
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    match (pattern.first(), key.first()) {
+        (None, None) => true,
+        (Some(&b'*'), _) => {
+            glob_match(&pattern[1..], key) || (!key.is_empty() && glob_match(pattern, &key[1..]))
+        },
+        (Some(&b'?'), Some(_)) => glob_match(&pattern[1..], &key[1..]),
+        (Some(p), Some(k)) if p == k => glob_match(&pattern[1..], &key[1..]),
+        _ => false,
+    }
+}
+
+fn bulk_array<'a, I>(items: I) -> Reply
+    where I: Iterator<Item = &'a Vec<u8>>
+{
+    Reply::Array(items.map(|item| Reply::Bulk(Some(item.clone()))).collect())
+}
+
+fn check_arity(name: &str, argc: usize) -> ::std::result::Result<(), Reply> {
+    let arity_ok = match name {
+        "PING" | "FLUSHDB" | "MULTI" | "EXEC" | "DISCARD" | "UNWATCH" => argc == 1,
+        "SELECT" | "GET" | "KEYS" | "SMEMBERS" | "TTL" | "PERSIST" => argc == 2,
+        "SET" | "SISMEMBER" | "EXPIRE" | "PUBLISH" => argc == 3,
+        "LRANGE" => argc == 4,
+        "DEL" | "EXISTS" | "SUBSCRIBE" | "WATCH" => argc >= 2,
+        "SADD" | "SREM" | "RPUSH" => argc >= 3,
+        "UNSUBSCRIBE" => argc >= 1,
+        _ => return Err(Reply::Error(format!("ERR unknown command '{}'", name))),
+    };
+    if arity_ok {
+        Ok(())
+    } else {
+        Err(Reply::Error(format!("ERR wrong number of arguments for '{}' command",
+                                 name.to_lowercase())))
+    }
+}
+
+/// Run a command that only touches the store, not the connection
+fn execute(store: &mut Store, db: &mut i64, args: Vec<Vec<u8>>) -> Reply {
+    let name = String::from_utf8_lossy(&args[0]).to_uppercase();
+    match name.as_str() {
+        "SELECT" => {
+            return match parse_int(&args[1]) {
+                Some(n) => {
+                    *db = n;
+                    Reply::Status("OK")
+                },
+                None => Reply::Error("ERR invalid DB index".to_string()),
+            };
+        },
+        "PUBLISH" => {
+            let message = vec![Reply::Array(vec![Reply::Bulk(Some(b"message".to_vec())),
+                                                 Reply::Bulk(Some(args[1].clone())),
+                                                 Reply::Bulk(Some(args[2].clone()))])];
+            let delivered = match (store.channels.get(&args[1]), encode(&message)) {
+                (Some(subscribers), Ok(bytes)) => {
+                    subscribers.values()
+                        .filter(|writer| writer.lock().unwrap().write_all(&bytes).is_ok())
+                        .count()
+                },
+                _ => 0,
+            };
+            return Reply::Int(delivered as i64);
+        },
+        _ => (),
+    }
+
+    let now = Instant::now();
+    let data = database(store, *db);
+    data.purge_expired(now);
+    match name.as_str() {
+        "PING" => Reply::Status("PONG"),
+        "FLUSHDB" => {
+            *data = Database::new();
+            Reply::Status("OK")
+        },
+        "GET" => {
+            match data.values.get(&args[1]) {
+                Some(&Value::Str(ref value)) => Reply::Bulk(Some(value.clone())),
+                Some(_) => Reply::Error(WRONGTYPE.to_string()),
+                None => Reply::Bulk(None),
+            }
+        },
+        "SET" => {
+            data.remove(&args[1]);
+            data.values.insert(args[1].clone(), Value::Str(args[2].clone()));
+            Reply::Status("OK")
+        },
+        "DEL" => {
+            let deleted = args[1..].iter().filter(|key| data.remove(key)).count();
+            Reply::Int(deleted as i64)
+        },
+        "EXISTS" => {
+            let found = args[1..].iter().filter(|key| data.values.contains_key(*key)).count();
+            Reply::Int(found as i64)
+        },
+        "KEYS" => bulk_array(data.values.keys().filter(|key| glob_match(&args[1], key))),
+        "EXPIRE" => {
+            match parse_int(&args[2]) {
+                None => Reply::Error("ERR value is not an integer or out of range".to_string()),
+                Some(_) if !data.values.contains_key(&args[1]) => Reply::Int(0),
+                Some(seconds) if seconds <= 0 => {
+                    data.remove(&args[1]);
+                    Reply::Int(1)
+                },
+                Some(seconds) => {
+                    data.expiry.insert(args[1].clone(), now + Duration::from_secs(seconds as u64));
+                    Reply::Int(1)
+                },
+            }
+        },
+        "TTL" => {
+            if !data.values.contains_key(&args[1]) {
+                return Reply::Int(-2);
+            }
+            match data.expiry.get(&args[1]) {
+                Some(at) => {
+                    let left = *at - now;
+                    let rounding = if left.subsec_nanos() > 0 { 1 } else { 0 };
+                    Reply::Int((left.as_secs() + rounding) as i64)
+                },
+                None => Reply::Int(-1),
+            }
+        },
+        "PERSIST" => Reply::Int(data.expiry.remove(&args[1]).is_some() as i64),
+        "SMEMBERS" => {
+            match data.values.get(&args[1]) {
+                Some(&Value::Set(ref members)) => bulk_array(members.iter()),
+                Some(_) => Reply::Error(WRONGTYPE.to_string()),
+                None => Reply::Array(Vec::new()),
+            }
+        },
+        "SISMEMBER" => {
+            match data.values.get(&args[1]) {
+                Some(&Value::Set(ref members)) => Reply::Int(members.contains(&args[2]) as i64),
+                Some(_) => Reply::Error(WRONGTYPE.to_string()),
+                None => Reply::Int(0),
+            }
+        },
+        "SADD" => {
+            let entry = data.values.entry(args[1].clone()).or_insert(Value::Set(BTreeSet::new()));
+            match *entry {
+                Value::Set(ref mut members) => {
+                    let added = args[2..].iter().filter(|m| members.insert((*m).clone())).count();
+                    Reply::Int(added as i64)
+                },
+                _ => Reply::Error(WRONGTYPE.to_string()),
+            }
+        },
+        "SREM" => {
+            let (removed, now_empty) = match data.values.get_mut(&args[1]) {
+                Some(&mut Value::Set(ref mut members)) => {
+                    let removed = args[2..].iter().filter(|m| members.remove(*m)).count();
+                    (removed, members.is_empty())
+                },
+                Some(_) => return Reply::Error(WRONGTYPE.to_string()),
+                None => (0, false),
+            };
+            if now_empty {
+                data.remove(&args[1]);
+            }
+            Reply::Int(removed as i64)
+        },
+        "RPUSH" => {
+            let entry = data.values.entry(args[1].clone()).or_insert(Value::List(Vec::new()));
+            match *entry {
+                Value::List(ref mut items) => {
+                    items.extend(args[2..].iter().cloned());
+                    Reply::Int(items.len() as i64)
+                },
+                _ => Reply::Error(WRONGTYPE.to_string()),
+            }
+        },
+        "LRANGE" => {
+            let (start, stop) = match (parse_int(&args[2]), parse_int(&args[3])) {
+                (Some(start), Some(stop)) => (start, stop),
+                _ => {
+                    return Reply::Error("ERR value is not an integer or out of range".to_string())
+                },
+            };
+            match data.values.get(&args[1]) {
+                Some(&Value::List(ref items)) => {
+                    // Negative indexes count back from the end
+                    let len = items.len() as i64;
+                    let start = (if start < 0 { len + start } else { start }).max(0);
+                    let stop = (if stop < 0 { len + stop } else { stop }).min(len - 1);
+                    if start > stop {
+                        Reply::Array(Vec::new())
+                    } else {
+                        bulk_array(items[start as usize..(stop + 1) as usize].iter())
+                    }
+                },
+                Some(_) => Reply::Error(WRONGTYPE.to_string()),
+                None => Reply::Array(Vec::new()),
+            }
+        },
+        _ => Reply::Error(format!("ERR unknown command '{}'", name)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use redis::{self, Commands};
+
+    fn connect() -> redis::Connection {
+        let url = shared().fresh_url();
+        redis::Client::open(url.as_str()).unwrap().get_connection().unwrap()
+    }
+
+    #[test]
+    fn strings_and_sets() {
+        let conn = connect();
+        let _: () = conn.set("greeting", "hello").unwrap();
+        let greeting: String = conn.get("greeting").unwrap();
+        assert_eq!(greeting, "hello");
+        let missing: Option<String> = conn.get("nothing").unwrap();
+        assert_eq!(missing, None);
+
+        let added: i64 = conn.sadd("letters", vec!["a", "b", "a"]).unwrap();
+        assert_eq!(added, 2);
+        let members: Vec<String> = conn.smembers("letters").unwrap();
+        assert_eq!(members, vec!["a".to_string(), "b".to_string()]);
+        let removed: i64 = conn.srem("letters", "a").unwrap();
+        assert_eq!(removed, 1);
+        let is_member: bool = conn.sismember("letters", "a").unwrap();
+        assert!(!is_member);
+
+        let keys: Vec<String> = conn.keys("gr?et*").unwrap();
+        assert_eq!(keys, vec!["greeting".to_string()]);
+        let deleted: i64 = conn.del(vec!["greeting", "letters", "nothing"]).unwrap();
+        assert_eq!(deleted, 2);
+    }
+
+    #[test]
+    fn lists() {
+        let conn = connect();
+        let length: i64 = conn.rpush("letters", "a").unwrap();
+        assert_eq!(length, 1);
+        let length: i64 = conn.rpush("letters", vec!["b", "c"]).unwrap();
+        assert_eq!(length, 3);
+        let all: Vec<String> = conn.lrange("letters", 0, -1).unwrap();
+        assert_eq!(all, vec!["a".to_string(), "b".to_string(), "c".to_string()]);
+        let last_two: Vec<String> = conn.lrange("letters", -2, 10).unwrap();
+        assert_eq!(last_two, vec!["b".to_string(), "c".to_string()]);
+        let none: Vec<String> = conn.lrange("nothing", 0, -1).unwrap();
+        assert_eq!(none, Vec::<String>::new());
+        let outcome: redis::RedisResult<Vec<String>> = conn.smembers("letters");
+        assert!(outcome.is_err());
+    }
+
+    #[test]
+    fn databases_are_separate() {
+        let first = connect();
+        let second = connect();
+        let _: () = first.set("key", "value").unwrap();
+        let exists: bool = second.exists("key").unwrap();
+        assert!(!exists);
+    }
+
+    #[test]
+    fn wrong_type_is_an_error() {
+        let conn = connect();
+        let _: () = conn.set("key", "value").unwrap();
+        let outcome: redis::RedisResult<Vec<String>> = conn.smembers("key");
+        assert!(outcome.is_err());
+    }
+
+    #[test]
+    fn keys_expire() {
+        let conn = connect();
+        let _: () = conn.set("short", "lived").unwrap();
+        let _: () = conn.set("long", "lived").unwrap();
+        let _: () = conn.set("forever", "lived").unwrap();
+        let set: bool = conn.expire("short", 1).unwrap();
+        assert!(set);
+        let _: () = conn.expire("long", 100).unwrap();
+        let missing: bool = conn.expire("nothing", 100).unwrap();
+        assert!(!missing);
+
+        let ttl: i64 = conn.ttl("long").unwrap();
+        assert!(ttl > 98 && ttl <= 100);
+        let ttl: i64 = conn.ttl("forever").unwrap();
+        assert_eq!(ttl, -1);
+
+        thread::sleep(Duration::from_millis(1100));
+        let expired: Option<String> = conn.get("short").unwrap();
+        assert_eq!(expired, None);
+        let ttl: i64 = conn.ttl("short").unwrap();
+        assert_eq!(ttl, -2);
+
+        let _: () = conn.set("long", "reset").unwrap();
+        let ttl: i64 = conn.ttl("long").unwrap();
+        assert_eq!(ttl, -1);
+    }
+
+    #[test]
+    fn transactions_run_together() {
+        let conn = connect();
+        let (added, members): (i64, Vec<String>) = redis::pipe()
+            .atomic()
+            .cmd("SADD")
+            .arg("letters")
+            .arg("a")
+            .cmd("SMEMBERS")
+            .arg("letters")
+            .query(&conn)
+            .unwrap();
+        assert_eq!(added, 1);
+        assert_eq!(members, vec!["a".to_string()]);
+    }
+
+    #[test]
+    fn failed_transactions_are_discarded() {
+        let conn = connect();
+        let _: () = redis::cmd("MULTI").query(&conn).unwrap();
+        let _: () = redis::cmd("SET").arg("key").arg("value").query(&conn).unwrap();
+        let bad: redis::RedisResult<()> = redis::cmd("GET").query(&conn);
+        assert!(bad.is_err());
+        let outcome: redis::RedisResult<()> = redis::cmd("EXEC").query(&conn);
+        assert!(outcome.is_err());
+        let exists: bool = conn.exists("key").unwrap();
+        assert!(!exists);
+    }
+
+    #[test]
+    fn watched_changes_abort_transactions() {
+        let client = redis::Client::open(shared().fresh_url().as_str()).unwrap();
+        let conn = client.get_connection().unwrap();
+        let other = client.get_connection().unwrap();
+        let _: () = conn.sadd("letters", "a").unwrap();
+
+        let _: () = redis::cmd("WATCH").arg("letters").query(&conn).unwrap();
+        let _: () = other.sadd("letters", "b").unwrap();
+        let aborted: Option<(i64,)> = redis::pipe()
+            .atomic()
+            .cmd("DEL")
+            .arg("letters")
+            .query(&conn)
+            .unwrap();
+        assert_eq!(aborted, None);
+
+        let _: () = redis::cmd("WATCH").arg("letters").query(&conn).unwrap();
+        let deleted: Option<(i64,)> = redis::pipe()
+            .atomic()
+            .cmd("DEL")
+            .arg("letters")
+            .query(&conn)
+            .unwrap();
+        assert_eq!(deleted, Some((1,)));
+    }
+
+    #[test]
+    fn publish_to_subscribers() {
+        let fake = FakeRedis::start().unwrap();
+        let client = redis::Client::open(fake.url(0).as_str()).unwrap();
+        let mut pubsub = client.get_pubsub().unwrap();
+        pubsub.subscribe("updates").unwrap();
+
+        let publisher = client.get_connection().unwrap();
+        let received: i64 = publisher.publish("updates", "hello").unwrap();
+        assert_eq!(received, 1);
+        let unheard: i64 = publisher.publish("elsewhere", "hello").unwrap();
+        assert_eq!(unheard, 0);
+
+        let message = pubsub.get_message().unwrap();
+        assert_eq!(message.get_channel_name(), "updates");
+        let payload: String = message.get_payload().unwrap();
+        assert_eq!(payload, "hello");
+    }
+}
@@ -1,9 +1,13 @@
 use super::StoryData;
+use super::expiry::has_expired;
+use config::ExpiryConfig;
 use errors::*;
+use estimates::audit::AuditEntry;
 use estimates::participant::Participant;
 use estimates::session::{Session, SessionID};
 use std::collections::BTreeMap;
 use user::{BasicUser, User, UserID};
+use util::clock;
 
 /// An in memory database of story entities
 /// Designed for testing, not production
@@ -13,6 +17,27 @@ pub struct MemoryDB {
     sessions: BTreeMap<SessionID, Session>,
     participants: BTreeMap<SessionID, Vec<Participant>>,
     admins: BTreeMap<SessionID, Vec<UserID>>,
+    audit: BTreeMap<SessionID, Vec<AuditEntry>>,
+    tombstones: Vec<AuditEntry>,
+}
+
+/// A copy of every record in a `MemoryDB`, in a form that can be saved to disk
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemorySnapshot {
+    users: Vec<BasicUser>,
+    sessions: Vec<Session>,
+    participants: Vec<Participant>,
+    admins: Vec<(SessionID, UserID)>,
+    /// Missing from snapshots taken before there were audit logs
+    #[serde(default)]
+    audit: Vec<AuditEntry>,
+    /// Missing from snapshots taken before sessions left tombstones
+    #[serde(default)]
+    tombstones: Vec<AuditEntry>,
+    /// Which journal carries on from the snapshot, see `JournaledMemoryDB`
+    #[serde(default)]
+    pub journal_generation: u64,
 }
 
 impl MemoryDB {
@@ -22,7 +47,95 @@ impl MemoryDB {
             sessions: BTreeMap::new(),
             participants: BTreeMap::new(),
             admins: BTreeMap::new(),
+            audit: BTreeMap::new(),
+            tombstones: Vec::new(),
+        }
+    }
+
+    pub fn snapshot(&self) -> MemorySnapshot {
+        MemorySnapshot {
+            users: self.users.values().map(|u| u.clone()).collect(),
+            sessions: self.sessions.values().map(|s| s.clone()).collect(),
+            participants: self.participants
+                .values()
+                .flat_map(|participants| participants.iter().map(|p| p.clone()))
+                .collect(),
+            admins: self.admins
+                .iter()
+                .flat_map(|(session_id, admins)| {
+                    admins.iter().map(move |user_id| (session_id.clone(), user_id.clone()))
+                })
+                .collect(),
+            audit: self.audit.values().flat_map(|entries| entries.iter().cloned()).collect(),
+            tombstones: self.tombstones.clone(),
+            journal_generation: 0,
+        }
+    }
+
+    pub fn from_snapshot(snapshot: MemorySnapshot) -> Self {
+        let mut memdal = MemoryDB::new();
+        for user in snapshot.users {
+            memdal.users.insert(user.user_id.clone(), user);
         }
+        for session in snapshot.sessions {
+            memdal.sessions.insert(session.session_id.clone(), session);
+        }
+        for participant in snapshot.participants {
+            memdal.participants
+                .entry(participant.session_id.clone())
+                .or_insert(Vec::new())
+                .push(participant);
+        }
+        for (session_id, user_id) in snapshot.admins {
+            memdal.admins.entry(session_id).or_insert(Vec::new()).push(user_id);
+        }
+        for entry in snapshot.audit {
+            memdal.audit.entry(entry.session_id.clone()).or_insert(Vec::new()).push(entry);
+        }
+        memdal.tombstones = snapshot.tombstones;
+        memdal
+    }
+
+    /// Set when the session was last used, if it exists
+    pub fn set_session_activity(&mut self, session_id: &SessionID, last_activity: u64) {
+        if let Some(session) = self.sessions.get_mut(session_id) {
+            session.last_activity = last_activity;
+        }
+    }
+
+    pub fn set_user_activity(&mut self, user_id: &UserID, last_activity: u64) -> Result<()> {
+        self.users
+            .get_mut(user_id)
+            .map(|u| u.last_activity = last_activity)
+            .ok_or(ErrorKind::ObjectNotFound(format!("Could not find user: {:?}", user_id))
+                .into())
+    }
+
+    /// The sessions and users idle for longer than their TTL as of `now`
+    pub fn expired(&self, expiry: &ExpiryConfig, now: u64) -> (Vec<SessionID>, Vec<UserID>) {
+        let sessions = self.sessions
+            .values()
+            .filter(|s| has_expired(expiry.session_ttl, s.last_activity, now))
+            .map(|s| s.session_id.clone())
+            .collect();
+        let users = self.users
+            .values()
+            .filter(|u| has_expired(expiry.user_ttl, u.last_activity, now))
+            .map(|u| u.user_id.clone())
+            .collect();
+        (sessions, users)
+    }
+
+    /// Remove a session along with its participants, admins and anything else belonging to it
+    pub fn remove_session(&mut self, session_id: &SessionID) {
+        self.sessions.remove(session_id);
+        self.participants.remove(session_id);
+        self.admins.remove(session_id);
+        self.audit.remove(session_id);
+    }
+
+    pub fn remove_user(&mut self, user_id: &UserID) {
+        self.users.remove(user_id);
     }
 }
 
@@ -34,10 +147,31 @@ impl StoryData for MemoryDB {
         self.users.insert(user.user_id().clone(), user);
         Ok(())
     }
+    fn del_user(&mut self, user_id: &UserID) -> Result<()> {
+        self.users
+            .remove(user_id)
+            .ok_or(ErrorKind::ObjectNotFound(format!("Could not find user: {:?}", user_id)).into())
+            .map(|_| ())
+    }
+    fn list_users(&self) -> Result<Vec<UserID>> {
+        Ok(self.users.keys().cloned().collect())
+    }
+    fn update_user<F>(&mut self, user_id: &UserID, mut plan: F) -> Result<()>
+        where F: FnMut(&mut BasicUser) -> Result<()>
+    {
+        let mut user = self.get_user(user_id)?
+            .ok_or(ErrorKind::ObjectNotFound(format!("Could not find user: {:?}", user_id)))?;
+        plan(&mut user)?;
+        self.users.insert(user_id.clone(), user);
+        Ok(())
+    }
 
     fn get_session(&self, session_id: &SessionID) -> Result<Option<Session>> {
         Ok(self.sessions.get(session_id).map(|s| s.clone()))
     }
+    fn list_sessions(&self) -> Result<Vec<SessionID>> {
+        Ok(self.sessions.keys().cloned().collect())
+    }
     fn add_session(&mut self, session: Session) -> Result<()> {
         self.sessions.insert(session.session_id.clone(), session);
         Ok(())
@@ -45,31 +179,36 @@ impl StoryData for MemoryDB {
     fn update_session<F>(&mut self, session_id: &SessionID, mut plan: F) -> Result<()>
         where F: FnMut(&mut Session, &mut Vec<Participant>) -> Result<()>
     {
-        let mut participants = self.participants
-            .entry(session_id.clone())
-            .or_insert(Vec::new());
-        self.sessions
-            .get_mut(session_id)
-            .ok_or(ErrorKind::ObjectNotFound(format!("Could not find session: {:?}", session_id))
-                .into())
-            .and_then(|s| plan(s, &mut participants))
+        let mut session = self.sessions
+            .get(session_id)
+            .cloned()
+            .ok_or(ErrorKind::ObjectNotFound(format!("Could not find session: {:?}", session_id)))?;
+        let mut participants = self.participants.get(session_id).cloned().unwrap_or(Vec::new());
+        session.last_activity = clock::now();
+        // The plan works on copies, so if it fails or panics the stored session is left alone
+        plan(&mut session, &mut participants)?;
+        self.sessions.insert(session_id.clone(), session);
+        self.participants.insert(session_id.clone(), participants);
+        Ok(())
     }
 
     fn del_session(&mut self, session_id: &SessionID) -> Result<()> {
-        self.sessions
-            .remove(&session_id)
-            .ok_or(ErrorKind::ObjectNotFound(format!("Could not find session: {:?}", session_id))
-                .into())
-            .map(|_| ())
+        if !self.sessions.contains_key(session_id) {
+            bail!(ErrorKind::ObjectNotFound(format!("Could not find session: {:?}", session_id)));
+        }
+        self.remove_session(session_id);
+        Ok(())
     }
 
     fn get_participants(&self, session_id: &SessionID) -> Result<Vec<Participant>> {
-        Ok(self.participants
+        let mut participants: Vec<Participant> = self.participants
             .get(session_id)
             .unwrap_or(&Vec::new())
             .iter()
             .map(|p| p.clone())
-            .collect())
+            .collect();
+        participants.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+        Ok(participants)
     }
     // TODO These should get error codes
     fn add_participant(&mut self, participant: Participant) -> Result<()> {
@@ -82,7 +221,8 @@ impl StoryData for MemoryDB {
         }
 
         participants.push(participant);
-        self.participants.insert(session_id, participants);
+        self.participants.insert(session_id.clone(), participants);
+        self.set_session_activity(&session_id, clock::now());
         Ok(())
     }
 
@@ -97,6 +237,7 @@ impl StoryData for MemoryDB {
                                                     session_id)));
         }
         self.participants.insert(session_id.clone(), participants);
+        self.set_session_activity(session_id, clock::now());
         Ok(())
     }
 
@@ -108,26 +249,29 @@ impl StoryData for MemoryDB {
     ) -> Result<()>
         where F: FnMut(&mut Participant) -> Result<()>
     {
-        self.participants
-            .entry(session_id.clone())
-            .or_insert(Vec::new())
-            .iter_mut()
+        let mut participants = self.participants.get(session_id).cloned().unwrap_or(Vec::new());
+        participants.iter_mut()
             .filter(|p| p.user_id == *user_id)
             .nth(0)
             .ok_or(ErrorKind::ObjectNotFound(format!("Could not find user {:?} in session {:?}",
                                                      user_id,
                                                      session_id))
                 .into())
-            .and_then(|p| plan(p))
+            .and_then(|p| plan(p))?;
+        self.participants.insert(session_id.clone(), participants);
+        self.set_session_activity(session_id, clock::now());
+        Ok(())
     }
 
     fn get_admins(&self, session_id: &SessionID) -> Result<Vec<UserID>> {
-        Ok(self.admins
+        let mut admins: Vec<UserID> = self.admins
             .get(session_id)
             .unwrap_or(&Vec::new())
             .iter()
             .map(|p| p.clone())
-            .collect())
+            .collect();
+        admins.sort();
+        Ok(admins)
     }
 
 
@@ -140,7 +284,8 @@ impl StoryData for MemoryDB {
             }
         }
         admins.push(user_id);
-        self.admins.insert(session_id, admins);
+        self.admins.insert(session_id.clone(), admins);
+        self.set_session_activity(&session_id, clock::now());
         Ok(())
     }
     fn del_admin(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<()> {
@@ -154,227 +299,114 @@ impl StoryData for MemoryDB {
                                                session_id)));
         }
         self.admins.insert(session_id.clone(), admins);
+        self.set_session_activity(session_id, clock::now());
         Ok(())
     }
-}
-
-
-#[cfg(test)]
-mod test {
-    use super::*;
-    use user::Nickname;
 
-    #[test]
-    fn save_and_get_user() {
-        let mut memdal = MemoryDB::new();
-        let my_user = BasicUser::new();
-        memdal.add_user(my_user.clone()).unwrap();
-        let same_user = memdal.get_user(&my_user.user_id).unwrap().unwrap();
-        assert!(my_user == same_user);
-    }
-
-    #[test]
-    fn get_non_existant_user() {
-        let memdal = MemoryDB::new();
-        let user_id = UserID::new();
-        let user_opt = memdal.get_user(&user_id).unwrap();
-        assert!(user_opt == None);
+    fn get_audit_log(&self, session_id: &SessionID) -> Result<Vec<AuditEntry>> {
+        Ok(self.audit.get(session_id).cloned().unwrap_or(Vec::new()))
     }
-
-    #[test]
-    fn save_and_get_session() {
-        let mut memdal = MemoryDB::new();
-        let my_session = Session::new();
-        memdal.add_session(my_session.clone()).unwrap();
-        let same_session = memdal.get_session(&my_session.session_id).unwrap().unwrap();
-        assert!(my_session == same_session);
+    fn add_audit_entry(&mut self, entry: AuditEntry) -> Result<()> {
+        if !self.sessions.contains_key(&entry.session_id) {
+            bail!(ErrorKind::ObjectNotFound(format!("Could not find session: {:?}",
+                                                    entry.session_id)));
+        }
+        self.audit.entry(entry.session_id.clone()).or_insert(Vec::new()).push(entry);
+        Ok(())
     }
-
-    #[test]
-    fn get_non_existant_session() {
-        let memdal = MemoryDB::new();
-        let session_id = SessionID::new();
-        let session_opt = memdal.get_session(&session_id).unwrap();
-        assert!(session_opt == None);
+    fn get_tombstones(&self) -> Result<Vec<AuditEntry>> {
+        Ok(self.tombstones.clone())
     }
-
-    fn add_n_admins_and_check(n: usize) {
-        let mut memdal = MemoryDB::new();
-        let s = Session::new();
-        let mut all_admins = Vec::new();
-        for _ in 0..n {
-            let u = BasicUser::new();
-            memdal.add_admin(u.user_id.clone(), s.session_id.clone())
-                .unwrap();
-            all_admins.push(u.user_id);
-        }
-
-        let same_admins = memdal.get_admins(&s.session_id).unwrap();
-        assert_eq!(same_admins, all_admins);
+    fn add_tombstone(&mut self, entry: AuditEntry) -> Result<()> {
+        self.tombstones.push(entry);
+        Ok(())
     }
 
-    fn add_n_participants_and_check(n: usize) {
-        let mut memdal = MemoryDB::new();
-        let s = Session::new();
-        let mut all_participants = Vec::new();
-        for i in 0..n {
-            let new_user = BasicUser::new();
-            let u = Participant::new(&new_user,
-                                     s.session_id.clone(),
-                                     Nickname::new(format!("bob_{}", i)));
-            memdal.add_participant(u.clone()).unwrap();
-            all_participants.push(u);
-        }
-
-        let same_participants = memdal.get_participants(&s.session_id).unwrap();
-        assert_eq!(same_participants, all_participants);
+    fn touch_user(&mut self, user_id: &UserID) -> Result<()> {
+        self.set_user_activity(user_id, clock::now())
     }
-
-
-    #[test]
-    fn save_and_get_admins() {
-        for i in 0..32 {
-            add_n_admins_and_check(i);
+    fn purge_expired(&mut self, expiry: &ExpiryConfig, now: u64) -> Result<usize> {
+        let (sessions, users) = self.expired(expiry, now);
+        for session_id in &sessions {
+            self.remove_session(session_id);
         }
-    }
-    #[test]
-    fn save_and_get_participant() {
-        for i in 0..32 {
-            add_n_participants_and_check(i);
+        for user_id in &users {
+            self.remove_user(user_id);
         }
+        Ok(sessions.len() + users.len())
     }
+}
 
-    #[test]
-    fn remove_participant() {
-        let mut memdal = MemoryDB::new();
-        let s = Session::new();
-        let new_user = BasicUser::new();
-        let u = Participant::new(&new_user, s.session_id.clone(), Nickname::new("bob"));
-        memdal.add_participant(u.clone()).unwrap();
-
-        let new_user2 = BasicUser::new();
-        let u2 = Participant::new(&new_user2, s.session_id.clone(), Nickname::new("bill"));
-        memdal.add_participant(u2.clone()).unwrap();
-
-        memdal.del_participant(&u.user_id, &s.session_id).unwrap();
-
-        let all_participants = memdal.get_participants(&s.session_id).unwrap();
-
-        assert_eq!(all_participants.len(), 1);
-        assert_eq!(all_participants[0].user_id, u2.user_id);
-
-    }
-
-    #[test]
-    fn remove_nonexistant_participant() {
-        let mut memdal = MemoryDB::new();
-        let s = Session::new();
-        let new_user = BasicUser::new();
-        let outcome = memdal.del_participant(&new_user.user_id, &s.session_id);
-        assert!(outcome.is_err())
-    }
-
-    #[test]
-    fn remove_admin() {
-        let mut memdal = MemoryDB::new();
-        let s = Session::new();
-        let new_user = BasicUser::new();
-        memdal.add_admin(new_user.user_id.clone(), s.session_id.clone())
-            .unwrap();
-
-        let new_user2 = BasicUser::new();
-        memdal.add_admin(new_user2.user_id.clone(), s.session_id.clone())
-            .unwrap();
-
-        memdal.del_admin(&new_user.user_id, &s.session_id).unwrap();
-
-        let all_admins = memdal.get_admins(&s.session_id).unwrap();
-
-        assert_eq!(all_admins.len(), 1);
-        assert_eq!(all_admins[0], new_user2.user_id);
-
-    }
 
-    #[test]
-    fn remove_nonexistant_admin() {
-        let mut memdal = MemoryDB::new();
-        let s = Session::new();
-        let new_user = BasicUser::new();
-        let outcome = memdal.del_admin(&new_user.user_id, &s.session_id);
-        assert!(outcome.is_err())
-    }
-
-    #[test]
-    fn test_is_admin() {
-        let mut memdal = MemoryDB::new();
-        let s = Session::new();
-        let real_admin = BasicUser::new();
-        memdal.add_admin(real_admin.user_id.clone(), s.session_id.clone())
-            .unwrap();
-
-        let fake_admin = BasicUser::new();
+#[cfg(test)]
+mod test {
+    use super::*;
+    use user::Nickname;
 
-        assert!(memdal.is_admin(&s.session_id, &real_admin.user_id).unwrap());
-        assert!(!memdal.is_admin(&s.session_id, &fake_admin.user_id).unwrap());
-    }
+    storydata_conformance_tests!(::dal::MemoryDB::new);
 
     #[test]
-    fn create_and_delete_session() {
+    fn restore_from_snapshot() {
         let mut memdal = MemoryDB::new();
         let my_session = Session::new();
         memdal.add_session(my_session.clone()).unwrap();
-        memdal.del_session(&my_session.session_id).unwrap();
-        let outcome = memdal.get_session(&my_session.session_id).unwrap();
-        assert_eq!(outcome, None);
-    }
+        let new_user = BasicUser::new();
+        memdal.add_user(new_user.clone()).unwrap();
+        memdal.add_admin(new_user.user_id.clone(), my_session.session_id.clone()).unwrap();
+        let u = Participant::new(&new_user, my_session.session_id.clone(), Nickname::new("bob"));
+        memdal.add_participant(u.clone()).unwrap();
 
-    #[test]
-    fn delete_non_existent_session() {
-        let mut memdal = MemoryDB::new();
-        let my_session = Session::new();
-        let outcome = memdal.del_session(&my_session.session_id);
-        assert!(match outcome.unwrap_err() {
-            Error(ErrorKind::ObjectNotFound(_), _) => true,
-            _ => false,
-        });
+        let snapshot = memdal.snapshot();
+        let restored = MemoryDB::from_snapshot(snapshot.clone());
+        assert_eq!(restored.snapshot(), snapshot);
+        assert_eq!(restored.get_user(&new_user.user_id).unwrap(), Some(new_user.clone()));
+        assert_eq!(restored.get_participants(&my_session.session_id).unwrap(), vec![u]);
+        assert!(restored.is_admin(&my_session.session_id, &new_user.user_id).unwrap());
     }
+
     #[test]
-    fn create_and_update_session() {
+    fn purge_expired_sessions_and_users() {
         let mut memdal = MemoryDB::new();
-        let my_session = Session::new();
-        memdal.add_session(my_session.clone()).unwrap();
-        let magic_number = 1234.5f32;
-        let add_magic = |s: &mut Session, _: &mut Vec<Participant>| -> Result<()> {
-            s.average = Some(magic_number);
-            Ok(())
-        };
-
-        memdal.update_session(&my_session.session_id, add_magic)
-            .unwrap();
-        let same_session = memdal.get_session(&my_session.session_id).unwrap().unwrap();
-        assert_eq!(same_session.average, Some(magic_number));
-
+        let expiry = ExpiryConfig::default();
+        let now = clock::now();
+        let long_ago = now - expiry.user_ttl.unwrap().as_secs() - 1;
+
+        let mut idle_session = Session::new();
+        idle_session.last_activity = long_ago;
+        let mut idle_user = BasicUser::new();
+        idle_user.last_activity = long_ago;
+        let u = Participant::new(&idle_user, idle_session.session_id.clone(), Nickname::new("bob"));
+        memdal.add_participant(u).unwrap();
+        memdal.add_admin(idle_user.user_id.clone(), idle_session.session_id.clone()).unwrap();
+        memdal.add_session(idle_session.clone()).unwrap();
+        memdal.add_user(idle_user.clone()).unwrap();
+
+        let active_session = Session::new();
+        memdal.add_session(active_session.clone()).unwrap();
+        let active_user = BasicUser::new();
+        memdal.add_user(active_user.clone()).unwrap();
+
+        assert_eq!(memdal.purge_expired(&expiry, now).unwrap(), 2);
+        assert_eq!(memdal.get_session(&idle_session.session_id).unwrap(), None);
+        assert_eq!(memdal.get_participants(&idle_session.session_id).unwrap(), vec![]);
+        assert_eq!(memdal.get_admins(&idle_session.session_id).unwrap(), vec![]);
+        assert_eq!(memdal.get_user(&idle_user.user_id).unwrap(), None);
+        assert_eq!(memdal.get_session(&active_session.session_id).unwrap(),
+                   Some(active_session));
+        assert_eq!(memdal.get_user(&active_user.user_id).unwrap(), Some(active_user));
     }
 
     #[test]
-    fn create_and_update_participant() {
+    fn nothing_expires_without_a_ttl() {
         let mut memdal = MemoryDB::new();
-        let my_session = Session::new();
-        memdal.add_session(my_session.clone()).unwrap();
-        let new_user = BasicUser::new();
-        let u = Participant::new(&new_user,
-                                 my_session.session_id.clone(),
-                                 Nickname::new("bob"));
-        memdal.add_participant(u.clone()).unwrap();
-        let new_name = Nickname::new("bill");
-        let update_username = |mut p: &mut Participant| {
-            p.nickname = new_name.clone();
-            Ok(())
+        let mut idle_session = Session::new();
+        idle_session.last_activity = 0;
+        memdal.add_session(idle_session).unwrap();
+        let expiry = ExpiryConfig {
+            session_ttl: None,
+            user_ttl: None,
+            ..ExpiryConfig::default()
         };
-        memdal.update_participant(&my_session.session_id, &new_user.user_id, update_username)
-            .unwrap();
-        let all_participants = memdal.get_participants(&my_session.session_id).unwrap();
-        assert_eq!(all_participants[0].nickname, new_name);
-
+        assert_eq!(memdal.purge_expired(&expiry, clock::now()).unwrap(), 0);
     }
 }
@@ -1,28 +1,61 @@
+use config::ExpiryConfig;
 use errors::*;
+use estimates::audit::AuditEntry;
 use estimates::participant::Participant;
 use estimates::session::{Session, SessionID};
+use std::sync::MutexGuard;
 use user::{BasicUser, UserID};
 
+#[cfg(test)]
+#[macro_use]
+mod conformance;
+
+mod backend;
+pub mod dump;
+mod durable;
+mod expiry;
+#[cfg(test)]
+mod fakeredis;
 mod memory;
 mod redis;
 mod sharedmemory;
+mod sqlite;
+pub use self::backend::{StoryBackend, StoryBackendInstance};
+pub use self::durable::{DurableMemoryDB, FsyncPolicy, JournaledMemoryDB};
+pub use self::expiry::has_expired;
 pub use self::memory::MemoryDB;
-pub use self::redis::RedisDB;
-pub use self::sharedmemory::{RedisDBManager, SharedMemoryDB};
+pub use self::redis::{DEFAULT_REDIS_NAMESPACE, IntegrityProblem, RedisDB, SCHEMA_VERSION};
+pub use self::sharedmemory::{RedisDBManager, SharedMemoryDB, SharedSqliteDB};
+pub use self::sqlite::SqliteDB;
 
 pub trait StoryData {
     fn get_user(&self, user_id: &UserID) -> Result<Option<BasicUser>>;
     fn add_user(&mut self, user: BasicUser) -> Result<()>;
+    /// Remove only the user's record, see `service::admin::remove_user`
+    fn del_user(&mut self, user_id: &UserID) -> Result<()>;
+    /// The IDs of every user, sorted
+    fn list_users(&self) -> Result<Vec<UserID>>;
+    /// Change a user in place, saving nothing if the plan fails
+    ///
+    /// Changes made at the same time, e.g. by `touch_user`, are not lost.
+    fn update_user<F>(&mut self, user_id: &UserID, plan: F) -> Result<()>
+        where F: FnMut(&mut BasicUser) -> Result<()>;
 
     fn get_session(&self, session_id: &SessionID) -> Result<Option<Session>>;
+    /// The IDs of every session, sorted
+    fn list_sessions(&self) -> Result<Vec<SessionID>>;
     fn add_session(&mut self, session: Session) -> Result<()>;
     fn del_session(&mut self, session_id: &SessionID) -> Result<()>;
+    /// Change a session and its participants in place, saving nothing if the plan fails
+    ///
+    /// Participants the plan adds to or removes from the list join or leave the session.
     fn update_session<F>(&mut self, session_id: &SessionID, plan: F) -> Result<()>
         where F: FnMut(&mut Session, &mut Vec<Participant>) -> Result<()>;
 
     fn get_participants(&self, session_id: &SessionID) -> Result<Vec<Participant>>;
     fn add_participant(&mut self, participant: Participant) -> Result<()>;
     fn del_participant(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<()>;
+    /// Change a participant in place, saving nothing if the plan fails
     fn update_participant<F>(
         &mut self,
         session_id: &SessionID,
@@ -34,6 +67,44 @@ pub trait StoryData {
     fn add_admin(&mut self, user_id: UserID, session_id: SessionID) -> Result<()>;
     fn del_admin(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<()>;
 
+    /// The session's audit log, oldest entry first
+    fn get_audit_log(&self, session_id: &SessionID) -> Result<Vec<AuditEntry>>;
+    /// Add to the end of an existing session's audit log
+    ///
+    /// The log belongs to the session, and goes when it does. Adding to it
+    /// doesn't count as activity, the action it records already did.
+    fn add_audit_entry(&mut self, entry: AuditEntry) -> Result<()>;
+    /// The record of every deleted session, oldest first
+    ///
+    /// Unlike audit logs these outlive their sessions, so nothing removes them.
+    fn get_tombstones(&self) -> Result<Vec<AuditEntry>>;
+    /// Record who deleted a session, whether or not it still exists
+    fn add_tombstone(&mut self, entry: AuditEntry) -> Result<()>;
+
+    /// Record that the user was just seen, pushing back when they expire
+    ///
+    /// Sessions don't need this: every change made to a session, its
+    /// participants or its admins counts as activity in that session.
+    fn touch_user(&mut self, user_id: &UserID) -> Result<()>;
+    /// Remove the sessions and users idle for longer than their TTL as of `now`
+    ///
+    /// Returns how many were removed. Users are removed without leaving their
+    /// sessions first, `service::admin::expire` does that before calling this.
+    /// Redis expires sessions on its own, so only removes users here.
+    fn purge_expired(&mut self, expiry: &ExpiryConfig, now: u64) -> Result<usize>;
+
+    /// Run `operation` with no other locked operation on the session running meanwhile
+    ///
+    /// Service functions that check the data and then change it hold this
+    /// across both, e.g. so two admins demoting each other can't leave the
+    /// session with none. `operation` may lock the same session again through
+    /// the handle it is given. Backends whose handles already keep every
+    /// other request out run `operation` as it is.
+    fn lock_session<T, F>(&mut self, _session_id: &SessionID, operation: F) -> Result<T>
+        where F: FnOnce(&mut Self) -> Result<T>
+    {
+        operation(self)
+    }
 
     fn is_admin(&self, session_id: &SessionID, user_id: &UserID) -> Result<bool> {
         let admins = self.get_admins(session_id)?;
@@ -41,10 +112,111 @@ pub trait StoryData {
     }
 }
 
+/// A locked backend is a backend, as handed out by the providers behind a single lock
+///
+/// `lock_session` is left as it is, as the lock already keeps every other
+/// request out.
+impl<'a, D> StoryData for MutexGuard<'a, D>
+    where D: StoryData
+{
+    fn get_user(&self, user_id: &UserID) -> Result<Option<BasicUser>> {
+        (**self).get_user(user_id)
+    }
+    fn add_user(&mut self, user: BasicUser) -> Result<()> {
+        (**self).add_user(user)
+    }
+    fn del_user(&mut self, user_id: &UserID) -> Result<()> {
+        (**self).del_user(user_id)
+    }
+    fn list_users(&self) -> Result<Vec<UserID>> {
+        (**self).list_users()
+    }
+    fn update_user<F>(&mut self, user_id: &UserID, plan: F) -> Result<()>
+        where F: FnMut(&mut BasicUser) -> Result<()>
+    {
+        (**self).update_user(user_id, plan)
+    }
+
+    fn get_session(&self, session_id: &SessionID) -> Result<Option<Session>> {
+        (**self).get_session(session_id)
+    }
+    fn list_sessions(&self) -> Result<Vec<SessionID>> {
+        (**self).list_sessions()
+    }
+    fn add_session(&mut self, session: Session) -> Result<()> {
+        (**self).add_session(session)
+    }
+    fn del_session(&mut self, session_id: &SessionID) -> Result<()> {
+        (**self).del_session(session_id)
+    }
+    fn update_session<F>(&mut self, session_id: &SessionID, plan: F) -> Result<()>
+        where F: FnMut(&mut Session, &mut Vec<Participant>) -> Result<()>
+    {
+        (**self).update_session(session_id, plan)
+    }
+
+    fn get_participants(&self, session_id: &SessionID) -> Result<Vec<Participant>> {
+        (**self).get_participants(session_id)
+    }
+    fn add_participant(&mut self, participant: Participant) -> Result<()> {
+        (**self).add_participant(participant)
+    }
+    fn del_participant(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<()> {
+        (**self).del_participant(user_id, session_id)
+    }
+    fn update_participant<F>(
+        &mut self,
+        session_id: &SessionID,
+        user_id: &UserID,
+        plan: F
+    ) -> Result<()>
+        where F: FnMut(&mut Participant) -> Result<()>
+    {
+        (**self).update_participant(session_id, user_id, plan)
+    }
+    fn get_admins(&self, session_id: &SessionID) -> Result<Vec<UserID>> {
+        (**self).get_admins(session_id)
+    }
+    fn add_admin(&mut self, user_id: UserID, session_id: SessionID) -> Result<()> {
+        (**self).add_admin(user_id, session_id)
+    }
+    fn del_admin(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<()> {
+        (**self).del_admin(user_id, session_id)
+    }
+
+    fn get_audit_log(&self, session_id: &SessionID) -> Result<Vec<AuditEntry>> {
+        (**self).get_audit_log(session_id)
+    }
+    fn add_audit_entry(&mut self, entry: AuditEntry) -> Result<()> {
+        (**self).add_audit_entry(entry)
+    }
+    fn get_tombstones(&self) -> Result<Vec<AuditEntry>> {
+        (**self).get_tombstones()
+    }
+    fn add_tombstone(&mut self, entry: AuditEntry) -> Result<()> {
+        (**self).add_tombstone(entry)
+    }
+
+    fn touch_user(&mut self, user_id: &UserID) -> Result<()> {
+        (**self).touch_user(user_id)
+    }
+    fn purge_expired(&mut self, expiry: &ExpiryConfig, now: u64) -> Result<usize> {
+        (**self).purge_expired(expiry, now)
+    }
+
+    fn is_admin(&self, session_id: &SessionID, user_id: &UserID) -> Result<bool> {
+        (**self).is_admin(session_id, user_id)
+    }
+}
+
 
-pub trait StoryDataProvider: Send + Sync {}
-// {
-//     type D: StoryData;
+/// Hands out access to a `StoryData`
+///
+/// The handle usually borrows from the provider (a lock guard, or a pooled
+/// connection), which is why the trait carries that lifetime. Getting a
+/// handle can fail, e.g. when the connection pool is exhausted.
+pub trait StoryDataProvider<'a>: Send + Sync {
+    type StoryData: StoryData + 'a;
 
-//     fn get_story_data<'a>(&'a mut self) -> &'a mut Self::D;
-// }
+    fn get_story_data(&'a self) -> Result<Self::StoryData>;
+}
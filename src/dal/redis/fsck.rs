@@ -0,0 +1,410 @@
+//! Checking, and repairing, the consistency of the data in Redis
+//!
+//! A session is spread over several keys that are written one after the
+//! other, so a crash part way through a change, or an old bug, can leave them
+//! disagreeing with each other.
+
+use super::{RedisDB, RedisList, RedisSet, RedisTable, SCHEMA_VERSION};
+use errors::*;
+use estimates::audit::AuditEntry;
+use estimates::participant::Participant;
+use estimates::session::{Session, SessionID};
+use redis::Commands;
+use serde::Deserialize;
+use serde_json;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::result;
+use user::{BasicUser, UserID};
+
+/// Something wrong with the data in Redis
+#[derive(Debug, Clone, PartialEq)]
+pub enum IntegrityProblem {
+    /// A key whose value can't be decoded
+    Undecodable(String),
+    /// A key belonging to a session that doesn't exist
+    Orphaned(String),
+    /// A participant missing from its session's list of members
+    Unlisted(Participant),
+    /// A set member with nothing behind it, given as the set's key and the member
+    Dangling(String, String),
+    /// A session nobody can administer, and the participant who could take it over
+    NoAdmins(SessionID, Option<UserID>),
+    /// A key that isn't part of any table or set
+    Unknown(String),
+}
+
+impl fmt::Display for IntegrityProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IntegrityProblem::Undecodable(ref key) => write!(f, "{} can not be decoded", key),
+            IntegrityProblem::Orphaned(ref key) => {
+                write!(f, "{} belongs to a session that does not exist", key)
+            },
+            IntegrityProblem::Unlisted(ref participant) => {
+                write!(f,
+                       "{} is missing from the members of session {}",
+                       participant.user_id,
+                       participant.session_id)
+            },
+            IntegrityProblem::Dangling(ref key, ref member) => {
+                write!(f, "{} lists {}, which does not exist", key, member)
+            },
+            IntegrityProblem::NoAdmins(ref session_id, _) => {
+                write!(f, "session {} has no admins", session_id)
+            },
+            IntegrityProblem::Unknown(ref key) => write!(f, "{} is not a known key", key),
+        }
+    }
+}
+
+enum KeyKind {
+    Record(RedisTable),
+    Set(RedisSet, SessionID),
+    List(RedisList, SessionID),
+    SchemaVersion,
+    Tombstones,
+}
+
+impl RedisDB {
+    /// Work out what a key holds from its name
+    fn classify(&self, key: &str) -> Option<KeyKind> {
+        if key == self.schema_version_key() {
+            return Some(KeyKind::SchemaVersion);
+        }
+        if key == self.tombstones_key() {
+            return Some(KeyKind::Tombstones);
+        }
+        for set in vec![RedisSet::ParticipantUID, RedisSet::ParticipantName, RedisSet::Admin] {
+            let prefix = self.set_key("", &set);
+            if key.starts_with(&prefix) {
+                let session_id = SessionID(key[prefix.len()..].to_string());
+                return Some(KeyKind::Set(set, session_id));
+            }
+        }
+        let prefix = self.list_key("", &RedisList::Audit);
+        if key.starts_with(&prefix) {
+            let session_id = SessionID(key[prefix.len()..].to_string());
+            return Some(KeyKind::List(RedisList::Audit, session_id));
+        }
+        for table in vec![RedisTable::User, RedisTable::Session, RedisTable::Participant] {
+            if key.starts_with(&self.table_key("", &table)) {
+                return Some(KeyKind::Record(table));
+            }
+        }
+        None
+    }
+
+    /// Read a record as JSON, or `None` if it expired after being listed
+    fn read_record<T>(&self, key: &str) -> Result<Option<result::Result<T, serde_json::Error>>>
+        where T: Deserialize
+    {
+        let data: Option<String> = self.conn.get(key)?;
+        Ok(data.map(|d| serde_json::from_str(&d)))
+    }
+
+    /// Look through every key for problems, without changing anything
+    ///
+    /// This lists keys with `KEYS`, which blocks Redis while it runs, so is
+    /// best kept away from busy servers.
+    pub fn check_integrity(&self) -> Result<Vec<IntegrityProblem>> {
+        let keys: Vec<String> = self.conn.keys(format!("{}_*", self.namespace))?;
+        let mut problems = Vec::new();
+        let mut sessions = BTreeSet::new();
+        let mut participants = Vec::new();
+        let mut sets = Vec::new();
+        let mut lists = Vec::new();
+        for key in keys {
+            match self.classify(&key) {
+                Some(KeyKind::Record(RedisTable::Session)) => {
+                    match self.read_record::<Session>(&key)? {
+                        Some(Ok(session)) => {
+                            sessions.insert(session.session_id);
+                        },
+                        Some(Err(_)) => problems.push(IntegrityProblem::Undecodable(key)),
+                        None => (),
+                    }
+                },
+                Some(KeyKind::Record(RedisTable::User)) => {
+                    if let Some(Err(_)) = self.read_record::<BasicUser>(&key)? {
+                        problems.push(IntegrityProblem::Undecodable(key));
+                    }
+                },
+                Some(KeyKind::Record(RedisTable::Participant)) => {
+                    match self.read_record::<Participant>(&key)? {
+                        Some(Ok(participant)) => participants.push((key, participant)),
+                        Some(Err(_)) => problems.push(IntegrityProblem::Undecodable(key)),
+                        None => (),
+                    }
+                },
+                Some(KeyKind::Set(set, session_id)) => sets.push((key, set, session_id)),
+                Some(KeyKind::List(_, session_id)) => lists.push((key, session_id)),
+                // Deleting the tombstones would be worse than anything wrong with them
+                Some(KeyKind::SchemaVersion) |
+                Some(KeyKind::Tombstones) => (),
+                None => problems.push(IntegrityProblem::Unknown(key)),
+            }
+        }
+
+        // Members and nicknames that have a participant record behind them
+        let mut members = BTreeSet::new();
+        let mut nicknames = BTreeMap::new();
+        for (key, participant) in participants {
+            if !sessions.contains(&participant.session_id) {
+                problems.push(IntegrityProblem::Orphaned(key));
+                continue;
+            }
+            nicknames.entry(participant.session_id.clone())
+                .or_insert(BTreeSet::new())
+                .insert(serde_json::to_string(&participant.nickname)?);
+            if self.sismember(&participant.session_id,
+                           &participant.user_id,
+                           &RedisSet::ParticipantUID)? {
+                members.insert((participant.session_id, participant.user_id));
+            } else {
+                problems.push(IntegrityProblem::Unlisted(participant));
+            }
+        }
+
+        let mut administered = BTreeSet::new();
+        for (key, set, session_id) in sets {
+            if !sessions.contains(&session_id) {
+                problems.push(IntegrityProblem::Orphaned(key));
+                continue;
+            }
+            let listed: Vec<String> = self.conn.smembers(key.as_str())?;
+            for member in listed {
+                let user_id = serde_json::from_str::<UserID>(&member).ok();
+                let valid = match set {
+                    RedisSet::ParticipantUID => {
+                        user_id.map_or(false, |u| members.contains(&(session_id.clone(), u)))
+                    },
+                    RedisSet::ParticipantName => {
+                        nicknames.get(&session_id).map_or(false, |n| n.contains(&member))
+                    },
+                    // Admins don't have to be taking part in the session
+                    RedisSet::Admin => user_id.is_some(),
+                };
+                if !valid {
+                    problems.push(IntegrityProblem::Dangling(key.clone(), member));
+                } else if let RedisSet::Admin = set {
+                    administered.insert(session_id.clone());
+                }
+            }
+        }
+
+        for (key, session_id) in lists {
+            if !sessions.contains(&session_id) {
+                problems.push(IntegrityProblem::Orphaned(key));
+                continue;
+            }
+            let entries: Vec<String> = self.conn.lrange(key.as_str(), 0, -1)?;
+            if entries.iter().any(|e| serde_json::from_str::<AuditEntry>(e).is_err()) {
+                problems.push(IntegrityProblem::Undecodable(key));
+            }
+        }
+
+        for session_id in sessions.difference(&administered) {
+            let successor = members.iter()
+                .find(|&&(ref s, _)| s == session_id)
+                .map(|&(_, ref user_id)| user_id.clone());
+            problems.push(IntegrityProblem::NoAdmins(session_id.clone(), successor));
+        }
+        Ok(problems)
+    }
+
+    /// Fix a problem found by `check_integrity`, returning false if it can't be fixed
+    ///
+    /// Undecodable and orphaned keys are deleted, dangling set members are
+    /// removed, unlisted participants are added back to their session and the
+    /// first participant of a session without admins is made an admin.
+    ///
+    /// Nothing is repaired until the data is at `SCHEMA_VERSION`, see
+    /// `require_current_schema`.
+    pub fn repair(&self, problem: &IntegrityProblem) -> Result<bool> {
+        self.require_current_schema()?;
+        match *problem {
+            IntegrityProblem::Undecodable(ref key) |
+            IntegrityProblem::Orphaned(ref key) => {
+                let _: i64 = self.conn.del(key.as_str())?;
+            },
+            IntegrityProblem::Dangling(ref key, ref member) => {
+                let _: i64 = self.conn.srem(key.as_str(), member.as_str())?;
+            },
+            IntegrityProblem::Unlisted(ref participant) => {
+                let session_id = &participant.session_id;
+                self.sadd(session_id, &participant.nickname, &RedisSet::ParticipantName)?;
+                self.sadd(session_id, &participant.user_id, &RedisSet::ParticipantUID)?;
+                self.expire_sets(session_id)?;
+            },
+            IntegrityProblem::NoAdmins(ref session_id, Some(ref user_id)) => {
+                self.sadd(session_id, user_id, &RedisSet::Admin)?;
+                self.expire_sets(session_id)?;
+            },
+            IntegrityProblem::NoAdmins(_, None) |
+            IntegrityProblem::Unknown(_) => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Fail unless the data is at `SCHEMA_VERSION`
+    ///
+    /// Records only an older schema can read look undecodable until they are
+    /// migrated, and repairing them would delete them.
+    pub fn require_current_schema(&self) -> Result<()> {
+        let version = self.schema_version()?;
+        if version != SCHEMA_VERSION {
+            bail!(ErrorKind::DataIntegrityError(format!("The data in Redis is schema version \
+                                                         {}, not {}, so it must be migrated \
+                                                         before it can be repaired",
+                                                        version,
+                                                        SCHEMA_VERSION)));
+        }
+        Ok(())
+    }
+
+    /// Give sets recreated by a repair a TTL, without counting it as activity
+    fn expire_sets(&self, session_id: &SessionID) -> Result<()> {
+        let sets: Vec<String> = self.session_keys(session_id, &[]).into_iter().skip(1).collect();
+        self.expire(&sets, self.expiry.session_ttl)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::participant_key;
+    use config::ExpiryConfig;
+    use dal::{DEFAULT_REDIS_NAMESPACE, StoryData, fakeredis};
+    use estimates::audit::AuditAction;
+    use user::Nickname;
+
+    fn populated() -> (RedisDB, Session, Participant) {
+        let mut db = RedisDB::new(fakeredis::shared().fresh_pool().get().unwrap(),
+                                  ExpiryConfig::default(),
+                                  DEFAULT_REDIS_NAMESPACE.to_string());
+        db.migrate().unwrap();
+        let new_user = BasicUser::new();
+        db.add_user(new_user.clone()).unwrap();
+        let my_session = Session::new();
+        db.add_session(my_session.clone()).unwrap();
+        let p = Participant::new(&new_user, my_session.session_id.clone(), Nickname::new("bob"));
+        db.add_participant(p.clone()).unwrap();
+        db.add_admin(new_user.user_id.clone(), my_session.session_id.clone()).unwrap();
+        (db, my_session, p)
+    }
+
+    fn repair_all(db: &RedisDB) {
+        for problem in db.check_integrity().unwrap() {
+            assert!(db.repair(&problem).unwrap(), "could not repair {}", problem);
+        }
+        assert_eq!(db.check_integrity().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn consistent_data_has_no_problems() {
+        let (db, _, _) = populated();
+        assert_eq!(db.check_integrity().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn missing_participant_record() {
+        let (db, my_session, p) = populated();
+        let pkey = participant_key(&my_session.session_id, &p.user_id);
+        db.del(&pkey, &RedisTable::Participant).unwrap();
+
+        let uid_key = db.set_key(&my_session.session_id, &RedisSet::ParticipantUID);
+        let name_key = db.set_key(&my_session.session_id, &RedisSet::ParticipantName);
+        let problems = db.check_integrity().unwrap();
+        assert!(problems.contains(&IntegrityProblem::Dangling(uid_key,
+                                                               serde_json::to_string(&p.user_id)
+                                                                   .unwrap())));
+        assert!(problems.contains(&IntegrityProblem::Dangling(name_key,
+                                                               serde_json::to_string(&p.nickname)
+                                                                   .unwrap())));
+        repair_all(&db);
+        assert_eq!(db.get_participants(&my_session.session_id).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn unlisted_participant() {
+        let (db, my_session, p) = populated();
+        db.srem(&my_session.session_id, &p.user_id, &RedisSet::ParticipantUID).unwrap();
+
+        assert_eq!(db.check_integrity().unwrap(),
+                   vec![IntegrityProblem::Unlisted(p.clone())]);
+        repair_all(&db);
+        assert_eq!(db.get_participants(&my_session.session_id).unwrap(), vec![p]);
+    }
+
+    #[test]
+    fn orphans_and_garbage() {
+        let (db, my_session, _) = populated();
+        let session_key = db.table_key(&my_session.session_id, &RedisTable::Session);
+        let _: () = db.conn.set(session_key.as_str(), "{not json").unwrap();
+        let _: () = db.conn.set("STORYESTIMATES_SOMETHING", "else").unwrap();
+
+        let problems = db.check_integrity().unwrap();
+        assert!(problems.contains(&IntegrityProblem::Undecodable(session_key)));
+        assert!(problems.contains(&IntegrityProblem::Unknown("STORYESTIMATES_SOMETHING"
+            .to_string())));
+        // Everything else belonged to the unreadable session
+        assert_eq!(problems.len(), 2 + 4);
+
+        let _: i64 = db.conn.del("STORYESTIMATES_SOMETHING").unwrap();
+        repair_all(&db);
+        // Only the user and the schema version are left
+        let keys: Vec<String> = db.conn.keys("STORYESTIMATES_*").unwrap();
+        assert_eq!(keys.len(), 2);
+        assert!(db.get_session(&my_session.session_id).unwrap().is_none());
+    }
+
+    #[test]
+    fn no_repairs_before_migrating() {
+        let (db, my_session, _) = populated();
+        let session_key = db.table_key(&my_session.session_id, &RedisTable::Session);
+        let _: () = db.conn.set(session_key.as_str(), "{not json").unwrap();
+        let _: () = db.conn.set(db.schema_version_key(), SCHEMA_VERSION - 1).unwrap();
+
+        let problem = IntegrityProblem::Undecodable(session_key.clone());
+        assert!(db.check_integrity().unwrap().contains(&problem));
+        assert!(db.require_current_schema().is_err());
+        assert!(db.repair(&problem).is_err());
+        let exists: bool = db.conn.exists(session_key.as_str()).unwrap();
+        assert!(exists);
+    }
+
+    #[test]
+    fn orphaned_audit_log() {
+        let (mut db, my_session, p) = populated();
+        let entry = AuditEntry::new(my_session.session_id.clone(),
+                                    p.user_id.clone(),
+                                    AuditAction::UpdatePolicy);
+        db.add_audit_entry(entry).unwrap();
+        assert_eq!(db.check_integrity().unwrap(), vec![]);
+
+        db.del(&my_session.session_id, &RedisTable::Session).unwrap();
+        let audit_key = db.list_key(&my_session.session_id, &RedisList::Audit);
+        assert!(db.check_integrity().unwrap().contains(&IntegrityProblem::Orphaned(audit_key)));
+        repair_all(&db);
+        assert_eq!(db.get_audit_log(&my_session.session_id).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn session_without_admins() {
+        let (mut db, my_session, p) = populated();
+        db.del_admin(&p.user_id, &my_session.session_id).unwrap();
+
+        assert_eq!(db.check_integrity().unwrap(),
+                   vec![IntegrityProblem::NoAdmins(my_session.session_id.clone(),
+                                                   Some(p.user_id.clone()))]);
+        repair_all(&db);
+        assert!(db.is_admin(&my_session.session_id, &p.user_id).unwrap());
+
+        db.del_participant(&p.user_id, &my_session.session_id).unwrap();
+        db.del_admin(&p.user_id, &my_session.session_id).unwrap();
+        let problem = IntegrityProblem::NoAdmins(my_session.session_id.clone(), None);
+        assert_eq!(db.check_integrity().unwrap(), vec![problem.clone()]);
+        assert!(!db.repair(&problem).unwrap());
+    }
+}
@@ -0,0 +1,677 @@
+use super::StoryData;
+use super::expiry::has_expired;
+use config::ExpiryConfig;
+use errors::*;
+use estimates::audit::AuditEntry;
+use estimates::participant::Participant;
+use estimates::session::{Session, SessionID};
+
+use r2d2::PooledConnection;
+use r2d2_redis::RedisConnectionManager;
+
+use redis::{self, Commands, FromRedisValue, Value};
+use serde::{Deserialize, Serialize};
+
+use serde_json;
+use std::fmt;
+use std::time::Duration;
+
+use user::{BasicUser, UserID};
+use util::clock;
+
+mod fsck;
+mod schema;
+pub use self::fsck::IntegrityProblem;
+pub use self::schema::SCHEMA_VERSION;
+
+/// The prefix of every key, unless another namespace is configured
+pub const DEFAULT_REDIS_NAMESPACE: &'static str = "STORYESTIMATES";
+
+#[derive(Debug)]
+enum RedisTable {
+    User,
+    Session,
+    Participant,
+}
+
+#[derive(Debug)]
+enum RedisSet {
+    ParticipantUID,
+    ParticipantName,
+    Admin,
+}
+
+#[derive(Debug)]
+enum RedisList {
+    Audit,
+}
+
+/// Sessions are stored with a TTL, so Redis expires them by itself
+///
+/// Users are not, so that they can leave their sessions before they are
+/// removed, see `service::admin::expire`.
+///
+/// Every key starts with the namespace, so separate deployments can share a
+/// Redis server without seeing each other's data.
+pub struct RedisDB {
+    conn: PooledConnection<RedisConnectionManager>,
+    expiry: ExpiryConfig,
+    namespace: String,
+}
+
+fn participant_key(sid: &SessionID, uid: &UserID) -> String {
+    format!("{}_{}", sid, uid)
+}
+
+impl RedisDB {
+    pub fn new(
+        conn: PooledConnection<RedisConnectionManager>,
+        expiry: ExpiryConfig,
+        namespace: String
+    ) -> Self {
+        RedisDB {
+            conn: conn,
+            expiry: expiry,
+            namespace: namespace,
+        }
+    }
+
+    fn set_key<K>(&self, key: K, set: &RedisSet) -> String
+        where K: fmt::Display
+    {
+        let set_name = match *set {
+            RedisSet::ParticipantUID => "PARTICIPANTUID",
+            RedisSet::ParticipantName => "PARTICIPANTNAME",
+            RedisSet::Admin => "ADMIN",
+        };
+        format!("{}_set_{}_{}", self.namespace, set_name, key)
+    }
+
+    fn list_key<K>(&self, key: K, list: &RedisList) -> String
+        where K: fmt::Display
+    {
+        let list_name = match *list {
+            RedisList::Audit => "AUDIT",
+        };
+        format!("{}_list_{}_{}", self.namespace, list_name, key)
+    }
+
+    fn table_key<K>(&self, key: K, table: &RedisTable) -> String
+        where K: fmt::Display
+    {
+        let table_name = match *table {
+            RedisTable::User => "USER",
+            RedisTable::Session => "SESSION",
+            RedisTable::Participant => "PARTICIPANT",
+        };
+        format!("{}_{}_{}", self.namespace, table_name, key)
+    }
+
+    /// The key holding the version of the data, see `schema`
+    fn schema_version_key(&self) -> String {
+        format!("{}_SCHEMA_VERSION", self.namespace)
+    }
+
+    /// The list of deleted sessions, which never expires
+    fn tombstones_key(&self) -> String {
+        format!("{}_TOMBSTONES", self.namespace)
+    }
+
+    /// Every key holding data that belongs to a session
+    ///
+    /// Anything stored per session must be listed here, so that it expires and
+    /// is deleted along with the session.
+    fn session_keys(&self, session_id: &SessionID, participants: &[UserID]) -> Vec<String> {
+        let mut keys = vec![self.table_key(session_id, &RedisTable::Session),
+                            self.set_key(session_id, &RedisSet::ParticipantUID),
+                            self.set_key(session_id, &RedisSet::ParticipantName),
+                            self.set_key(session_id, &RedisSet::Admin),
+                            self.list_key(session_id, &RedisList::Audit)];
+        for user_id in participants {
+            let pkey = participant_key(session_id, user_id);
+            keys.push(self.table_key(pkey, &RedisTable::Participant));
+        }
+        keys
+    }
+
+    /// Set a TTL on every key, writing a value clears it so this follows every write
+    fn expire(&self, keys: &[String], ttl: Option<Duration>) -> Result<()> {
+        let ttl = match ttl {
+            Some(ttl) => ttl.as_secs(),
+            None => return Ok(()),
+        };
+        let mut pipe = redis::pipe();
+        for key in keys {
+            pipe.cmd("EXPIRE").arg(key.as_str()).arg(ttl).ignore();
+        }
+        pipe.query::<()>(&*self.conn)?;
+        Ok(())
+    }
+
+    /// Note that the session was just used, and push back when all of its keys expire
+    fn refresh_session(&self, session_id: &SessionID) -> Result<()> {
+        if let Some(mut session) = self.get_session(session_id)? {
+            session.last_activity = clock::now();
+            self.set(session_id, &session, &RedisTable::Session)?;
+        }
+        let user_ids: Vec<UserID> = self.smembers(session_id, &RedisSet::ParticipantUID)?;
+        self.expire(&self.session_keys(session_id, &user_ids), self.expiry.session_ttl)
+    }
+
+    /// The sorted IDs of every record in a table
+    ///
+    /// This uses `KEYS`, which blocks Redis while it runs, so it is only meant
+    /// for administration.
+    fn list_ids(&self, table: &RedisTable) -> Result<Vec<String>> {
+        let prefix = self.table_key("", table);
+        let keys: Vec<String> = self.conn.keys(format!("{}*", prefix))?;
+        let mut ids: Vec<String> = keys.into_iter()
+            .map(|key| key[prefix.len()..].to_string())
+            .collect();
+        ids.sort();
+        Ok(ids)
+    }
+
+    fn get<T, K>(&self, key: K, table: &RedisTable) -> Result<Option<T>>
+        where T: Deserialize,
+              K: fmt::Display
+    {
+        let true_key = self.table_key(key, table);
+        match self.conn.get(true_key)? {
+            Value::Nil => Ok(None),
+            Value::Data(serialized) => {
+                serde_json::from_slice(&serialized)
+                    .map(|u| Some(u))
+                    .map_err(|e| e.into())
+            },
+            x => bail!(ErrorKind::UnexpectedRedisResponse(x)),
+        }
+    }
+
+    fn set<T, K>(&self, key: K, value: T, table: &RedisTable) -> Result<()>
+        where T: Serialize,
+              K: fmt::Display
+    {
+        let true_key = self.table_key(key, table);
+        let serialized_value = serde_json::to_string(&value)?;
+        let _: Value = self.conn.set(true_key, serialized_value)?;
+        Ok(())
+    }
+
+    fn del<K>(&self, key: K, table: &RedisTable) -> Result<bool>
+        where K: fmt::Display
+    {
+        let true_key = self.table_key(key, table);
+        let redis_result: Value = self.conn.del(true_key)?;
+        i64::from_redis_value(&redis_result)
+            .map(|n| n == 1)
+            .map_err(|e| e.into())
+    }
+
+    fn sadd<T, K>(&self, key: K, value: T, set: &RedisSet) -> Result<()>
+        where T: Serialize,
+              K: fmt::Display
+    {
+        let true_key = self.set_key(key, set);
+        let serialized_value = serde_json::to_string(&value)?;
+        let _: Value = self.conn.sadd(true_key, serialized_value)?;
+        Ok(())
+    }
+
+    fn sismember<T, K>(&self, key: K, value: T, set: &RedisSet) -> Result<bool>
+        where T: Serialize,
+              K: fmt::Display
+    {
+        let true_key = self.set_key(key, set);
+        let serialized_value = serde_json::to_string(&value)?;
+        let redis_result: Value = self.conn.sismember(true_key, serialized_value)?;
+        i64::from_redis_value(&redis_result)
+            .map(|n| n == 1)
+            .map_err(|e| e.into())
+    }
+    fn smembers<T, K>(&self, key: K, set: &RedisSet) -> Result<Vec<T>>
+        where T: Deserialize,
+              K: fmt::Display
+    {
+        let true_key = self.set_key(key, set);
+        let all_serialized: Vec<String> = self.conn.smembers(true_key)?;
+        all_serialized.iter()
+            .map(|serialized| serde_json::from_str(&serialized).map_err(|e| e.into()))
+            .collect()
+    }
+    fn srem<T, K>(&self, key: K, value: T, set: &RedisSet) -> Result<bool>
+        where T: Serialize,
+              K: fmt::Display
+    {
+        let true_key = self.set_key(key, set);
+        let serialized_value = serde_json::to_string(&value)?;
+        let redis_result: Value = self.conn.srem(true_key, serialized_value)?;
+        i64::from_redis_value(&redis_result)
+            .map(|n| n == 1)
+            .map_err(|e| e.into())
+    }
+
+    fn rpush<T, K>(&self, key: K, value: T, list: &RedisList) -> Result<()>
+        where T: Serialize,
+              K: fmt::Display
+    {
+        let true_key = self.list_key(key, list);
+        let serialized_value = serde_json::to_string(&value)?;
+        let _: Value = self.conn.rpush(true_key, serialized_value)?;
+        Ok(())
+    }
+    fn lrange<T, K>(&self, key: K, list: &RedisList) -> Result<Vec<T>>
+        where T: Deserialize,
+              K: fmt::Display
+    {
+        let true_key = self.list_key(key, list);
+        let all_serialized: Vec<String> = self.conn.lrange(true_key, 0, -1)?;
+        all_serialized.iter()
+            .map(|serialized| serde_json::from_str(&serialized).map_err(|e| e.into()))
+            .collect()
+    }
+}
+
+fn strict<T>(redis_result: Result<Option<T>>) -> Result<T> {
+    match redis_result {
+        Ok(Some(x)) => Ok(x),
+        Ok(None) => {
+            Err(ErrorKind::ObjectNotFound("Expected key missing from redis".to_string()).into())
+        },
+        Err(e) => Err(e),
+    }
+}
+
+impl StoryData for RedisDB {
+    fn get_user(&self, user_id: &UserID) -> Result<Option<BasicUser>> {
+        self.get(user_id, &RedisTable::User)
+    }
+    fn add_user(&mut self, user: BasicUser) -> Result<()> {
+        self.set(&user.user_id, &user, &RedisTable::User)
+    }
+    fn del_user(&mut self, user_id: &UserID) -> Result<()> {
+        if !self.del(user_id, &RedisTable::User)? {
+            bail!(ErrorKind::ObjectNotFound(format!("Could not find user: {:?}", user_id)));
+        }
+        Ok(())
+    }
+    fn list_users(&self) -> Result<Vec<UserID>> {
+        Ok(self.list_ids(&RedisTable::User)?.into_iter().map(UserID).collect())
+    }
+    fn update_user<F>(&mut self, user_id: &UserID, mut plan: F) -> Result<()>
+        where F: FnMut(&mut BasicUser) -> Result<()>
+    {
+        let key = self.table_key(user_id, &RedisTable::User);
+        // Watching the key means a write made in between, like a token being
+        // revoked, makes us start over rather than being overwritten
+        loop {
+            redis::cmd("WATCH").arg(key.as_str()).query::<()>(&*self.conn)?;
+            let planned = self.get_user(user_id)
+                .and_then(|user| {
+                    user.ok_or(ErrorKind::ObjectNotFound(format!("Could not find user: {:?}",
+                                                                 user_id))
+                        .into())
+                })
+                .and_then(|mut user| plan(&mut user).map(|_| user));
+            let user = match planned {
+                Ok(user) => user,
+                Err(e) => {
+                    redis::cmd("UNWATCH").query::<()>(&*self.conn)?;
+                    return Err(e);
+                },
+            };
+            let mut pipe = redis::pipe();
+            pipe.atomic().cmd("SET").arg(key.as_str()).arg(serde_json::to_string(&user)?).ignore();
+            let written: Option<()> = pipe.query(&*self.conn)?;
+            if written.is_some() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn get_session(&self, session_id: &SessionID) -> Result<Option<Session>> {
+        self.get(session_id, &RedisTable::Session)
+    }
+    fn list_sessions(&self) -> Result<Vec<SessionID>> {
+        Ok(self.list_ids(&RedisTable::Session)?.into_iter().map(SessionID).collect())
+    }
+    fn add_session(&mut self, session: Session) -> Result<()> {
+        self.set(&session.session_id, &session, &RedisTable::Session)?;
+        self.expire(&[self.table_key(&session.session_id, &RedisTable::Session)],
+                    self.expiry.session_ttl)
+    }
+    fn del_session(&mut self, session_id: &SessionID) -> Result<()> {
+        // Watching the participants means one joining while we delete makes us
+        // start over, instead of leaving their records behind
+        let participants_key = self.set_key(session_id, &RedisSet::ParticipantUID);
+        loop {
+            redis::cmd("WATCH").arg(participants_key.as_str()).query::<()>(&*self.conn)?;
+            let user_ids: Vec<UserID> = self.smembers(session_id, &RedisSet::ParticipantUID)?;
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            for key in self.session_keys(session_id, &user_ids) {
+                pipe.cmd("DEL").arg(key);
+            }
+            let deleted: Option<Vec<i64>> = pipe.query(&*self.conn)?;
+            match deleted {
+                // The session record is the first key
+                Some(ref counts) if counts.first() == Some(&1) => return Ok(()),
+                Some(_) => {
+                    bail!(ErrorKind::ObjectNotFound(format!("Could not find session: {:?}",
+                                                            session_id)))
+                },
+                None => continue,
+            }
+        }
+    }
+    fn update_session<F>(&mut self, session_id: &SessionID, mut plan: F) -> Result<()>
+        where F: FnMut(&mut Session, &mut Vec<Participant>) -> Result<()>
+    {
+        let before = self.get_participants(session_id)?;
+        let mut participants = before.clone();
+        let mut session = strict(self.get_session(session_id))?;
+        plan(&mut session, &mut participants)?;
+        self.set(&session.session_id, &session, &RedisTable::Session)?;
+        // The plan may have added or removed participants, or renamed them,
+        // so the sets of the session have to follow along
+        for old in &before {
+            match participants.iter().find(|p| p.user_id == old.user_id) {
+                Some(p) if p.nickname == old.nickname => {},
+                Some(_) => {
+                    self.srem(session_id, &old.nickname, &RedisSet::ParticipantName)?;
+                },
+                None => {
+                    self.srem(session_id, &old.user_id, &RedisSet::ParticipantUID)?;
+                    self.srem(session_id, &old.nickname, &RedisSet::ParticipantName)?;
+                    self.del(participant_key(session_id, &old.user_id),
+                             &RedisTable::Participant)?;
+                },
+            }
+        }
+        for p in participants {
+            let pkey = participant_key(session_id, &p.user_id);
+            self.set(&pkey, &p, &RedisTable::Participant)?;
+            self.sadd(session_id, &p.nickname, &RedisSet::ParticipantName)?;
+            self.sadd(session_id, &p.user_id, &RedisSet::ParticipantUID)?;
+        }
+        self.refresh_session(session_id)
+    }
+
+    fn get_participants(&self, session_id: &SessionID) -> Result<Vec<Participant>> {
+        let mut all_users: Vec<UserID> = self.smembers(&session_id, &RedisSet::ParticipantUID)?;
+        all_users.sort();
+        all_users.iter()
+            .map(|user_id| {
+                let pkey = participant_key(&session_id, &user_id);
+                let user: Result<Participant> = strict(self.get(pkey, &RedisTable::Participant))
+                    .map_err(|_| {
+                        ErrorKind::DataIntegrityError(format!("After finding {} in the \
+                                                               participants for session {}, the \
+                                                               corresponding Participant could \
+                                                               not be found!",
+                                                              &user_id,
+                                                              &session_id))
+                            .into()
+                    });
+                user
+            })
+            .collect()
+    }
+
+    // TODO These should get error codes
+    fn add_participant(&mut self, participant: Participant) -> Result<()> {
+        if self.sismember(&participant.session_id,
+                       &participant.user_id,
+                       &RedisSet::ParticipantUID)? {
+            bail!(ErrorKind::UserError("That user is already part of this session".to_string()))
+        }
+        let pkey = participant_key(&participant.session_id, &participant.user_id);
+        self.set(&pkey, &participant, &RedisTable::Participant)?;
+        self.sadd(&participant.session_id,
+                  &participant.nickname,
+                  &RedisSet::ParticipantName)?;
+        self.sadd(&participant.session_id,
+                  &participant.user_id,
+                  &RedisSet::ParticipantUID)?;
+        self.refresh_session(&participant.session_id)
+    }
+
+    // TODO These should get error codes
+    fn del_participant(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<()> {
+        let pkey = participant_key(&session_id, &user_id);
+        let participant: Option<Participant> = self.get(&pkey, &RedisTable::Participant)?;
+        if !self.srem(session_id, user_id, &RedisSet::ParticipantUID)? {
+            bail!(ErrorKind::ObjectNotFound(format!("User {} was not a member of session {}",
+                                                    user_id,
+                                                    session_id)));
+        }
+        if let Some(participant) = participant {
+            self.srem(session_id, &participant.nickname, &RedisSet::ParticipantName)?;
+        }
+        self.del(&pkey, &RedisTable::Participant)?;
+        self.refresh_session(session_id)
+    }
+    fn update_participant<F>(
+        &mut self,
+        session_id: &SessionID,
+        user_id: &UserID,
+        mut plan: F
+    ) -> Result<()>
+        where F: FnMut(&mut Participant) -> Result<()>
+    {
+        let pkey = participant_key(&session_id, &user_id);
+        let mut participant: Participant = strict(self.get(&pkey, &RedisTable::Participant))?;
+        plan(&mut participant)?;
+        self.set(&pkey, &participant, &RedisTable::Participant)?;
+        self.refresh_session(session_id)
+    }
+    fn get_admins(&self, session_id: &SessionID) -> Result<Vec<UserID>> {
+        let mut admins: Vec<UserID> = self.smembers(&session_id, &RedisSet::Admin)?;
+        admins.sort();
+        Ok(admins)
+    }
+    fn add_admin(&mut self, user_id: UserID, session_id: SessionID) -> Result<()> {
+        if self.sismember(&session_id, &user_id, &RedisSet::Admin)? {
+            bail!(ErrorKind::UserError("That user is already an admin of this session".to_string()))
+        }
+        self.sadd(&session_id, &user_id, &RedisSet::Admin)?;
+        self.refresh_session(&session_id)
+    }
+    fn del_admin(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<()> {
+        if !self.srem(session_id, user_id, &RedisSet::Admin)? {
+            bail!(ErrorKind::UserError(format!("User {} was not an admin of session {}",
+                                               user_id,
+                                               session_id)));
+        }
+        self.refresh_session(session_id)
+    }
+
+    fn get_audit_log(&self, session_id: &SessionID) -> Result<Vec<AuditEntry>> {
+        self.lrange(session_id, &RedisList::Audit)
+    }
+    fn add_audit_entry(&mut self, entry: AuditEntry) -> Result<()> {
+        if self.get_session(&entry.session_id)?.is_none() {
+            bail!(ErrorKind::ObjectNotFound(format!("Could not find session: {:?}",
+                                                    entry.session_id)));
+        }
+        let key = self.list_key(&entry.session_id, &RedisList::Audit);
+        self.rpush(&entry.session_id, &entry, &RedisList::Audit)?;
+        // A new list has no TTL, so give it whatever the rest of the session has left
+        let session_key = self.table_key(&entry.session_id, &RedisTable::Session);
+        let remaining: i64 = redis::cmd("TTL").arg(session_key).query(&*self.conn)?;
+        if remaining > 0 {
+            self.expire(&[key], Some(Duration::from_secs(remaining as u64)))?;
+        }
+        Ok(())
+    }
+    fn get_tombstones(&self) -> Result<Vec<AuditEntry>> {
+        let all_serialized: Vec<String> = self.conn.lrange(self.tombstones_key(), 0, -1)?;
+        all_serialized.iter()
+            .map(|serialized| serde_json::from_str(&serialized).map_err(|e| e.into()))
+            .collect()
+    }
+    fn add_tombstone(&mut self, entry: AuditEntry) -> Result<()> {
+        let _: Value = self.conn.rpush(self.tombstones_key(), serde_json::to_string(&entry)?)?;
+        Ok(())
+    }
+
+    fn touch_user(&mut self, user_id: &UserID) -> Result<()> {
+        self.update_user(user_id, |user| {
+            user.last_activity = clock::now();
+            Ok(())
+        })
+    }
+    fn purge_expired(&mut self, expiry: &ExpiryConfig, now: u64) -> Result<usize> {
+        let mut purged = 0;
+        if expiry.user_ttl.is_none() {
+            return Ok(purged);
+        }
+        for user_id in self.list_users()? {
+            let key = self.table_key(&user_id, &RedisTable::User);
+            // Watching the key means a user seen in between isn't removed
+            redis::cmd("WATCH").arg(key.as_str()).query::<()>(&*self.conn)?;
+            let expired = self.get_user(&user_id)?
+                .map_or(false, |u| has_expired(expiry.user_ttl, u.last_activity, now));
+            if !expired {
+                redis::cmd("UNWATCH").query::<()>(&*self.conn)?;
+                continue;
+            }
+            let deleted: Option<()> =
+                redis::pipe().atomic().cmd("DEL").arg(key.as_str()).ignore().query(&*self.conn)?;
+            if deleted.is_some() {
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+
+    fn is_admin(&self, session_id: &SessionID, user_id: &UserID) -> Result<bool> {
+        self.sismember(&session_id, &user_id, &RedisSet::Admin)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dal::fakeredis;
+    use estimates::audit::AuditAction;
+    use user::Nickname;
+
+    storydata_conformance_tests!(|| {
+        ::dal::RedisDB::new(::dal::fakeredis::shared().fresh_pool().get().unwrap(),
+                            ::config::ExpiryConfig::default(),
+                            ::dal::DEFAULT_REDIS_NAMESPACE.to_string())
+    });
+
+    fn ttl(db: &RedisDB, key: &str) -> i64 {
+        redis::cmd("TTL").arg(key).query(&*db.conn).unwrap()
+    }
+
+    #[test]
+    fn records_expire() {
+        let mut db = RedisDB::new(fakeredis::shared().fresh_pool().get().unwrap(),
+                                  ExpiryConfig::default(),
+                                  DEFAULT_REDIS_NAMESPACE.to_string());
+        let new_user = BasicUser::new();
+        db.add_user(new_user.clone()).unwrap();
+        let my_session = Session::new();
+        db.add_session(my_session.clone()).unwrap();
+        let p = Participant::new(&new_user, my_session.session_id.clone(), Nickname::new("bob"));
+        db.add_participant(p).unwrap();
+        db.add_admin(new_user.user_id.clone(), my_session.session_id.clone()).unwrap();
+        let entry = AuditEntry::new(my_session.session_id.clone(),
+                                    new_user.user_id.clone(),
+                                    AuditAction::UpdatePolicy);
+        db.add_audit_entry(entry).unwrap();
+
+        let session_ttl = ExpiryConfig::default().session_ttl.unwrap().as_secs() as i64;
+        // Users are removed by the sweeper instead, once they have left their sessions
+        let user_key = db.table_key(&new_user.user_id, &RedisTable::User);
+        assert_eq!(ttl(&db, &user_key), -1);
+        let sid = &my_session.session_id;
+        let pkey = participant_key(sid, &new_user.user_id);
+        for key in &[db.table_key(sid, &RedisTable::Session),
+                     db.table_key(&pkey, &RedisTable::Participant),
+                     db.set_key(sid, &RedisSet::ParticipantUID),
+                     db.set_key(sid, &RedisSet::ParticipantName),
+                     db.set_key(sid, &RedisSet::Admin),
+                     db.list_key(sid, &RedisList::Audit)] {
+            let remaining = ttl(&db, key);
+            assert!(remaining > session_ttl - 5 && remaining <= session_ttl,
+                    "{} expires in {}",
+                    key,
+                    remaining);
+        }
+    }
+
+    #[test]
+    fn del_session_leaves_nothing_behind() {
+        let mut db = RedisDB::new(fakeredis::shared().fresh_pool().get().unwrap(),
+                                  ExpiryConfig::default(),
+                                  DEFAULT_REDIS_NAMESPACE.to_string());
+        let new_user = BasicUser::new();
+        let my_session = Session::new();
+        db.add_session(my_session.clone()).unwrap();
+        let p = Participant::new(&new_user, my_session.session_id.clone(), Nickname::new("bob"));
+        db.add_participant(p).unwrap();
+        db.add_admin(new_user.user_id.clone(), my_session.session_id.clone()).unwrap();
+        let entry = AuditEntry::new(my_session.session_id.clone(),
+                                    new_user.user_id.clone(),
+                                    AuditAction::UpdatePolicy);
+        db.add_audit_entry(entry).unwrap();
+
+        db.del_session(&my_session.session_id).unwrap();
+        let keys: Vec<String> = db.conn.keys("*").unwrap();
+        assert_eq!(keys, Vec::<String>::new());
+    }
+
+    #[test]
+    fn del_participant_frees_their_nickname() {
+        let mut db = RedisDB::new(fakeredis::shared().fresh_pool().get().unwrap(),
+                                  ExpiryConfig::default(),
+                                  DEFAULT_REDIS_NAMESPACE.to_string());
+        let new_user = BasicUser::new();
+        let my_session = Session::new();
+        db.add_session(my_session.clone()).unwrap();
+        let p = Participant::new(&new_user, my_session.session_id.clone(), Nickname::new("bob"));
+        db.add_participant(p.clone()).unwrap();
+
+        db.del_participant(&new_user.user_id, &my_session.session_id).unwrap();
+        assert!(!db.sismember(&my_session.session_id, &p.nickname, &RedisSet::ParticipantName)
+            .unwrap());
+    }
+
+    #[test]
+    fn nothing_expires_without_a_ttl() {
+        let expiry = ExpiryConfig {
+            session_ttl: None,
+            user_ttl: None,
+            ..ExpiryConfig::default()
+        };
+        let mut db = RedisDB::new(fakeredis::shared().fresh_pool().get().unwrap(),
+                                  expiry,
+                                  DEFAULT_REDIS_NAMESPACE.to_string());
+        let new_user = BasicUser::new();
+        db.add_user(new_user.clone()).unwrap();
+        db.touch_user(&new_user.user_id).unwrap();
+        assert_eq!(ttl(&db, &db.table_key(&new_user.user_id, &RedisTable::User)), -1);
+    }
+
+    #[test]
+    fn purge_expired_users() {
+        let mut db = RedisDB::new(fakeredis::shared().fresh_pool().get().unwrap(),
+                                  ExpiryConfig::default(),
+                                  DEFAULT_REDIS_NAMESPACE.to_string());
+        let expiry = ExpiryConfig::default();
+        let now = clock::now();
+        let mut idle_user = BasicUser::new();
+        idle_user.last_activity = now - expiry.user_ttl.unwrap().as_secs() - 1;
+        db.add_user(idle_user.clone()).unwrap();
+        let active_user = BasicUser::new();
+        db.add_user(active_user.clone()).unwrap();
+
+        assert_eq!(db.purge_expired(&expiry, now).unwrap(), 1);
+        assert_eq!(db.get_user(&idle_user.user_id).unwrap(), None);
+        assert_eq!(db.get_user(&active_user.user_id).unwrap(), Some(active_user));
+    }
+}
@@ -0,0 +1,225 @@
+//! Versioning of the way data is laid out in Redis
+//!
+//! The version of the data is kept in a key of its own. A change to the keys,
+//! or to how records are serialized, bumps `SCHEMA_VERSION` and adds a
+//! migration that rewrites existing data. Fields added to a record need a
+//! serde default, so that the record can still be read before it is migrated.
+
+use super::{RedisDB, RedisTable};
+use errors::*;
+use estimates::participant::Participant;
+use estimates::session::Session;
+use redis::{self, Commands};
+use serde::{Deserialize, Serialize};
+use serde_json;
+use user::BasicUser;
+
+/// The version of the data written by this code
+pub const SCHEMA_VERSION: u32 = 3;
+
+/// A change to the data, bringing it up to `version`
+struct Migration {
+    version: u32,
+    description: &'static str,
+    /// Returns how many keys were rewritten
+    run: fn(&RedisDB) -> Result<usize>,
+}
+
+static MIGRATIONS: &'static [Migration] = &[
+    Migration {
+        version: 1,
+        description: "store fields filled in by default",
+        run: rewrite_records,
+    },
+    Migration {
+        version: 2,
+        description: "replace user tokens with a hash of them",
+        run: hash_user_tokens,
+    },
+    Migration {
+        version: 3,
+        description: "stop users expiring, the sweeper removes them",
+        run: persist_users,
+    },
+];
+
+/// Read and write back every record, so fields added since it was written are stored
+fn rewrite_records(db: &RedisDB) -> Result<usize> {
+    Ok(db.rewrite_table::<BasicUser>(&RedisTable::User)? +
+       db.rewrite_table::<Session>(&RedisTable::Session)? +
+       db.rewrite_table::<Participant>(&RedisTable::Participant)?)
+}
+
+/// Users stored with their token are given a hash of it when read, store that instead
+fn hash_user_tokens(db: &RedisDB) -> Result<usize> {
+    db.rewrite_table::<BasicUser>(&RedisTable::User)
+}
+
+/// Clear the TTL of every user, so they can leave their sessions before being removed
+fn persist_users(db: &RedisDB) -> Result<usize> {
+    let keys: Vec<String> = db.conn.keys(format!("{}*", db.table_key("", &RedisTable::User)))?;
+    let mut persisted = 0;
+    for key in keys {
+        let cleared: i64 = redis::cmd("PERSIST").arg(key.as_str()).query(&*db.conn)?;
+        persisted += cleared as usize;
+    }
+    Ok(persisted)
+}
+
+impl RedisDB {
+    /// The version of the data, which is 0 from before it was versioned
+    pub fn schema_version(&self) -> Result<u32> {
+        let version: Option<u32> = self.conn.get(self.schema_version_key())?;
+        Ok(version.unwrap_or(0))
+    }
+
+    /// Bring the data up to `SCHEMA_VERSION`, returning how many migrations ran
+    ///
+    /// Migrations work a key at a time and can safely be run again, so the
+    /// server can keep running while they do, and it doesn't matter if two
+    /// servers start them at once.
+    pub fn migrate(&self) -> Result<usize> {
+        let current = self.schema_version()?;
+        if current > SCHEMA_VERSION {
+            bail!(ErrorKind::DataIntegrityError(format!("The data in Redis is schema version \
+                                                         {}, newer than version {} which this \
+                                                         code understands",
+                                                        current,
+                                                        SCHEMA_VERSION)));
+        }
+        let mut ran = 0;
+        for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+            info!("Migrating Redis to schema version {}: {}",
+                  migration.version,
+                  migration.description);
+            let rewritten = (migration.run)(self)?;
+            let _: () = self.conn.set(self.schema_version_key(), migration.version)?;
+            info!("Rewrote {} keys for schema version {}", rewritten, migration.version);
+            ran += 1;
+        }
+        Ok(ran)
+    }
+
+    fn rewrite_table<T>(&self, table: &RedisTable) -> Result<usize>
+        where T: Serialize + Deserialize
+    {
+        let keys: Vec<String> = self.conn.keys(format!("{}*", self.table_key("", table)))?;
+        let mut rewritten = 0;
+        for key in keys {
+            if self.rewrite_record::<T>(&key)? {
+                rewritten += 1;
+            }
+        }
+        Ok(rewritten)
+    }
+
+    /// Decode a record and encode it again, keeping its TTL
+    ///
+    /// Watching the key means a write made in between makes us start over,
+    /// rather than being overwritten with the old value.
+    fn rewrite_record<T>(&self, key: &str) -> Result<bool>
+        where T: Serialize + Deserialize
+    {
+        loop {
+            redis::cmd("WATCH").arg(key).query::<()>(&*self.conn)?;
+            let data: Option<String> = self.conn.get(key)?;
+            let record: T = match data.map(|d| serde_json::from_str(&d)) {
+                Some(Ok(record)) => record,
+                // Expired since it was listed, or undecodable which is for fsck to report
+                _ => {
+                    redis::cmd("UNWATCH").query::<()>(&*self.conn)?;
+                    return Ok(false);
+                },
+            };
+            let ttl: i64 = redis::cmd("TTL").arg(key).query(&*self.conn)?;
+            let mut pipe = redis::pipe();
+            pipe.atomic().cmd("SET").arg(key).arg(serde_json::to_string(&record)?).ignore();
+            if ttl > 0 {
+                pipe.cmd("EXPIRE").arg(key).arg(ttl).ignore();
+            }
+            let written: Option<()> = pipe.query(&*self.conn)?;
+            if written.is_some() {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use config::ExpiryConfig;
+    use dal::DEFAULT_REDIS_NAMESPACE;
+    use dal::{StoryData, fakeredis};
+    use estimates::session::SessionID;
+    use user::{UserID, UserToken};
+
+    fn new_db() -> RedisDB {
+        RedisDB::new(fakeredis::shared().fresh_pool().get().unwrap(),
+                     ExpiryConfig::default(),
+                     DEFAULT_REDIS_NAMESPACE.to_string())
+    }
+
+    #[test]
+    fn migrate_to_the_latest_version() {
+        let mut db = new_db();
+        db.add_session(Session::new()).unwrap();
+        assert_eq!(db.schema_version().unwrap(), 0);
+        assert_eq!(db.migrate().unwrap(), MIGRATIONS.len());
+        assert_eq!(db.schema_version().unwrap(), SCHEMA_VERSION);
+        assert_eq!(db.migrate().unwrap(), 0);
+        assert_eq!(db.check_integrity().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn old_records_store_their_defaults() {
+        let db = new_db();
+        let session_id = SessionID("old".to_string());
+        let key = db.table_key(&session_id, &RedisTable::Session);
+        let _: () = db.conn.set(key.as_str(), r#"{"session_id":"old","average":null}"#).unwrap();
+        let _: () = db.conn.expire(key.as_str(), 100).unwrap();
+
+        db.migrate().unwrap();
+        let data: String = db.conn.get(key.as_str()).unwrap();
+        assert!(data.contains("last_activity"));
+        let ttl: i64 = redis::cmd("TTL").arg(key.as_str()).query(&*db.conn).unwrap();
+        assert!(ttl > 0);
+    }
+
+    #[test]
+    fn migrate_plain_text_tokens() {
+        let db = new_db();
+        let _: () = db.conn.set(db.schema_version_key(), 1).unwrap();
+        let user_id = UserID("bob".to_string());
+        let key = db.table_key(&user_id, &RedisTable::User);
+        let stored = r#"{"user_id":"bob","user_token":"secret"}"#;
+        let _: () = db.conn.set(key.as_str(), stored).unwrap();
+
+        assert_eq!(db.migrate().unwrap(), MIGRATIONS.len() - 1);
+        let data: String = db.conn.get(key.as_str()).unwrap();
+        assert!(!data.contains("secret"));
+        let user = db.get_user(&user_id).unwrap().unwrap();
+        assert!(user.authenticate(&UserToken("secret".to_string())).is_some());
+    }
+
+    #[test]
+    fn users_stop_expiring() {
+        let db = new_db();
+        let _: () = db.conn.set(db.schema_version_key(), 2).unwrap();
+        let user = BasicUser::new();
+        let key = db.table_key(&user.user_id, &RedisTable::User);
+        let _: () = db.conn.set(key.as_str(), serde_json::to_string(&user).unwrap()).unwrap();
+        let _: () = db.conn.expire(key.as_str(), 100).unwrap();
+
+        assert_eq!(db.migrate().unwrap(), 1);
+        let ttl: i64 = redis::cmd("TTL").arg(key.as_str()).query(&*db.conn).unwrap();
+        assert_eq!(ttl, -1);
+    }
+
+    #[test]
+    fn refuse_data_from_newer_code() {
+        let db = new_db();
+        let _: () = db.conn.set(db.schema_version_key(), SCHEMA_VERSION + 1).unwrap();
+        assert!(db.migrate().is_err());
+    }
+}
@@ -1,50 +1,385 @@
-use super::{MemoryDB, RedisDB, StoryDataProvider};
+use super::{MemoryDB, RedisDB, SqliteDB, StoryData, StoryDataProvider};
+use super::expiry;
+use config::{ExpiryConfig, RedisConfig};
+use errors::*;
+use estimates::audit::AuditEntry;
+use estimates::participant::Participant;
+use estimates::session::{Session, SessionID};
 use r2d2;
 use r2d2_redis;
-use std::sync::{Mutex, MutexGuard};
+use service::admin;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use user::{BasicUser, UserID};
 
+/// How many shards the in memory data is split into by default
+const MEMORY_SHARDS: usize = 32;
+
+/// The in memory backend, shared between requests
+///
+/// The data is split into shards, each a `MemoryDB` behind a lock of its own.
+/// A session lives in the shard picked by hashing its ID, together with its
+/// participants and admins, and a user in the shard picked by hashing theirs.
+/// Requests only wait on each other when what they touch shares a shard.
+///
+/// A shard is only locked for a single call, so operations made of several
+/// calls hold the session they work on, see `StoryData::lock_session`.
+#[derive(Clone)]
 pub struct SharedMemoryDB {
-    inner: Mutex<MemoryDB>,
+    shards: Arc<Vec<Mutex<MemoryDB>>>,
+    sessions: Arc<SessionLocks>,
 }
 
+/// A handle to the in memory backend, locking only the shard each call needs
+pub struct SharedMemoryHandle {
+    db: SharedMemoryDB,
+    /// Tells the sessions this handle holds from those others do
+    handle_id: usize,
+}
+
+/// The sessions in the middle of a locked operation, and the handles holding them
+struct SessionLocks {
+    held: Mutex<BTreeMap<SessionID, usize>>,
+    released: Condvar,
+    next_handle_id: AtomicUsize,
+}
+
+/// Holds a session until dropped, which happens even if the operation panics
+pub struct SessionLockGuard {
+    locks: Arc<SessionLocks>,
+    session_id: SessionID,
+}
+
+/// Lock a mutex, even if a thread panicked while holding the lock
+///
+/// `MemoryDB` runs update plans on copies of the records, so a panic can't
+/// leave anything half changed and the data is still fine to use.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        warn!("Recovering the in memory data after a panic");
+        poisoned.into_inner()
+    })
+}
+
+impl Drop for SessionLockGuard {
+    fn drop(&mut self) {
+        lock(&self.locks.held).remove(&self.session_id);
+        self.locks.released.notify_all();
+    }
+}
 
 impl SharedMemoryDB {
     pub fn new() -> Self {
-        SharedMemoryDB { inner: Mutex::new(MemoryDB::new()) }
+        SharedMemoryDB::with_shards(MEMORY_SHARDS)
+    }
+
+    /// Split the data into `shards` parts, one meaning everything shares a single lock
+    pub fn with_shards(shards: usize) -> Self {
+        let shards = (0..cmp::max(shards, 1)).map(|_| Mutex::new(MemoryDB::new())).collect();
+        SharedMemoryDB {
+            shards: Arc::new(shards),
+            sessions: Arc::new(SessionLocks {
+                held: Mutex::new(BTreeMap::new()),
+                released: Condvar::new(),
+                next_handle_id: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    fn shard(&self, key: &str) -> MutexGuard<MemoryDB> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        lock(&self.shards[hasher.finish() as usize % self.shards.len()])
+    }
+
+    /// Start removing idle sessions and users in the background
+    pub fn start_sweeper(&self, expiry: &ExpiryConfig) {
+        let db = self.clone();
+        expiry::sweep_with(expiry.clone(), move |expiry, now| {
+            admin::expire(&mut db.get_story_data()?, expiry, now)
+        });
+    }
+}
+
+impl<'a> StoryDataProvider<'a> for SharedMemoryDB {
+    type StoryData = SharedMemoryHandle;
+
+    fn get_story_data(&'a self) -> Result<Self::StoryData> {
+        Ok(SharedMemoryHandle {
+            db: self.clone(),
+            handle_id: self.sessions.next_handle_id.fetch_add(1, Ordering::SeqCst),
+        })
+    }
+}
+
+impl SharedMemoryHandle {
+    fn user_shard(&self, user_id: &UserID) -> MutexGuard<MemoryDB> {
+        self.db.shard(&user_id.0)
+    }
+
+    fn session_shard(&self, session_id: &SessionID) -> MutexGuard<MemoryDB> {
+        self.db.shard(&session_id.0)
+    }
+
+    /// Wait until no other handle holds the session, then hold it
+    ///
+    /// Returns `None` if this handle already holds it, so nested operations
+    /// release it only when the outermost one finishes.
+    pub fn hold_session(&self, session_id: &SessionID) -> Option<SessionLockGuard> {
+        let locks = &self.db.sessions;
+        let mut held = lock(&locks.held);
+        loop {
+            match held.get(session_id) {
+                Some(holder) if *holder == self.handle_id => return None,
+                Some(_) => {
+                    held = locks.released
+                        .wait(held)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                },
+                None => break,
+            }
+        }
+        held.insert(session_id.clone(), self.handle_id);
+        Some(SessionLockGuard {
+            locks: locks.clone(),
+            session_id: session_id.clone(),
+        })
+    }
+
+    /// Gather the results of `f` from every shard, one shard locked at a time
+    fn every_shard<T, F>(&self, mut f: F) -> Result<Vec<T>>
+        where F: FnMut(&mut MemoryDB) -> Result<Vec<T>>
+    {
+        let mut results = Vec::new();
+        for shard in self.db.shards.iter() {
+            results.extend(f(&mut lock(shard))?);
+        }
+        Ok(results)
+    }
+}
+
+impl StoryData for SharedMemoryHandle {
+    fn get_user(&self, user_id: &UserID) -> Result<Option<BasicUser>> {
+        self.user_shard(user_id).get_user(user_id)
     }
-    pub fn get(&self) -> MutexGuard<MemoryDB> {
-        self.inner.lock().unwrap()
+    fn add_user(&mut self, user: BasicUser) -> Result<()> {
+        let mut shard = self.user_shard(&user.user_id);
+        shard.add_user(user)
     }
+    fn del_user(&mut self, user_id: &UserID) -> Result<()> {
+        self.user_shard(user_id).del_user(user_id)
+    }
+    fn list_users(&self) -> Result<Vec<UserID>> {
+        let mut users = self.every_shard(|db| db.list_users())?;
+        users.sort();
+        Ok(users)
+    }
+    fn update_user<F>(&mut self, user_id: &UserID, plan: F) -> Result<()>
+        where F: FnMut(&mut BasicUser) -> Result<()>
+    {
+        self.user_shard(user_id).update_user(user_id, plan)
+    }
+
+    fn get_session(&self, session_id: &SessionID) -> Result<Option<Session>> {
+        self.session_shard(session_id).get_session(session_id)
+    }
+    fn list_sessions(&self) -> Result<Vec<SessionID>> {
+        let mut sessions = self.every_shard(|db| db.list_sessions())?;
+        sessions.sort();
+        Ok(sessions)
+    }
+    fn add_session(&mut self, session: Session) -> Result<()> {
+        let mut shard = self.session_shard(&session.session_id);
+        shard.add_session(session)
+    }
+    fn del_session(&mut self, session_id: &SessionID) -> Result<()> {
+        self.session_shard(session_id).del_session(session_id)
+    }
+    fn update_session<F>(&mut self, session_id: &SessionID, plan: F) -> Result<()>
+        where F: FnMut(&mut Session, &mut Vec<Participant>) -> Result<()>
+    {
+        self.session_shard(session_id).update_session(session_id, plan)
+    }
+
+    fn get_participants(&self, session_id: &SessionID) -> Result<Vec<Participant>> {
+        self.session_shard(session_id).get_participants(session_id)
+    }
+    fn add_participant(&mut self, participant: Participant) -> Result<()> {
+        let mut shard = self.session_shard(&participant.session_id);
+        shard.add_participant(participant)
+    }
+    fn del_participant(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<()> {
+        self.session_shard(session_id).del_participant(user_id, session_id)
+    }
+    fn update_participant<F>(
+        &mut self,
+        session_id: &SessionID,
+        user_id: &UserID,
+        plan: F
+    ) -> Result<()>
+        where F: FnMut(&mut Participant) -> Result<()>
+    {
+        self.session_shard(session_id).update_participant(session_id, user_id, plan)
+    }
+
+    fn get_admins(&self, session_id: &SessionID) -> Result<Vec<UserID>> {
+        self.session_shard(session_id).get_admins(session_id)
+    }
+    fn add_admin(&mut self, user_id: UserID, session_id: SessionID) -> Result<()> {
+        let mut shard = self.session_shard(&session_id);
+        shard.add_admin(user_id, session_id)
+    }
+    fn del_admin(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<()> {
+        self.session_shard(session_id).del_admin(user_id, session_id)
+    }
+
+    fn get_audit_log(&self, session_id: &SessionID) -> Result<Vec<AuditEntry>> {
+        self.session_shard(session_id).get_audit_log(session_id)
+    }
+    fn add_audit_entry(&mut self, entry: AuditEntry) -> Result<()> {
+        let mut shard = self.session_shard(&entry.session_id);
+        shard.add_audit_entry(entry)
+    }
+    fn get_tombstones(&self) -> Result<Vec<AuditEntry>> {
+        let mut tombstones = self.every_shard(|db| db.get_tombstones())?;
+        tombstones.sort_by_key(|entry| entry.timestamp);
+        Ok(tombstones)
+    }
+    fn add_tombstone(&mut self, entry: AuditEntry) -> Result<()> {
+        let mut shard = self.session_shard(&entry.session_id);
+        shard.add_tombstone(entry)
+    }
+
+    fn touch_user(&mut self, user_id: &UserID) -> Result<()> {
+        self.user_shard(user_id).touch_user(user_id)
+    }
+    fn purge_expired(&mut self, expiry: &ExpiryConfig, now: u64) -> Result<usize> {
+        let purged = self.every_shard(|db| db.purge_expired(expiry, now).map(|n| vec![n]))?;
+        Ok(purged.iter().sum())
+    }
+
+    fn lock_session<T, F>(&mut self, session_id: &SessionID, operation: F) -> Result<T>
+        where F: FnOnce(&mut Self) -> Result<T>
+    {
+        let _held = self.hold_session(session_id);
+        operation(self)
+    }
+}
+
+/// A single SQLite connection shared between all requests
+///
+/// SQLite only allows one writer at a time anyway, so there is little to
+/// gain from a pool of connections.
+pub struct SharedSqliteDB {
+    inner: Arc<Mutex<SqliteDB>>,
 }
 
-impl StoryDataProvider for SharedMemoryDB {}
-//     type D = MemoryDB;
+impl SharedSqliteDB {
+    pub fn open<P>(path: P) -> Result<Self>
+        where P: AsRef<Path>
+    {
+        Ok(SharedSqliteDB { inner: Arc::new(Mutex::new(SqliteDB::open(path)?)) })
+    }
 
-//     fn get_story_data<'a>(&'a mut self) -> &'a mut Self::D {
-//         self.inner.lock().unwrap()
-//     }
-// }
+    /// Start removing idle sessions and users in the background
+    pub fn start_sweeper(&self, expiry: &ExpiryConfig) {
+        expiry::start_sweeper(self.inner.clone(), expiry.clone());
+    }
+}
+
+impl<'a> StoryDataProvider<'a> for SharedSqliteDB {
+    type StoryData = MutexGuard<'a, SqliteDB>;
+
+    fn get_story_data(&'a self) -> Result<Self::StoryData> {
+        self.inner
+            .lock()
+            .map_err(|_| {
+                ErrorKind::BackendUnavailable("The SQLite database lock was poisoned".to_string())
+                    .into()
+            })
+    }
+}
 
 type RedisPool = r2d2::Pool<r2d2_redis::RedisConnectionManager>;
 
 pub struct RedisDBManager {
     inner: RedisPool,
+    expiry: ExpiryConfig,
+    namespace: String,
 }
 
 pub struct RedisDBInstance(RedisDB);
 
 
 impl RedisDBManager {
-    pub fn new(pool: RedisPool) -> Self {
-        RedisDBManager { inner: pool }
+    pub fn new(pool: RedisPool, expiry: ExpiryConfig, namespace: String) -> Self {
+        RedisDBManager {
+            inner: pool,
+            expiry: expiry,
+            namespace: namespace,
+        }
     }
-    pub fn get(&self) -> RedisDBInstance {
-        let conn = self.inner.get().unwrap();
-        RedisDBInstance(RedisDB::new(conn))
+
+    /// Connect to the Redis server described by the configuration
+    pub fn connect(redis: RedisConfig, expiry: ExpiryConfig) -> Result<Self> {
+        info!("Creating Redis Pool ({}x -> {:?}, db {})",
+              redis.pool_size,
+              redis.connection.addr,
+              redis.connection.db);
+        let pool_config = r2d2::Config::builder().pool_size(redis.pool_size).build();
+        let manager = r2d2_redis::RedisConnectionManager::new(redis.connection)?;
+        let pool = r2d2::Pool::new(pool_config, manager)
+            .chain_err(|| "Could not connect to Redis")?;
+        Ok(RedisDBManager::new(pool, expiry, redis.namespace))
+    }
+
+    /// The data of a tenant, kept under its own namespace on the same connections
+    ///
+    /// Namespaces and tenant names can't contain ':', so joining them with it
+    /// can't make the namespace of another deployment or tenant.
+    pub fn tenant(&self, name: &str) -> RedisDBManager {
+        RedisDBManager::new(self.inner.clone(),
+                            self.expiry.clone(),
+                            format!("{}:{}", self.namespace, name))
+    }
+
+    /// Bring the data in Redis up to the current schema version
+    pub fn migrate(&self) -> Result<usize> {
+        self.get_story_data()?.migrate()
+    }
+
+    /// Start removing idle users in the background, Redis expires sessions by itself
+    ///
+    /// Users don't expire in Redis, so that they leave their sessions first.
+    pub fn start_sweeper(&self, expiry: &ExpiryConfig) {
+        let db = RedisDBManager::new(self.inner.clone(),
+                                     self.expiry.clone(),
+                                     self.namespace.clone());
+        expiry::sweep_with(expiry.clone(), move |expiry, now| {
+            admin::expire(&mut *db.get_story_data()?, expiry, now)
+        });
+    }
+}
+
+impl<'a> StoryDataProvider<'a> for RedisDBManager {
+    type StoryData = RedisDBInstance;
+
+    fn get_story_data(&'a self) -> Result<Self::StoryData> {
+        let conn = self.inner
+            .get()
+            .chain_err(|| {
+                ErrorKind::BackendUnavailable("Could not get a Redis connection from the pool"
+                    .to_string())
+            })?;
+        Ok(RedisDBInstance(RedisDB::new(conn, self.expiry.clone(), self.namespace.clone())))
     }
 }
 
-impl StoryDataProvider for RedisDBManager {}
 use std::ops::{Deref, DerefMut};
 
 impl Deref for RedisDBInstance {
@@ -60,3 +395,230 @@ impl DerefMut for RedisDBInstance {
         &mut self.0
     }
 }
+
+impl StoryData for RedisDBInstance {
+    fn get_user(&self, user_id: &UserID) -> Result<Option<BasicUser>> {
+        self.0.get_user(user_id)
+    }
+    fn add_user(&mut self, user: BasicUser) -> Result<()> {
+        self.0.add_user(user)
+    }
+    fn del_user(&mut self, user_id: &UserID) -> Result<()> {
+        self.0.del_user(user_id)
+    }
+    fn list_users(&self) -> Result<Vec<UserID>> {
+        self.0.list_users()
+    }
+    fn update_user<F>(&mut self, user_id: &UserID, plan: F) -> Result<()>
+        where F: FnMut(&mut BasicUser) -> Result<()>
+    {
+        self.0.update_user(user_id, plan)
+    }
+
+    fn get_session(&self, session_id: &SessionID) -> Result<Option<Session>> {
+        self.0.get_session(session_id)
+    }
+    fn list_sessions(&self) -> Result<Vec<SessionID>> {
+        self.0.list_sessions()
+    }
+    fn add_session(&mut self, session: Session) -> Result<()> {
+        self.0.add_session(session)
+    }
+    fn del_session(&mut self, session_id: &SessionID) -> Result<()> {
+        self.0.del_session(session_id)
+    }
+    fn update_session<F>(&mut self, session_id: &SessionID, plan: F) -> Result<()>
+        where F: FnMut(&mut Session, &mut Vec<Participant>) -> Result<()>
+    {
+        self.0.update_session(session_id, plan)
+    }
+
+    fn get_participants(&self, session_id: &SessionID) -> Result<Vec<Participant>> {
+        self.0.get_participants(session_id)
+    }
+    fn add_participant(&mut self, participant: Participant) -> Result<()> {
+        self.0.add_participant(participant)
+    }
+    fn del_participant(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<()> {
+        self.0.del_participant(user_id, session_id)
+    }
+    fn update_participant<F>(
+        &mut self,
+        session_id: &SessionID,
+        user_id: &UserID,
+        plan: F
+    ) -> Result<()>
+        where F: FnMut(&mut Participant) -> Result<()>
+    {
+        self.0.update_participant(session_id, user_id, plan)
+    }
+    fn get_admins(&self, session_id: &SessionID) -> Result<Vec<UserID>> {
+        self.0.get_admins(session_id)
+    }
+    fn add_admin(&mut self, user_id: UserID, session_id: SessionID) -> Result<()> {
+        self.0.add_admin(user_id, session_id)
+    }
+    fn del_admin(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<()> {
+        self.0.del_admin(user_id, session_id)
+    }
+
+    fn get_audit_log(&self, session_id: &SessionID) -> Result<Vec<AuditEntry>> {
+        self.0.get_audit_log(session_id)
+    }
+    fn add_audit_entry(&mut self, entry: AuditEntry) -> Result<()> {
+        self.0.add_audit_entry(entry)
+    }
+    fn get_tombstones(&self) -> Result<Vec<AuditEntry>> {
+        self.0.get_tombstones()
+    }
+    fn add_tombstone(&mut self, entry: AuditEntry) -> Result<()> {
+        self.0.add_tombstone(entry)
+    }
+
+    fn touch_user(&mut self, user_id: &UserID) -> Result<()> {
+        self.0.touch_user(user_id)
+    }
+    fn purge_expired(&mut self, expiry: &ExpiryConfig, now: u64) -> Result<usize> {
+        self.0.purge_expired(expiry, now)
+    }
+
+    fn is_admin(&self, session_id: &SessionID, user_id: &UserID) -> Result<bool> {
+        self.0.is_admin(session_id, user_id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dal::DEFAULT_REDIS_NAMESPACE;
+    use dal::fakeredis;
+    use std::thread;
+
+    // Few shards, so that listing has to gather records from several of them
+    storydata_conformance_tests!(|| {
+        let db = ::dal::SharedMemoryDB::with_shards(4);
+        ::dal::StoryDataProvider::get_story_data(&db).unwrap()
+    });
+
+    #[test]
+    fn memory_db_recovers_from_a_panic() {
+        let shared = SharedMemoryDB::new();
+        let my_session = Session::new();
+        shared.get_story_data().unwrap().add_session(my_session.clone()).unwrap();
+        let panicker = shared.clone();
+        let session_id = my_session.session_id.clone();
+        let outcome = thread::spawn(move || {
+                panicker.get_story_data()
+                    .unwrap()
+                    .update_session(&session_id, |s, _| {
+                        s.average = Some(3.0);
+                        panic!("poison the lock");
+                    })
+            })
+            .join();
+        assert!(outcome.is_err());
+
+        // The half finished update was thrown away, and the session can still be changed
+        let mut dal = shared.get_story_data().unwrap();
+        assert_eq!(dal.get_session(&my_session.session_id).unwrap().unwrap().average, None);
+        dal.update_session(&my_session.session_id, |s, _| {
+                s.average = Some(2.0);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(dal.get_session(&my_session.session_id).unwrap().unwrap().average,
+                   Some(2.0));
+    }
+
+    #[test]
+    fn locked_sessions_keep_other_handles_out() {
+        let shared = SharedMemoryDB::new();
+        let my_session = Session::new();
+        let session_id = my_session.session_id.clone();
+        let mut dal = shared.get_story_data().unwrap();
+        dal.add_session(my_session).unwrap();
+
+        let other = shared.clone();
+        let waiting = dal.lock_session(&session_id, |dal| {
+                // Locking the session again through the same handle doesn't wait
+                dal.lock_session(&session_id, |_| Ok(()))?;
+                let their_id = session_id.clone();
+                let waiting = thread::spawn(move || {
+                    other.get_story_data()
+                        .unwrap()
+                        .lock_session(&their_id, |dal| dal.get_session(&their_id))
+                });
+                thread::sleep(::std::time::Duration::from_millis(50));
+                dal.update_session(&session_id, |s, _| {
+                    s.average = Some(5.0);
+                    Ok(())
+                })?;
+                Ok(waiting)
+            })
+            .unwrap();
+        // The other handle only got in once the first let go, so saw the change
+        let seen = waiting.join().unwrap().unwrap().unwrap();
+        assert_eq!(seen.average, Some(5.0));
+    }
+
+    #[test]
+    fn memory_shards_are_listed_together() {
+        let mut dal = SharedMemoryDB::with_shards(8).get_story_data().unwrap();
+        let mut session_ids = Vec::new();
+        for _ in 0..20 {
+            let my_session = Session::new();
+            session_ids.push(my_session.session_id.clone());
+            dal.add_session(my_session).unwrap();
+        }
+        session_ids.sort();
+        assert_eq!(dal.list_sessions().unwrap(), session_ids);
+    }
+
+    #[test]
+    fn redis_handles_share_data() {
+        let manager = RedisDBManager::new(fakeredis::shared().fresh_pool(),
+                                          ExpiryConfig::default(),
+                                          DEFAULT_REDIS_NAMESPACE.to_string());
+        let my_user = BasicUser::new();
+        manager.get_story_data().unwrap().add_user(my_user.clone()).unwrap();
+        let same_user = manager.get_story_data().unwrap().get_user(&my_user.user_id).unwrap();
+        assert_eq!(same_user, Some(my_user));
+    }
+
+    #[test]
+    fn redis_handles_return_to_the_pool() {
+        // The pool only holds one connection, so each handle must give it back
+        let manager = RedisDBManager::new(fakeredis::shared().fresh_pool(),
+                                          ExpiryConfig::default(),
+                                          DEFAULT_REDIS_NAMESPACE.to_string());
+        for _ in 0..4 {
+            let dal = manager.get_story_data().unwrap();
+            assert_eq!(dal.get_user(&BasicUser::new().user_id).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn redis_tenants_do_not_share_data() {
+        let manager = RedisDBManager::new(fakeredis::shared().fresh_pool(),
+                                          ExpiryConfig::default(),
+                                          DEFAULT_REDIS_NAMESPACE.to_string());
+        let tenant = manager.tenant("acme");
+        let my_user = BasicUser::new();
+        tenant.get_story_data().unwrap().add_user(my_user.clone()).unwrap();
+        assert_eq!(manager.get_story_data().unwrap().list_users().unwrap(), vec![]);
+        assert_eq!(manager.tenant("globex").get_story_data().unwrap().list_users().unwrap(),
+                   vec![]);
+        assert_eq!(tenant.get_story_data().unwrap().list_users().unwrap(),
+                   vec![my_user.user_id]);
+    }
+
+    #[test]
+    fn redis_tenants_do_not_share_data_with_other_namespaces() {
+        let pool = fakeredis::shared().fresh_pool();
+        let prod = RedisDBManager::new(pool.clone(), ExpiryConfig::default(), "prod".to_string());
+        let prod_x = RedisDBManager::new(pool, ExpiryConfig::default(), "prod-x".to_string());
+        let my_user = BasicUser::new();
+        prod.tenant("x").get_story_data().unwrap().add_user(my_user).unwrap();
+        assert_eq!(prod_x.get_story_data().unwrap().list_users().unwrap(), vec![]);
+    }
+}
@@ -0,0 +1,537 @@
+use super::StoryData;
+use super::expiry::has_expired;
+use config::ExpiryConfig;
+use errors::*;
+use estimates::audit::AuditEntry;
+use estimates::participant::Participant;
+use estimates::session::{Session, SessionID};
+use rusqlite::{self, Connection};
+use rusqlite::types::ToSql;
+use serde::{Deserialize, Serialize};
+use serde_json;
+use std::path::Path;
+use user::{BasicUser, User, UserID};
+use util::clock;
+
+/// A change to the schema, or to the records already stored
+enum Migration {
+    Sql(&'static str),
+    Code(fn(&Connection) -> Result<()>),
+}
+
+/// Schema changes, applied in order at startup
+///
+/// The number of migrations already applied is kept in the database's
+/// `user_version`, so entries must only ever be appended to this list.
+/// Records are stored as JSON (like the Redis backend), with the IDs pulled
+/// out into their own columns so they can be looked up and joined on.
+const MIGRATIONS: &'static [Migration] = &[Migration::Sql("
+    CREATE TABLE users (
+        user_id TEXT NOT NULL PRIMARY KEY,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE sessions (
+        session_id TEXT NOT NULL PRIMARY KEY,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE participants (
+        session_id TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        data TEXT NOT NULL,
+        PRIMARY KEY (session_id, user_id)
+    );
+    CREATE TABLE admins (
+        session_id TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        PRIMARY KEY (session_id, user_id)
+    );
+"),
+                                           Migration::Sql("
+    CREATE TABLE audit (
+        session_id TEXT NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX audit_session ON audit (session_id);
+"),
+                                           Migration::Code(hash_user_tokens),
+                                           Migration::Sql("
+    CREATE TABLE tombstones (
+        session_id TEXT NOT NULL,
+        data TEXT NOT NULL
+    );
+")];
+
+/// Store a hash of the token of users stored with the token itself
+///
+/// Such users are given the hash when they're read, which only needs writing back.
+fn hash_user_tokens(conn: &Connection) -> Result<()> {
+    let unhashed = query_all_data(conn,
+                                  "SELECT data FROM users WHERE data LIKE '%\"user_token\"%'",
+                                  &[])?;
+    info!("Hashing the tokens of {} users", unhashed.len());
+    for data in unhashed {
+        let user: BasicUser = decode(&data)?;
+        conn.execute("UPDATE users SET data = ?2 WHERE user_id = ?1",
+                     &[&user.user_id().0, &encode(&user)?])?;
+    }
+    Ok(())
+}
+
+/// A durable database of story entities kept in a single SQLite file
+pub struct SqliteDB {
+    conn: Connection,
+}
+
+impl SqliteDB {
+    pub fn open<P>(path: P) -> Result<Self>
+        where P: AsRef<Path>
+    {
+        SqliteDB::from_connection(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        SqliteDB::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(conn: Connection) -> Result<Self> {
+        let mut db = SqliteDB { conn: conn };
+        db.migrate()?;
+        Ok(db)
+    }
+
+    /// Apply any migrations this database hasn't seen yet
+    fn migrate(&mut self) -> Result<()> {
+        let applied: i64 = self.conn.query_row("PRAGMA user_version", &[], |row| row.get(0))?;
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(applied as usize) {
+            info!("Applying SQLite schema migration {}", index + 1);
+            let tx = self.conn.transaction()?;
+            match *migration {
+                Migration::Sql(sql) => tx.execute_batch(sql)?,
+                Migration::Code(apply) => apply(&tx)?,
+            }
+            tx.execute_batch(&format!("PRAGMA user_version = {}", index + 1))?;
+            tx.commit()?;
+        }
+        Ok(())
+    }
+}
+
+fn encode<T>(value: &T) -> Result<String>
+    where T: Serialize
+{
+    serde_json::to_string(value).map_err(|e| e.into())
+}
+
+fn decode<T>(data: &str) -> Result<T>
+    where T: Deserialize
+{
+    serde_json::from_str(data).map_err(|e| e.into())
+}
+
+/// Run a query for a single `data` column, which may not exist
+fn query_data(conn: &Connection, sql: &str, params: &[&ToSql]) -> Result<Option<String>> {
+    match conn.query_row(sql, params, |row| -> String { row.get(0) }) {
+        Ok(data) => Ok(Some(data)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Run a query for many `data` columns, in the order they were inserted
+fn query_all_data(conn: &Connection, sql: &str, params: &[&ToSql]) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(sql)?;
+    let rows = stmt.query_map(params, |row| -> String { row.get(0) })?;
+    let mut all_data = Vec::new();
+    for data in rows {
+        all_data.push(data?);
+    }
+    Ok(all_data)
+}
+
+fn read_session(conn: &Connection, session_id: &SessionID) -> Result<Option<Session>> {
+    match query_data(conn,
+                     "SELECT data FROM sessions WHERE session_id = ?1",
+                     &[&session_id.0])? {
+        Some(data) => decode(&data).map(|s| Some(s)),
+        None => Ok(None),
+    }
+}
+
+fn read_participants(conn: &Connection, session_id: &SessionID) -> Result<Vec<Participant>> {
+    query_all_data(conn,
+                   "SELECT data FROM participants WHERE session_id = ?1 ORDER BY user_id",
+                   &[&session_id.0])?
+        .iter()
+        .map(|data| decode(data))
+        .collect()
+}
+
+fn read_participant(
+    conn: &Connection,
+    session_id: &SessionID,
+    user_id: &UserID
+) -> Result<Option<Participant>> {
+    match query_data(conn,
+                     "SELECT data FROM participants WHERE session_id = ?1 AND user_id = ?2",
+                     &[&session_id.0, &user_id.0])? {
+        Some(data) => decode(&data).map(|p| Some(p)),
+        None => Ok(None),
+    }
+}
+
+fn write_session(conn: &Connection, session: &Session) -> Result<()> {
+    conn.execute("INSERT OR REPLACE INTO sessions (session_id, data) VALUES (?1, ?2)",
+                 &[&session.session_id.0, &encode(session)?])?;
+    Ok(())
+}
+
+/// Delete a session and every row belonging to it, returning whether it existed
+///
+/// Any table added for per-session data must be cleared here too.
+fn delete_session_rows(conn: &Connection, session_id: &SessionID) -> Result<bool> {
+    conn.execute("DELETE FROM participants WHERE session_id = ?1", &[&session_id.0])?;
+    conn.execute("DELETE FROM admins WHERE session_id = ?1", &[&session_id.0])?;
+    conn.execute("DELETE FROM audit WHERE session_id = ?1", &[&session_id.0])?;
+    let removed = conn.execute("DELETE FROM sessions WHERE session_id = ?1", &[&session_id.0])?;
+    Ok(removed > 0)
+}
+
+/// Note that the session was just used, if it exists
+fn touch_session(conn: &Connection, session_id: &SessionID) -> Result<()> {
+    if let Some(mut session) = read_session(conn, session_id)? {
+        session.last_activity = clock::now();
+        write_session(conn, &session)?;
+    }
+    Ok(())
+}
+
+/// Overwrite an existing participant, leaving missing ones missing
+fn write_participant(conn: &Connection, participant: &Participant) -> Result<()> {
+    conn.execute("UPDATE participants SET data = ?3 WHERE session_id = ?1 AND user_id = ?2",
+                 &[&participant.session_id.0, &participant.user_id.0, &encode(participant)?])?;
+    Ok(())
+}
+
+/// Make the participants of a session exactly those given, adding and removing rows as needed
+fn replace_participants(
+    conn: &Connection,
+    session_id: &SessionID,
+    participants: &[Participant]
+) -> Result<()> {
+    conn.execute("DELETE FROM participants WHERE session_id = ?1", &[&session_id.0])?;
+    for participant in participants {
+        conn.execute("INSERT INTO participants (session_id, user_id, data) VALUES (?1, ?2, ?3)",
+                     &[&session_id.0, &participant.user_id.0, &encode(participant)?])?;
+    }
+    Ok(())
+}
+
+impl StoryData for SqliteDB {
+    fn get_user(&self, user_id: &UserID) -> Result<Option<BasicUser>> {
+        match query_data(&self.conn,
+                         "SELECT data FROM users WHERE user_id = ?1",
+                         &[&user_id.0])? {
+            Some(data) => decode(&data).map(|u| Some(u)),
+            None => Ok(None),
+        }
+    }
+    fn add_user(&mut self, user: BasicUser) -> Result<()> {
+        self.conn
+            .execute("INSERT OR REPLACE INTO users (user_id, data) VALUES (?1, ?2)",
+                     &[&user.user_id().0, &encode(&user)?])?;
+        Ok(())
+    }
+    fn del_user(&mut self, user_id: &UserID) -> Result<()> {
+        let removed = self.conn.execute("DELETE FROM users WHERE user_id = ?1", &[&user_id.0])?;
+        if removed == 0 {
+            bail!(ErrorKind::ObjectNotFound(format!("Could not find user: {:?}", user_id)));
+        }
+        Ok(())
+    }
+    fn list_users(&self) -> Result<Vec<UserID>> {
+        let ids = query_all_data(&self.conn, "SELECT user_id FROM users ORDER BY user_id", &[])?;
+        Ok(ids.into_iter().map(UserID).collect())
+    }
+    fn update_user<F>(&mut self, user_id: &UserID, mut plan: F) -> Result<()>
+        where F: FnMut(&mut BasicUser) -> Result<()>
+    {
+        let tx = self.conn.transaction()?;
+        let mut user: BasicUser =
+            match query_data(&tx, "SELECT data FROM users WHERE user_id = ?1", &[&user_id.0])? {
+                Some(data) => decode(&data)?,
+                None => {
+                    bail!(ErrorKind::ObjectNotFound(format!("Could not find user: {:?}", user_id)))
+                },
+            };
+        plan(&mut user)?;
+        tx.execute("UPDATE users SET data = ?2 WHERE user_id = ?1",
+                   &[&user_id.0, &encode(&user)?])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_session(&self, session_id: &SessionID) -> Result<Option<Session>> {
+        read_session(&self.conn, session_id)
+    }
+    fn list_sessions(&self) -> Result<Vec<SessionID>> {
+        let ids = query_all_data(&self.conn,
+                                 "SELECT session_id FROM sessions ORDER BY session_id",
+                                 &[])?;
+        Ok(ids.into_iter().map(SessionID).collect())
+    }
+    fn add_session(&mut self, session: Session) -> Result<()> {
+        write_session(&self.conn, &session)
+    }
+    fn del_session(&mut self, session_id: &SessionID) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        if !delete_session_rows(&tx, session_id)? {
+            bail!(ErrorKind::ObjectNotFound(format!("Could not find session: {:?}", session_id)));
+        }
+        tx.commit()?;
+        Ok(())
+    }
+    fn update_session<F>(&mut self, session_id: &SessionID, mut plan: F) -> Result<()>
+        where F: FnMut(&mut Session, &mut Vec<Participant>) -> Result<()>
+    {
+        let tx = self.conn.transaction()?;
+        let mut session = read_session(&tx, session_id)?
+            .ok_or(ErrorKind::ObjectNotFound(format!("Could not find session: {:?}",
+                                                     session_id)))?;
+        let mut participants = read_participants(&tx, session_id)?;
+        session.last_activity = clock::now();
+        plan(&mut session, &mut participants)?;
+        write_session(&tx, &session)?;
+        replace_participants(&tx, session_id, &participants)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_participants(&self, session_id: &SessionID) -> Result<Vec<Participant>> {
+        read_participants(&self.conn, session_id)
+    }
+    // TODO These should get error codes
+    fn add_participant(&mut self, participant: Participant) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        if read_participant(&tx, &participant.session_id, &participant.user_id)?.is_some() {
+            bail!(ErrorKind::UserError("That user is already part of this session".to_string()))
+        }
+        tx.execute("INSERT INTO participants (session_id, user_id, data) VALUES (?1, ?2, ?3)",
+                   &[&participant.session_id.0, &participant.user_id.0, &encode(&participant)?])?;
+        touch_session(&tx, &participant.session_id)?;
+        tx.commit()?;
+        Ok(())
+    }
+    fn del_participant(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        let removed = tx.execute("DELETE FROM participants WHERE session_id = ?1 AND user_id = ?2",
+                     &[&session_id.0, &user_id.0])?;
+        if removed == 0 {
+            bail!(ErrorKind::ObjectNotFound(format!("User {} was not a member of session {}",
+                                                    user_id,
+                                                    session_id)));
+        }
+        touch_session(&tx, session_id)?;
+        tx.commit()?;
+        Ok(())
+    }
+    fn update_participant<F>(
+        &mut self,
+        session_id: &SessionID,
+        user_id: &UserID,
+        mut plan: F
+    ) -> Result<()>
+        where F: FnMut(&mut Participant) -> Result<()>
+    {
+        let tx = self.conn.transaction()?;
+        let mut participant = read_participant(&tx, session_id, user_id)?
+            .ok_or(ErrorKind::ObjectNotFound(format!("Could not find user {:?} in session {:?}",
+                                                     user_id,
+                                                     session_id)))?;
+        plan(&mut participant)?;
+        write_participant(&tx, &participant)?;
+        touch_session(&tx, session_id)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_admins(&self, session_id: &SessionID) -> Result<Vec<UserID>> {
+        Ok(query_all_data(&self.conn,
+                          "SELECT user_id FROM admins WHERE session_id = ?1 ORDER BY user_id",
+                          &[&session_id.0])?
+            .into_iter()
+            .map(|user_id| UserID(user_id))
+            .collect())
+    }
+    fn add_admin(&mut self, user_id: UserID, session_id: SessionID) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        let added = tx.execute("INSERT OR IGNORE INTO admins (session_id, user_id) VALUES (?1, ?2)",
+                     &[&session_id.0, &user_id.0])?;
+        if added == 0 {
+            bail!(ErrorKind::UserError("That user is already an admin of this session"
+                .to_string()))
+        }
+        touch_session(&tx, &session_id)?;
+        tx.commit()?;
+        Ok(())
+    }
+    fn del_admin(&mut self, user_id: &UserID, session_id: &SessionID) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        let removed = tx.execute("DELETE FROM admins WHERE session_id = ?1 AND user_id = ?2",
+                     &[&session_id.0, &user_id.0])?;
+        if removed == 0 {
+            bail!(ErrorKind::UserError(format!("User {} was not an admin of session {}",
+                                               user_id,
+                                               session_id)));
+        }
+        touch_session(&tx, session_id)?;
+        tx.commit()?;
+        Ok(())
+    }
+    fn is_admin(&self, session_id: &SessionID, user_id: &UserID) -> Result<bool> {
+        Ok(query_data(&self.conn,
+                      "SELECT user_id FROM admins WHERE session_id = ?1 AND user_id = ?2",
+                      &[&session_id.0, &user_id.0])?
+            .is_some())
+    }
+
+    fn get_audit_log(&self, session_id: &SessionID) -> Result<Vec<AuditEntry>> {
+        query_all_data(&self.conn,
+                       "SELECT data FROM audit WHERE session_id = ?1 ORDER BY rowid",
+                       &[&session_id.0])?
+            .iter()
+            .map(|data| decode(data))
+            .collect()
+    }
+    fn add_audit_entry(&mut self, entry: AuditEntry) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        if read_session(&tx, &entry.session_id)?.is_none() {
+            bail!(ErrorKind::ObjectNotFound(format!("Could not find session: {:?}",
+                                                    entry.session_id)));
+        }
+        tx.execute("INSERT INTO audit (session_id, data) VALUES (?1, ?2)",
+                   &[&entry.session_id.0, &encode(&entry)?])?;
+        tx.commit()?;
+        Ok(())
+    }
+    fn get_tombstones(&self) -> Result<Vec<AuditEntry>> {
+        query_all_data(&self.conn, "SELECT data FROM tombstones ORDER BY rowid", &[])?
+            .iter()
+            .map(|data| decode(data))
+            .collect()
+    }
+    fn add_tombstone(&mut self, entry: AuditEntry) -> Result<()> {
+        self.conn.execute("INSERT INTO tombstones (session_id, data) VALUES (?1, ?2)",
+                          &[&entry.session_id.0, &encode(&entry)?])?;
+        Ok(())
+    }
+
+    fn touch_user(&mut self, user_id: &UserID) -> Result<()> {
+        self.update_user(user_id, |user| {
+            user.last_activity = clock::now();
+            Ok(())
+        })
+    }
+    fn purge_expired(&mut self, expiry: &ExpiryConfig, now: u64) -> Result<usize> {
+        // Activity is only recorded inside the JSON, so every record is read back
+        let tx = self.conn.transaction()?;
+        let mut purged = 0;
+        for data in query_all_data(&tx, "SELECT data FROM sessions", &[])? {
+            let session: Session = decode(&data)?;
+            if has_expired(expiry.session_ttl, session.last_activity, now) {
+                delete_session_rows(&tx, &session.session_id)?;
+                purged += 1;
+            }
+        }
+        for data in query_all_data(&tx, "SELECT data FROM users", &[])? {
+            let user: BasicUser = decode(&data)?;
+            if has_expired(expiry.user_ttl, user.last_activity, now) {
+                tx.execute("DELETE FROM users WHERE user_id = ?1", &[&user.user_id.0])?;
+                purged += 1;
+            }
+        }
+        tx.commit()?;
+        Ok(purged)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use user::UserToken;
+
+    #[test]
+    fn migrations_are_idempotent() {
+        let mut sqlitedal = SqliteDB::open_in_memory().unwrap();
+        let my_user = BasicUser::new();
+        sqlitedal.add_user(my_user.clone()).unwrap();
+        sqlitedal.migrate().unwrap();
+        let applied: i64 = sqlitedal.conn
+            .query_row("PRAGMA user_version", &[], |row| row.get(0))
+            .unwrap();
+        assert_eq!(applied, MIGRATIONS.len() as i64);
+        assert_eq!(sqlitedal.get_user(&my_user.user_id).unwrap(), Some(my_user));
+    }
+
+    #[test]
+    fn migrate_plain_text_tokens() {
+        // As a database from before tokens were hashed would be
+        let conn = Connection::open_in_memory().unwrap();
+        for migration in &MIGRATIONS[..2] {
+            match *migration {
+                Migration::Sql(sql) => conn.execute_batch(sql).unwrap(),
+                Migration::Code(apply) => apply(&conn).unwrap(),
+            }
+        }
+        conn.execute_batch("PRAGMA user_version = 2").unwrap();
+        conn.execute("INSERT INTO users (user_id, data) VALUES ('bob', ?1)",
+                     &[&r#"{"user_id":"bob","user_token":"secret"}"#])
+            .unwrap();
+        let sqlitedal = SqliteDB::from_connection(conn).unwrap();
+        let data = query_data(&sqlitedal.conn, "SELECT data FROM users", &[]).unwrap().unwrap();
+        assert!(!data.contains("secret"));
+        let user = sqlitedal.get_user(&UserID("bob".to_string())).unwrap().unwrap();
+        assert!(user.authenticate(&UserToken("secret".to_string())).is_some());
+    }
+
+    storydata_conformance_tests!(|| ::dal::SqliteDB::open_in_memory().unwrap());
+
+    #[test]
+    fn failed_update_session_is_rolled_back() {
+        let mut sqlitedal = SqliteDB::open_in_memory().unwrap();
+        let my_session = Session::new();
+        sqlitedal.add_session(my_session.clone()).unwrap();
+        let add_magic_then_fail = |s: &mut Session, _: &mut Vec<Participant>| -> Result<()> {
+            s.average = Some(1234.5f32);
+            bail!(ErrorKind::UserError("nope".to_string()))
+        };
+
+        assert!(sqlitedal.update_session(&my_session.session_id, add_magic_then_fail).is_err());
+        let same_session = sqlitedal.get_session(&my_session.session_id).unwrap().unwrap();
+        assert_eq!(same_session.average, None);
+    }
+
+    #[test]
+    fn purge_expired_sessions_and_users() {
+        let mut sqlitedal = SqliteDB::open_in_memory().unwrap();
+        let expiry = ExpiryConfig::default();
+        let now = clock::now();
+
+        let mut idle_session = Session::new();
+        idle_session.last_activity = 0;
+        let mut idle_user = BasicUser::new();
+        idle_user.last_activity = 0;
+        let sid = idle_session.session_id.clone();
+        sqlitedal.add_admin(idle_user.user_id.clone(), sid.clone()).unwrap();
+        sqlitedal.add_session(idle_session).unwrap();
+        sqlitedal.add_user(idle_user.clone()).unwrap();
+        let active_user = BasicUser::new();
+        sqlitedal.add_user(active_user.clone()).unwrap();
+
+        assert_eq!(sqlitedal.purge_expired(&expiry, now).unwrap(), 2);
+        assert_eq!(sqlitedal.get_session(&sid).unwrap(), None);
+        assert_eq!(sqlitedal.get_admins(&sid).unwrap(), vec![]);
+        assert_eq!(sqlitedal.get_user(&idle_user.user_id).unwrap(), None);
+        assert_eq!(sqlitedal.get_user(&active_user.user_id).unwrap(), Some(active_user));
+    }
+}
@@ -1,6 +1,7 @@
 use hyper::error::Error as HyperError;
 use redis::{self, RedisError, Value};
 use rocket::config::ConfigError;
+use rusqlite::Error as SqliteError;
 use serde_json;
 use std::io;
 
@@ -19,6 +20,7 @@ error_chain! {
         IOError(io::Error);
         HyperError(HyperError);
         RocketConfigError(ConfigError);
+        SqliteError(SqliteError);
     }
     errors {
         // Web Errors
@@ -58,9 +60,23 @@ error_chain! {
             description("The internal data store is inconsistent")
                 display("{}", t)
         }
+        BackendUnavailable(t: String) {
+            description("The data backend could not be reached")
+                display("{}", t)
+        }
         UnexpectedRedisResponse(v: Value) {
             description("Got an unexpected response from redis")
                 display("{:?}", v)
         }
+        InvalidDump(t: String) {
+            description("A database dump could not be loaded")
+                display("invalid dump: {}", t)
+        }
+
+        // Startup Errors
+        InvalidConfig(key: String, reason: String) {
+            description("A configuration value was invalid")
+                display("invalid configuration for '{}': {}", key, reason)
+        }
     }
 }
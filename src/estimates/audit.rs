@@ -0,0 +1,68 @@
+use estimates::session::SessionID;
+use user::UserID;
+use util::clock;
+
+/// Something done to a session on someone else's behalf, or to everyone in it
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AuditAction {
+    /// Removed someone else from the session
+    #[serde(rename = "kick")]
+    Kick,
+    /// Gave someone a role, including making them an admin or no longer one
+    #[serde(rename = "set_role")]
+    SetRole,
+    #[serde(rename = "transfer_ownership")]
+    TransferOwnership,
+    #[serde(rename = "update_policy")]
+    UpdatePolicy,
+    /// Revealed, cleared or reset the votes
+    #[serde(rename = "update_session")]
+    UpdateSession,
+    /// Deleted the whole session, only ever found in tombstones
+    #[serde(rename = "delete_session")]
+    DeleteSession,
+}
+
+/// A line in a session's audit log
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct AuditEntry {
+    pub session_id: SessionID,
+    /// Who did it
+    pub actor: UserID,
+    pub action: AuditAction,
+    /// Who it was done to, if anyone in particular
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<UserID>,
+    /// Anything else about it, e.g. the state the session was put in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// When it was done, see `util::clock`
+    pub timestamp: u64,
+}
+
+impl AuditEntry {
+    pub fn new(session_id: SessionID, actor: UserID, action: AuditAction) -> AuditEntry {
+        AuditEntry {
+            session_id: session_id,
+            actor: actor,
+            action: action,
+            target: None,
+            detail: None,
+            timestamp: clock::now(),
+        }
+    }
+
+    pub fn with_target(mut self, target: UserID) -> AuditEntry {
+        self.target = Some(target);
+        self
+    }
+
+    pub fn with_detail<S>(mut self, detail: S) -> AuditEntry
+        where S: Into<String>
+    {
+        self.detail = Some(detail.into());
+        self
+    }
+}
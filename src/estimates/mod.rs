@@ -1,4 +1,6 @@
 
+pub mod audit;
 pub mod session;
 pub mod vote;
 pub mod participant;
+pub mod policy;
@@ -12,6 +12,9 @@ pub struct Participant {
     pub session_id: SessionID,
     pub nickname: Nickname,
     pub vote: VoteState,
+    /// Watches without voting, unless an admin
+    #[serde(default)]
+    pub observer: bool,
 }
 
 impl Participant {
@@ -23,6 +26,7 @@ impl Participant {
             session_id: session_id,
             nickname: nickname,
             vote: VoteState::new(),
+            observer: false,
         }
     }
 
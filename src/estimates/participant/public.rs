@@ -1,4 +1,5 @@
 use super::Participant;
+use estimates::policy::Role;
 use estimates::vote::{PublicVote, PublicVoteState};
 use user::{Nickname, UserID};
 
@@ -9,6 +10,7 @@ pub struct PublicParticipant {
     pub nickname: Nickname,
     pub vote_state: PublicVoteState,
     pub vote_amount: Option<u32>,
+    pub role: Role,
 }
 
 
@@ -20,6 +22,7 @@ impl<'a> From<Participant> for PublicParticipant {
             nickname: p.nickname,
             vote_state: publicvote.state,
             vote_amount: publicvote.amount,
+            role: if p.observer { Role::Observer } else { Role::Voter },
         }
     }
 }
@@ -0,0 +1,179 @@
+use std::fmt;
+
+/// What a user is to a session
+///
+/// Each role may do whatever the roles before it may. Facilitators are the
+/// session's admins, and the owner is the admin who made it.
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, PartialOrd, Ord, Hash)]
+pub enum Role {
+    /// A participant who watches without voting
+    #[serde(rename = "observer")]
+    Observer,
+    #[serde(rename = "voter")]
+    Voter,
+    #[serde(rename = "facilitator")]
+    Facilitator,
+    #[serde(rename = "owner")]
+    Owner,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Role::Observer => "observer",
+            Role::Voter => "voter",
+            Role::Facilitator => "facilitator",
+            Role::Owner => "owner",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Something done to a session that only some roles may do
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Permission {
+    Vote,
+    /// Show everyone's votes
+    Reveal,
+    /// Clear the votes, or start a new round
+    Reset,
+    /// Remove someone else from the session
+    Kick,
+    /// Change others' roles, up to the role of whoever changes them
+    GrantRoles,
+    /// Change the session's policy
+    EditSettings,
+    Delete,
+    /// Read the session's audit log
+    ViewAudit,
+}
+
+impl Permission {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Permission::Vote => "vote",
+            Permission::Reveal => "reveal",
+            Permission::Reset => "reset",
+            Permission::Kick => "kick",
+            Permission::GrantRoles => "grant_roles",
+            Permission::EditSettings => "edit_settings",
+            Permission::Delete => "delete",
+            Permission::ViewAudit => "view_audit",
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// The least role each permission needs in a session
+///
+/// By default admins may do everything, as they could before there were
+/// roles, and everyone else may only vote.
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Policy {
+    #[serde(default = "voter")]
+    pub vote: Role,
+    #[serde(default = "facilitator")]
+    pub reveal: Role,
+    #[serde(default = "facilitator")]
+    pub reset: Role,
+    #[serde(default = "facilitator")]
+    pub kick: Role,
+    #[serde(default = "facilitator")]
+    pub grant_roles: Role,
+    #[serde(default = "facilitator")]
+    pub edit_settings: Role,
+    #[serde(default = "facilitator")]
+    pub delete: Role,
+    #[serde(default = "facilitator")]
+    pub view_audit: Role,
+}
+
+fn voter() -> Role {
+    Role::Voter
+}
+
+fn facilitator() -> Role {
+    Role::Facilitator
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            vote: Role::Voter,
+            reveal: Role::Facilitator,
+            reset: Role::Facilitator,
+            kick: Role::Facilitator,
+            grant_roles: Role::Facilitator,
+            edit_settings: Role::Facilitator,
+            delete: Role::Facilitator,
+            view_audit: Role::Facilitator,
+        }
+    }
+}
+
+impl Policy {
+    /// The least role the permission needs
+    pub fn required(&self, permission: Permission) -> Role {
+        match permission {
+            Permission::Vote => self.vote,
+            Permission::Reveal => self.reveal,
+            Permission::Reset => self.reset,
+            Permission::Kick => self.kick,
+            Permission::GrantRoles => self.grant_roles,
+            Permission::EditSettings => self.edit_settings,
+            Permission::Delete => self.delete,
+            Permission::ViewAudit => self.view_audit,
+        }
+    }
+
+    /// Whether someone with `role`, or no role at all, has the permission
+    pub fn allows(&self, role: Option<Role>, permission: Permission) -> bool {
+        role.map_or(false, |role| role >= self.required(permission))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn roles_are_ordered() {
+        assert!(Role::Observer < Role::Voter);
+        assert!(Role::Voter < Role::Facilitator);
+        assert!(Role::Facilitator < Role::Owner);
+        assert_eq!(serde_json::to_string(&Role::Facilitator).unwrap(),
+                   r#""facilitator""#);
+    }
+
+    #[test]
+    fn default_policy() {
+        let policy = Policy::default();
+        assert!(policy.allows(Some(Role::Voter), Permission::Vote));
+        assert!(!policy.allows(Some(Role::Observer), Permission::Vote));
+        assert!(!policy.allows(Some(Role::Voter), Permission::Reveal));
+        assert!(policy.allows(Some(Role::Facilitator), Permission::Delete));
+        assert!(policy.allows(Some(Role::Owner), Permission::EditSettings));
+        assert!(!policy.allows(None, Permission::Vote));
+    }
+
+    #[test]
+    fn missing_permissions_get_their_default() {
+        let policy: Policy = serde_json::from_str(r#"{"reveal": "voter"}"#).unwrap();
+        assert_eq!(policy.reveal, Role::Voter);
+        assert_eq!(policy.kick, Role::Facilitator);
+        assert_eq!(serde_json::from_str::<Policy>("{}").unwrap(), Policy::default());
+    }
+}
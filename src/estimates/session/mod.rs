@@ -1,5 +1,8 @@
 use super::participant::Participant;
+use super::policy::Policy;
 use super::vote::PublicVote;
+use user::UserID;
+use util::clock;
 
 mod id;
 mod public;
@@ -13,6 +16,14 @@ pub use self::public::{PublicSession, SessionState};
 pub struct Session {
     pub session_id: SessionID,
     pub average: Option<f32>,
+    /// When anyone last did anything in the session, see `util::clock`
+    #[serde(default = "clock::now")]
+    pub last_activity: u64,
+    /// The admin who made the session, `None` for sessions from before owners
+    #[serde(default)]
+    pub owner: Option<UserID>,
+    #[serde(default)]
+    pub policy: Policy,
 }
 
 impl Session {
@@ -20,6 +31,9 @@ impl Session {
         Session {
             session_id: SessionID::new(),
             average: None,
+            last_activity: clock::now(),
+            owner: None,
+            policy: Policy::default(),
         }
     }
 
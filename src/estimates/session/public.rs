@@ -2,6 +2,7 @@ use super::Session;
 use super::SessionID;
 
 use estimates::participant::{Participant, PublicParticipant};
+use estimates::policy::{Policy, Role};
 use estimates::vote::VoteState;
 
 use user::UserID;
@@ -22,6 +23,8 @@ pub struct PublicSession {
     pub session_id: SessionID,
     pub users: Vec<PublicParticipant>,
     pub admins: Vec<UserID>,
+    pub owner: Option<UserID>,
+    pub policy: Policy,
     pub average: Option<f32>,
     pub state: SessionState,
 }
@@ -33,13 +36,24 @@ impl PublicSession {
         admins: Vec<UserID>
     ) -> PublicSession {
         let state = choose_session_state(&participants.iter().map(|p| p.vote).collect());
+        let users = participants.into_iter()
+            .map(|p| {
+                let mut user = PublicParticipant::from(p);
+                if session.owner.as_ref() == Some(&user.user_id) {
+                    user.role = Role::Owner;
+                } else if admins.contains(&user.user_id) {
+                    user.role = Role::Facilitator;
+                }
+                user
+            })
+            .collect();
         PublicSession {
             session_id: session.session_id,
-            users: participants.into_iter()
-                .map(|p| PublicParticipant::from(p))
-                .collect(),
+            users: users,
             average: session.average,
             admins: admins,
+            owner: session.owner,
+            policy: session.policy,
             state: state,
         }
     }
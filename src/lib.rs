@@ -0,0 +1,36 @@
+#![feature(plugin)]
+#![plugin(rocket_codegen)]
+// `error_chain!` can recurse deeply
+#![recursion_limit = "1024"]
+
+extern crate redis;
+extern crate rustc_serialize;
+extern crate rand;
+extern crate hyper;
+extern crate hyper_native_tls;
+extern crate serde_json;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate rocket;
+#[macro_use]
+extern crate rocket_contrib;
+#[macro_use(log,info,debug,warn)]
+extern crate log;
+#[macro_use]
+extern crate error_chain;
+extern crate r2d2;
+extern crate r2d2_redis;
+extern crate num_cpus;
+extern crate ctrlc;
+extern crate rusqlite;
+extern crate ring;
+
+pub mod config;
+pub mod errors;
+mod util;
+pub mod user;
+pub mod service;
+pub mod dal;
+pub mod estimates;
+pub mod webapp;
@@ -1,62 +1,42 @@
-#![feature(plugin)]
-#![plugin(rocket_codegen)]
-// `error_chain!` can recurse deeply
-#![recursion_limit = "1024"]
-
-extern crate redis;
-extern crate rustc_serialize;
-extern crate rand;
-extern crate hyper;
-extern crate serde_json;
-extern crate serde;
-#[macro_use]
-extern crate serde_derive;
+extern crate estimate;
 extern crate rocket;
-#[macro_use]
-extern crate rocket_contrib;
-#[macro_use(log,info,debug,warn)]
-extern crate log;
-#[macro_use]
-extern crate error_chain;
-extern crate r2d2;
-extern crate r2d2_redis;
-extern crate num_cpus;
-
-mod errors;
-mod util;
-mod user;
-mod service;
-mod dal;
-mod estimates;
-mod webapp;
-
-use dal::{RedisDBManager, SharedMemoryDB};
-use r2d2_redis::RedisConnectionManager;
-use redis::{ConnectionAddr, ConnectionInfo};
-use std::env;
 
-#[cfg(not(feature = "redis_estimates"))]
-fn get_backend() -> SharedMemoryDB {
-    SharedMemoryDB::new()
-}
-
-#[cfg(feature = "redis_estimates")]
-fn get_backend() -> RedisDBManager {
-    let cpus = num_cpus::get() as u32;
-    let redis_ctx = ConnectionInfo {
-        addr: Box::new(ConnectionAddr::Tcp("127.0.0.1".to_owned(), 6379)),
-        db: 0,
-        passwd: None,
-    };
-    info!("Creating Redis Pool ({}x -> {:?})", cpus, redis_ctx);
-    let config = r2d2::Config::builder().pool_size(cpus).build();
-    let manager = RedisConnectionManager::new(redis_ctx).unwrap();
-    let pool = r2d2::Pool::new(config, manager).unwrap();
+use estimate::config;
+use estimate::dal::StoryBackend;
+use estimate::errors::*;
+use estimate::webapp;
+use rocket::config::ConfigError;
+use std::io::Write;
 
-    RedisDBManager::new(pool)
+fn run() -> Result<()> {
+    let rocket = rocket::ignite();
+    let config = rocket::config::active().ok_or(ConfigError::NotFound)?;
+    let expiry = config::ExpiryConfig::from_config(config)?;
+    let backend = StoryBackend::from_config(config::BackendConfig::from_config(config)?, &expiry)?;
+    let tenant_configs = config::TenantConfig::from_config(config)?;
+    let tenants = webapp::Tenants::from_config(backend, tenant_configs)?;
+    let bearer = config::BearerConfig::from_config(config)?;
+    for backend in tenants.backends() {
+        backend.migrate()?;
+        backend.start_maintenance(&expiry)?;
+    }
+    let mut authenticators = webapp::Authenticators::new(webapp::bearer_signer(&bearer));
+    if let Some(oidc) = config::OidcConfig::from_config(config)? {
+        authenticators = authenticators.with(webapp::OidcAuthenticator::new(oidc)?);
+    }
+    webapp::mount_webapp(rocket, tenants, authenticators).launch();
+    Ok(())
 }
 
 fn main() {
-    let rocket = webapp::build_webapp(get_backend());
-    rocket.launch();
+    if let Err(ref e) = run() {
+        let stderr = &mut ::std::io::stderr();
+        let errmsg = "Error writing to stderr";
+
+        writeln!(stderr, "error: {}", e).expect(errmsg);
+        for e in e.iter().skip(1) {
+            writeln!(stderr, "caused by: {}", e).expect(errmsg);
+        }
+        ::std::process::exit(1);
+    }
 }
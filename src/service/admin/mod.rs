@@ -0,0 +1,243 @@
+//! Operations for the people running a deployment, which skip the checks
+//! made on behalf of users
+
+use config::ExpiryConfig;
+use dal;
+use dal::has_expired;
+use errors::*;
+use estimates::session::SessionID;
+use service::hand_over_session;
+use user::UserID;
+
+/// How much data a deployment holds
+#[derive(Serialize)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statistics {
+    pub users: usize,
+    pub sessions: usize,
+    pub participants: usize,
+    pub admins: usize,
+    /// Sessions nobody is taking part in
+    pub empty_sessions: usize,
+}
+
+/// The sessions whose ID contains `search`
+pub fn find_sessions<D>(dal: &D, search: &str) -> Result<Vec<SessionID>>
+    where D: dal::StoryData
+{
+    Ok(dal.list_sessions()?.into_iter().filter(|s| s.0.contains(search)).collect())
+}
+
+/// The users whose ID contains `search`
+pub fn find_users<D>(dal: &D, search: &str) -> Result<Vec<UserID>>
+    where D: dal::StoryData
+{
+    Ok(dal.list_users()?.into_iter().filter(|u| u.0.contains(search)).collect())
+}
+
+/// Remove a user, and take them out of every session they were part of
+///
+/// Someone else in the session takes over from them if they were its owner
+/// or last admin.
+pub fn remove_user<D>(dal: &mut D, user_id: &UserID) -> Result<()>
+    where D: dal::StoryData
+{
+    if dal.get_user(user_id)?.is_none() {
+        bail!(ErrorKind::ObjectNotFound(format!("Could not find user: {:?}", user_id)));
+    }
+    for session_id in dal.list_sessions()? {
+        leave_session(dal, &session_id, user_id)?;
+    }
+    dal.del_user(user_id)
+}
+
+/// Take a user out of a session, handing it over to someone else if they had to
+fn leave_session<D>(dal: &mut D, session_id: &SessionID, user_id: &UserID) -> Result<()>
+    where D: dal::StoryData
+{
+    dal.lock_session(session_id, |dal| {
+        if let Some(successor) = hand_over_session(dal, session_id, user_id)? {
+            info!("User {} took over the session {} from {}",
+                  successor,
+                  session_id,
+                  user_id);
+        }
+        if dal.get_participants(session_id)?.iter().any(|p| p.user_id == *user_id) {
+            dal.del_participant(user_id, session_id)?;
+        }
+        if dal.is_admin(session_id, user_id)? {
+            dal.del_admin(user_id, session_id)?;
+        }
+        Ok(())
+    })
+}
+
+/// Remove the sessions and users idle for longer than their TTL as of `now`
+///
+/// Expired users first leave their sessions like removed users do, see
+/// `remove_user`, so the sessions they owned or were the last admin of pass
+/// to someone else. Sessions that have expired too are left as they are,
+/// since leaving would count as activity in them. Returns how many sessions
+/// and users were removed.
+pub fn expire<D>(dal: &mut D, expiry: &ExpiryConfig, now: u64) -> Result<usize>
+    where D: dal::StoryData
+{
+    let mut expired_users = Vec::new();
+    if expiry.user_ttl.is_some() {
+        for user_id in dal.list_users()? {
+            if let Some(user) = dal.get_user(&user_id)? {
+                if has_expired(expiry.user_ttl, user.last_activity, now) {
+                    expired_users.push(user_id);
+                }
+            }
+        }
+    }
+    if !expired_users.is_empty() {
+        for session_id in dal.list_sessions()? {
+            let live = match dal.get_session(&session_id)? {
+                Some(session) => !has_expired(expiry.session_ttl, session.last_activity, now),
+                None => false,
+            };
+            if live {
+                for user_id in &expired_users {
+                    leave_session(dal, &session_id, user_id)?;
+                }
+            }
+        }
+    }
+    dal.purge_expired(expiry, now)
+}
+
+/// Make someone an admin of a session, whether or not anyone else agrees
+pub fn grant_admin<D>(dal: &mut D, session_id: &SessionID, user_id: &UserID) -> Result<()>
+    where D: dal::StoryData
+{
+    if dal.get_session(session_id)?.is_none() {
+        bail!(ErrorKind::ObjectNotFound(format!("Could not find session: {:?}", session_id)));
+    }
+    dal.add_admin(user_id.clone(), session_id.clone())
+}
+
+pub fn statistics<D>(dal: &D) -> Result<Statistics>
+    where D: dal::StoryData
+{
+    let mut stats = Statistics {
+        users: dal.list_users()?.len(),
+        sessions: 0,
+        participants: 0,
+        admins: 0,
+        empty_sessions: 0,
+    };
+    for session_id in dal.list_sessions()? {
+        let participants = dal.get_participants(&session_id)?.len();
+        stats.sessions += 1;
+        stats.participants += participants;
+        stats.admins += dal.get_admins(&session_id)?.len();
+        if participants == 0 {
+            stats.empty_sessions += 1;
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dal::{MemoryDB, StoryData};
+    use estimates::participant::Participant;
+    use estimates::session::Session;
+    use user::{BasicUser, Nickname};
+    use util::clock;
+
+    fn populate<D>(dal: &mut D) -> (BasicUser, Session)
+        where D: dal::StoryData
+    {
+        let new_user = BasicUser::new();
+        dal.add_user(new_user.clone()).unwrap();
+        let my_session = Session::new();
+        dal.add_session(my_session.clone()).unwrap();
+        let p = Participant::new(&new_user, my_session.session_id.clone(), Nickname::new("bob"));
+        dal.add_participant(p).unwrap();
+        dal.add_admin(new_user.user_id.clone(), my_session.session_id.clone()).unwrap();
+        dal.add_session(Session::new()).unwrap();
+        (new_user, my_session)
+    }
+
+    #[test]
+    fn remove_user_from_every_session() {
+        let mut dal = MemoryDB::new();
+        let (new_user, my_session) = populate(&mut dal);
+        remove_user(&mut dal, &new_user.user_id).unwrap();
+        assert_eq!(dal.get_user(&new_user.user_id).unwrap(), None);
+        assert_eq!(dal.get_participants(&my_session.session_id).unwrap(), vec![]);
+        assert!(!dal.is_admin(&my_session.session_id, &new_user.user_id).unwrap());
+        assert!(remove_user(&mut dal, &new_user.user_id).is_err());
+    }
+
+    #[test]
+    fn someone_takes_over_from_a_removed_owner() {
+        let mut dal = MemoryDB::new();
+        let (owner, _) = populate(&mut dal);
+        let mut my_session = Session::new();
+        my_session.owner = Some(owner.user_id.clone());
+        dal.add_session(my_session.clone()).unwrap();
+        dal.add_admin(owner.user_id.clone(), my_session.session_id.clone()).unwrap();
+        let other_user = BasicUser::new();
+        dal.add_user(other_user.clone()).unwrap();
+        let p = Participant::new(&other_user, my_session.session_id.clone(), Nickname::new("bill"));
+        dal.add_participant(p).unwrap();
+
+        remove_user(&mut dal, &owner.user_id).unwrap();
+        let session = dal.get_session(&my_session.session_id).unwrap().unwrap();
+        assert_eq!(session.owner, Some(other_user.user_id.clone()));
+        assert_eq!(dal.get_admins(&my_session.session_id).unwrap(), vec![other_user.user_id]);
+    }
+
+    #[test]
+    fn someone_takes_over_from_an_expired_owner() {
+        let mut dal = MemoryDB::new();
+        let expiry = ExpiryConfig::default();
+        let now = clock::now();
+        let mut owner = BasicUser::new();
+        owner.last_activity = now - expiry.user_ttl.unwrap().as_secs() - 1;
+        dal.add_user(owner.clone()).unwrap();
+        let mut my_session = Session::new();
+        my_session.owner = Some(owner.user_id.clone());
+        dal.add_session(my_session.clone()).unwrap();
+        dal.add_admin(owner.user_id.clone(), my_session.session_id.clone()).unwrap();
+        let other_user = BasicUser::new();
+        dal.add_user(other_user.clone()).unwrap();
+        let p = Participant::new(&other_user, my_session.session_id.clone(), Nickname::new("bill"));
+        dal.add_participant(p).unwrap();
+
+        assert_eq!(expire(&mut dal, &expiry, now).unwrap(), 1);
+        assert_eq!(dal.get_user(&owner.user_id).unwrap(), None);
+        let session = dal.get_session(&my_session.session_id).unwrap().unwrap();
+        assert_eq!(session.owner, Some(other_user.user_id.clone()));
+        assert_eq!(dal.get_admins(&my_session.session_id).unwrap(), vec![other_user.user_id]);
+    }
+
+    #[test]
+    fn count_everything() {
+        let mut dal = MemoryDB::new();
+        populate(&mut dal);
+        assert_eq!(statistics(&dal).unwrap(),
+                   Statistics {
+                       users: 1,
+                       sessions: 2,
+                       participants: 1,
+                       admins: 1,
+                       empty_sessions: 1,
+                   });
+    }
+
+    #[test]
+    fn search_by_id() {
+        let mut dal = MemoryDB::new();
+        let (new_user, my_session) = populate(&mut dal);
+        assert_eq!(find_sessions(&dal, &my_session.session_id.0).unwrap(),
+                   vec![my_session.session_id]);
+        assert_eq!(find_users(&dal, &new_user.user_id.0).unwrap(), vec![new_user.user_id]);
+        assert_eq!(find_users(&dal, "no such user").unwrap(), vec![]);
+    }
+}
@@ -1,3 +1,4 @@
+pub mod admin;
 
 mod user;
 mod session;
@@ -0,0 +1,90 @@
+use dal;
+use errors::*;
+use estimates::audit::AuditEntry;
+use estimates::policy::Permission;
+use estimates::session::SessionID;
+use super::authorize;
+use user::AuthenticatedUser;
+
+/// What has been done to the session on others' behalf, oldest first
+pub fn audit_log<D>(
+    dal: &D,
+    session_id: &SessionID,
+    requester: &AuthenticatedUser
+) -> Result<Vec<AuditEntry>>
+    where D: dal::StoryData
+{
+    authorize(dal, session_id, requester, Permission::ViewAudit)?;
+    dal.get_audit_log(session_id)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::*;
+    use super::super::super::user;
+    use estimates::audit::AuditAction;
+    use estimates::policy::Role;
+    use user::Nickname;
+
+    #[test]
+    fn administration_is_audited() {
+        let mut dal = dal::MemoryDB::new();
+        let owner = user::get_authenticated_user(&mut dal).unwrap();
+        let session_id = create_session(&mut dal, &owner).unwrap();
+        let voter = user::get_authenticated_user(&mut dal).unwrap();
+        join_session(&mut dal,
+                     &session_id,
+                     &voter.user_id,
+                     &voter,
+                     &Nickname::new("bob"))
+            .unwrap();
+        // Voting isn't administration
+        place_vote(&mut dal, &session_id, &voter.user_id, &voter, 3).unwrap();
+        update_session(&mut dal, &session_id, &SessionState::Visible, &owner).unwrap();
+        grant_admin(&mut dal, &session_id, &voter.user_id, &owner).unwrap();
+        kick_user(&mut dal, &session_id, &voter.user_id, &owner).unwrap();
+
+        let log = audit_log(&dal, &session_id, &owner).unwrap();
+        let actions: Vec<AuditAction> = log.iter().map(|e| e.action).collect();
+        assert_eq!(actions,
+                   vec![AuditAction::UpdateSession, AuditAction::SetRole, AuditAction::Kick]);
+        assert_eq!(log[0].detail, Some("Visible".to_string()));
+        assert_eq!(log[1].target, Some(voter.user_id.clone()));
+        assert_eq!(log[1].detail, Some(Role::Facilitator.as_str().to_string()));
+        assert!(log.iter().all(|e| e.actor == owner.user_id));
+    }
+
+    #[test]
+    fn leaving_is_not_audited() {
+        let mut dal = dal::MemoryDB::new();
+        let owner = user::get_authenticated_user(&mut dal).unwrap();
+        let session_id = create_session(&mut dal, &owner).unwrap();
+        let voter = user::get_authenticated_user(&mut dal).unwrap();
+        join_session(&mut dal,
+                     &session_id,
+                     &voter.user_id,
+                     &voter,
+                     &Nickname::new("bob"))
+            .unwrap();
+        kick_user(&mut dal, &session_id, &voter.user_id, &voter).unwrap();
+        assert_eq!(audit_log(&dal, &session_id, &owner).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn only_facilitators_see_the_log() {
+        let mut dal = dal::MemoryDB::new();
+        let owner = user::get_authenticated_user(&mut dal).unwrap();
+        let session_id = create_session(&mut dal, &owner).unwrap();
+        let voter = user::get_authenticated_user(&mut dal).unwrap();
+        join_session(&mut dal,
+                     &session_id,
+                     &voter.user_id,
+                     &voter,
+                     &Nickname::new("bob"))
+            .unwrap();
+        assert!(audit_log(&dal, &session_id, &voter).is_err());
+        grant_admin(&mut dal, &session_id, &voter.user_id, &owner).unwrap();
+        assert_eq!(audit_log(&dal, &session_id, &voter).unwrap().len(), 1);
+    }
+}
@@ -1,22 +1,32 @@
 use dal;
 use errors::*;
+use estimates::audit::{AuditAction, AuditEntry};
 use estimates::participant::Participant;
+use estimates::policy::{Permission, Role};
 use estimates::session::{PublicSession, Session, SessionID, SessionState};
 use user::{AuthenticatedUser, UserID};
 
+mod audit;
 mod participant;
+mod roles;
+pub use self::audit::*;
 pub use self::participant::*;
+pub use self::roles::*;
 
 pub fn create_session<D>(dal: &mut D, user: &AuthenticatedUser) -> Result<SessionID>
     where D: dal::StoryData
 {
-    let new_session = Session::new();
+    let mut new_session = Session::new();
+    new_session.owner = Some(user.user_id.clone());
     let session_id = new_session.session_id.clone();
     dal.add_session(new_session)?;
     dal.add_admin(user.user_id.clone(), session_id.clone())?;
     Ok(session_id)
 }
 
+/// Remove the user from the session, which anyone may do to themselves
+///
+/// Only removing someone else goes in the audit log.
 pub fn kick_user<D>(
     dal: &mut D,
     session_id: &SessionID,
@@ -25,15 +35,20 @@ pub fn kick_user<D>(
 ) -> Result<()>
     where D: dal::StoryData
 {
-    if requester.user_id == *user_id || dal.is_admin(session_id, &requester.user_id)? {
-        dal.del_participant(user_id, session_id)
-    } else {
-        bail!(ErrorKind::UserForbidden(format!("User {:?} is not an admin of the session {:?}",
-                                               requester,
-                                               session_id)))
-    }
+    dal.lock_session(session_id, |dal| {
+        if requester.user_id == *user_id {
+            return dal.del_participant(user_id, session_id);
+        }
+        authorize_over(dal, session_id, requester, Permission::Kick, user_id)?;
+        dal.del_participant(user_id, session_id)?;
+        dal.add_audit_entry(AuditEntry::new(session_id.clone(),
+                                            requester.user_id.clone(),
+                                            AuditAction::Kick)
+            .with_target(user_id.clone()))
+    })
 }
 
+/// Make the user a facilitator of the session
 pub fn grant_admin<D>(
     dal: &mut D,
     session_id: &SessionID,
@@ -42,23 +57,18 @@ pub fn grant_admin<D>(
 ) -> Result<()>
     where D: dal::StoryData
 {
-    if let Some(_) = dal.get_session(session_id)? {
-        if dal.is_admin(session_id, &requester.user_id)? {
-            dal.add_admin(user_id.clone(), session_id.clone())?;
-        } else {
-            bail!(ErrorKind::UserForbidden(format!("User {:?} is not an admin of the session \
-                                                    {:?}",
-                                                   requester,
-                                                   session_id)))
+    dal.lock_session(session_id, |dal| {
+        authorize(dal, session_id, requester, Permission::GrantRoles)?;
+        if dal.is_admin(session_id, user_id)? {
+            bail!(ErrorKind::UserError(format!("User {} is already an admin of the session {}",
+                                               user_id,
+                                               session_id)));
         }
-    } else {
-        bail!(ErrorKind::ObjectNotFound(format!("Can not make an admin for a non-existent \
-                                                 session ID {:?}",
-                                                session_id)));
-    }
-    Ok(())
+        set_role(dal, session_id, user_id, Role::Facilitator, requester)
+    })
 }
 
+/// Make an admin of the session a plain voter
 pub fn revoke_admin<D>(
     dal: &mut D,
     session_id: &SessionID,
@@ -67,13 +77,15 @@ pub fn revoke_admin<D>(
 ) -> Result<()>
     where D: dal::StoryData
 {
-    if dal.is_admin(session_id, &requester.user_id)? {
-        dal.del_admin(user_id, session_id)
-    } else {
-        bail!(ErrorKind::UserForbidden(format!("User {:?} is not an admin of the session {:?}",
-                                               requester,
-                                               session_id)))
-    }
+    dal.lock_session(session_id, |dal| {
+        authorize(dal, session_id, requester, Permission::GrantRoles)?;
+        if !dal.is_admin(session_id, user_id)? {
+            bail!(ErrorKind::UserError(format!("User {} is not an admin of the session {}",
+                                               user_id,
+                                               session_id)));
+        }
+        set_role(dal, session_id, user_id, Role::Voter, requester)
+    })
 }
 
 fn do_session_reset(s: &mut Session, mut participants: &mut Vec<Participant>) -> Result<()> {
@@ -106,22 +118,23 @@ pub fn update_session<D>(
 ) -> Result<()>
     where D: dal::StoryData
 {
-
-    if !dal.is_admin(session_id, &requester.user_id)? {
-        bail!(ErrorKind::UserForbidden(format!("User {:?} is not an admin of the session {:?}",
-                                               requester,
-                                               session_id)))
-    }
-
-    let session_action = match *into_state {
-        SessionState::Clean => do_session_reset,
-        SessionState::Voting => do_session_clear,
-        SessionState::Visible => do_session_vote,
-        SessionState::Dirty => {
-            bail!(ErrorKind::UserError("Can not set session to be 'Dirty'".to_string()))
-        },
-    };
-    dal.update_session(&session_id, session_action)
+    let (permission, session_action): (_, fn(&mut Session, &mut Vec<Participant>) -> Result<()>) =
+        match *into_state {
+            SessionState::Clean => (Permission::Reset, do_session_reset),
+            SessionState::Voting => (Permission::Reset, do_session_clear),
+            SessionState::Visible => (Permission::Reveal, do_session_vote),
+            SessionState::Dirty => {
+                bail!(ErrorKind::UserError("Can not set session to be 'Dirty'".to_string()))
+            },
+        };
+    dal.lock_session(session_id, |dal| {
+        authorize(dal, session_id, requester, permission)?;
+        dal.update_session(&session_id, session_action)?;
+        dal.add_audit_entry(AuditEntry::new(session_id.clone(),
+                                            requester.user_id.clone(),
+                                            AuditAction::UpdateSession)
+            .with_detail(format!("{:?}", into_state)))
+    })
 }
 
 
@@ -132,21 +145,15 @@ pub fn delete_session<D>(
 ) -> Result<()>
     where D: dal::StoryData
 {
-    // Verify that the session exists first
-
-    if let Some(_) = dal.get_session(session_id)? {
-        if dal.is_admin(session_id, &requester.user_id)? {
-            dal.del_session(session_id)
-        } else {
-            bail!(ErrorKind::UserForbidden(format!("User {} is not an admin of the session {}",
-                                                   requester,
-                                                   session_id)))
-        }
-    } else {
-        bail!(ErrorKind::ObjectNotFound(format!("Can not delete non-existent session ID {:?}",
-                                                session_id)));
-
-    }
+    dal.lock_session(session_id, |dal| {
+        authorize(dal, session_id, requester, Permission::Delete)?;
+        dal.del_session(session_id)?;
+        info!("User {} deleted the session {}", requester, session_id);
+        // The audit log went with the session, so this is kept apart from it
+        dal.add_tombstone(AuditEntry::new(session_id.clone(),
+                                          requester.user_id.clone(),
+                                          AuditAction::DeleteSession))
+    })
 }
 
 #[cfg(test)]
@@ -166,6 +173,20 @@ mod test {
         assert_eq!(new_session_id, saved_session.session_id);
     }
 
+    #[test]
+    fn deleting_a_session_leaves_a_tombstone() {
+        let mut dal = dal::MemoryDB::new();
+        let admin_user = user::get_authenticated_user(&mut dal).unwrap();
+        let new_session_id = create_session(&mut dal, &admin_user).unwrap();
+        delete_session(&mut dal, &new_session_id, &admin_user).unwrap();
+        assert_eq!(dal.get_session(&new_session_id).unwrap(), None);
+        let tombstones = dal.get_tombstones().unwrap();
+        assert_eq!(tombstones.len(), 1);
+        assert_eq!(tombstones[0].session_id, new_session_id);
+        assert_eq!(tombstones[0].actor, admin_user.user_id);
+        assert_eq!(tombstones[0].action, AuditAction::DeleteSession);
+    }
+
     #[test]
     fn join_session_and_check() {
         let mut dal = dal::MemoryDB::new();
@@ -1,8 +1,11 @@
 use dal;
 use errors::*;
 use estimates::participant::Participant;
+use estimates::policy::Permission;
 use estimates::session::SessionID;
-use user::{AuthenticatedUser, Nickname, UserID};
+use service::{authenticate_user, create_user};
+use super::authorize;
+use user::{AuthenticatedUser, NewUser, Nickname, UserID};
 
 pub fn join_session<D>(
     dal: &mut D,
@@ -13,26 +16,48 @@ pub fn join_session<D>(
 ) -> Result<()>
     where D: dal::StoryData
 {
-    if *user_id != user.user_id {
-        bail!(ErrorKind::UserUnauthorized);
-    }
-    if dal.get_session(session_id)?.is_none() {
-        bail!(ErrorKind::ObjectNotFound(format!("Can not participate in non-existent session \
-                                                 ID {:?}",
-                                                session_id)));
-    }
+    dal.lock_session(session_id, |dal| {
+        if *user_id != user.user_id {
+            bail!(ErrorKind::UserUnauthorized);
+        }
+        if dal.get_session(session_id)?.is_none() {
+            bail!(ErrorKind::ObjectNotFound(format!("Can not participate in non-existent session \
+                                                     ID {:?}",
+                                                    session_id)));
+        }
 
-    let update_nickname = |mut p: &mut Participant| {
-        p.nickname = nickname.clone();
-        Ok(())
-    };
-    match dal.update_participant(session_id, &user.user_id, update_nickname) {
-        Err(Error(ErrorKind::ObjectNotFound(_), _)) => {
-            let member = Participant::new(user, session_id.clone(), nickname.clone());
-            dal.add_participant(member)
-        },
-        x => x,
-    }
+        let update_nickname = |mut p: &mut Participant| {
+            p.nickname = nickname.clone();
+            Ok(())
+        };
+        match dal.update_participant(session_id, &user.user_id, update_nickname) {
+            Err(Error(ErrorKind::ObjectNotFound(_), _)) => {
+                let member = Participant::new(user, session_id.clone(), nickname.clone());
+                dal.add_participant(member)
+            },
+            x => x,
+        }
+    })
+}
+
+/// Make a user just to join the session, returning its token
+///
+/// The user is removed again if it can't join.
+pub fn join_as_guest<D>(dal: &mut D, session_id: &SessionID, nickname: &Nickname) -> Result<NewUser>
+    where D: dal::StoryData
+{
+    dal.lock_session(session_id, |dal| {
+        let new_user = create_user(dal)?;
+        let user = authenticate_user(dal, &new_user.user_id, &new_user.user_token)
+            ?
+            .ok_or(ErrorKind::DataIntegrityError("A new user could not authenticate"
+                .to_string()))?;
+        if let Err(e) = join_session(dal, session_id, &user.user_id, &user, nickname) {
+            dal.del_user(&user.user_id)?;
+            return Err(e);
+        }
+        Ok(new_user)
+    })
 }
 
 pub fn place_vote<D>(
@@ -44,14 +69,17 @@ pub fn place_vote<D>(
 ) -> Result<()>
     where D: dal::StoryData
 {
-    if *user_id != user.user_id {
-        bail!(ErrorKind::UserUnauthorized);
-    }
-    let place_vote = |mut p: &mut Participant| {
-        p.vote(vote);
-        Ok(())
-    };
-    dal.update_participant(session_id, &user.user_id, place_vote)
+    dal.lock_session(session_id, |dal| {
+        if *user_id != user.user_id {
+            bail!(ErrorKind::UserUnauthorized);
+        }
+        authorize(dal, session_id, user, Permission::Vote)?;
+        let place_vote = |mut p: &mut Participant| {
+            p.vote(vote);
+            Ok(())
+        };
+        dal.update_participant(session_id, &user.user_id, place_vote)
+    })
 }
 
 #[cfg(test)]
@@ -106,6 +134,23 @@ mod test {
         }
     }
 
+    #[test]
+    fn join_as_a_guest() {
+        let mut dal = dal::MemoryDB::new();
+        let admin_user = user::get_authenticated_user(&mut dal).unwrap();
+        let new_session_id = create_session(&mut dal, &admin_user).unwrap();
+        let guest = join_as_guest(&mut dal, &new_session_id, &Nickname::new("bob")).unwrap();
+        assert!(authenticate_user(&dal, &guest.user_id, &guest.user_token).unwrap().is_some());
+        let all_participants = dal.get_participants(&new_session_id).unwrap();
+        assert_eq!(all_participants.len(), 1);
+        assert_eq!(all_participants[0].user_id, guest.user_id);
+
+        let users = dal.list_users().unwrap();
+        let bad_session_id = SessionID("foo".to_string());
+        assert!(join_as_guest(&mut dal, &bad_session_id, &Nickname::new("eve")).is_err());
+        assert_eq!(dal.list_users().unwrap(), users);
+    }
+
     #[test]
     fn update_vote() {
         let mut dal = dal::MemoryDB::new();
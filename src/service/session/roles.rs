@@ -0,0 +1,405 @@
+use dal;
+use errors::*;
+use estimates::audit::{AuditAction, AuditEntry};
+use estimates::participant::Participant;
+use estimates::policy::{Permission, Policy, Role};
+use estimates::session::{Session, SessionID};
+use user::{AuthenticatedUser, UserID};
+
+/// The user's role in the session, `None` if they have no part in it
+pub fn role_of<D>(dal: &D, session: &Session, user_id: &UserID) -> Result<Option<Role>>
+    where D: dal::StoryData
+{
+    if session.owner.as_ref() == Some(user_id) {
+        return Ok(Some(Role::Owner));
+    }
+    if dal.is_admin(&session.session_id, user_id)? {
+        return Ok(Some(Role::Facilitator));
+    }
+    let participants = dal.get_participants(&session.session_id)?;
+    Ok(participants.iter()
+        .find(|p| p.user_id == *user_id)
+        .map(|p| if p.observer { Role::Observer } else { Role::Voter }))
+}
+
+/// Check the session's policy gives the user the permission
+///
+/// Returns the session and the user's role in it.
+pub fn authorize<D>(
+    dal: &D,
+    session_id: &SessionID,
+    user: &AuthenticatedUser,
+    permission: Permission
+) -> Result<(Session, Option<Role>)>
+    where D: dal::StoryData
+{
+    let session = dal.get_session(session_id)?
+        .ok_or(ErrorKind::ObjectNotFound(format!("Could not find session ID {:?}", session_id)))?;
+    let role = role_of(dal, &session, &user.user_id)?;
+    if !session.policy.allows(role, permission) {
+        bail!(ErrorKind::UserForbidden(format!("User {} may not {} in the session {}",
+                                               user,
+                                               permission,
+                                               session_id)));
+    }
+    Ok((session, role))
+}
+
+/// Check the user may act on `target`, who mustn't have a role above theirs
+///
+/// Returns the session, the user's role and the target's role.
+pub fn authorize_over<D>(
+    dal: &D,
+    session_id: &SessionID,
+    user: &AuthenticatedUser,
+    permission: Permission,
+    target: &UserID
+) -> Result<(Session, Option<Role>, Option<Role>)>
+    where D: dal::StoryData
+{
+    let (session, role) = authorize(dal, session_id, user, permission)?;
+    let target_role = role_of(dal, &session, target)?;
+    if target_role > role {
+        bail!(ErrorKind::UserForbidden(format!("User {} may not {} the {} of the session {}",
+                                               user,
+                                               permission,
+                                               target_role.map_or("", |r| r.as_str()),
+                                               session_id)));
+    }
+    Ok((session, role, target_role))
+}
+
+/// Give the user a role in the session, up to the role of the requester
+///
+/// Voters and observers have to have joined the session first. The owner
+/// can't be changed this way, see `transfer_ownership`, and the session
+/// always keeps at least one admin.
+pub fn set_role<D>(
+    dal: &mut D,
+    session_id: &SessionID,
+    user_id: &UserID,
+    role: Role,
+    requester: &AuthenticatedUser
+) -> Result<()>
+    where D: dal::StoryData
+{
+    dal.lock_session(session_id, |dal| {
+        let (session, requester_role, current) =
+            authorize_over(dal, session_id, requester, Permission::GrantRoles, user_id)?;
+        if role == Role::Owner || current == Some(Role::Owner) {
+            bail!(ErrorKind::UserError("The owner can only be changed by transferring the session"
+                .to_string()));
+        }
+        if Some(role) > requester_role {
+            bail!(ErrorKind::UserForbidden(format!("User {} may not make others a {} of the \
+                                                    session {}",
+                                                   requester,
+                                                   role,
+                                                   session.session_id)));
+        }
+        match role {
+            Role::Facilitator => {
+                if current != Some(Role::Facilitator) {
+                    dal.add_admin(user_id.clone(), session_id.clone())?;
+                }
+            },
+            _ => {
+                let demoted = current == Some(Role::Facilitator);
+                if demoted && dal.get_admins(session_id)?.len() <= 1 {
+                    bail!(ErrorKind::UserError("A session has to keep at least one admin"
+                        .to_string()));
+                }
+                let observer = role == Role::Observer;
+                // Admins needn't have joined, and stop being admins all the same
+                let may_be_absent = !observer && current.is_some();
+                let set_observer = |p: &mut Participant| {
+                    p.observer = observer;
+                    Ok(())
+                };
+                match dal.update_participant(session_id, user_id, set_observer) {
+                    Err(Error(ErrorKind::ObjectNotFound(_), _)) if may_be_absent => (),
+                    result => result?,
+                }
+                if demoted {
+                    dal.del_admin(user_id, session_id)?;
+                }
+            },
+        }
+        dal.add_audit_entry(AuditEntry::new(session_id.clone(),
+                                            requester.user_id.clone(),
+                                            AuditAction::SetRole)
+            .with_target(user_id.clone())
+            .with_detail(role.as_str()))
+    })
+}
+
+/// Change which roles may do what in the session
+///
+/// The requester has to be able to edit the policy afterwards too, so a
+/// session can't be locked out of its own settings.
+pub fn update_policy<D>(
+    dal: &mut D,
+    session_id: &SessionID,
+    policy: &Policy,
+    requester: &AuthenticatedUser
+) -> Result<()>
+    where D: dal::StoryData
+{
+    dal.lock_session(session_id, |dal| {
+        let (_, role) = authorize(dal, session_id, requester, Permission::EditSettings)?;
+        if !policy.allows(role, Permission::EditSettings) {
+            bail!(ErrorKind::UserError("The policy would stop you editing it".to_string()));
+        }
+        dal.update_session(session_id, |session, _| {
+                session.policy = policy.clone();
+                Ok(())
+            })?;
+        dal.add_audit_entry(AuditEntry::new(session_id.clone(),
+                                            requester.user_id.clone(),
+                                            AuditAction::UpdatePolicy))
+    })
+}
+
+/// Make someone else in the session its owner, leaving the old one a facilitator
+///
+/// Only the owner can do this, or a facilitator of a session from before
+/// there were owners.
+pub fn transfer_ownership<D>(
+    dal: &mut D,
+    session_id: &SessionID,
+    user_id: &UserID,
+    requester: &AuthenticatedUser
+) -> Result<()>
+    where D: dal::StoryData
+{
+    dal.lock_session(session_id, |dal| {
+        let (session, role) = authorize(dal, session_id, requester, Permission::GrantRoles)?;
+        let may_transfer = match session.owner {
+            Some(ref owner) => *owner == requester.user_id,
+            None => role >= Some(Role::Facilitator),
+        };
+        if !may_transfer {
+            bail!(ErrorKind::UserForbidden(format!("User {} is not the owner of the session {}",
+                                                   requester,
+                                                   session_id)));
+        }
+        if role_of(dal, &session, user_id)?.is_none() {
+            bail!(ErrorKind::ObjectNotFound(format!("User {} is not part of the session {}",
+                                                    user_id,
+                                                    session_id)));
+        }
+        make_owner(dal, session_id, user_id)?;
+        dal.add_audit_entry(AuditEntry::new(session_id.clone(),
+                                            requester.user_id.clone(),
+                                            AuditAction::TransferOwnership)
+            .with_target(user_id.clone()))
+    })
+}
+
+/// Find someone to take over from a user who is going, if they had to
+///
+/// Owners are replaced by a facilitator, or failing that a voter or an
+/// observer. The last admin is replaced the same way, but doesn't make
+/// anyone an owner. Returns who took over, if anyone did.
+pub fn hand_over_session<D>(
+    dal: &mut D,
+    session_id: &SessionID,
+    leaving: &UserID
+) -> Result<Option<UserID>>
+    where D: dal::StoryData
+{
+    dal.lock_session(session_id, |dal| {
+        let session = dal.get_session(session_id)?
+            .ok_or(ErrorKind::ObjectNotFound(format!("Could not find session ID {:?}",
+                                                     session_id)))?;
+        let was_owner = session.owner.as_ref() == Some(leaving);
+        let admins = dal.get_admins(session_id)?;
+        let other_admins: Vec<UserID> = admins.iter().filter(|a| *a != leaving).cloned().collect();
+        if !was_owner && (!admins.contains(leaving) || !other_admins.is_empty()) {
+            return Ok(None);
+        }
+
+        let participants = dal.get_participants(session_id)?;
+        let successor = match other_admins.into_iter().next() {
+            Some(admin) => Some(admin),
+            None => {
+                participants.into_iter()
+                    .filter(|p| p.user_id != *leaving)
+                    .min_by_key(|p| p.observer)
+                    .map(|p| p.user_id)
+            },
+        };
+        match successor {
+            Some(ref successor) if was_owner => make_owner(dal, session_id, successor)?,
+            Some(ref successor) => dal.add_admin(successor.clone(), session_id.clone())?,
+            None if was_owner => {
+                dal.update_session(session_id, |session, _| {
+                    session.owner = None;
+                    Ok(())
+                })?
+            },
+            None => (),
+        }
+        Ok(successor)
+    })
+}
+
+fn make_owner<D>(dal: &mut D, session_id: &SessionID, user_id: &UserID) -> Result<()>
+    where D: dal::StoryData
+{
+    if !dal.is_admin(session_id, user_id)? {
+        dal.add_admin(user_id.clone(), session_id.clone())?;
+    }
+    dal.update_session(session_id, |session, _| {
+        session.owner = Some(user_id.clone());
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::*;
+    use super::super::super::user;
+    use dal::StoryData;
+    use user::Nickname;
+
+    fn session_with_voter<D>(dal: &mut D) -> (SessionID, AuthenticatedUser, AuthenticatedUser)
+        where D: dal::StoryData
+    {
+        let owner = user::get_authenticated_user(dal).unwrap();
+        let session_id = create_session(dal, &owner).unwrap();
+        let voter = user::get_authenticated_user(dal).unwrap();
+        join_session(dal,
+                     &session_id,
+                     &voter.user_id,
+                     &voter,
+                     &Nickname::new("bob"))
+            .unwrap();
+        (session_id, owner, voter)
+    }
+
+    fn is_forbidden<T>(result: Result<T>) -> bool {
+        match result {
+            Err(Error(ErrorKind::UserForbidden(_), _)) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn roles_in_a_session() {
+        let mut dal = dal::MemoryDB::new();
+        let (session_id, owner, voter) = session_with_voter(&mut dal);
+        let stranger = user::get_authenticated_user(&mut dal).unwrap();
+        let session = dal.get_session(&session_id).unwrap().unwrap();
+        assert_eq!(role_of(&dal, &session, &owner.user_id).unwrap(), Some(Role::Owner));
+        assert_eq!(role_of(&dal, &session, &voter.user_id).unwrap(), Some(Role::Voter));
+        assert_eq!(role_of(&dal, &session, &stranger.user_id).unwrap(), None);
+
+        set_role(&mut dal, &session_id, &voter.user_id, Role::Observer, &owner).unwrap();
+        assert_eq!(role_of(&dal, &session, &voter.user_id).unwrap(), Some(Role::Observer));
+        set_role(&mut dal, &session_id, &voter.user_id, Role::Facilitator, &owner).unwrap();
+        assert_eq!(role_of(&dal, &session, &voter.user_id).unwrap(), Some(Role::Facilitator));
+        // Only ones who have joined can be voters
+        assert!(set_role(&mut dal, &session_id, &stranger.user_id, Role::Voter, &owner).is_err());
+    }
+
+    #[test]
+    fn observers_can_not_vote() {
+        let mut dal = dal::MemoryDB::new();
+        let (session_id, owner, voter) = session_with_voter(&mut dal);
+        set_role(&mut dal, &session_id, &voter.user_id, Role::Observer, &owner).unwrap();
+        assert!(is_forbidden(place_vote(&mut dal, &session_id, &voter.user_id, &voter, 3)));
+        set_role(&mut dal, &session_id, &voter.user_id, Role::Voter, &owner).unwrap();
+        place_vote(&mut dal, &session_id, &voter.user_id, &voter, 3).unwrap();
+    }
+
+    #[test]
+    fn facilitators_can_not_act_on_the_owner() {
+        let mut dal = dal::MemoryDB::new();
+        let (session_id, owner, facilitator) = session_with_voter(&mut dal);
+        grant_admin(&mut dal, &session_id, &facilitator.user_id, &owner).unwrap();
+        assert!(is_forbidden(revoke_admin(&mut dal, &session_id, &owner.user_id, &facilitator)));
+        assert!(is_forbidden(kick_user(&mut dal, &session_id, &owner.user_id, &facilitator)));
+        assert!(set_role(&mut dal, &session_id, &owner.user_id, Role::Owner, &owner).is_err());
+        // But the owner can demote them
+        revoke_admin(&mut dal, &session_id, &facilitator.user_id, &owner).unwrap();
+        assert!(!dal.is_admin(&session_id, &facilitator.user_id).unwrap());
+    }
+
+    #[test]
+    fn keep_the_last_admin() {
+        let mut dal = dal::MemoryDB::new();
+        let (session_id, admin, voter) = session_with_voter(&mut dal);
+        // As in sessions from before there were owners
+        dal.update_session(&session_id, |session, _| {
+                session.owner = None;
+                Ok(())
+            })
+            .unwrap();
+        assert!(revoke_admin(&mut dal, &session_id, &admin.user_id, &admin).is_err());
+        assert!(dal.is_admin(&session_id, &admin.user_id).unwrap());
+
+        grant_admin(&mut dal, &session_id, &voter.user_id, &admin).unwrap();
+        revoke_admin(&mut dal, &session_id, &admin.user_id, &admin).unwrap();
+        assert_eq!(dal.get_admins(&session_id).unwrap(), vec![voter.user_id]);
+    }
+
+    #[test]
+    fn transfer_a_session() {
+        let mut dal = dal::MemoryDB::new();
+        let (session_id, owner, voter) = session_with_voter(&mut dal);
+        let stranger = user::get_authenticated_user(&mut dal).unwrap();
+        assert!(is_forbidden(transfer_ownership(&mut dal, &session_id, &voter.user_id, &voter)));
+        assert!(transfer_ownership(&mut dal, &session_id, &stranger.user_id, &owner).is_err());
+
+        transfer_ownership(&mut dal, &session_id, &voter.user_id, &owner).unwrap();
+        let session = dal.get_session(&session_id).unwrap().unwrap();
+        assert_eq!(session.owner, Some(voter.user_id.clone()));
+        assert_eq!(role_of(&dal, &session, &owner.user_id).unwrap(), Some(Role::Facilitator));
+        assert!(is_forbidden(transfer_ownership(&mut dal, &session_id, &owner.user_id, &owner)));
+    }
+
+    #[test]
+    fn hand_over_to_a_voter_before_an_observer() {
+        let mut dal = dal::MemoryDB::new();
+        let (session_id, owner, observer) = session_with_voter(&mut dal);
+        set_role(&mut dal, &session_id, &observer.user_id, Role::Observer, &owner).unwrap();
+        let voter = user::get_authenticated_user(&mut dal).unwrap();
+        join_session(&mut dal,
+                     &session_id,
+                     &voter.user_id,
+                     &voter,
+                     &Nickname::new("bill"))
+            .unwrap();
+
+        assert_eq!(hand_over_session(&mut dal, &session_id, &observer.user_id).unwrap(), None);
+        assert_eq!(hand_over_session(&mut dal, &session_id, &owner.user_id).unwrap(),
+                   Some(voter.user_id.clone()));
+        let session = dal.get_session(&session_id).unwrap().unwrap();
+        assert_eq!(session.owner, Some(voter.user_id));
+    }
+
+    #[test]
+    fn policies_change_who_may_do_what() {
+        let mut dal = dal::MemoryDB::new();
+        let (session_id, owner, voter) = session_with_voter(&mut dal);
+        let reveal = SessionState::Visible;
+        assert!(is_forbidden(update_session(&mut dal, &session_id, &reveal, &voter)));
+        assert!(is_forbidden(update_policy(&mut dal, &session_id, &Policy::default(), &voter)));
+
+        let mut policy = Policy::default();
+        policy.reveal = Role::Voter;
+        update_policy(&mut dal, &session_id, &policy, &owner).unwrap();
+        update_session(&mut dal, &session_id, &reveal, &voter).unwrap();
+        assert!(is_forbidden(update_session(&mut dal, &session_id, &SessionState::Clean, &voter)));
+
+        // Facilitators can't lock themselves out of the settings, only be locked out
+        grant_admin(&mut dal, &session_id, &voter.user_id, &owner).unwrap();
+        policy.edit_settings = Role::Owner;
+        assert!(update_policy(&mut dal, &session_id, &policy, &voter).is_err());
+        update_policy(&mut dal, &session_id, &policy, &owner).unwrap();
+        assert_eq!(dal.get_session(&session_id).unwrap().unwrap().policy, policy);
+        assert!(is_forbidden(update_policy(&mut dal, &session_id, &policy, &voter)));
+    }
+}
@@ -1,12 +1,19 @@
 use dal;
 use errors::*;
-use user::{AuthenticatedUser, BasicUser, UserID, UserToken};
+use user::{AuthenticatedUser, BearerSigner, BearerToken, DeviceToken, NewToken, NewUser,
+           PublicToken, Scope, TokenHash, TokenID, UserID, UserToken};
 
-pub fn create_user<D>(dal: &mut D) -> Result<BasicUser>
+/// How many device tokens a user can have at once
+pub const MAX_DEVICE_TOKENS: usize = 20;
+/// The longest name a device token can have
+const MAX_TOKEN_NAME_LENGTH: usize = 64;
+
+/// Store a new user, returning its token which can't be looked up again
+pub fn create_user<D>(dal: &mut D) -> Result<NewUser>
     where D: dal::StoryData
 {
-    let new_user = BasicUser::new();
-    dal.add_user(new_user.clone())?;
+    let new_user = NewUser::new();
+    dal.add_user(new_user.to_user())?;
     Ok(new_user)
 }
 
@@ -27,24 +34,131 @@ pub fn authenticate_user<D>(
 pub fn get_authenticated_user<D>(dal: &mut D) -> Result<AuthenticatedUser>
     where D: dal::StoryData
 {
-    let new_user: BasicUser = create_user(dal)?;
+    let new_user = create_user(dal)?;
     Ok(authenticate_user(dal, &new_user.user_id, &new_user.user_token)
         ?
         .unwrap())
 }
 
+/// Make another token for the user, e.g. for a new device
+///
+/// The token can't have a scope the token used to make it doesn't.
+pub fn create_token<D>(
+    dal: &mut D,
+    user: &AuthenticatedUser,
+    name: &str,
+    scopes: &[Scope]
+) -> Result<NewToken>
+    where D: dal::StoryData
+{
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_TOKEN_NAME_LENGTH {
+        bail!(ErrorKind::UserError(format!("A token needs a name of 1 to {} characters",
+                                           MAX_TOKEN_NAME_LENGTH)));
+    }
+    let scopes = check_scopes(user, scopes)?;
+    if scopes.contains(&Scope::UserAccount) {
+        bail!(ErrorKind::UserError(format!("A device token can't have the {} scope",
+                                           Scope::UserAccount)));
+    }
+    let mut created = None;
+    dal.update_user(&user.user_id, |u| {
+        if u.device_tokens.len() >= MAX_DEVICE_TOKENS {
+            bail!(ErrorKind::UserError(format!("A user can't have more than {} device tokens",
+                                               MAX_DEVICE_TOKENS)));
+        }
+        let (device_token, new_token) = DeviceToken::new(name.to_string(), scopes.clone());
+        u.device_tokens.push(device_token);
+        created = Some(new_token);
+        Ok(())
+    })?;
+    created.ok_or("The device token was not saved".into())
+}
+
+/// Sign a short lived bearer token for the user, which only works for `tenant`
+pub fn issue_bearer(
+    signer: &BearerSigner,
+    user: &AuthenticatedUser,
+    scopes: &[Scope],
+    tenant: Option<&str>
+) -> Result<BearerToken> {
+    let scopes = check_scopes(user, scopes)?;
+    Ok(signer.issue(&user.user_id, scopes, tenant))
+}
+
+/// The scopes for a token made by `user`, who can't hand out scopes it doesn't have
+fn check_scopes(user: &AuthenticatedUser, scopes: &[Scope]) -> Result<Vec<Scope>> {
+    if scopes.is_empty() {
+        bail!(ErrorKind::UserError("A token needs at least one scope".to_string()));
+    }
+    for scope in scopes {
+        user.require_scope(*scope)?;
+    }
+    let mut scopes = scopes.to_vec();
+    scopes.sort();
+    scopes.dedup();
+    Ok(scopes)
+}
+
+/// The user's device tokens, without anything that would authenticate as them
+pub fn list_tokens<D>(dal: &D, user: &AuthenticatedUser) -> Result<Vec<PublicToken>>
+    where D: dal::StoryData
+{
+    let stored = dal.get_user(&user.user_id)?
+        .ok_or(ErrorKind::ObjectNotFound(format!("Could not find user: {}", user.user_id)))?;
+    Ok(stored.device_tokens.iter().map(|t| t.to_public()).collect())
+}
+
+/// Stop one of the user's device tokens from working
+pub fn revoke_token<D>(dal: &mut D, user: &AuthenticatedUser, token_id: &TokenID) -> Result<()>
+    where D: dal::StoryData
+{
+    dal.update_user(&user.user_id, |u| {
+        let before = u.device_tokens.len();
+        u.device_tokens.retain(|t| t.token_id != *token_id);
+        if u.device_tokens.len() == before {
+            bail!(ErrorKind::ObjectNotFound(format!("No device token {} for user {}",
+                                                    token_id,
+                                                    u.user_id)));
+        }
+        Ok(())
+    })
+}
+
+/// Replace the user's primary token, returning the new one
+///
+/// Device tokens keep working, each has to be revoked on its own, and can't
+/// do this themselves.
+pub fn rotate_token<D>(dal: &mut D, user: &AuthenticatedUser) -> Result<NewUser>
+    where D: dal::StoryData
+{
+    user.require_scope(Scope::UserAccount)?;
+    let user_token = UserToken::new();
+    let token_hash = TokenHash::new(&user_token);
+    dal.update_user(&user.user_id, |u| {
+        u.token_hash = token_hash.clone();
+        Ok(())
+    })?;
+    Ok(NewUser {
+        user_id: user.user_id.clone(),
+        user_token: user_token,
+    })
+}
+
 
 #[cfg(test)]
 mod test {
     use super::*;
     use dal::StoryData;
+    use std::time::Duration;
 
     #[test]
     fn create_user_and_check() {
         let mut dal = dal::MemoryDB::new();
         let new_user = create_user(&mut dal).unwrap();
         let saved_user = dal.get_user(&new_user.user_id).unwrap().unwrap();
-        assert_eq!(new_user, saved_user);
+        assert_eq!(new_user.user_id, saved_user.user_id);
+        assert!(saved_user.authenticate(&new_user.user_token).is_some());
     }
 
     #[test]
@@ -70,7 +184,7 @@ mod test {
     fn auth_missing_user() {
         let mut dal = dal::MemoryDB::new();
         create_user(&mut dal).unwrap();
-        let bad_user = BasicUser::new();
+        let bad_user = NewUser::new();
         let auth_user_opt = authenticate_user(&dal, &bad_user.user_id, &bad_user.user_token)
             .unwrap();
         assert_eq!(auth_user_opt, None);
@@ -81,4 +195,106 @@ mod test {
         let mut dal = dal::MemoryDB::new();
         let auth_user = get_authenticated_user(&mut dal).unwrap();
     }
+
+    #[test]
+    fn device_tokens_authenticate() {
+        let mut dal = dal::MemoryDB::new();
+        let auth_user = get_authenticated_user(&mut dal).unwrap();
+        let laptop = create_token(&mut dal, &auth_user, " laptop ", &Scope::device()).unwrap();
+        assert_eq!(laptop.name, "laptop");
+        let phone = create_token(&mut dal, &auth_user, "phone", &Scope::device()).unwrap();
+        assert_eq!(authenticate_user(&dal, &auth_user.user_id, &laptop.user_token).unwrap(),
+                   Some(auth_user.clone()));
+
+        let names: Vec<String> = list_tokens(&dal, &auth_user)
+            .unwrap()
+            .into_iter()
+            .map(|t| t.name)
+            .collect();
+        assert_eq!(names, vec!["laptop".to_string(), "phone".to_string()]);
+
+        revoke_token(&mut dal, &auth_user, &laptop.token_id).unwrap();
+        assert_eq!(authenticate_user(&dal, &auth_user.user_id, &laptop.user_token).unwrap(),
+                   None);
+        assert!(authenticate_user(&dal, &auth_user.user_id, &phone.user_token)
+            .unwrap()
+            .is_some());
+        assert!(revoke_token(&mut dal, &auth_user, &laptop.token_id).is_err());
+    }
+
+    #[test]
+    fn device_tokens_need_a_name() {
+        let mut dal = dal::MemoryDB::new();
+        let auth_user = get_authenticated_user(&mut dal).unwrap();
+        assert!(create_token(&mut dal, &auth_user, "  ", &Scope::device()).is_err());
+        let long_name = "x".repeat(MAX_TOKEN_NAME_LENGTH + 1);
+        assert!(create_token(&mut dal, &auth_user, &long_name, &Scope::device()).is_err());
+    }
+
+    #[test]
+    fn device_tokens_are_limited() {
+        let mut dal = dal::MemoryDB::new();
+        let auth_user = get_authenticated_user(&mut dal).unwrap();
+        for _ in 0..MAX_DEVICE_TOKENS {
+            create_token(&mut dal, &auth_user, "device", &Scope::device()).unwrap();
+        }
+        assert!(create_token(&mut dal, &auth_user, "one too many", &Scope::device()).is_err());
+    }
+
+    #[test]
+    fn rotate_primary_token() {
+        let mut dal = dal::MemoryDB::new();
+        let new_user = create_user(&mut dal).unwrap();
+        let auth_user = authenticate_user(&dal, &new_user.user_id, &new_user.user_token)
+            .unwrap()
+            .unwrap();
+        let phone = create_token(&mut dal, &auth_user, "phone", &Scope::device()).unwrap();
+        let rotated = rotate_token(&mut dal, &auth_user).unwrap();
+        assert_eq!(authenticate_user(&dal, &new_user.user_id, &new_user.user_token).unwrap(),
+                   None);
+        for token in &[&rotated.user_token, &phone.user_token] {
+            assert!(authenticate_user(&dal, &new_user.user_id, token).unwrap().is_some());
+        }
+    }
+    #[test]
+    fn device_tokens_cannot_rotate_primary_token() {
+        let mut dal = dal::MemoryDB::new();
+        let auth_user = get_authenticated_user(&mut dal).unwrap();
+        assert!(create_token(&mut dal, &auth_user, "phone", &Scope::all()).is_err());
+        let phone = create_token(&mut dal, &auth_user, "phone", &Scope::device()).unwrap();
+        let phone_user = authenticate_user(&dal, &auth_user.user_id, &phone.user_token)
+            .unwrap()
+            .unwrap();
+        assert!(rotate_token(&mut dal, &phone_user).is_err());
+        assert!(create_token(&mut dal, &phone_user, "laptop", &[Scope::UserAccount]).is_err());
+    }
+    #[test]
+    fn device_tokens_can_only_narrow_scopes() {
+        let mut dal = dal::MemoryDB::new();
+        let auth_user = get_authenticated_user(&mut dal).unwrap();
+        assert!(create_token(&mut dal, &auth_user, "nothing", &[]).is_err());
+        let scopes = [Scope::SessionVote, Scope::SessionRead, Scope::SessionVote];
+        let bot = create_token(&mut dal, &auth_user, "bot", &scopes).unwrap();
+        assert_eq!(bot.scopes, vec![Scope::SessionRead, Scope::SessionVote]);
+
+        let bot_user = authenticate_user(&dal, &auth_user.user_id, &bot.user_token)
+            .unwrap()
+            .unwrap();
+        assert_eq!(bot_user.scopes, bot.scopes);
+        assert!(bot_user.require_scope(Scope::SessionAdmin).is_err());
+        assert!(create_token(&mut dal, &bot_user, "admin", &[Scope::SessionAdmin]).is_err());
+        assert!(create_token(&mut dal, &bot_user, "reader", &[Scope::SessionRead]).is_ok());
+    }
+
+    #[test]
+    fn bearer_tokens_carry_scopes() {
+        let mut dal = dal::MemoryDB::new();
+        let auth_user = get_authenticated_user(&mut dal).unwrap();
+        let signer = BearerSigner::random(Duration::from_secs(60));
+        let token = issue_bearer(&signer, &auth_user, &[Scope::SessionVote], None).unwrap();
+        let bearer_user = signer.verify(&token.access_token, None).unwrap();
+        assert_eq!(bearer_user.user_id, auth_user.user_id);
+        assert_eq!(bearer_user.scopes, vec![Scope::SessionVote]);
+        assert!(issue_bearer(&signer, &bearer_user, &[Scope::SessionAdmin], None).is_err());
+    }
 }
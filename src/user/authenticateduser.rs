@@ -1,9 +1,23 @@
-use super::{User, UserID};
+use super::{Scope, User, UserID};
+use errors::*;
 use std::fmt;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct AuthenticatedUser {
     pub user_id: UserID,
+    /// What the token the user authenticated with allows
+    pub scopes: Vec<Scope>,
+}
+
+impl AuthenticatedUser {
+    /// Fail unless the user's token allows `scope`
+    pub fn require_scope(&self, scope: Scope) -> Result<()> {
+        if self.scopes.contains(&scope) {
+            Ok(())
+        } else {
+            bail!(ErrorKind::UserForbidden(format!("This token lacks the {} scope", scope)))
+        }
+    }
 }
 
 impl User for AuthenticatedUser {
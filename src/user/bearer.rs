@@ -0,0 +1,225 @@
+//! Short lived tokens signed by the server, which can be checked without
+//! looking the user up
+//!
+//! They are JSON Web Tokens signed with HMAC-SHA256 (RFC 7519), so clients
+//! can read when they expire with any JWT library.
+
+use super::{AuthenticatedUser, Scope, UserID};
+use rustc_serialize::base64::{FromBase64, ToBase64, URL_SAFE};
+use serde_json;
+use std::time::Duration;
+use util::{clock, crypto, generator};
+
+/// `{"alg":"HS256","typ":"JWT"}`, the header of every token we sign
+const JWT_HEADER: &'static str = "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9";
+
+/// What a bearer token says about its holder
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct Claims {
+    sub: UserID,
+    scopes: Vec<Scope>,
+    /// The tenant the user belongs to, `None` for the default tenant
+    #[serde(default)]
+    tenant: Option<String>,
+    iat: u64,
+    exp: u64,
+}
+
+/// A bearer token that was just issued
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BearerToken {
+    pub access_token: String,
+    /// Always `Bearer`, as in OAuth 2.0 token responses
+    pub token_type: String,
+    /// Seconds until the token stops working
+    pub expires_in: u64,
+    pub scopes: Vec<Scope>,
+}
+
+/// Issues and checks bearer tokens with a secret only the server knows
+pub struct BearerSigner {
+    hmac: crypto::Hmac,
+    ttl: u64,
+    /// How long the tokens in browsers' session cookies last
+    cookie_ttl: u64,
+}
+
+impl BearerSigner {
+    pub fn new(secret: &[u8], ttl: Duration) -> Self {
+        BearerSigner {
+            hmac: crypto::Hmac::new(secret),
+            ttl: ttl.as_secs(),
+            cookie_ttl: ttl.as_secs(),
+        }
+    }
+
+    /// Make the tokens of session cookies last `cookie_ttl`, rather than as long as others
+    pub fn with_cookie_ttl(mut self, cookie_ttl: Duration) -> Self {
+        self.cookie_ttl = cookie_ttl.as_secs();
+        self
+    }
+
+    /// Sign with a secret nothing else knows, which is lost on restart
+    pub fn random(ttl: Duration) -> Self {
+        BearerSigner::new(&generator::secret_key(), ttl)
+    }
+
+    /// A token for the user with `scopes`, which only works for `tenant`
+    pub fn issue(
+        &self,
+        user_id: &UserID,
+        scopes: Vec<Scope>,
+        tenant: Option<&str>
+    ) -> BearerToken {
+        self.issue_for(user_id, scopes, tenant, self.ttl)
+    }
+
+    /// A token for a browser's session cookie, otherwise like `issue`
+    pub fn issue_for_cookie(
+        &self,
+        user_id: &UserID,
+        scopes: Vec<Scope>,
+        tenant: Option<&str>
+    ) -> BearerToken {
+        self.issue_for(user_id, scopes, tenant, self.cookie_ttl)
+    }
+
+    fn issue_for(
+        &self,
+        user_id: &UserID,
+        scopes: Vec<Scope>,
+        tenant: Option<&str>,
+        ttl: u64
+    ) -> BearerToken {
+        let now = clock::now();
+        let claims = Claims {
+            sub: user_id.clone(),
+            scopes: scopes.clone(),
+            tenant: tenant.map(|t| t.to_string()),
+            iat: now,
+            exp: now + ttl,
+        };
+        let payload = serde_json::to_vec(&claims)
+            .expect("bearer token claims are always valid JSON")
+            .to_base64(URL_SAFE);
+        let signed = format!("{}.{}", JWT_HEADER, payload);
+        let signature = self.hmac.mac(signed.as_bytes()).to_base64(URL_SAFE);
+        BearerToken {
+            access_token: format!("{}.{}", signed, signature),
+            token_type: "Bearer".to_string(),
+            expires_in: ttl,
+            scopes: scopes,
+        }
+    }
+
+    /// The CSRF token that goes with a token, for when it's sent as a cookie
+    ///
+    /// Other sites can make a browser send its cookies, but can't read the
+    /// token to work this out.
+    pub fn csrf_token(&self, access_token: &str) -> String {
+        self.hmac.mac(format!("csrf\n{}", access_token).as_bytes()).to_base64(URL_SAFE)
+    }
+
+    pub fn check_csrf_token(&self, access_token: &str, csrf_token: &str) -> bool {
+        let expected = self.hmac.mac(format!("csrf\n{}", access_token).as_bytes());
+        match csrf_token.from_base64() {
+            Ok(ref mac) => crypto::constant_time_eq(&expected, mac),
+            Err(_) => false,
+        }
+    }
+
+    /// The user a token was issued to, if we signed it for `tenant` and it hasn't expired
+    pub fn verify(&self, token: &str, tenant: Option<&str>) -> Option<AuthenticatedUser> {
+        let parts: Vec<&str> = token.split('.').collect();
+        if parts.len() != 3 || parts[0] != JWT_HEADER {
+            return None;
+        }
+        let expected = self.hmac.mac(format!("{}.{}", parts[0], parts[1]).as_bytes());
+        match parts[2].from_base64() {
+            Ok(ref signature) if crypto::constant_time_eq(&expected, signature) => (),
+            _ => return None,
+        }
+        let claims: Claims = match parts[1].from_base64().map(|p| serde_json::from_slice(&p)) {
+            Ok(Ok(claims)) => claims,
+            _ => return None,
+        };
+        if claims.exp <= clock::now() || claims.tenant.as_ref().map(|t| t.as_str()) != tenant {
+            return None;
+        }
+        Some(AuthenticatedUser {
+            user_id: claims.sub,
+            scopes: claims.scopes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn signer() -> BearerSigner {
+        BearerSigner::new(b"a secret that is long enough to sign with", Duration::from_secs(60))
+    }
+
+    #[test]
+    fn header_is_hs256() {
+        assert_eq!(JWT_HEADER,
+                   br#"{"alg":"HS256","typ":"JWT"}"#.to_base64(URL_SAFE));
+    }
+
+    #[test]
+    fn verify_issued_tokens() {
+        let user_id = UserID::new();
+        let token = signer().issue(&user_id, vec![Scope::SessionRead], Some("acme"));
+        assert_eq!(token.expires_in, 60);
+        let user = signer().verify(&token.access_token, Some("acme")).unwrap();
+        assert_eq!(user.user_id, user_id);
+        assert_eq!(user.scopes, vec![Scope::SessionRead]);
+
+        assert_eq!(signer().verify(&token.access_token, None), None);
+        let other = BearerSigner::new(b"another secret, just as long as the first",
+                                      Duration::from_secs(60));
+        assert_eq!(other.verify(&token.access_token, Some("acme")), None);
+    }
+
+    #[test]
+    fn reject_altered_tokens() {
+        let token = signer().issue(&UserID::new(), vec![Scope::SessionRead], None).access_token;
+        let parts: Vec<&str> = token.split('.').collect();
+        let claims = Claims {
+            sub: UserID::new(),
+            scopes: Scope::all(),
+            tenant: None,
+            iat: 0,
+            exp: clock::now() + 60,
+        };
+        let forged_payload = serde_json::to_vec(&claims).unwrap().to_base64(URL_SAFE);
+        let forged = format!("{}.{}.{}", parts[0], forged_payload, parts[2]);
+        assert_eq!(signer().verify(&forged, None), None);
+        assert_eq!(signer().verify(&format!("{}.{}.", parts[0], parts[1]), None), None);
+        assert_eq!(signer().verify("not.a.token", None), None);
+    }
+
+    #[test]
+    fn csrf_tokens_go_with_their_token() {
+        let signer = signer().with_cookie_ttl(Duration::from_secs(3600));
+        let token = signer.issue_for_cookie(&UserID::new(), Scope::all(), None);
+        assert_eq!(token.expires_in, 3600);
+        let other = signer.issue_for_cookie(&UserID::new(), Scope::all(), None);
+        let csrf_token = signer.csrf_token(&token.access_token);
+        assert!(signer.check_csrf_token(&token.access_token, &csrf_token));
+        assert!(!signer.check_csrf_token(&other.access_token, &csrf_token));
+        assert!(!signer.check_csrf_token(&token.access_token, ""));
+        assert!(!signer.check_csrf_token(&token.access_token, &token.access_token));
+    }
+
+    #[test]
+    fn reject_expired_tokens() {
+        let expired = BearerSigner::new(b"a secret that is long enough to sign with",
+                                        Duration::from_secs(0));
+        let token = expired.issue(&UserID::new(), Scope::all(), None).access_token;
+        assert_eq!(signer().verify(&token, None), None);
+    }
+}
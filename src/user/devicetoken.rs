@@ -0,0 +1,96 @@
+use super::{Scope, TokenHash, UserToken};
+use std::fmt;
+use util::{clock, generator};
+
+/// Identifies one of a user's device tokens
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TokenID(pub String);
+
+/// An extra token a user made, e.g. for another device, which can be revoked on its own
+///
+/// The token itself reads `<token_id>.<secret>`, so authenticating with it
+/// only has to check the hash of the one token it names.
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DeviceToken {
+    pub token_id: TokenID,
+    pub name: String,
+    pub token_hash: TokenHash,
+    /// What the token allows, tokens from before scopes allow all a device token may
+    #[serde(default = "Scope::device")]
+    pub scopes: Vec<Scope>,
+    /// When the token was made, see `util::clock`
+    pub created: u64,
+}
+
+/// What anyone may see of a device token
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PublicToken {
+    pub token_id: TokenID,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created: u64,
+}
+
+/// A device token that was just made, the only time the token itself is known
+#[derive(Serialize, Deserialize)]
+#[derive(PartialEq, Eq, Clone)]
+pub struct NewToken {
+    pub token_id: TokenID,
+    pub name: String,
+    pub user_token: UserToken,
+    pub scopes: Vec<Scope>,
+    pub created: u64,
+}
+
+impl fmt::Display for TokenID {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Debug for NewToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "NewToken{{ token_id: {:?}, name: {:?}, user_token: REDACTED, scopes: {:?}, \
+                created: {} }}",
+               self.token_id,
+               self.name,
+               self.scopes,
+               self.created)
+    }
+}
+
+impl DeviceToken {
+    /// Make a token, returning both the record to store and what to hand to the user
+    pub fn new(name: String, scopes: Vec<Scope>) -> (DeviceToken, NewToken) {
+        let token_id = TokenID(generator::token_id());
+        let user_token = UserToken(format!("{}.{}", token_id, generator::authtoken()));
+        let device_token = DeviceToken {
+            token_id: token_id.clone(),
+            name: name.clone(),
+            token_hash: TokenHash::new(&user_token),
+            scopes: scopes.clone(),
+            created: clock::now(),
+        };
+        let new_token = NewToken {
+            token_id: token_id,
+            name: name,
+            user_token: user_token,
+            scopes: scopes,
+            created: device_token.created,
+        };
+        (device_token, new_token)
+    }
+
+    pub fn to_public(&self) -> PublicToken {
+        PublicToken {
+            token_id: self.token_id.clone(),
+            name: self.name.clone(),
+            scopes: self.scopes.clone(),
+            created: self.created,
+        }
+    }
+}
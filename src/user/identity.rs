@@ -0,0 +1,52 @@
+use super::UserID;
+use rustc_serialize::base64::{ToBase64, URL_SAFE};
+use util::crypto;
+
+/// Who a user is to an identity provider, such as an OpenID Connect issuer
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ExternalIdentity {
+    /// Identifies the provider, e.g. the `iss` of an OpenID Connect ID token
+    pub issuer: String,
+    /// Identifies the user to the provider, which never reuses it
+    pub subject: String,
+    /// What the provider calls the user, if it said
+    pub name: Option<String>,
+}
+
+impl ExternalIdentity {
+    /// The ID of the user with this identity, the same every time they sign in
+    pub fn user_id(&self) -> UserID {
+        let digest = crypto::sha256(format!("{}\n{}", self.issuer, self.subject).as_bytes());
+        UserID(digest.to_base64(URL_SAFE)[..15].to_string())
+    }
+
+    /// Whether this is the same identity, whatever its name is now
+    pub fn is(&self, other: &ExternalIdentity) -> bool {
+        self.issuer == other.issuer && self.subject == other.subject
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn identity(issuer: &str, subject: &str) -> ExternalIdentity {
+        ExternalIdentity {
+            issuer: issuer.to_string(),
+            subject: subject.to_string(),
+            name: None,
+        }
+    }
+
+    #[test]
+    fn user_ids_follow_the_identity() {
+        let alice = identity("https://sso.example.com", "alice");
+        assert_eq!(alice.user_id(), alice.user_id());
+        assert_eq!(alice.user_id().0.len(), 15);
+        assert!(alice.user_id() != identity("https://sso.example.com", "bob").user_id());
+        assert!(alice.user_id() != identity("https://sso.example.org", "alice").user_id());
+        // The separator keeps the issuer and subject apart
+        assert!(identity("a", "b\nc").user_id() != identity("a\nb", "c").user_id());
+    }
+}
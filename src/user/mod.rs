@@ -1,8 +1,19 @@
+use rustc_serialize::hex::{FromHex, ToHex};
+use serde::{Deserialize, Deserializer};
+use serde::de;
 use std::fmt;
-use util::generator;
+use util::{clock, crypto, generator};
 
 mod authenticateduser;
+mod bearer;
+mod devicetoken;
+mod identity;
+mod scope;
 pub use self::authenticateduser::AuthenticatedUser;
+pub use self::bearer::{BearerSigner, BearerToken};
+pub use self::devicetoken::{DeviceToken, NewToken, PublicToken, TokenID};
+pub use self::identity::ExternalIdentity;
+pub use self::scope::Scope;
 
 #[derive(Serialize, Deserialize)]
 #[derive(Debug, PartialEq, Eq, Clone, PartialOrd, Ord)]
@@ -34,6 +45,11 @@ impl UserToken {
     pub fn new() -> Self {
         UserToken(generator::authtoken())
     }
+
+    /// The device token this claims to be, or `None` for a user's primary token
+    pub fn token_id(&self) -> Option<TokenID> {
+        self.0.find('.').map(|end| TokenID(self.0[..end].to_string()))
+    }
 }
 
 impl From<String> for UserToken {
@@ -42,9 +58,110 @@ impl From<String> for UserToken {
     }
 }
 
+/// How a `TokenHash` was made, which is the first part of it
+const TOKEN_HASH_SCHEME: &'static str = "pbkdf2-sha256";
+
+/// PBKDF2 rounds for newly hashed tokens, making each guess at a token slow
+#[cfg(not(test))]
+const TOKEN_HASH_ITERATIONS: u32 = 10000;
+// The tests create a lot of users, and don't need their tokens kept safe
+#[cfg(test)]
+const TOKEN_HASH_ITERATIONS: u32 = 2;
+
+/// A salted hash of a `UserToken`, which is all that gets stored
+///
+/// It reads `pbkdf2-sha256$<iterations>$<salt>$<hash>`, with the salt and
+/// hash in hex, so the number of iterations can be raised later without
+/// invalidating the hashes already stored.
 #[derive(Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TokenHash(String);
+
+impl TokenHash {
+    pub fn new(token: &UserToken) -> Self {
+        TokenHash::with_salt(token, &generator::salt(), TOKEN_HASH_ITERATIONS)
+    }
+
+    fn with_salt(token: &UserToken, salt: &[u8], iterations: u32) -> Self {
+        let hash = crypto::pbkdf2_sha256(token.0.as_bytes(), salt, iterations);
+        TokenHash(format!("{}${}${}${}",
+                          TOKEN_HASH_SCHEME,
+                          iterations,
+                          salt.to_hex(),
+                          hash.to_hex()))
+    }
+
+    /// Whether this is a hash of `token`
+    pub fn verify(&self, token: &UserToken) -> bool {
+        let parts: Vec<&str> = self.0.split('$').collect();
+        if parts.len() != 4 || parts[0] != TOKEN_HASH_SCHEME {
+            return false;
+        }
+        match (parts[1].parse(), parts[2].from_hex(), parts[3].from_hex()) {
+            (Ok(iterations), Ok(salt), Ok(expected)) if iterations > 0 => {
+                let hash = crypto::pbkdf2_sha256(token.0.as_bytes(), &salt, iterations);
+                crypto::constant_time_eq(&hash, &expected)
+            },
+            _ => false,
+        }
+    }
+}
+
+#[derive(Serialize)]
 #[derive(PartialEq, Eq, Clone)]
 pub struct BasicUser {
+    pub user_id: UserID,
+    pub token_hash: TokenHash,
+    /// Further tokens, each of which also authenticates the user
+    pub device_tokens: Vec<DeviceToken>,
+    /// Who signed in as the user through an identity provider, if anyone
+    pub identity: Option<ExternalIdentity>,
+    /// When the user last authenticated, see `util::clock`
+    pub last_activity: u64,
+}
+
+/// A stored user, which from before tokens were hashed has the token itself
+#[derive(Deserialize)]
+struct StoredUser {
+    user_id: UserID,
+    #[serde(default)]
+    token_hash: Option<TokenHash>,
+    #[serde(default)]
+    user_token: Option<UserToken>,
+    #[serde(default)]
+    device_tokens: Vec<DeviceToken>,
+    #[serde(default)]
+    identity: Option<ExternalIdentity>,
+    #[serde(default = "clock::now")]
+    last_activity: u64,
+}
+
+impl Deserialize for BasicUser {
+    fn deserialize<D>(deserializer: D) -> ::std::result::Result<Self, D::Error>
+        where D: Deserializer
+    {
+        let stored = StoredUser::deserialize(deserializer)?;
+        let token_hash = match (stored.token_hash, stored.user_token) {
+            (Some(token_hash), _) => token_hash,
+            (None, Some(user_token)) => TokenHash::new(&user_token),
+            (None, None) => return Err(de::Error::missing_field("token_hash")),
+        };
+        Ok(BasicUser {
+            user_id: stored.user_id,
+            token_hash: token_hash,
+            device_tokens: stored.device_tokens,
+            identity: stored.identity,
+            last_activity: stored.last_activity,
+        })
+    }
+}
+
+/// A user that was just created, along with its token
+///
+/// This is the only time the token is known, as only its hash is stored.
+#[derive(Serialize, Deserialize)]
+#[derive(PartialEq, Eq, Clone)]
+pub struct NewUser {
     pub user_id: UserID,
     pub user_token: UserToken,
 }
@@ -58,30 +175,70 @@ impl fmt::Display for UserID {
 impl fmt::Debug for BasicUser {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f,
-               "BasicUser{{ user_id: {:?}, user_token: REDACTED }}",
-               self.user_id)
+               "BasicUser{{ user_id: {:?}, token_hash: REDACTED, identity: {:?}, \
+                last_activity: {} }}",
+               self.user_id,
+               self.identity,
+               self.last_activity)
     }
 }
 
-
+impl fmt::Debug for NewUser {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NewUser{{ user_id: {:?}, user_token: REDACTED }}", self.user_id)
+    }
+}
 
 impl BasicUser {
+    /// A user with a token of its own, which isn't kept
     pub fn new() -> Self {
-        BasicUser {
-            user_id: UserID::new(),
-            user_token: UserToken::new(),
-        }
+        NewUser::new().to_user()
     }
 
+    /// Check a token against the user's primary token, or the device token it names
+    ///
+    /// The primary token has every scope, a device token the scopes it was made with.
     pub fn authenticate(&self, token: &UserToken) -> Option<AuthenticatedUser> {
-        if self.user_token == *token {
-            Some(AuthenticatedUser { user_id: self.user_id.clone() })
+        let (token_hash, scopes) = match token.token_id() {
+            None => (&self.token_hash, Scope::all()),
+            Some(token_id) => {
+                match self.device_tokens.iter().find(|t| t.token_id == token_id) {
+                    Some(device_token) => (&device_token.token_hash, device_token.scopes.clone()),
+                    None => return None,
+                }
+            },
+        };
+        if token_hash.verify(token) {
+            Some(AuthenticatedUser {
+                user_id: self.user_id.clone(),
+                scopes: scopes,
+            })
         } else {
             None
         }
     }
 }
 
+impl NewUser {
+    pub fn new() -> Self {
+        NewUser {
+            user_id: UserID::new(),
+            user_token: UserToken::new(),
+        }
+    }
+
+    /// The record to store for this user, holding only a hash of the token
+    pub fn to_user(&self) -> BasicUser {
+        BasicUser {
+            user_id: self.user_id.clone(),
+            token_hash: TokenHash::new(&self.user_token),
+            device_tokens: Vec::new(),
+            identity: None,
+            last_activity: clock::now(),
+        }
+    }
+}
+
 pub trait User {
     fn user_id(&self) -> &UserID;
 }
@@ -95,11 +252,12 @@ impl User for BasicUser {
 #[cfg(test)]
 mod test {
     use super::*;
+    use serde_json;
+
     #[test]
     fn get_valid_auth_user() {
-        let new_user = BasicUser::new();
-        let my_token = new_user.user_token.clone();
-        let auth_user = new_user.authenticate(&my_token).unwrap();
+        let new_user = NewUser::new();
+        let auth_user = new_user.to_user().authenticate(&new_user.user_token).unwrap();
         assert_eq!(auth_user.user_id, new_user.user_id);
     }
     #[test]
@@ -109,4 +267,61 @@ mod test {
         let auth_user = new_user.authenticate(&my_token);
         assert_eq!(auth_user, None);
     }
+
+    #[test]
+    fn authenticate_with_device_tokens() {
+        let new_user = NewUser::new();
+        let mut user = new_user.to_user();
+        let (phone, phone_token) = DeviceToken::new("phone".to_string(),
+                                                    vec![Scope::SessionRead]);
+        user.device_tokens.push(phone);
+        assert_eq!(user.authenticate(&phone_token.user_token).unwrap().scopes,
+                   vec![Scope::SessionRead]);
+        assert_eq!(user.authenticate(&new_user.user_token).unwrap().scopes, Scope::all());
+
+        // Naming a token that exists isn't enough
+        let forged = UserToken(format!("{}.{}", phone_token.token_id, new_user.user_token.0));
+        assert!(user.authenticate(&forged).is_none());
+        user.device_tokens.clear();
+        assert!(user.authenticate(&phone_token.user_token).is_none());
+    }
+
+    #[test]
+    fn token_hashes_are_salted() {
+        let my_token = UserToken::new();
+        let first = TokenHash::new(&my_token);
+        let second = TokenHash::new(&my_token);
+        assert!(first != second);
+        assert!(first.verify(&my_token) && second.verify(&my_token));
+        assert!(!first.0.contains(&my_token.0));
+    }
+
+    #[test]
+    fn verify_older_iteration_counts() {
+        let my_token = UserToken::new();
+        let token_hash = TokenHash::with_salt(&my_token, b"salt", 1);
+        assert!(token_hash.0.starts_with("pbkdf2-sha256$1$73616c74$"));
+        assert!(token_hash.verify(&my_token));
+        assert!(!TokenHash("plain".to_string()).verify(&UserToken("plain".to_string())));
+    }
+
+    #[test]
+    fn hash_tokens_stored_in_plain_text() {
+        let stored = r#"{"user_id":"bob","user_token":"secret","last_activity":10}"#;
+        let user: BasicUser = serde_json::from_str(stored).unwrap();
+        assert_eq!(user.last_activity, 10);
+        assert!(user.authenticate(&UserToken("secret".to_string())).is_some());
+        let restored = serde_json::to_string(&user).unwrap();
+        assert!(!restored.contains("secret"));
+        assert_eq!(serde_json::from_str::<BasicUser>(&restored).unwrap(), user);
+    }
+
+    #[test]
+    fn device_tokens_from_before_scopes_allow_all_a_device_may() {
+        let (phone, _) = DeviceToken::new("phone".to_string(), vec![Scope::SessionVote]);
+        let mut stored = serde_json::to_value(&phone).unwrap();
+        stored.as_object_mut().unwrap().remove("scopes");
+        let restored: DeviceToken = serde_json::from_value(stored).unwrap();
+        assert_eq!(restored.scopes, Scope::device());
+    }
 }
@@ -0,0 +1,78 @@
+use std::fmt;
+
+/// Something a token allows its holder to do
+///
+/// A user's primary token has every scope, device tokens can be limited to
+/// fewer so they can be handed to dashboards and bots.
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, PartialOrd, Ord, Hash)]
+pub enum Scope {
+    /// Authenticate and read sessions, which is all a dashboard needs
+    #[serde(rename = "session:read")]
+    SessionRead,
+    /// Join and leave sessions, and vote in them
+    #[serde(rename = "session:vote")]
+    SessionVote,
+    /// Create, control and delete sessions, and manage their participants
+    #[serde(rename = "session:admin")]
+    SessionAdmin,
+    /// Make, list and revoke device tokens
+    #[serde(rename = "user:tokens")]
+    UserTokens,
+    /// Rotate the primary token, which no device token may do
+    ///
+    /// Otherwise a leaked device token could lock the user out of their account.
+    #[serde(rename = "user:account")]
+    UserAccount,
+}
+
+impl Scope {
+    /// Every scope, as held by a user's primary token
+    pub fn all() -> Vec<Scope> {
+        vec![Scope::SessionRead,
+             Scope::SessionVote,
+             Scope::SessionAdmin,
+             Scope::UserTokens,
+             Scope::UserAccount]
+    }
+
+    /// Every scope a device token may have
+    pub fn device() -> Vec<Scope> {
+        Scope::all().into_iter().filter(|s| *s != Scope::UserAccount).collect()
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Scope::SessionRead => "session:read",
+            Scope::SessionVote => "session:vote",
+            Scope::SessionAdmin => "session:admin",
+            Scope::UserTokens => "user:tokens",
+            Scope::UserAccount => "user:account",
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn scopes_are_named() {
+        let scopes = Scope::all();
+        let json = serde_json::to_string(&scopes).unwrap();
+        assert_eq!(json,
+                   r#"["session:read","session:vote","session:admin","user:tokens","user:account"]"#);
+        assert_eq!(serde_json::from_str::<Vec<Scope>>(&json).unwrap(), scopes);
+        for scope in scopes {
+            assert_eq!(json.matches(&format!("\"{}\"", scope)).count(), 1);
+        }
+        assert!(serde_json::from_str::<Scope>(r#""session:everything""#).is_err());
+    }
+}
@@ -0,0 +1,84 @@
+//! The little cryptography needed to store user tokens and sign things:
+//! SHA-256, HMAC, PBKDF2 and comparing secrets, all done by `ring`
+
+use ring::{constant_time, digest, hmac, pbkdf2};
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(digest::digest(&digest::SHA256, data).as_ref());
+    hash
+}
+
+/// HMAC-SHA256 with the key already prepared, so many messages can share the work
+pub struct Hmac {
+    key: hmac::SigningKey,
+}
+
+impl Hmac {
+    pub fn new(key: &[u8]) -> Self {
+        Hmac { key: hmac::SigningKey::new(&digest::SHA256, key) }
+    }
+
+    pub fn mac(&self, message: &[u8]) -> [u8; 32] {
+        let mut mac = [0; 32];
+        mac.copy_from_slice(hmac::sign(&self.key, message).as_ref());
+        mac
+    }
+}
+
+/// Derive a 32 byte key from a password with PBKDF2-HMAC-SHA256
+///
+/// `iterations` must be at least 1.
+pub fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut key = [0; 32];
+    pbkdf2::derive(&digest::SHA256, iterations, salt, password, &mut key);
+    key
+}
+
+/// Compare two secrets, taking the same time wherever they differ
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    constant_time::verify_slices_are_equal(a, b).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rustc_serialize::hex::ToHex;
+
+    #[test]
+    fn sha256_vectors() {
+        assert_eq!(sha256(b"").to_hex(),
+                   "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(sha256(b"abc").to_hex(),
+                   "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        // Long enough to need padding in a block of its own
+        assert_eq!(sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq").to_hex(),
+                   "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+    }
+
+    #[test]
+    fn hmac_vectors() {
+        // RFC 4231, test cases 2 and 6
+        assert_eq!(Hmac::new(b"Jefe").mac(b"what do ya want for nothing?").to_hex(),
+                   "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        assert_eq!(Hmac::new(&[0xaa; 131])
+                       .mac(b"Test Using Larger Than Block-Size Key - Hash Key First")
+                       .to_hex(),
+                   "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+    }
+
+    #[test]
+    fn pbkdf2_vectors() {
+        assert_eq!(pbkdf2_sha256(b"password", b"salt", 1).to_hex(),
+                   "120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b");
+        assert_eq!(pbkdf2_sha256(b"password", b"salt", 4096).to_hex(),
+                   "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a");
+    }
+
+    #[test]
+    fn compare_secrets() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+    }
+}
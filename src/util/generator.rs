@@ -1,4 +1,4 @@
-use rand::{self, Rng};
+use rand::{self, OsRng, Rng};
 
 pub fn session_id() -> String {
     rand::thread_rng()
@@ -22,3 +22,29 @@ pub fn authtoken() -> String {
         .take(25)
         .collect::<String>()
 }
+
+/// Random bytes from the operating system, for anything that must not be guessed
+fn os_random(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    OsRng::new()
+        .expect("the operating system provides random numbers")
+        .fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Random bytes to salt a hash with
+pub fn salt() -> Vec<u8> {
+    os_random(16)
+}
+
+/// Random bytes to sign with, as long as an HMAC-SHA256 key needs to be
+pub fn secret_key() -> Vec<u8> {
+    os_random(32)
+}
+
+pub fn token_id() -> String {
+    rand::thread_rng()
+        .gen_ascii_chars()
+        .take(8)
+        .collect::<String>()
+}
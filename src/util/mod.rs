@@ -1 +1,3 @@
+pub mod clock;
+pub mod crypto;
 pub mod generator;
@@ -1,36 +1,106 @@
 use hyper::header::Basic;
-use rocket::Outcome;
+use rocket::{Outcome, State};
 use rocket::http::Status;
 use rocket::request::{self, FromRequest, Request};
 use std::str::FromStr;
+use user::{UserID, UserToken};
+use webapp::auth::{Authenticators, Credentials};
+use webapp::cookiesession;
+use webapp::tenant::Tenant;
 
-#[derive(Serialize, Deserialize)]
-#[derive(Debug, PartialEq, Eq, Clone)]
+/// The credentials a request came with, and everything that can check them
 pub struct APIKey {
-    pub user_id: String,
-    pub user_key: Option<String>,
+    pub credentials: Credentials,
+    /// The tenant the request is for, as bearer tokens only work for one
+    pub tenant: Tenant,
+    pub authenticators: Authenticators,
 }
 
 impl<'a, 'r> FromRequest<'a, 'r> for APIKey {
     type Error = ();
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<APIKey, ()> {
-        let basic_auth = get_basic_auth(request);
+        let authenticators = match State::<Authenticators>::from_request(request) {
+            Outcome::Success(authenticators) => (*authenticators).clone(),
+            _ => {
+                warn!("No authenticators are being managed");
+                return Outcome::Failure((Status::InternalServerError, ()));
+            },
+        };
+        let tenant = match Tenant::from_request(request) {
+            Outcome::Success(tenant) => tenant,
+            _ => return Outcome::Failure((Status::InternalServerError, ())),
+        };
         let token_auth = get_token_auth(request);
-        match (basic_auth, token_auth) {
-            (None, None) => Outcome::Failure((Status::Unauthorized, ())),
-            (Some(_), Some(_)) => {
-                warn!("User passed both a TOK header and HTTP Basic Auth");
-                Outcome::Failure((Status::Unauthorized, ()))
+        let credentials = match get_bearer_token(request) {
+            Some(_) if token_auth.is_some() => {
+                warn!("User passed both a TOK header and a bearer token");
+                return Outcome::Failure((Status::Unauthorized, ()));
+            },
+            Some(bearer_token) => Credentials::Bearer(bearer_token),
+            None => {
+                match (get_basic_auth(request), token_auth) {
+                    // Browsers signed in with a cookie send nothing else
+                    (None, None) => {
+                        match cookiesession::session_token(request, authenticators.signer()) {
+                            Outcome::Success(token) => Credentials::Bearer(token),
+                            Outcome::Failure(failure) => return Outcome::Failure(failure),
+                            Outcome::Forward(()) => {
+                                return Outcome::Failure((Status::Unauthorized, ()))
+                            },
+                        }
+                    },
+                    (Some(_), Some(_)) => {
+                        warn!("User passed both a TOK header and HTTP Basic Auth");
+                        return Outcome::Failure((Status::Unauthorized, ()));
+                    },
+                    (Some(auth), None) => auth,
+                    (None, Some(auth)) => auth,
+                }
             },
-            (Some(auth), None) => Outcome::Success(auth),
-            (None, Some(auth)) => Outcome::Success(auth),
+        };
+        Outcome::Success(APIKey {
+            credentials: credentials,
+            tenant: tenant,
+            authenticators: authenticators,
+        })
+    }
+}
+
+/// The credentials of a request that can be made without any
+///
+/// Credentials that are there but not valid still fail the request.
+pub struct OptionalAPIKey(pub Option<APIKey>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for OptionalAPIKey {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<OptionalAPIKey, ()> {
+        let headers = request.headers();
+        if headers.get_one("Authorization").is_none() && headers.get_one("X-API-Key").is_none() &&
+           request.cookies().find(cookiesession::SESSION_COOKIE).is_none() {
+            return Outcome::Success(OptionalAPIKey(None));
+        }
+        match APIKey::from_request(request) {
+            Outcome::Success(api_key) => Outcome::Success(OptionalAPIKey(Some(api_key))),
+            Outcome::Failure(failure) => Outcome::Failure(failure),
+            Outcome::Forward(()) => Outcome::Forward(()),
         }
     }
 }
 
 
-fn get_basic_auth<'a, 'r>(request: &'a Request<'r>) -> Option<APIKey> {
+fn get_bearer_token<'a, 'r>(request: &'a Request<'r>) -> Option<String> {
+    request.headers()
+        .get_one("Authorization")
+        .and_then(|auth| if auth.starts_with("Bearer ") {
+            Some(auth["Bearer ".len()..].trim().to_string())
+        } else {
+            None
+        })
+}
+
+fn get_basic_auth<'a, 'r>(request: &'a Request<'r>) -> Option<Credentials> {
     let user_auth: String = match request.headers().get_one("Authorization") {
         Some(auth_data) => auth_data.to_string(),
         None => return None,
@@ -41,13 +111,13 @@ fn get_basic_auth<'a, 'r>(request: &'a Request<'r>) -> Option<APIKey> {
         Ok(authdata) => authdata,
         Err(_) => return None,
     };
-    Some(APIKey {
-        user_id: authdata.username,
-        user_key: authdata.password,
+    Some(Credentials::Token {
+        user_id: UserID(authdata.username),
+        user_token: authdata.password.map(UserToken::from),
     })
 }
 
-fn get_token_auth<'a, 'r>(request: &'a Request<'r>) -> Option<APIKey> {
+fn get_token_auth<'a, 'r>(request: &'a Request<'r>) -> Option<Credentials> {
     let user_token: String = match request.headers().get_one("X-API-Key") {
         Some(auth_data) => auth_data.to_string(),
         None => return None,
@@ -60,15 +130,15 @@ fn get_token_auth<'a, 'r>(request: &'a Request<'r>) -> Option<APIKey> {
             None
         },
         1 => {
-            Some(APIKey {
-                user_id: user_data[0].to_string(),
-                user_key: None,
+            Some(Credentials::Token {
+                user_id: UserID(user_data[0].to_string()),
+                user_token: None,
             })
         },
         2 => {
-            Some(APIKey {
-                user_id: user_data[0].to_string(),
-                user_key: Some(user_data[1].to_string()),
+            Some(Credentials::Token {
+                user_id: UserID(user_data[0].to_string()),
+                user_token: Some(user_data[1].to_string().into()),
             })
         },
         x => {
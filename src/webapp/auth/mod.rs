@@ -0,0 +1,111 @@
+//! Working out who made a request
+//!
+//! Each way of signing in is an `Authenticator`, and `Authenticators` asks
+//! each in turn about the credentials a request came with. Tokens and bearer
+//! tokens are always accepted, and an OpenID Connect provider can be added.
+
+use dal::StoryBackendInstance;
+use errors::*;
+use std::sync::Arc;
+use user::{AuthenticatedUser, BearerSigner, UserID, UserToken};
+use webapp::tenant::Tenant;
+
+mod oidc;
+mod token;
+
+pub use self::oidc::{HttpClient, HyperClient, OidcAuthenticator};
+pub use self::token::{BearerAuthenticator, TokenAuthenticator};
+
+/// What a request says about who made it
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Credentials {
+    /// A user ID and token, from HTTP Basic Auth or `X-API-Key`
+    Token {
+        user_id: UserID,
+        user_token: Option<UserToken>,
+    },
+    /// A token from `Authorization: Bearer`
+    Bearer(String),
+    /// What a provider sent a browser back with after the user signed in there
+    Callback {
+        provider: String,
+        code: String,
+        state: String,
+    },
+}
+
+/// A way of signing in
+pub trait Authenticator: Send + Sync {
+    /// Names the authenticator in the URLs of providers users sign in with
+    fn name(&self) -> &'static str;
+
+    /// The user the credentials are for
+    ///
+    /// Credentials of a kind this doesn't handle give `Ok(None)`, and ones it
+    /// handles but which aren't valid give `UserUnauthorized`.
+    fn authenticate(
+        &self,
+        dal: &mut StoryBackendInstance,
+        credentials: &Credentials,
+        tenant: &Tenant
+    ) -> Result<Option<AuthenticatedUser>>;
+
+    /// Where to send a browser to sign in, for authenticators with a provider
+    #[allow(unused_variables)]
+    fn login_url(&self, tenant: &Tenant) -> Option<String> {
+        None
+    }
+}
+
+/// Every way of signing in, shared by all requests
+#[derive(Clone)]
+pub struct Authenticators {
+    signer: Arc<BearerSigner>,
+    authenticators: Vec<Arc<Authenticator>>,
+}
+
+impl Authenticators {
+    /// Accept tokens, and bearer tokens signed by `signer`
+    pub fn new(signer: BearerSigner) -> Authenticators {
+        let signer = Arc::new(signer);
+        let token: Arc<Authenticator> = Arc::new(TokenAuthenticator);
+        let bearer: Arc<Authenticator> = Arc::new(BearerAuthenticator::new(signer.clone()));
+        Authenticators {
+            signer: signer,
+            authenticators: vec![token, bearer],
+        }
+    }
+
+    /// Accept another way of signing in as well
+    pub fn with<A>(mut self, authenticator: A) -> Authenticators
+        where A: Authenticator + 'static
+    {
+        self.authenticators.push(Arc::new(authenticator));
+        self
+    }
+
+    /// What bearer tokens are issued with
+    pub fn signer(&self) -> &BearerSigner {
+        &self.signer
+    }
+
+    /// The authenticator called `name`
+    pub fn get(&self, name: &str) -> Option<&Authenticator> {
+        self.authenticators.iter().map(|a| &**a).find(|a| a.name() == name)
+    }
+
+    /// The user the credentials are for, asking each authenticator in turn
+    pub fn authenticate(
+        &self,
+        dal: &mut StoryBackendInstance,
+        credentials: &Credentials,
+        tenant: &Tenant
+    ) -> Result<AuthenticatedUser> {
+        for authenticator in &self.authenticators {
+            if let Some(user) = authenticator.authenticate(dal, credentials, tenant)? {
+                return Ok(user);
+            }
+        }
+        Err(ErrorKind::UserUnauthorized.into())
+    }
+}
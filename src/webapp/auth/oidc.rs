@@ -0,0 +1,545 @@
+//! Signing in with an OpenID Connect provider, using the authorization code flow
+//!
+//! A browser is sent to the provider with a signed `state`, and comes back
+//! with a code the server exchanges for an ID token at the provider's token
+//! endpoint over TLS. The ID token has to be signed with the client secret
+//! (HS256), anything else is refused, as checking the provider's own keys
+//! (RS256 and the like) isn't supported. Its issuer, audience, expiry and
+//! nonce are checked too.
+//!
+//! Each identity gets a user of its own the first time it signs in, whose ID
+//! is derived from the identity so it's the same every time.
+
+use super::{Authenticator, Credentials};
+use config::OidcConfig;
+use dal::{StoryBackendInstance, StoryData};
+use errors::*;
+use hyper::Client;
+use hyper::header::ContentType;
+use hyper::net::HttpsConnector;
+use hyper_native_tls::NativeTlsClient;
+use rustc_serialize::base64::{FromBase64, ToBase64, URL_SAFE};
+use serde::Deserialize;
+use serde_json::{self, Value};
+use std::io::Read;
+use user::{AuthenticatedUser, BasicUser, ExternalIdentity, Scope};
+use util::{clock, crypto, generator};
+use webapp::tenant::Tenant;
+
+/// How long a browser has to sign in with the provider
+const STATE_TTL_SECS: u64 = 10 * 60;
+
+/// Makes the requests to the provider, so tests can stand in for it
+pub trait HttpClient: Send + Sync {
+    /// POST a form, returning the status and body of the response
+    fn post_form(&self, url: &str, form: &[(&str, &str)]) -> Result<(u16, String)>;
+}
+
+/// Makes requests with hyper, over TLS for `https://` URLs
+pub struct HyperClient {
+    client: Client,
+}
+
+impl HyperClient {
+    pub fn new() -> Result<HyperClient> {
+        let tls = NativeTlsClient::new().chain_err(|| "Could not set up TLS")?;
+        Ok(HyperClient { client: Client::with_connector(HttpsConnector::new(tls)) })
+    }
+}
+
+impl HttpClient for HyperClient {
+    fn post_form(&self, url: &str, form: &[(&str, &str)]) -> Result<(u16, String)> {
+        let body = form_urlencode(form);
+        let mut response = self.client
+            .post(url)
+            .header(ContentType::form_url_encoded())
+            .body(body.as_str())
+            .send()?;
+        let mut text = String::new();
+        response.read_to_string(&mut text)?;
+        Ok((response.status.to_u16(), text))
+    }
+}
+
+/// What the `state` of a login remembers until the browser comes back
+#[derive(Serialize, Deserialize)]
+struct LoginState {
+    nonce: String,
+    tenant: Option<String>,
+    exp: u64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct IdTokenHeader {
+    alg: String,
+}
+
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    /// A client ID, or a list of them
+    aud: Value,
+    azp: Option<String>,
+    exp: u64,
+    nonce: Option<String>,
+    name: Option<String>,
+    preferred_username: Option<String>,
+    email: Option<String>,
+}
+
+pub struct OidcAuthenticator {
+    config: OidcConfig,
+    client: Box<HttpClient>,
+    /// Signs the `state` of logins, so a callback can only finish a login we started
+    state_key: crypto::Hmac,
+}
+
+impl OidcAuthenticator {
+    pub fn new(config: OidcConfig) -> Result<OidcAuthenticator> {
+        Ok(OidcAuthenticator::with_client(config, HyperClient::new()?))
+    }
+
+    pub fn with_client<C>(config: OidcConfig, client: C) -> OidcAuthenticator
+        where C: HttpClient + 'static
+    {
+        // Derived from the client secret so every server accepts the others' logins
+        let state_key = crypto::sha256(format!("oidc state\n{}", config.client_secret).as_bytes());
+        OidcAuthenticator {
+            config: config,
+            client: Box::new(client),
+            state_key: crypto::Hmac::new(&state_key),
+        }
+    }
+
+    /// Where the provider sends browsers back to, which has to be registered with it
+    pub fn redirect_uri(&self, tenant: &Tenant) -> String {
+        let base = &self.config.redirect_base;
+        match tenant.0 {
+            Some(ref name) => format!("{}/{}/api/auth/oidc/callback", base, name),
+            None => format!("{}/api/auth/oidc/callback", base),
+        }
+    }
+
+    fn sign_state(&self, state: &LoginState) -> String {
+        let payload = serde_json::to_vec(state)
+            .expect("login state is always valid JSON")
+            .to_base64(URL_SAFE);
+        let signature = self.state_key.mac(payload.as_bytes()).to_base64(URL_SAFE);
+        format!("{}.{}", payload, signature)
+    }
+
+    /// The nonce of a login we started for `tenant` that hasn't expired
+    fn check_state(&self, state: &str, tenant: &Tenant) -> Option<String> {
+        let parts: Vec<&str> = state.split('.').collect();
+        if parts.len() != 2 {
+            return None;
+        }
+        let expected = self.state_key.mac(parts[0].as_bytes());
+        match parts[1].from_base64() {
+            Ok(ref signature) if crypto::constant_time_eq(&expected, signature) => (),
+            _ => return None,
+        }
+        match decode_json::<LoginState>(parts[0]) {
+            Some(ref login) if login.exp > clock::now() && login.tenant == tenant.0 => {
+                Some(login.nonce.clone())
+            },
+            _ => None,
+        }
+    }
+
+    /// Who an ID token from the provider is for, if it is for us and this login
+    fn check_id_token(&self, id_token: &str, nonce: &str) -> Option<ExternalIdentity> {
+        let parts: Vec<&str> = id_token.split('.').collect();
+        if parts.len() != 3 {
+            return None;
+        }
+        let header = match decode_json::<IdTokenHeader>(parts[0]) {
+            Some(header) => header,
+            None => return None,
+        };
+        // Only the client secret is checked, see the module docs
+        if header.alg != "HS256" {
+            return None;
+        }
+        let key = crypto::Hmac::new(self.config.client_secret.as_bytes());
+        let expected = key.mac(format!("{}.{}", parts[0], parts[1]).as_bytes());
+        match parts[2].from_base64() {
+            Ok(ref signature) if crypto::constant_time_eq(&expected, signature) => (),
+            _ => return None,
+        }
+        let claims = match decode_json::<IdTokenClaims>(parts[1]) {
+            Some(claims) => claims,
+            None => return None,
+        };
+        let client_id = self.config.client_id.as_str();
+        let for_us = match claims.aud {
+            Value::String(ref aud) => aud == client_id,
+            Value::Array(ref auds) => auds.iter().any(|aud| aud.as_str() == Some(client_id)),
+            _ => false,
+        };
+        if claims.iss != self.config.issuer || !for_us ||
+           claims.azp.as_ref().map_or(false, |azp| azp != client_id) ||
+           claims.exp <= clock::now() || claims.nonce.as_ref().map(|n| n.as_str()) != Some(nonce) {
+            return None;
+        }
+        Some(ExternalIdentity {
+            issuer: claims.iss,
+            subject: claims.sub,
+            name: claims.name.or(claims.preferred_username).or(claims.email),
+        })
+    }
+}
+
+impl Authenticator for OidcAuthenticator {
+    fn name(&self) -> &'static str {
+        "oidc"
+    }
+
+    fn authenticate(
+        &self,
+        dal: &mut StoryBackendInstance,
+        credentials: &Credentials,
+        tenant: &Tenant
+    ) -> Result<Option<AuthenticatedUser>> {
+        let (provider, code, state) = match *credentials {
+            Credentials::Callback { ref provider, ref code, ref state } => (provider, code, state),
+            _ => return Ok(None),
+        };
+        if provider != self.name() {
+            return Ok(None);
+        }
+        let nonce = match self.check_state(state, tenant) {
+            Some(nonce) => nonce,
+            None => {
+                info!("User came back from the OpenID Connect provider with an invalid state");
+                bail!(ErrorKind::UserUnauthorized)
+            },
+        };
+        let redirect_uri = self.redirect_uri(tenant);
+        let (status, body) = self.client
+            .post_form(&self.config.token_endpoint,
+                       &[("grant_type", "authorization_code"),
+                         ("code", code),
+                         ("redirect_uri", &redirect_uri),
+                         ("client_id", &self.config.client_id),
+                         ("client_secret", &self.config.client_secret)])
+            .chain_err(|| {
+                ErrorKind::BackendUnavailable("Could not reach the OpenID Connect provider"
+                    .to_string())
+            })?;
+        if status != 200 {
+            warn!("The OpenID Connect provider wouldn't exchange a code ({}): {}",
+                  status,
+                  body);
+            bail!(ErrorKind::UserUnauthorized);
+        }
+        let response: TokenResponse = serde_json::from_str(&body)?;
+        match self.check_id_token(&response.id_token, &nonce) {
+            Some(identity) => link_identity(dal, identity).map(Some),
+            None => {
+                warn!("The OpenID Connect provider sent an ID token that isn't valid for us");
+                bail!(ErrorKind::UserUnauthorized)
+            },
+        }
+    }
+
+    fn login_url(&self, tenant: &Tenant) -> Option<String> {
+        let nonce = generator::authtoken();
+        let state = self.sign_state(&LoginState {
+            nonce: nonce.clone(),
+            tenant: tenant.0.clone(),
+            exp: clock::now() + STATE_TTL_SECS,
+        });
+        let query = form_urlencode(&[("response_type", "code"),
+                                     ("client_id", &self.config.client_id),
+                                     ("redirect_uri", &self.redirect_uri(tenant)),
+                                     ("scope", &self.config.scope),
+                                     ("state", &state),
+                                     ("nonce", &nonce)]);
+        let endpoint = &self.config.authorization_endpoint;
+        let separator = if endpoint.contains('?') { '&' } else { '?' };
+        Some(format!("{}{}{}", endpoint, separator, query))
+    }
+}
+
+/// The user an identity signs in as, made the first time it signs in
+fn link_identity(
+    dal: &mut StoryBackendInstance,
+    identity: ExternalIdentity
+) -> Result<AuthenticatedUser> {
+    let user_id = identity.user_id();
+    if dal.get_user(&user_id)?.is_none() {
+        let mut user = BasicUser::new();
+        user.user_id = user_id.clone();
+        user.identity = Some(identity.clone());
+        match dal.add_user(user) {
+            // The identity signed in twice at once
+            Ok(()) |
+            Err(Error(ErrorKind::UserAlreadyExists, _)) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    dal.update_user(&user_id, |user| {
+        if let Some(ref linked) = user.identity {
+            if !linked.is(&identity) {
+                bail!(ErrorKind::DataIntegrityError(format!("User {} has another identity",
+                                                            user.user_id)));
+            }
+        }
+        // Keep the name up to date, and count signing in as activity
+        user.identity = Some(identity.clone());
+        user.last_activity = clock::now();
+        Ok(())
+    })?;
+    Ok(AuthenticatedUser {
+        user_id: user_id,
+        scopes: Scope::all(),
+    })
+}
+
+fn decode_json<T>(segment: &str) -> Option<T>
+    where T: Deserialize
+{
+    segment.from_base64().ok().and_then(|bytes| serde_json::from_slice(&bytes).ok())
+}
+
+/// Encode a form as `application/x-www-form-urlencoded`, also fit for a query string
+fn form_urlencode(form: &[(&str, &str)]) -> String {
+    form.iter()
+        .map(|&(key, value)| format!("{}={}", form_escape(key), form_escape(value)))
+        .collect::<Vec<String>>()
+        .join("&")
+}
+
+fn form_escape(s: &str) -> String {
+    let mut escaped = String::new();
+    for byte in s.bytes() {
+        match byte {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'.' | b'_' | b'~' => {
+                escaped.push(byte as char)
+            },
+            b' ' => escaped.push('+'),
+            _ => escaped.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dal::{SharedMemoryDB, StoryBackend, StoryDataProvider};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    const ISSUER: &'static str = "https://sso.example.com";
+    const CLIENT_SECRET: &'static str = "a secret shared with the provider";
+
+    fn authenticator(token_endpoint: &str) -> OidcAuthenticator {
+        OidcAuthenticator::new(OidcConfig {
+            issuer: ISSUER.to_string(),
+            client_id: "estimates".to_string(),
+            client_secret: CLIENT_SECRET.to_string(),
+            authorization_endpoint: format!("{}/authorize", ISSUER),
+            token_endpoint: token_endpoint.to_string(),
+            redirect_base: "https://estimates.example.com".to_string(),
+            scope: "openid profile".to_string(),
+        })
+            .unwrap()
+    }
+
+    fn backend() -> StoryBackend {
+        StoryBackend::from(SharedMemoryDB::new())
+    }
+
+    fn id_token(claims: Value) -> String {
+        let signed = format!("{}.{}",
+                             br#"{"alg":"HS256","typ":"JWT"}"#.to_base64(URL_SAFE),
+                             claims.to_string().as_bytes().to_base64(URL_SAFE));
+        let signature = crypto::Hmac::new(CLIENT_SECRET.as_bytes()).mac(signed.as_bytes());
+        format!("{}.{}", signed, signature.to_base64(URL_SAFE))
+    }
+
+    fn claims(nonce: &str) -> Value {
+        json!({
+            "iss": ISSUER,
+            "sub": "alice",
+            "aud": "estimates",
+            "exp": clock::now() + 60,
+            "nonce": nonce,
+            "name": "Alice"
+        })
+    }
+
+    /// A provider's token endpoint on a local port, which answers one request
+    ///
+    /// The thread returns the body of the request it answered. It speaks plain
+    /// HTTP, which only the configuration refuses, see `OidcConfig::from_config`.
+    fn mock_issuer(id_token: String) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/token", listener.local_addr().unwrap());
+        let issuer = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if line.to_lowercase().starts_with("content-length:") {
+                    content_length = line[15..].trim().parse().unwrap();
+                }
+            }
+            let mut request = vec![0; content_length];
+            reader.read_exact(&mut request).unwrap();
+
+            let body = json!({
+                "access_token": "ignored",
+                "token_type": "Bearer",
+                "id_token": id_token
+            }).to_string();
+            write!(&stream,
+                   "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: \
+                    {}\r\nConnection: close\r\n\r\n{}",
+                   body.len(),
+                   body)
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (url, issuer)
+    }
+
+    /// Start logging in, returning the `state` and nonce sent to the provider
+    fn start_login(oidc: &OidcAuthenticator, tenant: &Tenant) -> (String, String) {
+        let login_url = oidc.login_url(tenant).unwrap();
+        assert!(login_url.starts_with("https://sso.example.com/authorize?response_type=code&"));
+        let param = |name: &str| {
+            login_url.split(|c: char| c == '?' || c == '&')
+                .find(|p| p.starts_with(&format!("{}=", name)))
+                .map(|p| p[name.len() + 1..].to_string())
+                .unwrap()
+        };
+        assert_eq!(param("redirect_uri"),
+                   "https%3A%2F%2Festimates.example.com%2Fapi%2Fauth%2Foidc%2Fcallback");
+        (param("state"), param("nonce"))
+    }
+
+    fn callback(code: &str, state: &str) -> Credentials {
+        Credentials::Callback {
+            provider: "oidc".to_string(),
+            code: code.to_string(),
+            state: state.to_string(),
+        }
+    }
+
+    #[test]
+    fn sign_in_with_a_mock_issuer() {
+        let backend = backend();
+        let mut dal = backend.get_story_data().unwrap();
+        let mut users = Vec::new();
+        for code in &["first-code", "second code"] {
+            // Logins started on one server can finish on another
+            let oidc = authenticator("http://127.0.0.1:1/token");
+            let (state, nonce) = start_login(&oidc, &Tenant(None));
+            let (token_endpoint, issuer) = mock_issuer(id_token(claims(&nonce)));
+            let oidc = authenticator(&token_endpoint);
+            let user = oidc.authenticate(&mut dal, &callback(code, &state), &Tenant(None))
+                .unwrap()
+                .unwrap();
+            let request = issuer.join().unwrap();
+            assert!(request.contains(&format!("code={}", form_escape(code))));
+            assert!(request.contains("grant_type=authorization_code"));
+            assert!(request.contains("client_id=estimates"));
+            assert_eq!(user.scopes, Scope::all());
+            users.push(user.user_id);
+        }
+        assert_eq!(users[0], users[1]);
+
+        let stored = dal.get_user(&users[0]).unwrap().unwrap();
+        let identity = stored.identity.unwrap();
+        assert_eq!((identity.issuer.as_str(), identity.subject.as_str()), (ISSUER, "alice"));
+        assert_eq!(identity.name, Some("Alice".to_string()));
+        assert_eq!(identity.user_id(), users[0]);
+    }
+
+    #[test]
+    fn reject_a_replayed_nonce() {
+        let backend = backend();
+        let mut dal = backend.get_story_data().unwrap();
+        let oidc = authenticator("http://127.0.0.1:1/token");
+        let (state, _) = start_login(&oidc, &Tenant(None));
+        let (_, old_nonce) = start_login(&oidc, &Tenant(None));
+        let (token_endpoint, issuer) = mock_issuer(id_token(claims(&old_nonce)));
+        let oidc = authenticator(&token_endpoint);
+        assert!(oidc.authenticate(&mut dal, &callback("code", &state), &Tenant(None)).is_err());
+        issuer.join().unwrap();
+    }
+
+    #[test]
+    fn reject_logins_we_did_not_start() {
+        let backend = backend();
+        let mut dal = backend.get_story_data().unwrap();
+        // Nothing listens here, so these must fail before asking the provider
+        let oidc = authenticator("http://127.0.0.1:1/token");
+        let (state, _) = start_login(&oidc, &Tenant(None));
+        let acme = Tenant(Some("acme".to_string()));
+        match oidc.authenticate(&mut dal, &callback("code", &state), &acme) {
+            Err(Error(ErrorKind::UserUnauthorized, _)) => (),
+            other => panic!("Expected UserUnauthorized, got {:?}", other),
+        }
+        let forged = format!("{}x", state);
+        assert!(oidc.authenticate(&mut dal, &callback("code", &forged), &Tenant(None)).is_err());
+        let other_provider = Credentials::Callback {
+            provider: "saml".to_string(),
+            code: "code".to_string(),
+            state: state,
+        };
+        assert_eq!(oidc.authenticate(&mut dal, &other_provider, &Tenant(None)).unwrap(), None);
+    }
+
+    #[test]
+    fn check_id_tokens() {
+        let oidc = authenticator("http://127.0.0.1:1/token");
+        assert!(oidc.check_id_token(&id_token(claims("nonce")), "nonce").is_some());
+        assert!(oidc.check_id_token(&id_token(claims("nonce")), "other").is_none());
+
+        let changes = vec![("iss", json!("https://evil.example.com")),
+                           ("aud", json!(["someone-else"])),
+                           ("azp", json!("someone-else")),
+                           ("exp", json!(clock::now() - 1))];
+        for (claim, value) in changes {
+            let mut changed = claims("nonce");
+            changed.as_object_mut().unwrap().insert(claim.to_string(), value);
+            assert!(oidc.check_id_token(&id_token(changed), "nonce").is_none(), "{}", claim);
+        }
+
+        let token = id_token(claims("nonce"));
+        let parts: Vec<&str> = token.split('.').collect();
+        let unsigned = format!("{}.{}.",
+                               br#"{"alg":"none"}"#.to_base64(URL_SAFE),
+                               parts[1]);
+        assert!(oidc.check_id_token(&unsigned, "nonce").is_none());
+        let rs256 = format!("{}.{}.{}",
+                            br#"{"alg":"RS256","typ":"JWT"}"#.to_base64(URL_SAFE),
+                            parts[1],
+                            parts[2]);
+        assert!(oidc.check_id_token(&rs256, "nonce").is_none());
+        let resigned = format!("{}.{}.{}", parts[0], parts[1], &parts[2][1..]);
+        assert!(oidc.check_id_token(&resigned, "nonce").is_none());
+    }
+
+    #[test]
+    fn escape_forms() {
+        assert_eq!(form_urlencode(&[("scope", "openid profile"), ("a&b", "c=d/é")]),
+                   "scope=openid+profile&a%26b=c%3Dd%2F%C3%A9");
+    }
+}
@@ -0,0 +1,78 @@
+use super::{Authenticator, Credentials};
+use dal::{StoryBackendInstance, StoryData};
+use errors::*;
+use service;
+use std::sync::Arc;
+use user::{AuthenticatedUser, BearerSigner};
+use webapp::tenant::Tenant;
+
+/// Checks a user's token, or one of their device tokens, against the backend
+///
+/// This counts as activity, so the user doesn't expire.
+pub struct TokenAuthenticator;
+
+impl Authenticator for TokenAuthenticator {
+    fn name(&self) -> &'static str {
+        "token"
+    }
+
+    #[allow(unused_variables)]
+    fn authenticate(
+        &self,
+        dal: &mut StoryBackendInstance,
+        credentials: &Credentials,
+        tenant: &Tenant
+    ) -> Result<Option<AuthenticatedUser>> {
+        let (user_id, user_token) = match *credentials {
+            Credentials::Token { ref user_id, user_token: Some(ref user_token) } => {
+                (user_id, user_token)
+            },
+            Credentials::Token { user_token: None, .. } => bail!(ErrorKind::UserUnauthorized),
+            _ => return Ok(None),
+        };
+        let user = service::authenticate_user(&*dal, user_id, user_token)
+            ?
+            .ok_or(ErrorKind::UserUnauthorized)?;
+        dal.touch_user(user_id)?;
+        Ok(Some(user))
+    }
+}
+
+/// Checks the signature of bearer tokens, without reading the backend
+///
+/// Being short lived, they don't count as activity.
+pub struct BearerAuthenticator {
+    signer: Arc<BearerSigner>,
+}
+
+impl BearerAuthenticator {
+    pub fn new(signer: Arc<BearerSigner>) -> BearerAuthenticator {
+        BearerAuthenticator { signer: signer }
+    }
+}
+
+impl Authenticator for BearerAuthenticator {
+    fn name(&self) -> &'static str {
+        "bearer"
+    }
+
+    #[allow(unused_variables)]
+    fn authenticate(
+        &self,
+        dal: &mut StoryBackendInstance,
+        credentials: &Credentials,
+        tenant: &Tenant
+    ) -> Result<Option<AuthenticatedUser>> {
+        let bearer_token = match *credentials {
+            Credentials::Bearer(ref bearer_token) => bearer_token,
+            _ => return Ok(None),
+        };
+        match self.signer.verify(bearer_token, tenant.0.as_ref().map(|t| t.as_str())) {
+            Some(user) => Ok(Some(user)),
+            None => {
+                info!("User passed a bearer token that was invalid or expired");
+                bail!(ErrorKind::UserUnauthorized)
+            },
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Keeping a browser signed in with a cookie scripts can't read
+//!
+//! The cookie holds a bearer token, so it is signed and checked like one.
+//! As other sites can make a browser send it, requests that change anything
+//! must also send the `X-CSRF-Token` that came with the cookie.
+
+use rocket::Outcome;
+use rocket::http::{Cookie, Cookies, Method, Status};
+use rocket::request::{self, Request};
+use user::{AuthenticatedUser, BearerSigner, UserID};
+use webapp::tenant::Tenant;
+
+pub const SESSION_COOKIE: &'static str = "estimates_session";
+pub const CSRF_HEADER: &'static str = "X-CSRF-Token";
+
+/// A browser that was just signed in with a cookie
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CookieSession {
+    pub user_id: UserID,
+    /// To send as `X-CSRF-Token` with every request that changes anything
+    pub csrf_token: String,
+    /// Seconds until the cookie stops working
+    pub expires_in: u64,
+}
+
+/// Sign the user in with a cookie, which is only sent over HTTPS if `secure`
+pub fn start(
+    cookies: &Cookies,
+    signer: &BearerSigner,
+    user: &AuthenticatedUser,
+    tenant: &Tenant,
+    secure: bool
+) -> CookieSession {
+    let token = signer.issue_for_cookie(&user.user_id,
+                                        user.scopes.clone(),
+                                        tenant.0.as_ref().map(|t| t.as_str()));
+    let mut cookie = Cookie::new(SESSION_COOKIE.to_string(), token.access_token.clone());
+    cookie.path = Some("/".to_string());
+    cookie.max_age = Some(token.expires_in);
+    cookie.httponly = true;
+    cookie.secure = secure;
+    cookies.add(cookie);
+    CookieSession {
+        user_id: user.user_id.clone(),
+        csrf_token: signer.csrf_token(&token.access_token),
+        expires_in: token.expires_in,
+    }
+}
+
+/// Have the browser forget the cookie
+///
+/// The token in it keeps working until it expires, for anyone who copied it.
+pub fn end(cookies: &Cookies) {
+    let mut cookie = Cookie::new(SESSION_COOKIE.to_string(), String::new());
+    cookie.path = Some("/".to_string());
+    cookie.max_age = Some(0);
+    cookie.httponly = true;
+    cookies.add(cookie);
+}
+
+/// The token in the request's session cookie
+///
+/// Requests without the cookie are forwarded, and ones that could change
+/// something without the right CSRF token fail.
+pub fn session_token<'a, 'r>(
+    request: &'a Request<'r>,
+    signer: &BearerSigner
+) -> request::Outcome<String, ()> {
+    let token = match request.cookies().find(SESSION_COOKIE) {
+        Some(ref cookie) if !cookie.value.is_empty() => cookie.value.clone(),
+        _ => return Outcome::Forward(()),
+    };
+    match request.method() {
+        Method::Get | Method::Head | Method::Options => return Outcome::Success(token),
+        _ => (),
+    }
+    match request.headers().get_one(CSRF_HEADER) {
+        Some(csrf_token) if signer.check_csrf_token(&token, csrf_token) => Outcome::Success(token),
+        _ => {
+            warn!("User sent a session cookie without its CSRF token");
+            Outcome::Failure((Status::Forbidden, ()))
+        },
+    }
+}
@@ -20,6 +20,9 @@ impl<'r> Responder<'r> for Error {
             Error(ErrorKind::ResourceNotFound(reason), _) => (reason, Status::NotFound),
             Error(ErrorKind::UserError(reason), _) => (reason, Status::BadRequest),
             Error(ErrorKind::UserForbidden(reason), _) => (reason, Status::Forbidden),
+            Error(ErrorKind::BackendUnavailable(reason), _) => {
+                (reason, Status::ServiceUnavailable)
+            },
             Error(ErrorKind::UserUnauthorized, _) => {
                 ("Unauthorized".to_string(), Status::Unauthorized)
             },
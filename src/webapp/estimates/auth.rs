@@ -0,0 +1,150 @@
+use super::MyStoryDataProvider;
+
+use errors::*;
+use rocket::State;
+use rocket::http::Cookies;
+use rocket::response::Redirect;
+
+use rocket_contrib::JSON;
+use service;
+use user::{BearerToken, UserID};
+
+use webapp::auth::{Authenticators, Credentials};
+use webapp::cookiesession;
+use webapp::proxydata::ProxyData;
+use webapp::tenant::Tenant;
+
+
+/// What a provider sends a browser back with
+#[derive(FromForm)]
+pub struct CallbackForm {
+    code: Option<String>,
+    state: Option<String>,
+    /// Set instead of `code` when the user didn't sign in
+    error: Option<String>,
+}
+
+/// A user who signed in with a provider, and a bearer token for them
+///
+/// The browser is also signed in with a cookie, which needs `csrf_token`.
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SignedIn {
+    pub user_id: UserID,
+    pub bearer: BearerToken,
+    pub csrf_token: String,
+}
+
+
+/// Send a browser to sign in with a provider
+#[get("/auth/<provider>/login")]
+pub fn login(
+    provider: String,
+    tenant: Tenant,
+    authenticators: State<Authenticators>
+) -> Result<Redirect> {
+    match authenticators.get(&provider).and_then(|a| a.login_url(&tenant)) {
+        Some(login_url) => Ok(Redirect::to(&login_url)),
+        None => bail!(ErrorKind::ResourceNotFound(format!("/auth/{}/login", provider))),
+    }
+}
+
+/// Finish signing in once a provider sends the browser back
+#[get("/auth/<provider>/callback?<callback>")]
+pub fn callback(
+    provider: String,
+    callback: CallbackForm,
+    tenant: Tenant,
+    cookies: &Cookies,
+    proxy_data: ProxyData,
+    authenticators: State<Authenticators>,
+    storydata_provider: State<MyStoryDataProvider>
+) -> Result<JSON<SignedIn>> {
+    if let Some(error) = callback.error {
+        info!("User did not sign in with {}: {}", provider, error);
+        bail!(ErrorKind::UserUnauthorized);
+    }
+    let credentials = match (callback.code, callback.state) {
+        (Some(code), Some(state)) => {
+            Credentials::Callback {
+                provider: provider,
+                code: code,
+                state: state,
+            }
+        },
+        _ => bail!(ErrorKind::UserError("The code and state are required".to_string())),
+    };
+    let mut dal = storydata_provider.get_story_data(&tenant)?;
+    let user = authenticators.authenticate(&mut dal, &credentials, &tenant)?;
+    let bearer = service::issue_bearer(authenticators.signer(),
+                                       &user,
+                                       &user.scopes,
+                                       tenant.0.as_ref().map(|t| t.as_str()))?;
+    let session = cookiesession::start(cookies,
+                                       authenticators.signer(),
+                                       &user,
+                                       &tenant,
+                                       proxy_data.scheme == "https");
+    Ok(JSON(SignedIn {
+        user_id: user.user_id,
+        bearer: bearer,
+        csrf_token: session.csrf_token,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test::*;
+    use config::OidcConfig;
+    use rocket;
+    use std::time::Duration;
+    use user::BearerSigner;
+    use webapp::{self, Authenticators, OidcAuthenticator, Tenants};
+
+    fn oidc_webapp() -> rocket::Rocket {
+        let oidc = OidcAuthenticator::new(OidcConfig {
+            issuer: "https://sso.example.com".to_string(),
+            client_id: "estimates".to_string(),
+            client_secret: "a secret shared with the provider".to_string(),
+            authorization_endpoint: "https://sso.example.com/authorize".to_string(),
+            token_endpoint: "http://127.0.0.1:1/token".to_string(),
+            redirect_base: "https://estimates.example.com".to_string(),
+            scope: "openid profile".to_string(),
+        })
+            .unwrap();
+        let authenticators = Authenticators::new(BearerSigner::random(Duration::from_secs(60)))
+            .with(oidc);
+        webapp::mount_webapp(rocket::ignite(),
+                             Tenants::new(SharedMemoryDB::new().into()),
+                             authenticators)
+    }
+
+    #[test]
+    fn login_redirects_to_the_provider() {
+        let rocket = oidc_webapp();
+        let mut req = MockRequest::new(Method::Get, "/api/auth/oidc/login");
+        let response = req.dispatch_with(&rocket);
+        assert_eq!(response.status(), Status::SeeOther);
+        let location = response.headers().get_one("Location").unwrap();
+        assert!(location.starts_with("https://sso.example.com/authorize?"));
+        assert!(location.contains("&state="));
+
+        let mut req = MockRequest::new(Method::Get, "/api/auth/saml/login");
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::NotFound);
+        let rocket = webapp::build_webapp(SharedMemoryDB::new());
+        let mut req = MockRequest::new(Method::Get, "/api/auth/oidc/login");
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::NotFound);
+    }
+
+    #[test]
+    fn callback_needs_a_login_we_started() {
+        let rocket = oidc_webapp();
+        for &(query, status) in &[("code=abc&state=forged", Status::Unauthorized),
+                                  ("error=access_denied&state=forged", Status::Unauthorized),
+                                  ("state=forged", Status::BadRequest)] {
+            let path = format!("/api/auth/oidc/callback?{}", query);
+            let mut req = MockRequest::new(Method::Get, path);
+            assert_eq!(req.dispatch_with(&rocket).status(), status, "{}", query);
+        }
+    }
+}
@@ -3,11 +3,12 @@ use dal;
 use errors::*;
 use rocket::Route;
 
-use service;
-use user::{AuthenticatedUser, UserID};
+use user::{AuthenticatedUser, Scope};
 
+use webapp;
 use webapp::apikey::APIKey;
 
+mod auth;
 mod user;
 mod session;
 mod participant;
@@ -17,35 +18,52 @@ pub fn routes() -> Vec<Route> {
     return routes![
         self::user::create_user,
         self::user::check_user,
+        self::user::create_token,
+        self::user::list_tokens,
+        self::user::revoke_token,
+        self::user::rotate_token,
+        self::user::issue_bearer,
+        self::user::login,
+        self::user::logout,
+        self::auth::login,
+        self::auth::callback,
         self::session::create_session,
         self::session::lookup_session,
         self::session::delete_session,
         self::session::update_session,
+        self::session::update_policy,
+        self::session::audit_log,
         self::participant::join_session,
+        self::participant::join_by_code,
         self::participant::kick_user,
         self::participant::place_vote,
         self::participant::grant_admin,
         self::participant::revoke_admin,
+        self::participant::set_role,
+        self::participant::transfer_ownership,
     ];
 }
 
-#[cfg(not(feature = "redis_estimates"))]
-type MyStoryDataProvider = dal::SharedMemoryDB;
-#[cfg(feature = "redis_estimates")]
-type MyStoryDataProvider = dal::RedisDBManager;
-
-
-pub fn get_authenticated_user<D>(dal: &D, api_key: APIKey) -> Result<AuthenticatedUser>
-    where D: dal::StoryData
-{
-    let err = ErrorKind::UserUnauthorized;
-    if let APIKey { user_id, user_key: Some(user_key) } = api_key {
-        service::authenticate_user(dal, &UserID(user_id), &user_key.into())
-            ?
-            .ok_or(err.into())
-    } else {
-        Err(err.into())
-    }
+type MyStoryDataProvider = webapp::Tenants;
+
+
+/// Authenticate the request with whichever authenticator takes its credentials
+pub fn get_authenticated_user(
+    dal: &mut dal::StoryBackendInstance,
+    api_key: APIKey
+) -> Result<AuthenticatedUser> {
+    api_key.authenticators.authenticate(dal, &api_key.credentials, &api_key.tenant)
+}
+
+/// Authenticate the request, and check the token allows what it's doing
+pub fn get_authorized_user(
+    dal: &mut dal::StoryBackendInstance,
+    api_key: APIKey,
+    scope: Scope
+) -> Result<AuthenticatedUser> {
+    let user = get_authenticated_user(dal, api_key)?;
+    user.require_scope(scope)?;
+    Ok(user)
 }
 
 #[cfg(test)]
@@ -54,29 +72,29 @@ mod test {
 
 
     use dal;
-    pub use dal::SharedMemoryDB;
+    pub use dal::{SharedMemoryDB, StoryDataProvider};
     pub use rocket::http::{Header, Method, Status};
     pub use rocket::testing::MockRequest;
 
     pub use serde_json::{self, Value};
     use service;
-    pub use user::BasicUser;
+    pub use user::NewUser;
     use webapp;
 
-    pub fn basic_auth(user: &BasicUser) -> Header<'static> {
+    pub fn basic_auth(user: &NewUser) -> Header<'static> {
         let auth_string = format!("{}:{}", user.user_id, user.user_token.0);
         let b64_auth = data_encoding::base64::encode(auth_string.as_bytes());
         Header::new("Authorization".to_string(), format!("Basic {}", b64_auth))
     }
 
-    pub fn register_user<D>(dal: &mut D) -> BasicUser
+    pub fn register_user<D>(dal: &mut D) -> NewUser
         where D: dal::StoryData
     {
         service::create_user(&mut *dal).unwrap()
     }
 
-    pub fn fake_user() -> BasicUser {
-        BasicUser::new()
+    pub fn fake_user() -> NewUser {
+        NewUser::new()
     }
 
 }
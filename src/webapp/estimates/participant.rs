@@ -1,14 +1,16 @@
 use super::MyStoryDataProvider;
 
 use errors::*;
+use estimates::policy::Role;
 use estimates::session::SessionID;
 use rocket::State;
 
 use rocket_contrib::{JSON, Value};
 use service;
-use user::{Nickname, UserID};
+use user::{Nickname, Scope, UserID, UserToken};
 
-use webapp::apikey::APIKey;
+use webapp::apikey::{APIKey, OptionalAPIKey};
+use webapp::tenant::Tenant;
 use webapp::assumejson::AlwaysJSON;
 
 
@@ -24,12 +26,19 @@ pub struct VoteForm {
     vote: u32,
 }
 
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct RoleForm {
+    role: Role,
+}
+
 #[put("/session/<session_id_string>/user/<user_id_string>", data = "<public_nickname>")]
 pub fn join_session(
     session_id_string: String,
     user_id_string: String,
     public_nickname: Option<AlwaysJSON<NicknameForm>>,
     api_key: APIKey,
+    tenant: Tenant,
     storydata_provider: State<MyStoryDataProvider>
 ) -> Result<JSON<Value>> {
 
@@ -39,9 +48,9 @@ pub fn join_session(
     let session_id = SessionID(session_id_string);
     let user_id = UserID(user_id_string);
 
-    let mut dal = storydata_provider.get();
-    let requesting_user = super::get_authenticated_user(&*dal, api_key)?;
-    service::join_session(&mut *dal,
+    let mut dal = storydata_provider.get_story_data(&tenant)?;
+    let requesting_user = super::get_authorized_user(&mut dal, api_key, Scope::SessionVote)?;
+    service::join_session(&mut dal,
                           &session_id,
                           &user_id,
                           &requesting_user,
@@ -49,12 +58,62 @@ pub fn join_session(
     Ok(JSON(json!({})))
 }
 
+/// Who joined a session, with the token of a guest made to join it
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct JoinedSession {
+    pub user_id: UserID,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_token: Option<UserToken>,
+}
+
+/// Join a session in one step, as a new guest user unless credentials are given
+#[post("/session/<session_id_string>/join", data = "<public_nickname>")]
+pub fn join_by_code(
+    session_id_string: String,
+    public_nickname: Option<AlwaysJSON<NicknameForm>>,
+    api_key: OptionalAPIKey,
+    tenant: Tenant,
+    storydata_provider: State<MyStoryDataProvider>
+) -> Result<JSON<JoinedSession>> {
+
+    let ref nickname = public_nickname
+        .ok_or(ErrorKind::UserError("Please provide nickname to join session".to_string()))?
+        .nickname;
+    let session_id = SessionID(session_id_string);
+
+    let mut dal = storydata_provider.get_story_data(&tenant)?;
+    match api_key.0 {
+        Some(api_key) => {
+            let requesting_user =
+                super::get_authorized_user(&mut dal, api_key, Scope::SessionVote)?;
+            service::join_session(&mut dal,
+                                  &session_id,
+                                  &requesting_user.user_id,
+                                  &requesting_user,
+                                  &nickname)?;
+            Ok(JSON(JoinedSession {
+                user_id: requesting_user.user_id,
+                user_token: None,
+            }))
+        },
+        None => {
+            let guest = service::join_as_guest(&mut dal, &session_id, &nickname)?;
+            Ok(JSON(JoinedSession {
+                user_id: guest.user_id,
+                user_token: Some(guest.user_token),
+            }))
+        },
+    }
+}
+
 #[post("/session/<session_id_string>/user/<user_id_string>/vote", data = "<vote_form>")]
 pub fn place_vote(
     session_id_string: String,
     user_id_string: String,
     vote_form: Option<AlwaysJSON<VoteForm>>,
     api_key: APIKey,
+    tenant: Tenant,
     storydata_provider: State<MyStoryDataProvider>
 ) -> Result<JSON<Value>> {
 
@@ -64,9 +123,9 @@ pub fn place_vote(
     let session_id = SessionID(session_id_string);
     let user_id = UserID(user_id_string);
 
-    let mut dal = storydata_provider.get();
-    let requesting_user = super::get_authenticated_user(&*dal, api_key)?;
-    service::place_vote(&mut *dal, &session_id, &user_id, &requesting_user, *vote)?;
+    let mut dal = storydata_provider.get_story_data(&tenant)?;
+    let requesting_user = super::get_authorized_user(&mut dal, api_key, Scope::SessionVote)?;
+    service::place_vote(&mut dal, &session_id, &user_id, &requesting_user, *vote)?;
     Ok(JSON(json!({})))
 }
 
@@ -75,15 +134,22 @@ pub fn kick_user(
     session_id_string: String,
     user_id_string: String,
     api_key: APIKey,
+    tenant: Tenant,
     storydata_provider: State<MyStoryDataProvider>
 ) -> Result<JSON<Value>> {
 
     let session_id = SessionID(session_id_string);
     let user_id = UserID(user_id_string);
 
-    let mut dal = storydata_provider.get();
-    let requesting_user = super::get_authenticated_user(&*dal, api_key)?;
-    service::kick_user(&mut *dal, &session_id, &user_id, &requesting_user)?;
+    let mut dal = storydata_provider.get_story_data(&tenant)?;
+    let requesting_user = super::get_authenticated_user(&mut dal, api_key)?;
+    // Leaving a session is part of taking part in it, removing others is not
+    if requesting_user.user_id == user_id {
+        requesting_user.require_scope(Scope::SessionVote)?;
+    } else {
+        requesting_user.require_scope(Scope::SessionAdmin)?;
+    }
+    service::kick_user(&mut dal, &session_id, &user_id, &requesting_user)?;
     Ok(JSON(json!({})))
 }
 
@@ -92,15 +158,16 @@ pub fn grant_admin(
     session_id_string: String,
     user_id_string: String,
     api_key: APIKey,
+    tenant: Tenant,
     storydata_provider: State<MyStoryDataProvider>
 ) -> Result<JSON<Value>> {
 
     let session_id = SessionID(session_id_string);
     let user_id = UserID(user_id_string);
 
-    let mut dal = storydata_provider.get();
-    let requesting_user = super::get_authenticated_user(&*dal, api_key)?;
-    service::grant_admin(&mut *dal, &session_id, &user_id, &requesting_user)?;
+    let mut dal = storydata_provider.get_story_data(&tenant)?;
+    let requesting_user = super::get_authorized_user(&mut dal, api_key, Scope::SessionAdmin)?;
+    service::grant_admin(&mut dal, &session_id, &user_id, &requesting_user)?;
     Ok(JSON(json!({})))
 }
 
@@ -109,20 +176,185 @@ pub fn revoke_admin(
     session_id_string: String,
     user_id_string: String,
     api_key: APIKey,
+    tenant: Tenant,
+    storydata_provider: State<MyStoryDataProvider>
+) -> Result<JSON<Value>> {
+
+    let session_id = SessionID(session_id_string);
+    let user_id = UserID(user_id_string);
+
+    let mut dal = storydata_provider.get_story_data(&tenant)?;
+    let requesting_user = super::get_authorized_user(&mut dal, api_key, Scope::SessionAdmin)?;
+    service::revoke_admin(&mut dal, &session_id, &user_id, &requesting_user)?;
+    Ok(JSON(json!({})))
+}
+
+#[put("/session/<session_id_string>/role/<user_id_string>", data = "<role_form>")]
+pub fn set_role(
+    session_id_string: String,
+    user_id_string: String,
+    role_form: Option<AlwaysJSON<RoleForm>>,
+    api_key: APIKey,
+    tenant: Tenant,
+    storydata_provider: State<MyStoryDataProvider>
+) -> Result<JSON<Value>> {
+
+    let role = role_form.ok_or(ErrorKind::UserError("Please provide a role".to_string()))?
+        .role;
+    let session_id = SessionID(session_id_string);
+    let user_id = UserID(user_id_string);
+
+    let mut dal = storydata_provider.get_story_data(&tenant)?;
+    let requesting_user = super::get_authorized_user(&mut dal, api_key, Scope::SessionAdmin)?;
+    service::set_role(&mut dal, &session_id, &user_id, role, &requesting_user)?;
+    Ok(JSON(json!({})))
+}
+
+#[put("/session/<session_id_string>/owner/<user_id_string>")]
+pub fn transfer_ownership(
+    session_id_string: String,
+    user_id_string: String,
+    api_key: APIKey,
+    tenant: Tenant,
     storydata_provider: State<MyStoryDataProvider>
 ) -> Result<JSON<Value>> {
 
     let session_id = SessionID(session_id_string);
     let user_id = UserID(user_id_string);
 
-    let mut dal = storydata_provider.get();
-    let requesting_user = super::get_authenticated_user(&*dal, api_key)?;
-    service::revoke_admin(&mut *dal, &session_id, &user_id, &requesting_user)?;
+    let mut dal = storydata_provider.get_story_data(&tenant)?;
+    let requesting_user = super::get_authorized_user(&mut dal, api_key, Scope::SessionAdmin)?;
+    service::transfer_ownership(&mut dal, &session_id, &user_id, &requesting_user)?;
     Ok(JSON(json!({})))
 }
 
 #[cfg(test)]
 mod test {
     use super::super::test::*;
+    use super::JoinedSession;
+    use estimates::session::SessionID;
+    use rocket;
+    use service;
     use webapp;
+
+    fn session_with_admin(mem_data: &SharedMemoryDB) -> (NewUser, SessionID) {
+        let mut dal = mem_data.get_story_data().unwrap();
+        let admin = register_user(&mut dal);
+        let admin_user = service::authenticate_user(&dal, &admin.user_id, &admin.user_token)
+            .unwrap()
+            .unwrap();
+        let session_id = service::create_session(&mut dal, &admin_user).unwrap();
+        (admin, session_id)
+    }
+
+    fn join(
+        rocket: &rocket::Rocket,
+        session_id: &SessionID,
+        auth: Option<Header<'static>>
+    ) -> (Status, Option<JoinedSession>) {
+        let path = format!("/api/session/{}/join", session_id.0);
+        let mut req = MockRequest::new(Method::Post, path).body(r#"{"nickname": "bob"}"#);
+        if let Some(auth) = auth {
+            req.add_header(auth);
+        }
+        let mut response = req.dispatch_with(rocket);
+        let joined = response.body()
+            .and_then(|b| b.into_string())
+            .and_then(|body_str| serde_json::from_str(&body_str).ok());
+        (response.status(), joined)
+    }
+
+    #[test]
+    fn join_as_a_guest() {
+        let mem_data = SharedMemoryDB::new();
+        let (_, session_id) = session_with_admin(&mem_data);
+        let rocket = webapp::build_webapp(mem_data);
+
+        let (status, joined) = join(&rocket, &session_id, None);
+        assert_eq!(status, Status::Ok);
+        let joined = joined.unwrap();
+        let guest = NewUser {
+            user_id: joined.user_id,
+            user_token: joined.user_token.unwrap(),
+        };
+        let path = format!("/api/session/{}/user/{}/vote", session_id.0, guest.user_id);
+        let mut req = MockRequest::new(Method::Post, path).body(r#"{"vote": 3}"#);
+        req.add_header(basic_auth(&guest));
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Ok);
+
+        let (status, _) = join(&rocket, &SessionID("missing".to_string()), None);
+        assert_eq!(status, Status::NotFound);
+    }
+
+    #[test]
+    fn join_with_credentials() {
+        let mem_data = SharedMemoryDB::new();
+        let (admin, session_id) = session_with_admin(&mem_data);
+        let rocket = webapp::build_webapp(mem_data);
+
+        let (status, joined) = join(&rocket, &session_id, Some(basic_auth(&admin)));
+        assert_eq!(status, Status::Ok);
+        assert_eq!(joined,
+                   Some(JoinedSession {
+                       user_id: admin.user_id,
+                       user_token: None,
+                   }));
+
+        // Rather than quietly joining as a guest
+        let (status, _) = join(&rocket, &session_id, Some(basic_auth(&fake_user())));
+        assert_eq!(status, Status::Unauthorized);
+    }
+
+    #[test]
+    fn make_a_voter_an_observer() {
+        let mem_data = SharedMemoryDB::new();
+        let (admin, session_id) = session_with_admin(&mem_data);
+        let rocket = webapp::build_webapp(mem_data);
+        let (_, joined) = join(&rocket, &session_id, None);
+        let guest = joined.unwrap();
+
+        let path = format!("/api/session/{}/role/{}", session_id.0, guest.user_id);
+        let mut req = MockRequest::new(Method::Put, path.clone()).body(r#"{"role": "observer"}"#);
+        req.add_header(basic_auth(&admin));
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Ok);
+        let mut req = MockRequest::new(Method::Put, path).body(r#"{"role": "owner"}"#);
+        req.add_header(basic_auth(&admin));
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::BadRequest);
+
+        let guest = NewUser {
+            user_id: guest.user_id,
+            user_token: guest.user_token.unwrap(),
+        };
+        let path = format!("/api/session/{}/user/{}/vote", session_id.0, guest.user_id);
+        let mut req = MockRequest::new(Method::Post, path).body(r#"{"vote": 3}"#);
+        req.add_header(basic_auth(&guest));
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn transfer_a_session() {
+        let mem_data = SharedMemoryDB::new();
+        let (admin, session_id) = session_with_admin(&mem_data);
+        let rocket = webapp::build_webapp(mem_data);
+        let (_, joined) = join(&rocket, &session_id, None);
+        let guest = joined.unwrap();
+
+        let path = format!("/api/session/{}/owner/{}", session_id.0, guest.user_id);
+        let mut req = MockRequest::new(Method::Put, path);
+        req.add_header(basic_auth(&admin));
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Ok);
+        let path = format!("/api/session/{}/owner/{}", session_id.0, admin.user_id);
+        let mut req = MockRequest::new(Method::Put, path);
+        req.add_header(basic_auth(&admin));
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Forbidden);
+
+        let path = format!("/api/session/{}/admin/{}", session_id.0, admin.user_id);
+        let mut req = MockRequest::new(Method::Delete, path);
+        req.add_header(basic_auth(&admin));
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Ok);
+        let path = format!("/api/session/{}/admin/{}", session_id.0, guest.user_id);
+        let mut req = MockRequest::new(Method::Delete, path);
+        req.add_header(basic_auth(&admin));
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Forbidden);
+    }
 }
@@ -3,14 +3,18 @@
 use super::MyStoryDataProvider;
 
 use errors::*;
+use estimates::audit::AuditEntry;
+use estimates::policy::Policy;
 use estimates::session::{PublicSession, SessionID};
 use estimates::session::SessionState;
 use rocket::State;
 
 use rocket_contrib::{JSON, Value};
 use service;
+use user::Scope;
 
 use webapp::apikey::APIKey;
+use webapp::tenant::Tenant;
 
 use webapp::assumejson::AlwaysJSON;
 
@@ -25,23 +29,25 @@ pub struct SessionStateForm {
 #[post("/session")]
 pub fn create_session(
     api_key: APIKey,
+    tenant: Tenant,
     storydata_provider: State<MyStoryDataProvider>
 ) -> Result<JSON<PublicSession>> {
-    let mut dal = storydata_provider.get();
-    let requesting_user = super::get_authenticated_user(&*dal, api_key)?;
-    let session_id: SessionID = service::create_session(&mut *dal, &requesting_user)?;
-    service::lookup_session(&mut *dal, &session_id).map(|s| JSON(s.unwrap()))
+    let mut dal = storydata_provider.get_story_data(&tenant)?;
+    let requesting_user = super::get_authorized_user(&mut dal, api_key, Scope::SessionAdmin)?;
+    let session_id: SessionID = service::create_session(&mut dal, &requesting_user)?;
+    service::lookup_session(&mut dal, &session_id).map(|s| JSON(s.unwrap()))
 }
 
 #[get("/session/<session_id_string>")]
 pub fn lookup_session(
     session_id_string: String,
+    tenant: Tenant,
     storydata_provider: State<MyStoryDataProvider>
 ) -> Result<JSON<PublicSession>> {
-    let dal = storydata_provider.get();
+    let dal = storydata_provider.get_story_data(&tenant)?;
 
     let session_id = SessionID(session_id_string);
-    service::lookup_session(&*dal, &session_id)
+    service::lookup_session(&dal, &session_id)
         .and_then(|session_opt| {
             session_opt.ok_or(
                     ErrorKind::ObjectNotFound(
@@ -57,12 +63,13 @@ pub fn lookup_session(
 pub fn delete_session(
     session_id_string: String,
     api_key: APIKey,
+    tenant: Tenant,
     storydata_provider: State<MyStoryDataProvider>
 ) -> Result<()> {
-    let mut dal = storydata_provider.get();
+    let mut dal = storydata_provider.get_story_data(&tenant)?;
     let session_id = SessionID(session_id_string);
-    let requesting_user = super::get_authenticated_user(&*dal, api_key)?;
-    service::delete_session(&mut *dal, &session_id, &requesting_user)
+    let requesting_user = super::get_authorized_user(&mut dal, api_key, Scope::SessionAdmin)?;
+    service::delete_session(&mut dal, &session_id, &requesting_user)
 }
 
 #[patch("/session/<session_id_string>", data = "<session_state>")]
@@ -70,24 +77,57 @@ pub fn update_session(
     session_id_string: String,
     api_key: APIKey,
     session_state: Option<AlwaysJSON<SessionStateForm>>,
+    tenant: Tenant,
     storydata_provider: State<MyStoryDataProvider>
 ) -> Result<JSON<Value>> {
-    let mut dal = storydata_provider.get();
+    let mut dal = storydata_provider.get_story_data(&tenant)?;
     let session_id = SessionID(session_id_string);
 
     let ref state = session_state
         .ok_or(ErrorKind::UserError("Please provide a state for the session".to_string()))?
         .state;
 
-    let requesting_user = super::get_authenticated_user(&*dal, api_key)?;
-    service::update_session(&mut *dal, &session_id, &state, &requesting_user)?;
+    let requesting_user = super::get_authorized_user(&mut dal, api_key, Scope::SessionAdmin)?;
+    service::update_session(&mut dal, &session_id, &state, &requesting_user)?;
     Ok(JSON(json!({})))
 }
 
+#[put("/session/<session_id_string>/policy", data = "<policy>")]
+pub fn update_policy(
+    session_id_string: String,
+    api_key: APIKey,
+    policy: Option<AlwaysJSON<Policy>>,
+    tenant: Tenant,
+    storydata_provider: State<MyStoryDataProvider>
+) -> Result<JSON<Value>> {
+    let mut dal = storydata_provider.get_story_data(&tenant)?;
+    let session_id = SessionID(session_id_string);
+
+    let policy = policy.ok_or(ErrorKind::UserError("Please provide a policy".to_string()))?;
+
+    let requesting_user = super::get_authorized_user(&mut dal, api_key, Scope::SessionAdmin)?;
+    service::update_policy(&mut dal, &session_id, &policy, &requesting_user)?;
+    Ok(JSON(json!({})))
+}
+
+#[get("/session/<session_id_string>/audit")]
+pub fn audit_log(
+    session_id_string: String,
+    api_key: APIKey,
+    tenant: Tenant,
+    storydata_provider: State<MyStoryDataProvider>
+) -> Result<JSON<Vec<AuditEntry>>> {
+    let mut dal = storydata_provider.get_story_data(&tenant)?;
+    let session_id = SessionID(session_id_string);
+    let requesting_user = super::get_authorized_user(&mut dal, api_key, Scope::SessionRead)?;
+    service::audit_log(&dal, &session_id, &requesting_user).map(|log| JSON(log))
+}
+
 
 #[cfg(test)]
 mod test {
     use super::super::test::*;
+    use user::NewToken;
     use webapp;
 
 
@@ -106,7 +146,7 @@ mod test {
     #[test]
     fn create_session() {
         let mem_data = SharedMemoryDB::new();
-        let alice_header = basic_auth(&register_user(&mut *mem_data.get()));
+        let alice_header = basic_auth(&register_user(&mut mem_data.get_story_data().unwrap()));
         let rocket = webapp::build_webapp(mem_data);
         let mut req = MockRequest::new(Method::Post, "/api/session");
         req.add_header(alice_header);
@@ -140,7 +180,7 @@ mod test {
     #[test]
     fn lookup_session() {
         let mem_data = SharedMemoryDB::new();
-        let alice_header = basic_auth(&register_user(&mut *mem_data.get()));
+        let alice_header = basic_auth(&register_user(&mut mem_data.get_story_data().unwrap()));
         let rocket = webapp::build_webapp(mem_data);
         let mut req = MockRequest::new(Method::Post, "/api/session");
         req.add_header(alice_header);
@@ -176,7 +216,7 @@ mod test {
     #[test]
     fn delete_session() {
         let mem_data = SharedMemoryDB::new();
-        let alice_header = basic_auth(&register_user(&mut *mem_data.get()));
+        let alice_header = basic_auth(&register_user(&mut mem_data.get_story_data().unwrap()));
         let rocket = webapp::build_webapp(mem_data);
         let mut req = MockRequest::new(Method::Post, "/api/session");
         req.add_header(alice_header.clone());
@@ -202,7 +242,7 @@ mod test {
     #[test]
     fn delete_session_without_auth() {
         let mem_data = SharedMemoryDB::new();
-        let alice_header = basic_auth(&register_user(&mut *mem_data.get()));
+        let alice_header = basic_auth(&register_user(&mut mem_data.get_story_data().unwrap()));
         let rocket = webapp::build_webapp(mem_data);
         let mut req = MockRequest::new(Method::Post, "/api/session");
         req.add_header(alice_header);
@@ -231,7 +271,7 @@ mod test {
     #[test]
     fn delete_session_with_bad_auth() {
         let mem_data = SharedMemoryDB::new();
-        let alice_header = basic_auth(&register_user(&mut *mem_data.get()));
+        let alice_header = basic_auth(&register_user(&mut mem_data.get_story_data().unwrap()));
         let bob_header = basic_auth(&fake_user());
         let rocket = webapp::build_webapp(mem_data);
         let mut req = MockRequest::new(Method::Post, "/api/session");
@@ -262,8 +302,8 @@ mod test {
     #[test]
     fn delete_session_with_non_admin_auth() {
         let mem_data = SharedMemoryDB::new();
-        let alice_header = basic_auth(&register_user(&mut *mem_data.get()));
-        let bob_header = basic_auth(&register_user(&mut *mem_data.get()));
+        let alice_header = basic_auth(&register_user(&mut mem_data.get_story_data().unwrap()));
+        let bob_header = basic_auth(&register_user(&mut mem_data.get_story_data().unwrap()));
         let rocket = webapp::build_webapp(mem_data);
         let mut req = MockRequest::new(Method::Post, "/api/session");
         req.add_header(alice_header);
@@ -289,4 +329,80 @@ mod test {
         let v: Value = serde_json::from_str(&body_str).expect("could not get serde value");
         check_is_session(&v);
     }
+
+    #[test]
+    fn delete_session_with_read_only_token() {
+        let mem_data = SharedMemoryDB::new();
+        let alice = register_user(&mut mem_data.get_story_data().unwrap());
+        let rocket = webapp::build_webapp(mem_data);
+        let mut req = MockRequest::new(Method::Post, "/api/session");
+        req.add_header(basic_auth(&alice));
+        let mut response = req.dispatch_with(&rocket);
+        let body_str = response.body().and_then(|b| b.into_string()).unwrap();
+        let v: Value = serde_json::from_str(&body_str).unwrap();
+        let session_id = v.get("session_id").map(|sv| sv.as_str().unwrap()).unwrap();
+
+        let mut req = MockRequest::new(Method::Post, "/api/user/token")
+            .body(r#"{"name": "dashboard", "scopes": ["session:read"]}"#);
+        req.add_header(basic_auth(&alice));
+        let mut response = req.dispatch_with(&rocket);
+        assert_eq!(response.status(), Status::Ok);
+        let body_str = response.body().and_then(|b| b.into_string()).unwrap();
+        let token: NewToken = serde_json::from_str(&body_str).unwrap();
+        let dashboard = NewUser {
+            user_id: alice.user_id.clone(),
+            user_token: token.user_token,
+        };
+
+        let mut req = MockRequest::new(Method::Get, "/api/user");
+        req.add_header(basic_auth(&dashboard));
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Ok);
+        for method in &[Method::Delete, Method::Patch] {
+            let mut req = MockRequest::new(*method, format!("/api/session/{}", session_id))
+                .body(r#"{"state": "Visible"}"#);
+            req.add_header(basic_auth(&dashboard));
+            assert_eq!(req.dispatch_with(&rocket).status(), Status::Forbidden);
+        }
+        let mut req = MockRequest::new(Method::Post, "/api/session");
+        req.add_header(basic_auth(&dashboard));
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Forbidden);
+
+        let mut req = MockRequest::new(Method::Get, format!("/api/session/{}", session_id));
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Ok);
+    }
+
+    #[test]
+    fn read_the_audit_log() {
+        let mem_data = SharedMemoryDB::new();
+        let alice_header = basic_auth(&register_user(&mut mem_data.get_story_data().unwrap()));
+        let bob_header = basic_auth(&register_user(&mut mem_data.get_story_data().unwrap()));
+        let rocket = webapp::build_webapp(mem_data);
+        let mut req = MockRequest::new(Method::Post, "/api/session");
+        req.add_header(alice_header.clone());
+        let mut response = req.dispatch_with(&rocket);
+        let body_str = response.body().and_then(|b| b.into_string()).unwrap();
+        let v: Value = serde_json::from_str(&body_str).unwrap();
+        let session_id = v.get("session_id").map(|sv| sv.as_str().unwrap()).unwrap();
+
+        let mut req = MockRequest::new(Method::Patch, format!("/api/session/{}", session_id))
+            .body(r#"{"state": "Visible"}"#);
+        req.add_header(alice_header.clone());
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Ok);
+
+        let mut req = MockRequest::new(Method::Get, format!("/api/session/{}/audit", session_id));
+        req.add_header(alice_header);
+        let mut response = req.dispatch_with(&rocket);
+        assert_eq!(response.status(), Status::Ok);
+        let body_str = response.body().and_then(|b| b.into_string()).unwrap();
+        let v: Value = serde_json::from_str(&body_str).unwrap();
+        let entries = v.as_array().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].get("action").and_then(|a| a.as_str()),
+                   Some("update_session"));
+        assert_eq!(entries[0].get("detail").and_then(|d| d.as_str()), Some("Visible"));
+
+        let mut req = MockRequest::new(Method::Get, format!("/api/session/{}/audit", session_id));
+        req.add_header(bob_header);
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Forbidden);
+    }
 }
@@ -4,34 +4,176 @@ use super::MyStoryDataProvider;
 
 use errors::*;
 use rocket::State;
+use rocket::http::Cookies;
 
 
 use rocket_contrib::{JSON, Value};
 use service;
-use user::BasicUser;
+use user::{BearerToken, NewToken, NewUser, PublicToken, Scope, TokenID};
 use webapp::apikey::APIKey;
+use webapp::assumejson::AlwaysJSON;
+use webapp::auth::Credentials;
+use webapp::cookiesession::{self, CookieSession};
+use webapp::proxydata::ProxyData;
+use webapp::tenant::Tenant;
+
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TokenForm {
+    name: String,
+    /// What the token allows, by default everything the requesting token does
+    /// that a device token may
+    #[serde(default)]
+    scopes: Option<Vec<Scope>>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BearerForm {
+    /// What the token allows, by default everything the requesting token does
+    #[serde(default)]
+    scopes: Option<Vec<Scope>>,
+}
 
 #[post("/user")]
 //fn create_user(pool: State<RedisPool>) -> Result<CORS<JSON<Value>>> {
-pub fn create_user(storydata_provider: State<MyStoryDataProvider>) -> Result<JSON<BasicUser>> {
-    let mut dal = storydata_provider.get();
-    service::create_user(&mut *dal).map(|u| JSON(u))
+pub fn create_user(
+    tenant: Tenant,
+    storydata_provider: State<MyStoryDataProvider>
+) -> Result<JSON<NewUser>> {
+    let mut dal = storydata_provider.get_story_data(&tenant)?;
+    service::create_user(&mut dal).map(|u| JSON(u))
 }
 
 #[get("/user")]
 pub fn check_user(
     api_key: APIKey,
+    tenant: Tenant,
     storydata_provider: State<MyStoryDataProvider>
 ) -> Result<JSON<Value>> {
-    let dal = storydata_provider.get();
-    let _ = super::get_authenticated_user(&*dal, api_key)?;
+    let mut dal = storydata_provider.get_story_data(&tenant)?;
+    let _ = super::get_authenticated_user(&mut dal, api_key)?;
     Ok(JSON(json!({})))
 }
 
+#[post("/user/token", data = "<token_form>")]
+pub fn create_token(
+    token_form: Option<AlwaysJSON<TokenForm>>,
+    api_key: APIKey,
+    tenant: Tenant,
+    storydata_provider: State<MyStoryDataProvider>
+) -> Result<JSON<NewToken>> {
+    let token_form = token_form
+        .ok_or(ErrorKind::UserError("Please provide a name for the token".to_string()))?;
+    let mut dal = storydata_provider.get_story_data(&tenant)?;
+    let requesting_user = super::get_authorized_user(&mut dal, api_key, Scope::UserTokens)?;
+    let scopes = token_form.scopes.clone().unwrap_or(requesting_user.scopes
+        .iter()
+        .filter(|s| Scope::device().contains(s))
+        .cloned()
+        .collect());
+    service::create_token(&mut dal, &requesting_user, &token_form.name, &scopes)
+        .map(|t| JSON(t))
+}
+
+#[get("/user/token")]
+pub fn list_tokens(
+    api_key: APIKey,
+    tenant: Tenant,
+    storydata_provider: State<MyStoryDataProvider>
+) -> Result<JSON<Vec<PublicToken>>> {
+    let mut dal = storydata_provider.get_story_data(&tenant)?;
+    let requesting_user = super::get_authorized_user(&mut dal, api_key, Scope::UserTokens)?;
+    service::list_tokens(&dal, &requesting_user).map(|t| JSON(t))
+}
+
+#[delete("/user/token/<token_id_string>")]
+pub fn revoke_token(
+    token_id_string: String,
+    api_key: APIKey,
+    tenant: Tenant,
+    storydata_provider: State<MyStoryDataProvider>
+) -> Result<()> {
+    let mut dal = storydata_provider.get_story_data(&tenant)?;
+    let requesting_user = super::get_authorized_user(&mut dal, api_key, Scope::UserTokens)?;
+    service::revoke_token(&mut dal, &requesting_user, &TokenID(token_id_string))
+}
+
+/// Exchange a user's token for a short lived bearer token
+#[post("/user/bearer", data = "<bearer_form>")]
+pub fn issue_bearer(
+    bearer_form: Option<AlwaysJSON<BearerForm>>,
+    api_key: APIKey,
+    tenant: Tenant,
+    storydata_provider: State<MyStoryDataProvider>
+) -> Result<JSON<BearerToken>> {
+    // Otherwise a bearer token could be kept alive forever
+    if let Credentials::Bearer(_) = api_key.credentials {
+        bail!(ErrorKind::UserForbidden("A bearer token can't be exchanged for another"
+            .to_string()));
+    }
+    let authenticators = api_key.authenticators.clone();
+    let mut dal = storydata_provider.get_story_data(&tenant)?;
+    let requesting_user = super::get_authenticated_user(&mut dal, api_key)?;
+    let scopes = bearer_form.and_then(|f| f.scopes.clone())
+        .unwrap_or(requesting_user.scopes.clone());
+    service::issue_bearer(authenticators.signer(),
+                          &requesting_user,
+                          &scopes,
+                          tenant.0.as_ref().map(|t| t.as_str()))
+        .map(|t| JSON(t))
+}
+
+/// Sign a browser in with a cookie, so it needn't keep the user's token
+#[post("/user/login")]
+pub fn login(
+    api_key: APIKey,
+    tenant: Tenant,
+    cookies: &Cookies,
+    proxy_data: ProxyData,
+    storydata_provider: State<MyStoryDataProvider>
+) -> Result<JSON<CookieSession>> {
+    // Otherwise a cookie could be kept alive forever
+    if let Credentials::Bearer(_) = api_key.credentials {
+        bail!(ErrorKind::UserForbidden("A bearer token or cookie can't be exchanged for a cookie"
+            .to_string()));
+    }
+    let authenticators = api_key.authenticators.clone();
+    let mut dal = storydata_provider.get_story_data(&tenant)?;
+    let requesting_user = super::get_authenticated_user(&mut dal, api_key)?;
+    Ok(JSON(cookiesession::start(cookies,
+                                 authenticators.signer(),
+                                 &requesting_user,
+                                 &tenant,
+                                 proxy_data.scheme == "https")))
+}
+
+/// Have the browser forget its session cookie
+#[post("/user/logout")]
+pub fn logout(cookies: &Cookies) -> Result<()> {
+    cookiesession::end(cookies);
+    Ok(())
+}
+
+#[post("/user/rotate")]
+pub fn rotate_token(
+    api_key: APIKey,
+    tenant: Tenant,
+    storydata_provider: State<MyStoryDataProvider>
+) -> Result<JSON<NewUser>> {
+    let mut dal = storydata_provider.get_story_data(&tenant)?;
+    let requesting_user = super::get_authorized_user(&mut dal, api_key, Scope::UserAccount)?;
+    service::rotate_token(&mut dal, &requesting_user).map(|u| JSON(u))
+}
+
 #[cfg(test)]
 mod test {
     use super::super::test::*;
+    use rocket;
+    use rocket::http::Cookie;
+    use user::{BearerToken, NewToken, PublicToken, UserID, UserToken};
     use webapp;
+    use webapp::cookiesession::CookieSession;
 
     #[test]
     fn create_user_has_fields() {
@@ -46,6 +188,7 @@ mod test {
         if let Value::Object(user_map) = v {
             assert!(user_map.contains_key("user_id"));
             assert!(user_map.contains_key("user_token"));
+            assert!(!user_map.contains_key("token_hash"));
         } else {
             panic!("JSON for user data was not an object: {:?}", v);
         }
@@ -54,7 +197,7 @@ mod test {
     #[test]
     fn check_user() {
         let mem_data = SharedMemoryDB::new();
-        let alice_header = basic_auth(&register_user(&mut *mem_data.get()));
+        let alice_header = basic_auth(&register_user(&mut mem_data.get_story_data().unwrap()));
         let rocket = webapp::build_webapp(mem_data);
         let mut req = MockRequest::new(Method::Get, "/api/user");
         req.add_header(alice_header);
@@ -82,4 +225,132 @@ mod test {
         assert_eq!(response.status(), Status::Unauthorized);
     }
 
+    fn check_with(rocket: &rocket::Rocket, user_id: &UserID, user_token: &UserToken) -> Status {
+        let mut req = MockRequest::new(Method::Get, "/api/user");
+        req.add_header(Header::new("X-API-Key", format!("{}:{}", user_id, user_token.0)));
+        req.dispatch_with(rocket).status()
+    }
+
+    #[test]
+    fn make_use_and_revoke_device_tokens() {
+        let mem_data = SharedMemoryDB::new();
+        let alice = register_user(&mut mem_data.get_story_data().unwrap());
+        let rocket = webapp::build_webapp(mem_data);
+
+        let mut req = MockRequest::new(Method::Post, "/api/user/token")
+            .body(r#"{"name": "phone"}"#);
+        req.add_header(basic_auth(&alice));
+        let mut response = req.dispatch_with(&rocket);
+        assert_eq!(response.status(), Status::Ok);
+        let body_str = response.body().and_then(|b| b.into_string()).unwrap();
+        let phone: NewToken = serde_json::from_str(&body_str).unwrap();
+        assert_eq!(check_with(&rocket, &alice.user_id, &phone.user_token), Status::Ok);
+
+        let mut req = MockRequest::new(Method::Get, "/api/user/token");
+        req.add_header(basic_auth(&alice));
+        let mut response = req.dispatch_with(&rocket);
+        let body_str = response.body().and_then(|b| b.into_string()).unwrap();
+        assert!(!body_str.contains(&phone.user_token.0));
+        let tokens: Vec<PublicToken> = serde_json::from_str(&body_str).unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].token_id, phone.token_id);
+
+        let path = format!("/api/user/token/{}", phone.token_id);
+        let mut req = MockRequest::new(Method::Delete, path);
+        req.add_header(basic_auth(&alice));
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Ok);
+        assert_eq!(check_with(&rocket, &alice.user_id, &phone.user_token),
+                   Status::Unauthorized);
+    }
+
+    #[test]
+    fn rotate_the_primary_token() {
+        let mem_data = SharedMemoryDB::new();
+        let alice = register_user(&mut mem_data.get_story_data().unwrap());
+        let rocket = webapp::build_webapp(mem_data);
+
+        let mut req = MockRequest::new(Method::Post, "/api/user/rotate");
+        req.add_header(basic_auth(&alice));
+        let mut response = req.dispatch_with(&rocket);
+        assert_eq!(response.status(), Status::Ok);
+        let body_str = response.body().and_then(|b| b.into_string()).unwrap();
+        let rotated: NewUser = serde_json::from_str(&body_str).unwrap();
+        assert_eq!(check_with(&rocket, &alice.user_id, &alice.user_token),
+                   Status::Unauthorized);
+        assert_eq!(check_with(&rocket, &alice.user_id, &rotated.user_token), Status::Ok);
+    }
+
+    #[test]
+    fn exchange_for_a_bearer_token() {
+        let mem_data = SharedMemoryDB::new();
+        let alice = register_user(&mut mem_data.get_story_data().unwrap());
+        let rocket = webapp::build_webapp(mem_data);
+
+        let mut req = MockRequest::new(Method::Post, "/api/user/bearer")
+            .body(r#"{"scopes": ["session:read"]}"#);
+        req.add_header(basic_auth(&alice));
+        let mut response = req.dispatch_with(&rocket);
+        assert_eq!(response.status(), Status::Ok);
+        let body_str = response.body().and_then(|b| b.into_string()).unwrap();
+        let bearer: BearerToken = serde_json::from_str(&body_str).unwrap();
+        assert_eq!(bearer.token_type, "Bearer");
+        let auth = Header::new("Authorization", format!("Bearer {}", bearer.access_token));
+
+        let mut req = MockRequest::new(Method::Get, "/api/user");
+        req.add_header(auth.clone());
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Ok);
+        let mut req = MockRequest::new(Method::Post, "/api/session");
+        req.add_header(auth.clone());
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Forbidden);
+        let mut req = MockRequest::new(Method::Post, "/api/user/bearer");
+        req.add_header(auth);
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Forbidden);
+
+        let forged = format!("Bearer {}x", bearer.access_token);
+        let mut req = MockRequest::new(Method::Get, "/api/user");
+        req.add_header(Header::new("Authorization", forged));
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn sign_in_with_a_cookie() {
+        let mem_data = SharedMemoryDB::new();
+        let alice = register_user(&mut mem_data.get_story_data().unwrap());
+        let rocket = webapp::build_webapp(mem_data);
+
+        let mut req = MockRequest::new(Method::Post, "/api/user/login");
+        req.add_header(basic_auth(&alice));
+        let mut response = req.dispatch_with(&rocket);
+        assert_eq!(response.status(), Status::Ok);
+        let set_cookie = response.headers().get_one("Set-Cookie").unwrap().to_string();
+        assert!(set_cookie.contains("HttpOnly"));
+        let value = set_cookie.split(';').next().unwrap()["estimates_session=".len()..]
+            .to_string();
+        let body_str = response.body().and_then(|b| b.into_string()).unwrap();
+        let session: CookieSession = serde_json::from_str(&body_str).unwrap();
+        assert_eq!(session.user_id, alice.user_id);
+        let cookie = || Cookie::new("estimates_session".to_string(), value.clone());
+
+        let mut req = MockRequest::new(Method::Get, "/api/user").cookie(cookie());
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Ok);
+
+        // Other sites can make the browser send the cookie, but not the CSRF token
+        let mut req = MockRequest::new(Method::Post, "/api/session").cookie(cookie());
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Forbidden);
+        let mut req = MockRequest::new(Method::Post, "/api/session").cookie(cookie());
+        req.add_header(Header::new("X-CSRF-Token", "forged"));
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Forbidden);
+        let mut req = MockRequest::new(Method::Post, "/api/session").cookie(cookie());
+        req.add_header(Header::new("X-CSRF-Token", session.csrf_token.clone()));
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Ok);
+
+        let mut req = MockRequest::new(Method::Post, "/api/user/login").cookie(cookie());
+        req.add_header(Header::new("X-CSRF-Token", session.csrf_token));
+        assert_eq!(req.dispatch_with(&rocket).status(), Status::Forbidden);
+
+        let mut req = MockRequest::new(Method::Post, "/api/user/logout").cookie(cookie());
+        let response = req.dispatch_with(&rocket);
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.headers().get_one("Set-Cookie").unwrap().contains("Max-Age=0"));
+    }
 }
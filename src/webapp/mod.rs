@@ -1,23 +1,32 @@
 #![allow(unmounted_route)]
+use config::BearerConfig;
 use dal;
 use errors::*;
 use rocket;
 use rocket::http::Method;
 use std::path::PathBuf;
+use user::BearerSigner;
 
 // Modules containing HTTP helpers
 mod apikey;
+mod auth;
 mod proxydata;
 mod filelike;
 mod errors;
 mod cors;
+mod cookiesession;
 mod assumejson;
+mod tenant;
 
 // Modules containing endpoints
 mod estimates;
 mod swagger;
 
 
+pub use self::auth::{Authenticator, Authenticators, Credentials, HttpClient, HyperClient,
+                     OidcAuthenticator};
+pub use self::tenant::{Tenant, Tenants};
+
 const WELCOME_TEXT: &'static str = "Welcome to the StoryEstimates WebApp!";
 
 #[get("/")]
@@ -43,19 +52,47 @@ fn cors_preflight(any_url: PathBuf) -> self::cors::PreflightCORS {
                        "Accept",
                        "Authorization",
                        "X-Requested-With",
-                       "X-API-Key"])
+                       "X-API-Key",
+                       "X-CSRF-Token"])
 }
 
 pub fn build_webapp<P>(storydata_provider: P) -> rocket::Rocket
-    where P: 'static,
-          P: dal::StoryDataProvider
+    where P: Into<dal::StoryBackend>
 {
-    rocket::ignite()
-        .mount("/", routes![hello, cors_preflight])
-        .mount("/api", self::estimates::routes())
-        .mount("/docs", self::swagger::routes())
+    mount_webapp(rocket::ignite(),
+                 Tenants::new(storydata_provider.into()),
+                 Authenticators::new(bearer_signer(&BearerConfig::default())))
+}
+
+/// Sign bearer tokens as configured, with a random secret if none was
+pub fn bearer_signer(config: &BearerConfig) -> BearerSigner {
+    let signer = match config.secret {
+        Some(ref secret) => BearerSigner::new(secret.as_bytes(), config.ttl),
+        None => {
+            warn!("No bearer_secret is set, so bearer tokens stop working on restart");
+            BearerSigner::random(config.ttl)
+        },
+    };
+    signer.with_cookie_ttl(config.cookie_ttl)
+}
+
+/// Attach the StoryEstimates routes and data to an already configured `Rocket`
+///
+/// Every tenant other than the default one gets a copy of the API under its name.
+pub fn mount_webapp(
+    rocket: rocket::Rocket,
+    tenants: Tenants,
+    authenticators: Authenticators
+) -> rocket::Rocket {
+    let mut rocket = rocket.mount("/", routes![hello, cors_preflight])
+        .mount("/api", self::estimates::routes());
+    for name in tenants.names() {
+        rocket = rocket.mount(&format!("/{}/api", name), self::estimates::routes());
+    }
+    rocket.mount("/docs", self::swagger::routes())
         .catch(self::errors::errors())
-        .manage(storydata_provider)
+        .manage(tenants)
+        .manage(authenticators)
 }
 
 #[cfg(test)]
@@ -0,0 +1,223 @@
+//! Serving several tenants, each with data of its own, from one process
+//!
+//! A request is for a tenant when its path starts with the tenant's name, as
+//! in `/acme/api/session`, or when it was made to one of the tenant's hosts.
+//! Everything else is for the default tenant, which is the only one when no
+//! tenants are configured.
+
+use config::TenantConfig;
+use dal::{StoryBackend, StoryBackendInstance, StoryDataProvider};
+use errors::*;
+use rocket::{Outcome, State};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+
+struct NamedTenant {
+    config: TenantConfig,
+    backend: StoryBackend,
+}
+
+/// The backend of every tenant
+pub struct Tenants {
+    default: StoryBackend,
+    named: Vec<NamedTenant>,
+}
+
+/// The tenant a request is for, `None` being the default tenant
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tenant(pub Option<String>);
+
+impl Tenants {
+    /// Serve only the default tenant
+    pub fn new(default: StoryBackend) -> Tenants {
+        Tenants {
+            default: default,
+            named: Vec::new(),
+        }
+    }
+
+    /// Serve the configured tenants as well, each from a part of the default backend
+    pub fn from_config(default: StoryBackend, configs: Vec<TenantConfig>) -> Result<Tenants> {
+        let mut named = Vec::new();
+        for config in configs {
+            let backend = default.tenant(&config.name)?;
+            named.push(NamedTenant {
+                config: config,
+                backend: backend,
+            });
+        }
+        Ok(Tenants {
+            default: default,
+            named: named,
+        })
+    }
+
+    /// The names of every tenant other than the default one
+    pub fn names(&self) -> Vec<&str> {
+        self.named.iter().map(|t| t.config.name.as_str()).collect()
+    }
+
+    /// Every backend, starting with the default tenant's
+    pub fn backends(&self) -> Vec<&StoryBackend> {
+        let mut backends = vec![&self.default];
+        backends.extend(self.named.iter().map(|t| &t.backend));
+        backends
+    }
+
+    /// Work out which tenant a request is for, a path prefix taking precedence over the host
+    pub fn resolve(&self, path: &str, host: Option<&str>) -> Tenant {
+        for tenant in &self.named {
+            if path.starts_with(&format!("/{}/", tenant.config.name)) {
+                return Tenant(Some(tenant.config.name.clone()));
+            }
+        }
+        // The Host header may include a port
+        let host = host.and_then(|h| h.split(':').next()).unwrap_or("").to_lowercase();
+        for tenant in &self.named {
+            if tenant.config.hosts.contains(&host) {
+                return Tenant(Some(tenant.config.name.clone()));
+            }
+        }
+        Tenant(None)
+    }
+
+    /// A handle to the data of a tenant
+    pub fn get_story_data(&self, tenant: &Tenant) -> Result<StoryBackendInstance> {
+        let backend = match tenant.0 {
+            None => &self.default,
+            Some(ref name) => {
+                self.named
+                    .iter()
+                    .find(|t| t.config.name == *name)
+                    .map(|t| &t.backend)
+                    .ok_or(ErrorKind::ResourceNotFound(format!("tenant {}", name)))?
+            },
+        };
+        backend.get_story_data()
+    }
+}
+
+impl From<StoryBackend> for Tenants {
+    fn from(backend: StoryBackend) -> Tenants {
+        Tenants::new(backend)
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Tenant {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Tenant, ()> {
+        match State::<Tenants>::from_request(request) {
+            Outcome::Success(tenants) => {
+                let host = request.headers().get_one("Host");
+                Outcome::Success(tenants.resolve(request.uri().path(), host))
+            },
+            _ => {
+                warn!("No tenants are being managed");
+                Outcome::Failure((Status::InternalServerError, ()))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dal::SharedMemoryDB;
+    use rocket;
+    use rocket::http::{Header, Method};
+    use rocket::testing::MockRequest;
+    use serde_json;
+    use std::time::Duration;
+    use user::{BearerSigner, BearerToken, NewUser};
+    use webapp;
+    use webapp::auth::Authenticators;
+
+    fn authenticators() -> Authenticators {
+        Authenticators::new(BearerSigner::random(Duration::from_secs(60)))
+    }
+
+    fn tenants() -> Tenants {
+        let mut tenants = Tenants::new(SharedMemoryDB::new().into());
+        for &(name, ref hosts) in &[("acme", vec!["estimates.acme.com"]), ("globex", vec![])] {
+            tenants.named.push(NamedTenant {
+                config: TenantConfig {
+                    name: name.to_string(),
+                    hosts: hosts.iter().map(|h| h.to_string()).collect(),
+                },
+                backend: SharedMemoryDB::new().into(),
+            });
+        }
+        tenants
+    }
+
+    #[test]
+    fn resolve_by_path() {
+        let tenants = tenants();
+        assert_eq!(tenants.resolve("/acme/api/user", None), Tenant(Some("acme".to_string())));
+        assert_eq!(tenants.resolve("/globex/api/user", Some("estimates.acme.com")),
+                   Tenant(Some("globex".to_string())));
+        assert_eq!(tenants.resolve("/acmecorp/api/user", None), Tenant(None));
+        assert_eq!(tenants.resolve("/api/user", None), Tenant(None));
+    }
+
+    #[test]
+    fn resolve_by_host() {
+        let tenants = tenants();
+        assert_eq!(tenants.resolve("/api/user", Some("Estimates.Acme.com:8000")),
+                   Tenant(Some("acme".to_string())));
+        assert_eq!(tenants.resolve("/api/user", Some("estimates.example.com")), Tenant(None));
+    }
+
+    #[test]
+    fn unknown_tenant_has_no_data() {
+        let tenants = tenants();
+        assert!(tenants.get_story_data(&Tenant(Some("initech".to_string()))).is_err());
+        assert!(tenants.get_story_data(&Tenant(Some("acme".to_string()))).is_ok());
+    }
+
+    #[test]
+    fn tenants_are_served_under_their_name() {
+        let rocket = webapp::mount_webapp(rocket::ignite(), tenants(), authenticators());
+        let mut req = MockRequest::new(Method::Post, "/acme/api/user");
+        let mut response = req.dispatch_with(&rocket);
+        assert_eq!(response.status(), Status::Ok);
+        let body_str = response.body().and_then(|b| b.into_string()).unwrap();
+        let user: NewUser = serde_json::from_str(&body_str).unwrap();
+
+        let api_key = format!("{}:{}", user.user_id, user.user_token.0);
+        for &(path, status) in &[("/acme/api/user", Status::Ok),
+                                 ("/api/user", Status::Unauthorized),
+                                 ("/globex/api/user", Status::Unauthorized)] {
+            let mut req = MockRequest::new(Method::Get, path);
+            req.add_header(Header::new("X-API-Key", api_key.clone()));
+            assert_eq!(req.dispatch_with(&rocket).status(), status, "{}", path);
+        }
+    }
+
+    #[test]
+    fn bearer_tokens_only_work_for_their_tenant() {
+        let rocket = webapp::mount_webapp(rocket::ignite(), tenants(), authenticators());
+        let mut req = MockRequest::new(Method::Post, "/acme/api/user");
+        let mut response = req.dispatch_with(&rocket);
+        let body_str = response.body().and_then(|b| b.into_string()).unwrap();
+        let user: NewUser = serde_json::from_str(&body_str).unwrap();
+
+        let mut req = MockRequest::new(Method::Post, "/acme/api/user/bearer");
+        req.add_header(Header::new("X-API-Key",
+                                   format!("{}:{}", user.user_id, user.user_token.0)));
+        let mut response = req.dispatch_with(&rocket);
+        assert_eq!(response.status(), Status::Ok);
+        let body_str = response.body().and_then(|b| b.into_string()).unwrap();
+        let bearer: BearerToken = serde_json::from_str(&body_str).unwrap();
+
+        let auth = format!("Bearer {}", bearer.access_token);
+        for &(path, status) in &[("/acme/api/user", Status::Ok),
+                                 ("/api/user", Status::Unauthorized),
+                                 ("/globex/api/user", Status::Unauthorized)] {
+            let mut req = MockRequest::new(Method::Get, path);
+            req.add_header(Header::new("Authorization", auth.clone()));
+            assert_eq!(req.dispatch_with(&rocket).status(), status, "{}", path);
+        }
+    }
+}
@@ -128,3 +128,9 @@ class StoryClient:
 
     def revoke_admin(self, user_id):
         self._do_delete('api/session/{}/admin/{}'.format(self.session, user_id))
+
+    def transfer_ownership(self, user_id):
+        self._do_put('api/session/{}/owner/{}'.format(self.session, user_id), {})
+
+    def audit_log(self):
+        return self._do_get('api/session/{}/audit'.format(self.session), {})
@@ -320,10 +320,35 @@ def test_revoke_self_admin(url, port):
     assert bob.user_id in lookup_data['admins']
     assert bill.user_id not in lookup_data['admins']
 
+    # The owner of the session, who is also its only admin
+    with pytest.raises(requests.HTTPError) as excinfo:
+        bob.revoke_admin(bob.user_id)
+    assert excinfo.value.response.status_code == 400
+    lookup_data = bob.lookup_session()
+    assert bob.user_id in lookup_data['admins']
+
+def test_transfer_ownership(url, port):
+    bob, bill = _create_active_session(url, port)
+
+    bob.transfer_ownership(bill.user_id)
+    lookup_data = bob.lookup_session()
+    assert lookup_data['owner'] == bill.user_id
+    assert bill.user_id in lookup_data['admins']
+
     bob.revoke_admin(bob.user_id)
     lookup_data = bob.lookup_session()
-    assert bob.user_id not in lookup_data['admins']
-    assert bill.user_id not in lookup_data['admins']
+    assert lookup_data['admins'] == [bill.user_id]
+
+def test_audit_log(url, port):
+    bob, bill = _create_active_session(url, port)
+
+    bob.grant_admin(bill.user_id)
+    bill.reveal_votes()
+    entries = bob.audit_log()
+    assert [e['action'] for e in entries] == ['set_role', 'update_session']
+    assert entries[0]['actor'] == bob.user_id
+    assert entries[0]['target'] == bill.user_id
+    assert entries[1]['actor'] == bill.user_id
 
 def test_grant_admin_to_admin(url, port):
     bob, bill = _create_active_session(url, port)